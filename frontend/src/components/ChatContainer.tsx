@@ -2,11 +2,12 @@ import React, { useState, useRef, useEffect } from 'react';
 import { useChatStore } from '../store/chatStore';
 import { useWebSocket } from '../hooks/useWebSocket';
 import Message from './Message';
+import { api } from '../services/api';
 
 const ChatContainer: React.FC = () => {
   const [input, setInput] = useState('');
   const chatEndRef = useRef<HTMLDivElement>(null);
-  const { messages, isGenerating } = useChatStore();
+  const { messages, isGenerating, sessionId } = useChatStore();
   const { sendMessage, stopGeneration } = useWebSocket();
 
   useEffect(() => {
@@ -46,9 +47,22 @@ const ChatContainer: React.FC = () => {
           </div>
         ) : (
           <div className="max-w-4xl mx-auto space-y-4">
-            {messages.map((msg, idx) => (
-              <Message key={idx} message={msg} />
-            ))}
+            {messages.map((msg, idx) => {
+              // Replies can be rated once they are finished and saved to the session
+              const done = !isGenerating || idx < messages.length - 1;
+              const reply = messages.slice(0, idx).filter((m) => m.role === 'assistant').length;
+              return (
+                <Message
+                  key={idx}
+                  message={msg}
+                  onRate={
+                    msg.role === 'assistant' && done
+                      ? (rating) => api.rateReply(sessionId, reply, rating)
+                      : undefined
+                  }
+                />
+              );
+            })}
             <div ref={chatEndRef} />
           </div>
         )}
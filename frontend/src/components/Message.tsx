@@ -6,10 +6,22 @@ import 'highlight.js/styles/github-dark.css';
 
 interface MessageProps {
   message: MessageType;
+  onRate?: (rating: 'up' | 'down') => Promise<void>;
 }
 
-const Message: React.FC<MessageProps> = ({ message }) => {
+const Message: React.FC<MessageProps> = ({ message, onRate }) => {
   const [copied, setCopied] = useState(false);
+  const [rating, setRating] = useState<'up' | 'down' | null>(null);
+
+  const rate = async (value: 'up' | 'down') => {
+    if (!onRate) return;
+    try {
+      await onRate(value);
+      setRating(value);
+    } catch (error) {
+      console.error('Failed to send feedback:', error);
+    }
+  };
 
   const copyCode = (code: string) => {
     navigator.clipboard.writeText(code);
@@ -66,6 +78,22 @@ const Message: React.FC<MessageProps> = ({ message }) => {
             <span /><span /><span />
           </div>
         )}
+        {onRate && message.content && (
+          <div className="not-prose mt-2 flex gap-1 text-xs">
+            {(['up', 'down'] as const).map((value) => (
+              <button
+                key={value}
+                onClick={() => rate(value)}
+                title={value === 'up' ? 'Good response' : 'Bad response'}
+                className={`px-2 py-1 rounded transition-colors ${
+                  rating === value ? 'bg-indigo-600 text-white' : 'text-gray-500 hover:bg-gray-800 hover:text-gray-300'
+                }`}
+              >
+                {value === 'up' ? '👍' : '👎'}
+              </button>
+            ))}
+          </div>
+        )}
       </div>
     </div>
   );
@@ -69,6 +69,22 @@ export const api = {
     }
   },
 
+  // Feedback on the `reply`-th assistant message. The UI hides system messages, so look up the
+  // reply's position in the stored history first.
+  async rateReply(sessionId: string, reply: number, rating: 'up' | 'down', comment?: string): Promise<void> {
+    const history = await this.getHistory(sessionId);
+    const index = history
+      .map((msg, i) => (msg.role === 'assistant' ? i : -1))
+      .filter((i) => i >= 0)[reply];
+    if (index === undefined) throw new Error('Reply not found in history');
+    const res = await fetch(`${API_BASE}/chat/history/${sessionId}/messages/${index}/feedback`, {
+      method: 'POST',
+      headers: { 'Content-Type': 'application/json' },
+      body: JSON.stringify({ rating, comment }),
+    });
+    if (!res.ok) throw new Error('Failed to send feedback');
+  },
+
   // WebSocket
   createWebSocket(): WebSocket {
     const protocol = window.location.protocol === 'https:' ? 'wss:' : 'ws:';
@@ -0,0 +1,162 @@
+//! Errors the HTTP API answers with.
+//!
+//! Routes refuse requests with an [`ApiError`], and a generation that fails with an
+//! [`EngineError`] is answered as [`ApiError::Engine`]. The status code and the JSON body
+//! (`error`, plus a stable `code` for all but the rate limits) come from the variant, so handlers
+//! never pick statuses themselves.
+
+use crate::engine::EngineError;
+use crate::guardrails::Refusal;
+use crate::middleware::ApiKeyError;
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::response::IntoResponse;
+use axum::Json;
+use serde_json::json;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Why a request was turned away, by the server or by the engine
+#[derive(Debug)]
+pub enum ApiError {
+    Unauthorized { code: &'static str, message: String },
+    RateLimited(u32),
+    TooManyConcurrent,
+    Forbidden,
+    Draining(u64),
+    ReadOnly,
+    TokenizerOnly,
+    Cancelled,
+    ModelUnhealthy(String),
+    UnsupportedProtocol(String),
+    Refused(Refusal),
+    FederationLoop(String),
+    Engine(EngineError),
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::Unauthorized { .. } => StatusCode::UNAUTHORIZED,
+            ApiError::RateLimited(_) | ApiError::TooManyConcurrent => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Forbidden => StatusCode::FORBIDDEN,
+            ApiError::Draining(_)
+            | ApiError::ReadOnly
+            | ApiError::TokenizerOnly
+            | ApiError::Cancelled
+            | ApiError::ModelUnhealthy(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::UnsupportedProtocol(_) | ApiError::Refused(_) => StatusCode::BAD_REQUEST,
+            ApiError::FederationLoop(_) => StatusCode::LOOP_DETECTED,
+            ApiError::Engine(error) => engine_status(error),
+        }
+    }
+
+    /// Stable identifier sent to clients as the error `code`
+    pub fn code(&self) -> Option<&'static str> {
+        match self {
+            ApiError::Unauthorized { code, .. } => Some(code),
+            ApiError::RateLimited(_) | ApiError::TooManyConcurrent => None,
+            ApiError::Forbidden => Some("admin_required"),
+            ApiError::Draining(_) => Some("draining"),
+            ApiError::ReadOnly => Some("read_only"),
+            ApiError::TokenizerOnly => Some("tokenizer_only"),
+            ApiError::Cancelled => Some("cancelled"),
+            ApiError::ModelUnhealthy(_) => Some("model_unhealthy"),
+            ApiError::UnsupportedProtocol(_) => Some("unsupported_protocol_version"),
+            ApiError::Refused(_) => Some("refused"),
+            ApiError::FederationLoop(_) => Some("federation_loop"),
+            ApiError::Engine(error) => Some(error.code()),
+        }
+    }
+}
+
+/// HTTP status for a generation that failed with `error`
+pub fn engine_status(error: &EngineError) -> StatusCode {
+    match error {
+        EngineError::ModelNotFound(_) => StatusCode::NOT_FOUND,
+        EngineError::ContextOverflow(_) => StatusCode::BAD_REQUEST,
+        EngineError::Cancelled | EngineError::DeviceUnavailable(_) | EngineError::Overloaded(_) => {
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+        EngineError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+        EngineError::Io(_) | EngineError::Cuda(_) | EngineError::Backend(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Unauthorized { message, .. } => f.write_str(message),
+            ApiError::RateLimited(_) => f.write_str("rate limit exceeded"),
+            ApiError::TooManyConcurrent => f.write_str("too many concurrent requests for this key"),
+            ApiError::Forbidden => f.write_str("Admin API key required"),
+            ApiError::Draining(_) => f.write_str("server is draining; retry shortly"),
+            ApiError::ReadOnly => {
+                f.write_str("this server is a read-only replica; send generations to a serving instance")
+            }
+            ApiError::TokenizerOnly => {
+                f.write_str("this server loads tokenizers only; send generations to a serving instance")
+            }
+            ApiError::Cancelled => f.write_str("request cancelled by an operator"),
+            ApiError::ModelUnhealthy(model) => write!(f, "model {} is failing its health checks", model),
+            ApiError::UnsupportedProtocol(error) => f.write_str(error),
+            ApiError::Refused(refusal) => f.write_str(&refusal.message),
+            ApiError::FederationLoop(model) => write!(
+                f,
+                "model {} is not served here and forwarded requests are not forwarded again",
+                model
+            ),
+            ApiError::Engine(error) => error.fmt(f),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> axum::response::Response {
+        let status = self.status();
+        let mut body = json!({"error": self.to_string()});
+        if let Some(code) = self.code() {
+            body["code"] = json!(code);
+        }
+        let mut retry_after = None;
+        match &self {
+            ApiError::Refused(refusal) => body["refusal"] = json!(refusal),
+            ApiError::Draining(secs) => retry_after = Some(*secs),
+            ApiError::Engine(EngineError::Overloaded(secs)) => {
+                body["retry_after_seconds"] = json!(secs);
+                retry_after = Some(*secs);
+            }
+            ApiError::Engine(EngineError::DeviceUnavailable(fallback)) => {
+                body["requested"] = json!(fallback.requested);
+                body["device"] = json!(fallback.device);
+            }
+            _ => {}
+        }
+
+        let mut res = (status, Json(body)).into_response();
+        if let Some(secs) = retry_after {
+            res.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(secs));
+        }
+        if let ApiError::RateLimited(limit) = self {
+            let reset_ts = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() + 60).unwrap_or(0);
+            res.headers_mut().insert("X-RateLimit-Limit", HeaderValue::from(limit));
+            res.headers_mut().insert("X-RateLimit-Remaining", HeaderValue::from(0));
+            res.headers_mut().insert("X-RateLimit-Reset", HeaderValue::from(reset_ts));
+        }
+        res
+    }
+}
+
+impl From<EngineError> for ApiError {
+    fn from(err: EngineError) -> Self {
+        ApiError::Engine(err)
+    }
+}
+
+impl From<ApiKeyError> for ApiError {
+    fn from(err: ApiKeyError) -> Self {
+        ApiError::Unauthorized {
+            code: err.code(),
+            message: err.to_string(),
+        }
+    }
+}
@@ -0,0 +1,366 @@
+//! Load generator for comparing engine and scheduler changes.
+//!
+//! Fires streaming requests at a running server (`--url`) or at an in-process server backed by
+//! the MockEngine (the default), then prints time to first token, tokens/second and latency
+//! percentiles as JSON or CSV. Tokens are counted as SSE events, so with
+//! `streaming.coalesce_*` enabled on the target they count chunks instead.
+//!
+//! ```text
+//! bench [--url http://127.0.0.1:3000] [--config config.toml] [--endpoint completions|chat]
+//!       [--concurrency 8] [--requests 100] [--model mock-model] [--prompt "..."]
+//!       [--max-tokens 128] [--api-key sk-...] [--format json|csv] [--output report.json]
+//! ```
+
+use anyhow::{anyhow, bail, Context, Result};
+use llm_inference::config::Config;
+use llm_inference::engine_mock::MockEngine;
+use llm_inference::routes;
+use llm_inference::state::AppState;
+use metrics_exporter_prometheus::PrometheusBuilder;
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const USAGE: &str =
+    "usage: bench [--url <base url>] [--config <path>] [--endpoint completions|chat] \
+[--concurrency <n>] [--requests <n>] [--model <name>] [--prompt <text>] [--max-tokens <n>] \
+[--api-key <key>] [--format json|csv] [--output <path>]";
+
+struct Options {
+    url: Option<String>,
+    config: Option<String>,
+    endpoint: String,
+    concurrency: usize,
+    requests: usize,
+    model: String,
+    prompt: String,
+    max_tokens: usize,
+    api_key: Option<String>,
+    format: String,
+    output: Option<String>,
+}
+
+impl Options {
+    fn parse(args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut options = Options {
+            url: None,
+            config: None,
+            endpoint: "completions".to_string(),
+            concurrency: 8,
+            requests: 100,
+            model: "mock-model".to_string(),
+            prompt: "Write a haiku about load testing.".to_string(),
+            max_tokens: 128,
+            api_key: None,
+            format: "json".to_string(),
+            output: None,
+        };
+
+        let mut args = args;
+        while let Some(flag) = args.next() {
+            if flag == "--help" || flag == "-h" {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            let value = args
+                .next()
+                .ok_or_else(|| anyhow!("{} needs a value\n{}", flag, USAGE))?;
+            let number = |v: &str| -> Result<usize> {
+                v.parse()
+                    .with_context(|| format!("{} expects a number, got {:?}", flag, v))
+            };
+            match flag.as_str() {
+                "--url" => options.url = Some(value.trim_end_matches('/').to_string()),
+                "--config" => options.config = Some(value),
+                "--endpoint" => options.endpoint = value,
+                "--concurrency" => options.concurrency = number(&value)?,
+                "--requests" => options.requests = number(&value)?,
+                "--model" => options.model = value,
+                "--prompt" => options.prompt = value,
+                "--max-tokens" => options.max_tokens = number(&value)?,
+                "--api-key" => options.api_key = Some(value),
+                "--format" => options.format = value,
+                "--output" => options.output = Some(value),
+                _ => bail!("unknown option {}\n{}", flag, USAGE),
+            }
+        }
+
+        if !matches!(options.endpoint.as_str(), "completions" | "chat") {
+            bail!("--endpoint must be completions or chat");
+        }
+        if !matches!(options.format.as_str(), "json" | "csv") {
+            bail!("--format must be json or csv");
+        }
+        if options.concurrency == 0 || options.requests == 0 {
+            bail!("--concurrency and --requests must be greater than 0");
+        }
+        Ok(options)
+    }
+}
+
+/// Outcome of one streaming request
+struct Sample {
+    ttft: Option<f64>,
+    latency: f64,
+    tokens: usize,
+    error: Option<String>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let options = Options::parse(std::env::args().skip(1))?;
+
+    let base_url = match &options.url {
+        Some(url) => url.clone(),
+        None => spawn_mock_server(options.config.as_deref()).await?,
+    };
+    let path = match options.endpoint.as_str() {
+        "chat" => "/chat/completions",
+        _ => "/completions",
+    };
+    let target = format!("{}{}", base_url, path);
+
+    let client = reqwest::Client::new();
+    let next = Arc::new(AtomicUsize::new(0));
+    let options = Arc::new(options);
+    let started = Instant::now();
+
+    let workers: Vec<_> = (0..options.concurrency.min(options.requests))
+        .map(|_| {
+            let client = client.clone();
+            let next = next.clone();
+            let options = options.clone();
+            let target = target.clone();
+            tokio::spawn(async move {
+                let mut samples = Vec::new();
+                while next.fetch_add(1, Ordering::SeqCst) < options.requests {
+                    samples.push(run_request(&client, &target, &options).await);
+                }
+                samples
+            })
+        })
+        .collect();
+
+    let mut samples = Vec::with_capacity(options.requests);
+    for worker in workers {
+        samples.extend(worker.await?);
+    }
+    let wall = started.elapsed();
+
+    let report = match options.format.as_str() {
+        "csv" => csv_report(&samples),
+        _ => serde_json::to_string_pretty(&json_report(&options, &target, &samples, wall))? + "\n",
+    };
+    match &options.output {
+        Some(path) => {
+            std::fs::write(path, report)?;
+            eprintln!("✅ Wrote benchmark report to {}", path);
+        }
+        None => print!("{}", report),
+    }
+    Ok(())
+}
+
+// Serve the regular router over the MockEngine on an ephemeral port
+async fn spawn_mock_server(config_path: Option<&str>) -> Result<String> {
+    let config = match config_path {
+        Some(path) => Config::from_file_with_profile(path, None)?,
+        None => {
+            // The default per-minute limit would otherwise throttle the run itself
+            let mut config = Config::default();
+            config.limits.default_rate_limit_per_minute = u32::MAX;
+            config
+        }
+    };
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let engine = Arc::new(MockEngine::from_config(&config.mock));
+    let state = AppState::new_in_memory(engine, handle, config).await?;
+    let app = routes::router().with_state(state);
+
+    let server =
+        axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(app.into_make_service());
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    Ok(format!("http://{}", addr))
+}
+
+async fn run_request(client: &reqwest::Client, target: &str, options: &Options) -> Sample {
+    let body = match options.endpoint.as_str() {
+        "chat" => json!({
+            "model-name": options.model,
+            "prompt": options.prompt,
+            "max-token": options.max_tokens,
+        }),
+        _ => json!({
+            "model": options.model,
+            "prompt": options.prompt,
+            "max_tokens": options.max_tokens,
+            "stream": true,
+        }),
+    };
+
+    let start = Instant::now();
+    let mut sample = Sample {
+        ttft: None,
+        latency: 0.0,
+        tokens: 0,
+        error: None,
+    };
+    if let Err(e) = stream_events(client, target, options, &body, start, &mut sample).await {
+        sample.error = Some(e.to_string());
+    }
+    sample.latency = start.elapsed().as_secs_f64();
+    sample
+}
+
+async fn stream_events(
+    client: &reqwest::Client,
+    target: &str,
+    options: &Options,
+    body: &Value,
+    start: Instant,
+    sample: &mut Sample,
+) -> Result<()> {
+    let mut request = client.post(target).json(body);
+    if let Some(key) = &options.api_key {
+        request = request.bearer_auth(key);
+    }
+    let mut response = request.send().await?;
+    if !response.status().is_success() {
+        bail!("HTTP {}", response.status());
+    }
+
+    // Raw bytes, as a chunk may end in the middle of a multi-byte character
+    let mut buffer = Vec::new();
+    while let Some(bytes) = response.chunk().await? {
+        buffer.extend_from_slice(&bytes);
+        while let Some(event) = next_event(&mut buffer) {
+            // Multi-line tokens arrive as several data lines of one event
+            let data: Vec<&str> = event
+                .lines()
+                .filter_map(|l| l.strip_prefix("data:"))
+                .map(|d| d.strip_prefix(' ').unwrap_or(d))
+                .collect();
+            // Named events (such as `finish`) carry metadata, not tokens
+            if data.is_empty() || event.lines().any(|l| l.starts_with("event:")) {
+                continue;
+            }
+            if let Some(error) = data[0].strip_prefix("__ERROR__:") {
+                bail!("stream error: {}", error);
+            }
+            if sample.tokens == 0 {
+                sample.ttft = Some(start.elapsed().as_secs_f64());
+            }
+            sample.tokens += 1;
+        }
+    }
+    Ok(())
+}
+
+// Take the first complete SSE event off the buffer. Events end at a blank line, which never
+// falls inside a character, so each one decodes whole.
+fn next_event(buffer: &mut Vec<u8>) -> Option<String> {
+    let end = buffer.windows(2).position(|w| w == b"\n\n")?;
+    let event: Vec<u8> = buffer.drain(..end + 2).collect();
+    Some(String::from_utf8_lossy(&event).into_owned())
+}
+
+// Nearest-rank percentiles over the successful requests
+fn distribution(mut values: Vec<f64>) -> Value {
+    if values.is_empty() {
+        return Value::Null;
+    }
+    values.sort_by(|a, b| a.total_cmp(b));
+    let percentile = |p: f64| {
+        let rank = ((p / 100.0) * values.len() as f64).ceil() as usize;
+        values[rank.clamp(1, values.len()) - 1]
+    };
+    json!({
+        "count": values.len(),
+        "mean": values.iter().sum::<f64>() / values.len() as f64,
+        "p50": percentile(50.0),
+        "p90": percentile(90.0),
+        "p99": percentile(99.0),
+        "max": values[values.len() - 1],
+    })
+}
+
+fn distributions(samples: &[Sample]) -> [(&'static str, Value); 3] {
+    let ok: Vec<&Sample> = samples.iter().filter(|s| s.error.is_none()).collect();
+    [
+        (
+            "ttft_seconds",
+            distribution(ok.iter().filter_map(|s| s.ttft).collect()),
+        ),
+        (
+            "latency_seconds",
+            distribution(ok.iter().map(|s| s.latency).collect()),
+        ),
+        (
+            "tokens_per_second",
+            distribution(
+                ok.iter()
+                    .filter(|s| s.latency > 0.0)
+                    .map(|s| s.tokens as f64 / s.latency)
+                    .collect(),
+            ),
+        ),
+    ]
+}
+
+fn json_report(options: &Options, target: &str, samples: &[Sample], wall: Duration) -> Value {
+    let failed: Vec<&Sample> = samples.iter().filter(|s| s.error.is_some()).collect();
+    let tokens: usize = samples.iter().map(|s| s.tokens).sum();
+    let mut report = json!({
+        "target": target,
+        "concurrency": options.concurrency,
+        "requests": samples.len(),
+        "succeeded": samples.len() - failed.len(),
+        "failed": failed.len(),
+        "wall_seconds": wall.as_secs_f64(),
+        "requests_per_second": samples.len() as f64 / wall.as_secs_f64(),
+        "tokens": tokens,
+        "aggregate_tokens_per_second": tokens as f64 / wall.as_secs_f64(),
+        "errors": failed.iter().take(10).filter_map(|s| s.error.clone()).collect::<Vec<_>>(),
+    });
+    for (name, value) in distributions(samples) {
+        report[name] = value;
+    }
+    report
+}
+
+fn csv_report(samples: &[Sample]) -> String {
+    let mut out = String::from("metric,count,mean,p50,p90,p99,max\n");
+    for (name, value) in distributions(samples) {
+        let field = |key: &str| value.get(key).map(|v| v.to_string()).unwrap_or_default();
+        out.push_str(&format!(
+            "{},{},{},{},{},{},{}\n",
+            name,
+            field("count"),
+            field("mean"),
+            field("p50"),
+            field("p90"),
+            field("p99"),
+            field("max")
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events_split_inside_a_character() {
+        let event = "data: héllo\n\n".as_bytes();
+        let split = event.iter().position(|&b| b == 0xc3).unwrap() + 1;
+        let mut buffer = event[..split].to_vec();
+        assert_eq!(next_event(&mut buffer), None);
+        buffer.extend_from_slice(&event[split..]);
+        assert_eq!(next_event(&mut buffer).as_deref(), Some("data: héllo\n\n"));
+        assert!(buffer.is_empty());
+    }
+}
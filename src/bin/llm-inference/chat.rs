@@ -0,0 +1,208 @@
+//! `chat`: an interactive conversation, optionally kept in the session database.
+
+use crate::infer::{generate, GenerationArgs};
+use anyhow::Result;
+use clap::Args;
+use llm_inference::config::Config;
+use llm_inference::engine::M1EngineAdapter;
+use llm_inference::models::ChatMessage;
+use llm_inference::routes::{new_session_history, prune_history};
+use llm_inference::state::{SessionStore, SESSIONS_DB};
+use std::io::Write;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+#[derive(Debug, Args)]
+pub struct ChatArgs {
+    /// Resume this session from the session database and save it after every reply
+    #[arg(long, value_name = "ID")]
+    session: Option<String>,
+
+    /// Session database used by --session and /save
+    #[arg(long, value_name = "PATH", default_value = SESSIONS_DB)]
+    db: String,
+
+    #[command(flatten)]
+    generation: GenerationArgs,
+}
+
+/// A `/command` typed at the chat prompt
+#[derive(Debug, PartialEq)]
+enum ReplCommand {
+    /// Forget the conversation, keeping the system prompt
+    Reset,
+    /// Show the system prompt, or replace it
+    System(Option<String>),
+    /// Save to the session database, under the given id or the current session
+    Save(Option<String>),
+    Help,
+    Exit,
+    Unknown(String),
+}
+
+impl ReplCommand {
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.strip_prefix('/')?;
+        let (name, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let arg = Some(rest.trim())
+            .filter(|a| !a.is_empty())
+            .map(str::to_string);
+        Some(match name {
+            "reset" => Self::Reset,
+            "system" => Self::System(arg),
+            "save" => Self::Save(arg),
+            "help" => Self::Help,
+            "exit" | "quit" => Self::Exit,
+            other => Self::Unknown(other.to_string()),
+        })
+    }
+}
+
+const REPL_HELP: &str = "/reset            start over (keeps the system prompt)
+/system [text]    show or replace the system prompt
+/save [id]        save to the session database and keep saving after each reply
+/exit             quit (also Ctrl-D)";
+
+pub async fn run(args: ChatArgs, config: Config) -> Result<()> {
+    let engine = M1EngineAdapter::new(config.models.available_models.clone());
+
+    let mut store = None;
+    let mut session = args.session.clone();
+    let mut history = None;
+    if let Some(id) = &session {
+        let opened = SessionStore::new(&args.db, &config.session_store, None).await?;
+        history = opened.load_sessions().await?.remove(id);
+        if history.is_some() {
+            eprintln!("📂 Resumed session {}", id);
+        }
+        store = Some(opened);
+    }
+    let mut history = history.unwrap_or_else(|| new_session_history(&config.chat));
+
+    let model = args.generation.request(&config, String::new())?.model_name;
+    eprintln!("💬 Chatting with {} (/help for commands)", model);
+
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        print!("> ");
+        std::io::stdout().flush()?;
+        let Some(line) = lines.next_line().await? else {
+            println!();
+            break;
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(command) = ReplCommand::parse(line) {
+            match command {
+                ReplCommand::Reset => {
+                    history.retain(|m| m.role == "system");
+                    eprintln!("🧹 Conversation cleared");
+                }
+                ReplCommand::System(None) => match history.first() {
+                    Some(m) if m.role == "system" => println!("{}", m.content),
+                    _ => eprintln!("(no system prompt)"),
+                },
+                ReplCommand::System(Some(text)) => {
+                    history.retain(|m| m.role != "system");
+                    history.insert(
+                        0,
+                        ChatMessage {
+                            role: "system".to_string(),
+                            content: text,
+                            truncated: false,
+                            partial: false,
+                            timestamp: None,
+                            continues: false,
+                        },
+                    );
+                    eprintln!("✅ System prompt updated");
+                }
+                ReplCommand::Save(id) => {
+                    let Some(id) = id.or_else(|| session.clone()) else {
+                        eprintln!("usage: /save <id>");
+                        continue;
+                    };
+                    if store.is_none() {
+                        store =
+                            Some(SessionStore::new(&args.db, &config.session_store, None).await?);
+                    }
+                    if let Some(store) = &store {
+                        store.upsert_session(&id, &history).await?;
+                    }
+                    eprintln!("💾 Saved session {} to {}", id, args.db);
+                    session = Some(id);
+                }
+                ReplCommand::Help => eprintln!("{}", REPL_HELP),
+                ReplCommand::Exit => break,
+                ReplCommand::Unknown(name) => {
+                    eprintln!("unknown command /{} (try /help)", name)
+                }
+            }
+            continue;
+        }
+
+        history.push(ChatMessage {
+            role: "user".to_string(),
+            content: line.to_string(),
+            truncated: false,
+            partial: false,
+            timestamp: None,
+            continues: false,
+        });
+        prune_history(&mut history, &config.chat);
+
+        let mut request = args.generation.request(&config, line.to_string())?;
+        request.messages = Some(history.clone());
+        match generate(&engine, request, true).await {
+            Ok(reply) => {
+                if args.generation.stats {
+                    reply.print_stats();
+                }
+                history.push(ChatMessage {
+                    role: "assistant".to_string(),
+                    content: reply.text,
+                    truncated: false,
+                    partial: false,
+                    timestamp: None,
+                    continues: false,
+                })
+            }
+            Err(e) => {
+                // Leave the unanswered prompt out so the next turn starts clean
+                history.pop();
+                eprintln!("❌ {:#}", e);
+                continue;
+            }
+        }
+
+        if let (Some(store), Some(id)) = (&store, &session) {
+            if let Err(e) = store.upsert_session(id, &history).await {
+                eprintln!("⚠️ Could not save session {}: {:#}", id, e);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repl_commands() {
+        assert_eq!(ReplCommand::parse("hello"), None);
+        assert_eq!(ReplCommand::parse("/reset"), Some(ReplCommand::Reset));
+        assert_eq!(
+            ReplCommand::parse("/system  Be terse. "),
+            Some(ReplCommand::System(Some("Be terse.".to_string())))
+        );
+        assert_eq!(ReplCommand::parse("/save"), Some(ReplCommand::Save(None)));
+        assert_eq!(ReplCommand::parse("/quit"), Some(ReplCommand::Exit));
+        assert_eq!(
+            ReplCommand::parse("/undo"),
+            Some(ReplCommand::Unknown("undo".to_string()))
+        );
+    }
+}
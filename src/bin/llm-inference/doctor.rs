@@ -0,0 +1,360 @@
+//! `doctor`: check the environment the server would run in and explain how to fix what is
+//! missing. Exits non-zero when something would stop a model from loading.
+
+use anyhow::{bail, Result};
+use llm_inference::config::{Config, ModelConfig, AUTO_DEVICE};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Cargo features this binary was built with
+const FEATURES: &[(&str, bool)] = &[
+    ("cuda", cfg!(feature = "cuda")),
+    ("metal", cfg!(feature = "metal")),
+    ("flash-attn", cfg!(feature = "flash-attn")),
+];
+
+/// Collects findings so the summary can count them
+#[derive(Default)]
+struct Report {
+    warnings: usize,
+    errors: usize,
+}
+
+impl Report {
+    fn section(&self, title: &str) {
+        println!("\n{}", title);
+    }
+
+    fn ok(&self, message: impl AsRef<str>) {
+        println!("  ✅ {}", message.as_ref());
+    }
+
+    fn info(&self, message: impl AsRef<str>) {
+        println!("  ℹ️ {}", message.as_ref());
+    }
+
+    fn warn(&mut self, message: impl AsRef<str>, hint: impl AsRef<str>) {
+        self.warnings += 1;
+        println!("  ⚠️ {}\n     → {}", message.as_ref(), hint.as_ref());
+    }
+
+    fn error(&mut self, message: impl AsRef<str>, hint: impl AsRef<str>) {
+        self.errors += 1;
+        println!("  ❌ {}\n     → {}", message.as_ref(), hint.as_ref());
+    }
+}
+
+/// One GPU as reported by `nvidia-smi`
+#[derive(Debug, PartialEq)]
+struct NvidiaGpu {
+    name: String,
+    driver: String,
+    memory_total_mib: u64,
+    memory_free_mib: u64,
+}
+
+// A line of `nvidia-smi --query-gpu=name,driver_version,memory.total,memory.free
+// --format=csv,noheader,nounits`
+fn parse_nvidia_smi(line: &str) -> Option<NvidiaGpu> {
+    let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+    let [name, driver, total, free] = fields.as_slice() else {
+        return None;
+    };
+    Some(NvidiaGpu {
+        name: name.to_string(),
+        driver: driver.to_string(),
+        memory_total_mib: total.parse().ok()?,
+        memory_free_mib: free.parse().ok()?,
+    })
+}
+
+/// `None` when nvidia-smi is missing or fails (no driver, no device)
+fn nvidia_gpus() -> Option<Vec<NvidiaGpu>> {
+    let output = Command::new("nvidia-smi")
+        .args([
+            "--query-gpu=name,driver_version,memory.total,memory.free",
+            "--format=csv,noheader,nounits",
+        ])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(parse_nvidia_smi)
+            .collect(),
+    )
+}
+
+fn cuda_version() -> Option<String> {
+    let output = Command::new("nvcc").arg("--version").output().ok()?;
+    let text = String::from_utf8_lossy(&output.stdout);
+    let release = text.lines().find_map(|l| l.split("release ").nth(1))?;
+    Some(release.split(',').next()?.trim().to_string())
+}
+
+#[cfg(target_os = "macos")]
+fn metal_gpus() -> Vec<String> {
+    let Ok(output) = Command::new("system_profiler")
+        .arg("SPDisplaysDataType")
+        .output()
+    else {
+        return Vec::new();
+    };
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|l| l.trim().strip_prefix("Chipset Model:"))
+        .map(|name| name.trim().to_string())
+        .collect()
+}
+
+#[cfg(not(target_os = "macos"))]
+fn metal_gpus() -> Vec<String> {
+    Vec::new()
+}
+
+/// Total and available system memory in bytes, where the platform exposes them
+fn system_memory() -> Option<(u64, Option<u64>)> {
+    if let Ok(meminfo) = std::fs::read_to_string("/proc/meminfo") {
+        let field = |name: &str| {
+            meminfo
+                .lines()
+                .find_map(|l| l.strip_prefix(name))
+                .and_then(|v| v.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+                .map(|kib| kib * 1024)
+        };
+        return Some((field("MemTotal:")?, field("MemAvailable:")));
+    }
+    let output = Command::new("sysctl")
+        .args(["-n", "hw.memsize"])
+        .output()
+        .ok()?;
+    let total = String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse()
+        .ok()?;
+    Some((total, None))
+}
+
+fn gib(bytes: u64) -> String {
+    format!("{:.1} GiB", bytes as f64 / (1u64 << 30) as f64)
+}
+
+/// Where the Hugging Face hub caches a repo such as `Qwen/Qwen2-0.5B`
+pub fn hf_cache_dir(repo: &str) -> PathBuf {
+    let hub = match (
+        std::env::var_os("HF_HUB_CACHE"),
+        std::env::var_os("HF_HOME"),
+    ) {
+        (Some(hub), _) => PathBuf::from(hub),
+        (None, Some(home)) => PathBuf::from(home).join("hub"),
+        (None, None) => std::env::var_os("HOME")
+            .map(PathBuf::from)
+            .unwrap_or_default()
+            .join(".cache/huggingface/hub"),
+    };
+    hub.join(format!("models--{}", repo.replace('/', "--")))
+}
+
+// A local model directory is usable once it has a config and some weights
+fn has_model_files(dir: &Path) -> bool {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return false;
+    };
+    let names: Vec<String> = entries
+        .flatten()
+        .map(|e| e.file_name().to_string_lossy().into_owned())
+        .collect();
+    names.iter().any(|n| n == "config.json")
+        && names
+            .iter()
+            .any(|n| n.ends_with(".safetensors") || n.ends_with(".gguf") || n.ends_with(".bin"))
+}
+
+fn check_model(report: &mut Report, model: &ModelConfig) {
+    let label = format!("{} ({})", model.name, model.id);
+    match &model.path {
+        Some(path) if has_model_files(path) => {
+            report.ok(format!("{}: local files in {}", label, path.display()))
+        }
+        Some(path) if path.exists() => report.error(
+            format!("{}: {} has no config.json or weight files", label, path.display()),
+            "download the full model snapshot into that directory, or remove `path` to load it from the Hugging Face hub",
+        ),
+        Some(path) => report.error(
+            format!("{}: {} does not exist", label, path.display()),
+            "fix `path` in [[models.available_models]], or remove it to load the model from the Hugging Face hub",
+        ),
+        None => {
+            let cache = hf_cache_dir(&model.name);
+            if cache.join("snapshots").is_dir() {
+                report.ok(format!("{}: cached in {}", label, cache.display()));
+            } else {
+                report.warn(
+                    format!("{}: not in the Hugging Face cache", label),
+                    format!(
+                        "it is downloaded on first use; pre-fetch with `huggingface-cli download {}` (set HF_TOKEN for gated models)",
+                        model.name
+                    ),
+                );
+            }
+        }
+    }
+}
+
+pub fn run(config: Config) -> Result<()> {
+    let mut report = Report::default();
+
+    report.section("🧩 Build");
+    report.ok(format!("llm-inference {}", env!("CARGO_PKG_VERSION")));
+    let enabled: Vec<&str> = FEATURES
+        .iter()
+        .filter(|(_, on)| *on)
+        .map(|(f, _)| *f)
+        .collect();
+    if enabled.is_empty() {
+        report.info("cargo features: none (CPU only)");
+    } else {
+        report.info(format!("cargo features: {}", enabled.join(", ")));
+    }
+
+    report.section("🖥️ Devices");
+    let nvidia = nvidia_gpus();
+    match &nvidia {
+        Some(gpus) if !gpus.is_empty() => {
+            for (index, gpu) in gpus.iter().enumerate() {
+                report.ok(format!(
+                    "cuda:{} {} (driver {}, {} MiB free of {} MiB)",
+                    index, gpu.name, gpu.driver, gpu.memory_free_mib, gpu.memory_total_mib
+                ));
+            }
+            match cuda_version() {
+                Some(version) => report.ok(format!("CUDA toolkit {}", version)),
+                None if cfg!(feature = "cuda") => report.info("nvcc not on PATH (only needed to rebuild)"),
+                None => {}
+            }
+            if !cfg!(feature = "cuda") {
+                report.warn(
+                    "NVIDIA GPU found but this build has no CUDA support",
+                    "rebuild with `cargo build --release --features cuda`",
+                );
+            }
+        }
+        _ if cfg!(feature = "cuda") => report.error(
+            "built with CUDA but no NVIDIA GPU or driver was found (nvidia-smi failed)",
+            "install the NVIDIA driver and check `nvidia-smi`, or set models.default_device = \"cpu\"",
+        ),
+        _ => report.info("no NVIDIA GPU detected"),
+    }
+
+    let metal = metal_gpus();
+    for name in &metal {
+        report.ok(format!("Metal: {}", name));
+    }
+    if !metal.is_empty() && !cfg!(feature = "metal") {
+        report.warn(
+            "Metal GPU found but this build has no Metal support",
+            "rebuild with `cargo build --release --features metal`",
+        );
+    }
+    if metal.is_empty() && cfg!(feature = "metal") {
+        report.error(
+            "built with Metal but no Metal device was found",
+            "Metal needs macOS on Apple silicon or a supported GPU; otherwise set models.default_device = \"cpu\"",
+        );
+    }
+
+    let device = config.models.default_device.to_lowercase();
+    let device_usable = match device.as_str() {
+        "cuda" => cfg!(feature = "cuda") && nvidia.as_ref().is_some_and(|g| !g.is_empty()),
+        "metal" => cfg!(feature = "metal") && !metal.is_empty(),
+        _ => true,
+    };
+    if device == AUTO_DEVICE {
+        report.ok(format!("models.default_device = \"{}\"", device));
+        let candidates = llm_inference::device::probe();
+        for model in &config.models.available_models {
+            let required = llm_inference::device::required_bytes(model);
+            let placement = llm_inference::device::choose(&candidates, required);
+            report.info(format!(
+                "{} would load on {} ({})",
+                model.id,
+                llm_inference::device::device_name(placement.kind, placement.ordinal),
+                placement.reason
+            ));
+        }
+    } else if device_usable {
+        report.ok(format!("models.default_device = \"{}\"", device));
+    } else {
+        report.warn(
+            format!("models.default_device = \"{}\" is not usable here; models fall back to the CPU", device),
+            format!("build with `--features {}` on a machine with that GPU, or set models.default_device = \"cpu\"", device),
+        );
+    }
+
+    report.section("🧠 Memory");
+    match system_memory() {
+        Some((total, Some(available))) => {
+            report.ok(format!("{} available of {}", gib(available), gib(total)))
+        }
+        Some((total, None)) => report.ok(format!("{} total", gib(total))),
+        None => report.info("could not read system memory"),
+    }
+
+    report.section("📦 Models");
+    if config.models.available_models.is_empty() {
+        report.error(
+            "no models configured",
+            "add a [[models.available_models]] entry (see `llm-inference config init`)",
+        );
+    }
+    for model in &config.models.available_models {
+        check_model(&mut report, model);
+    }
+
+    println!(
+        "\n{} error(s), {} warning(s)",
+        report.errors, report.warnings
+    );
+    if report.errors > 0 {
+        bail!(
+            "{} problem(s) would stop models from loading",
+            report.errors
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_nvidia_smi() {
+        assert_eq!(
+            parse_nvidia_smi("NVIDIA GeForce RTX 4090, 550.54.14, 24564, 23012"),
+            Some(NvidiaGpu {
+                name: "NVIDIA GeForce RTX 4090".to_string(),
+                driver: "550.54.14".to_string(),
+                memory_total_mib: 24564,
+                memory_free_mib: 23012,
+            })
+        );
+        assert_eq!(parse_nvidia_smi("No devices were found"), None);
+    }
+
+    #[test]
+    fn test_model_files() {
+        let dir = std::env::temp_dir().join(format!("doctor-model-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("config.json"), "{}").unwrap();
+        assert!(!has_model_files(&dir));
+        std::fs::write(dir.join("model.safetensors"), "").unwrap();
+        assert!(has_model_files(&dir));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(hf_cache_dir("Qwen/Qwen2-0.5B").ends_with("models--Qwen--Qwen2-0.5B"));
+    }
+}
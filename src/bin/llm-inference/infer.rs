@@ -0,0 +1,308 @@
+//! `infer`: one generation from flags, stdin or a JSON request.
+
+use anyhow::{bail, Context, Result};
+use clap::{Args, ValueEnum};
+use futures_util::StreamExt;
+use llm_inference::config::Config;
+use llm_inference::engine::{InferenceEngine, M1EngineAdapter};
+use llm_inference::models::{DeviceFallback, InferenceRequest};
+use llm_inference::routes::finish_reason;
+use serde_json::json;
+use std::io::{Read, Write};
+use std::time::Instant;
+
+#[derive(Debug, Args)]
+pub struct InferArgs {
+    /// Prompt to complete; read from stdin when omitted
+    #[arg(short, long, env = "LLM_PROMPT")]
+    prompt: Option<String>,
+
+    /// Read the whole request as JSON from a file (`-` for stdin) instead of the flags
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["prompt", "model", "device", "stop"]
+    )]
+    json: Option<String>,
+
+    /// text streams the answer as it is generated; json and markdown print a document at the end
+    #[arg(short, long, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+
+    #[command(flatten)]
+    generation: GenerationArgs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum OutputFormat {
+    Text,
+    Json,
+    Markdown,
+}
+
+/// Model and sampler selection shared by `infer` and `chat`
+#[derive(Debug, Args)]
+pub struct GenerationArgs {
+    /// Model name or id from the config (defaults to the first configured model)
+    #[arg(short, long, env = "LLM_MODEL")]
+    model: Option<String>,
+
+    /// Maximum number of tokens to generate
+    #[arg(long, env = "LLM_MAX_TOKENS", default_value_t = 128)]
+    max_tokens: usize,
+
+    #[arg(long, env = "LLM_TEMPERATURE", default_value_t = 0.7)]
+    temperature: f64,
+
+    #[arg(long, env = "LLM_TOP_P", default_value_t = 0.95)]
+    top_p: f64,
+
+    #[arg(long, env = "LLM_TOP_K", default_value_t = 10)]
+    top_k: i32,
+
+    #[arg(long, env = "LLM_REPEAT_PENALTY", default_value_t = 1.0)]
+    repeat_penalty: f32,
+
+    /// Stop sequence; repeat for several
+    #[arg(long = "stop", value_name = "TEXT")]
+    stop: Vec<String>,
+
+    /// cuda, cpu, metal or auto (defaults to models.default_device)
+    #[arg(long, env = "LLM_DEVICE")]
+    device: Option<String>,
+
+    /// Print tokens generated, time to first token and tokens/second to stderr afterwards
+    #[arg(long)]
+    pub stats: bool,
+}
+
+impl GenerationArgs {
+    pub fn request(&self, config: &Config, prompt: String) -> Result<InferenceRequest> {
+        let model_name = match &self.model {
+            Some(model) => model.clone(),
+            None => match config.models.available_models.first() {
+                Some(model) => model.name.clone(),
+                None => {
+                    bail!("no models configured; pass --model or add [[models.available_models]]")
+                }
+            },
+        };
+
+        Ok(InferenceRequest {
+            model_name,
+            model_dir: config.models.model_dir.clone(),
+            prompt,
+            messages: None,
+            session_id: None,
+            create_session: false,
+            stream: true,
+            max_token: self.max_tokens,
+            auto_fit: false,
+            temperature: self.temperature,
+            top_p: self.top_p,
+            top_k: self.top_k,
+            repeat_penalty: self.repeat_penalty,
+            stop: self.stop.clone(),
+            device: self
+                .device
+                .clone()
+                .unwrap_or_else(|| config.models.default_device.clone()),
+            strict_device: false,
+            priority: None,
+            compress_history: false,
+            template: None,
+            variables: Default::default(),
+            persona: None,
+            prefill: None,
+            placement: None,
+            sent: Default::default(),
+        })
+    }
+}
+
+impl InferArgs {
+    fn inference_request(&self, config: &Config) -> Result<InferenceRequest> {
+        if let Some(source) = &self.json {
+            let body = read_input(source)?;
+            return serde_json::from_str(&body).context("invalid request JSON");
+        }
+
+        let prompt = match &self.prompt {
+            Some(prompt) => prompt.clone(),
+            None => read_input("-")?.trim_end().to_string(),
+        };
+        if prompt.is_empty() {
+            bail!("empty prompt");
+        }
+        self.generation.request(config, prompt)
+    }
+}
+
+pub fn read_input(source: &str) -> Result<String> {
+    if source == "-" {
+        let mut input = String::new();
+        std::io::stdin()
+            .read_to_string(&mut input)
+            .context("failed to read stdin")?;
+        Ok(input)
+    } else {
+        std::fs::read_to_string(source).with_context(|| format!("failed to read {}", source))
+    }
+}
+
+/// One finished generation, timed the way the server times requests
+pub struct Generation {
+    pub text: String,
+    tokens: u64,
+    ttft: Option<f64>,
+    duration: f64,
+}
+
+impl Generation {
+    fn tokens_per_second(&self) -> Option<f64> {
+        (self.duration > 0.0).then(|| self.tokens as f64 / self.duration)
+    }
+
+    // key=value pairs so evaluation scripts can pick them out of stderr
+    pub fn print_stats(&self) {
+        let ttft = self.ttft.map_or("-".to_string(), |t| format!("{:.3}", t));
+        let rate = self
+            .tokens_per_second()
+            .map_or("-".to_string(), |r| format!("{:.1}", r));
+        eprintln!(
+            "📈 tokens={} ttft_seconds={} tokens_per_second={} duration_seconds={:.3}",
+            self.tokens, ttft, rate, self.duration
+        );
+    }
+}
+
+/// Run one generation, streaming it to stdout when `echo` is set
+pub async fn generate(
+    engine: &M1EngineAdapter,
+    request: InferenceRequest,
+    echo: bool,
+) -> Result<Generation> {
+    let start = Instant::now();
+    let model = request.model_name.clone();
+    let requested = request.device.to_lowercase();
+    let strict_device = request.strict_device;
+    let mut stream = engine.run_streaming_inference(request).await?;
+    // The engine quietly builds on the CPU when the requested device is missing
+    if let Some(device) = engine.loaded_device(&model).await {
+        if device != requested {
+            let fallback = DeviceFallback { requested, device };
+            if strict_device {
+                bail!("{}", fallback.message());
+            }
+            eprintln!("⚠️  {}", fallback.message());
+        }
+    }
+    let mut text = String::new();
+    let mut tokens = 0;
+    let mut ttft = None;
+    while let Some(token) = stream.next().await {
+        let token = token?;
+        if tokens == 0 {
+            ttft = Some(start.elapsed().as_secs_f64());
+        }
+        tokens += 1;
+        if echo {
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(token.as_bytes())?;
+            stdout.flush()?;
+        }
+        text.push_str(&token);
+    }
+    if echo {
+        println!();
+    }
+    Ok(Generation {
+        text,
+        tokens,
+        ttft,
+        duration: start.elapsed().as_secs_f64(),
+    })
+}
+
+// The finished answer as a JSON (same fields as a non-streaming /completions response) or
+// Markdown document
+fn render(format: OutputFormat, request: &InferenceRequest, generation: &Generation) -> String {
+    match format {
+        OutputFormat::Json => {
+            let body = json!({
+                "text": generation.text,
+                "model": request.model_name,
+                "tokens": generation.tokens,
+                "finish_reason": finish_reason(false, generation.tokens, request.max_token),
+                "duration_seconds": generation.duration,
+                "tokens_per_second": generation.tokens_per_second(),
+                "ttft_seconds": generation.ttft,
+            });
+            format!("{:#}\n", body)
+        }
+        OutputFormat::Markdown => format!(
+            "**Model:** {}\n\n### Prompt\n\n{}\n\n### Response\n\n{}\n",
+            request.model_name,
+            request.prompt.trim(),
+            generation.text.trim()
+        ),
+        OutputFormat::Text => format!("{}\n", generation.text),
+    }
+}
+
+pub async fn run(args: InferArgs, config: Config) -> Result<()> {
+    let request = args.inference_request(&config)?;
+    let engine = M1EngineAdapter::new(config.models.available_models.clone());
+    let streamed = args.output == OutputFormat::Text;
+    let generation = generate(&engine, request.clone(), streamed).await?;
+    if !streamed {
+        print!("{}", render(args.output, &request, &generation));
+    }
+    if args.generation.stats {
+        generation.print_stats();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cli, Command};
+    use clap::Parser;
+
+    #[test]
+    fn test_json_conflicts_with_prompt_flags() {
+        let err = Cli::try_parse_from(["llm-inference", "infer", "--json", "-", "--prompt", "hi"]);
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn test_render_formats() {
+        let cli =
+            Cli::try_parse_from(["llm-inference", "infer", "-m", "tiny", "--max-tokens", "2"]);
+        let Command::Infer(args) = cli.unwrap().command else {
+            panic!("expected the infer subcommand");
+        };
+        let request = args
+            .generation
+            .request(&Config::default(), "Hi".to_string())
+            .unwrap();
+        let generation = Generation {
+            text: "Hello there".to_string(),
+            tokens: 2,
+            ttft: Some(0.1),
+            duration: 0.5,
+        };
+
+        let json: serde_json::Value =
+            serde_json::from_str(&render(OutputFormat::Json, &request, &generation)).unwrap();
+        assert_eq!(json["text"], "Hello there");
+        assert_eq!(json["model"], "tiny");
+        assert_eq!(json["finish_reason"], "length");
+        assert_eq!(json["tokens_per_second"], 4.0);
+
+        let markdown = render(OutputFormat::Markdown, &request, &generation);
+        assert!(markdown.contains("### Prompt\n\nHi\n"));
+        assert!(markdown.ends_with("### Response\n\nHello there\n"));
+    }
+}
@@ -0,0 +1,268 @@
+//! `llm-inference`: the inference server and its command-line tools in one binary.
+//!
+//! Every subcommand reads the same config file (`--config`, else the first of config.toml,
+//! config.yaml, config.yml, config.json) with the same `--profile` and `LLM__*` overrides, and
+//! logs to stderr so stdout carries only generated text or command output.
+//!
+//! ```text
+//! llm-inference serve [--mock]
+//! llm-inference infer --prompt "Write a haiku about Rust" --max-tokens 64 --temperature 0.2
+//! llm-inference infer --prompt "2 + 2 =" --output json --stats
+//! echo '{"model-name": "qwen", "prompt": "Hi"}' | llm-inference infer --json -
+//! llm-inference chat --session notes
+//! llm-inference sessions list | show <id> | delete <id> | export [id] [--url http://host:3000]
+//! llm-inference models list
+//! llm-inference tokens count --model qwen --file prompt.txt | tokens encode --text "Hello"
+//! llm-inference doctor
+//! llm-inference config init [path] [--force] | config check [path]
+//! ```
+
+mod chat;
+mod doctor;
+mod infer;
+mod serve;
+mod sessions;
+mod tokens;
+
+use anyhow::Result;
+use clap::{Args, Parser, Subcommand};
+use llm_inference::config::{self, Config};
+use llm_inference::debug_trace;
+use llm_inference::state::LogLevelReloader;
+use std::sync::Arc;
+use tracing_subscriber::filter::FilterExt;
+use tracing_subscriber::prelude::*;
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "llm-inference",
+    version,
+    about = "LLM inference server and command-line client"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+
+    #[command(flatten)]
+    config: ConfigArgs,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Run the HTTP server
+    Serve(serve::ServeArgs),
+    /// Run one prompt and stream the answer to stdout
+    Infer(infer::InferArgs),
+    /// Interactive chat; the conversation is kept in memory between turns
+    Chat(chat::ChatArgs),
+    /// Inspect, export or delete chat sessions, locally or on a running server
+    Sessions(sessions::SessionsArgs),
+    /// Inspect the configured models
+    Models {
+        #[command(subcommand)]
+        command: ModelsCommand,
+    },
+    /// Count or show the tokens a model's tokenizer makes of some text
+    Tokens(tokens::TokensArgs),
+    /// Check GPUs, drivers, memory, build features and model files, with hints for fixing them
+    Doctor,
+    /// Write or validate configuration files
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum ModelsCommand {
+    /// Print the models in [models.available_models]
+    List,
+}
+
+#[derive(Debug, Subcommand)]
+enum ConfigCommand {
+    /// Write a commented config with every default
+    Init {
+        #[arg(default_value = config::DEFAULT_CONFIG_PATH)]
+        path: String,
+        /// Overwrite an existing file
+        #[arg(long)]
+        force: bool,
+    },
+    /// Validate a config file and print the effective settings, secrets masked
+    Check {
+        /// Defaults to --config, then the first config file found
+        path: Option<String>,
+    },
+}
+
+/// Config selection shared by every subcommand
+#[derive(Debug, Args)]
+struct ConfigArgs {
+    /// Config file (defaults to the first of config.toml, config.yaml, config.yml, config.json)
+    #[arg(long, global = true, value_name = "PATH")]
+    config: Option<String>,
+
+    /// Apply a [profile.<name>] section of the config
+    #[arg(long, global = true, env = config::PROFILE_ENV)]
+    profile: Option<String>,
+}
+
+impl ConfigArgs {
+    // An explicit --config must load; the default lookup falls back to built-in defaults
+    fn load(&self) -> Result<Config> {
+        Ok(match &self.config {
+            Some(path) => Config::load_from(path, self.profile.as_deref())?,
+            None => Config::load(self.profile.as_deref()),
+        })
+    }
+}
+
+/// Log to stderr at `level` unless `RUST_LOG` is set; requests sent with `X-Debug-Trace` are
+/// logged at debug level regardless. The returned handle swaps the filter when the server
+/// reloads its config.
+fn init_logging(level: &str) -> LogLevelReloader {
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new(level));
+    let (filter, filter_handle) = tracing_subscriber::reload::Layer::new(filter);
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(std::io::stderr)
+                .with_filter(filter.or(debug_trace::filter())),
+        )
+        .init();
+    Arc::new(move |level: &str| {
+        filter_handle.reload(tracing_subscriber::EnvFilter::new(level))?;
+        Ok(())
+    })
+}
+
+fn list_models(config: &Config) {
+    for model in &config.models.available_models {
+        let source = model
+            .path
+            .as_ref()
+            .map(|p| p.display().to_string())
+            .unwrap_or_else(|| model.name.clone());
+        println!("{}\t{}\t{}", model.id, model.name, source);
+    }
+}
+
+fn config_command(command: ConfigCommand, args: &ConfigArgs) -> Result<()> {
+    match command {
+        ConfigCommand::Init { path, force } => {
+            if std::path::Path::new(&path).exists() && !force {
+                anyhow::bail!("{} already exists (use --force to overwrite)", path);
+            }
+            std::fs::write(&path, Config::commented_template()?)?;
+            println!("✅ Wrote default configuration to {}", path);
+        }
+        ConfigCommand::Check { path } => {
+            let path = path
+                .or_else(|| args.config.clone())
+                .unwrap_or_else(|| Config::default_path().to_string());
+            print!("{}", check_config(&path, args.profile.as_deref())?);
+            eprintln!("✅ {} is valid", path);
+        }
+    }
+    Ok(())
+}
+
+// The effective settings of a config file with `profile` applied, as TOML with secrets masked
+fn check_config(path: &str, profile: Option<&str>) -> Result<String> {
+    let config = Config::from_file_with_profile(path, profile)?;
+    Ok(toml::to_string_pretty(&config.redacted())?)
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let command = match cli.command {
+        Command::Config { command } => return config_command(command, &cli.config),
+        command => command,
+    };
+
+    let config = cli.config.load()?;
+    let level = match command {
+        Command::Serve(_) => config.server.log_level.clone(),
+        _ => "warn".to_string(),
+    };
+    let log_level_reloader = init_logging(&level);
+
+    match command {
+        Command::Serve(args) => {
+            let ConfigArgs {
+                config: path,
+                profile,
+            } = cli.config;
+            serve::run(args, config, path, profile, log_level_reloader).await
+        }
+        Command::Infer(args) => infer::run(args, config).await,
+        Command::Chat(args) => chat::run(args, config).await,
+        Command::Sessions(args) => sessions::run(args, config).await,
+        Command::Models {
+            command: ModelsCommand::List,
+        } => {
+            list_models(&config);
+            Ok(())
+        }
+        Command::Tokens(args) => tokens::run(args, config).await,
+        Command::Doctor => doctor::run(config),
+        Command::Config { .. } => unreachable!("handled before loading the config"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_cli_definition() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_global_config_flags() {
+        let cli = Cli::try_parse_from(["llm-inference", "serve", "--mock", "--profile", "dev"]);
+        let cli = cli.unwrap();
+        assert!(matches!(cli.command, Command::Serve(_)));
+        assert_eq!(cli.config.profile.as_deref(), Some("dev"));
+    }
+
+    #[test]
+    fn test_config_check_masks_keys() {
+        let path = std::env::temp_dir().join(format!("llm_inference_check_{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+[server]
+[models]
+available_models = [{ id = "qwen", name = "Qwen/Qwen2.5-0.5B-Instruct" }]
+
+[limits]
+[observability]
+
+[[security.api_keys]]
+key = "sk-base-secret-0001"
+name = "base"
+
+[profile.prod.security]
+enable_auth = true
+
+[[profile.prod.security.api_keys]]
+key = "sk-prod-secret-0002"
+name = "prod"
+"#,
+        )
+        .unwrap();
+
+        let output = check_config(path.to_str().unwrap(), Some("prod")).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(output.contains("enable_auth = true"));
+        assert!(output.contains("****0002"));
+        assert!(!output.contains("sk-prod-secret"));
+        assert!(!output.contains("sk-base-secret"));
+    }
+}
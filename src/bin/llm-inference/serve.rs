@@ -0,0 +1,314 @@
+use axum::Server;
+use clap::Args;
+use llm_inference::config::Config;
+use llm_inference::engine::{InferenceEngine, M1EngineAdapter};
+use llm_inference::engine_mock::MockEngine;
+use llm_inference::engine_tokenizer::TokenizerEngine;
+use llm_inference::frontend;
+use llm_inference::gpu_metrics;
+use llm_inference::listener;
+use llm_inference::metrics_push;
+use llm_inference::request_metrics;
+use llm_inference::routes;
+use llm_inference::state::{prometheus_builder, AppState, LogLevelReloader};
+use axum::Router;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tower_http::cors::{Any, CorsLayer};
+use tracing::info;
+
+#[derive(Debug, Args)]
+pub struct ServeArgs {
+    /// Answer with the MockEngine instead of loading models (no GPU or downloads needed)
+    #[arg(long)]
+    mock: bool,
+}
+
+pub async fn run(
+    args: ServeArgs,
+    config: Config,
+    config_path: Option<String>,
+    profile: Option<String>,
+    log_level_reloader: LogLevelReloader,
+) -> anyhow::Result<()> {
+    info!("🚀 Starting Rust LLM Inference Service");
+    info!("📝 Configuration loaded");
+
+    // Initialize Prometheus Metrics
+    if config.observability.enable_metrics {
+        let builder = prometheus_builder(&config.observability)?;
+        let handle = builder
+            .install_recorder()
+            .expect("failed to install Prometheus recorder");
+        info!(
+            "📊 Prometheus metrics initialized at {}",
+            config.observability.metrics_path
+        );
+
+        if let Some(url) = &config.observability.metrics_push_url {
+            metrics_push::spawn_pusher(
+                handle.clone(),
+                url.clone(),
+                std::time::Duration::from_secs(config.observability.metrics_push_interval_seconds),
+            );
+        }
+
+        if config.observability.gpu_metrics_interval_seconds > 0 {
+            gpu_metrics::spawn_collector(std::time::Duration::from_secs(
+                config.observability.gpu_metrics_interval_seconds,
+            ));
+        }
+
+        let engine: Arc<dyn InferenceEngine> = if args.mock {
+            info!("🧪 Using the mock inference engine");
+            Arc::new(MockEngine::from_config(&config.mock))
+        } else if config.server.read_only {
+            // The catalog still answers /models, but nothing is loaded
+            info!("📖 Read-only replica: generations are disabled and no models are loaded");
+            Arc::new(M1EngineAdapter::new(config.models.available_models.clone()))
+        } else if config.server.tokenizer_only {
+            info!("🔤 Tokenizer-only mode: generations are disabled and no weights are loaded");
+            Arc::new(TokenizerEngine::new(config.models.available_models.clone()))
+        } else {
+            info!("🤖 Initializing Inference Engine...");
+            Arc::new(load_engine(&config).await)
+        };
+
+        // Initialize AppState
+        let state = AppState::new(engine, handle, config.clone())
+            .await?
+            .with_log_level_reloader(log_level_reloader)
+            .with_profile(profile)
+            .with_config_path(config_path);
+        #[cfg(feature = "chaos")]
+        let state = {
+            tracing::warn!("🐒 Built with chaos mode: faults can be injected with PUT /debug/chaos");
+            let chaos = Arc::new(llm_inference::chaos::ChaosEngine::new(state.engine.clone()));
+            state.with_chaos(chaos)
+        };
+
+        // Reload the config file on SIGHUP
+        #[cfg(unix)]
+        {
+            let state = state.clone();
+            tokio::spawn(async move {
+                use tokio::signal::unix::{signal, SignalKind};
+                let Ok(mut hangup) = signal(SignalKind::hangup()) else {
+                    tracing::warn!(
+                        "⚠️ Could not install SIGHUP handler; config reload via API only"
+                    );
+                    return;
+                };
+                while hangup.recv().await.is_some() {
+                    info!("🔄 SIGHUP received, reloading configuration");
+                    if let Err(e) = state.reload_config().await {
+                        tracing::warn!("⚠️ Config reload rejected: {}", e);
+                    }
+                }
+            });
+        }
+
+        // Setup CORS
+        let cors = CorsLayer::new()
+            .allow_origin(Any)
+            .allow_methods(Any)
+            .allow_headers(Any);
+
+        // Build router and attach rate-limit middleware (uses AppState clone)
+        // Build router
+        // Attach global rate-limit middleware so all routes (including /sessions)
+        // receive X-RateLimit headers and 429 when exceeded.
+        let app = routes::router_for(&config.observability)
+            .route_layer(axum::middleware::from_fn_with_state(
+                state.clone(),
+                routes::rate_limit,
+            ))
+            // Outermost, so a traced request's rate limiting is logged too
+            .route_layer(axum::middleware::from_fn_with_state(
+                state.clone(),
+                routes::debug_trace,
+            ))
+            // Counts every request, including those turned away above
+            .route_layer(axum::middleware::from_fn(request_metrics::record))
+            .with_state(state.clone())
+            .layer(cors)
+            .fallback(frontend::serve);
+
+        // Bind and serve
+        let host = config
+            .server
+            .host
+            .parse::<std::net::IpAddr>()
+            .unwrap_or(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)));
+        let addr = SocketAddr::from((host, config.server.port));
+
+        if let Some(port) = config.observability.metrics_port {
+            let metrics_addr = SocketAddr::from((host, port));
+            info!(
+                "📊 Metrics listening on http://{}{}",
+                metrics_addr, config.observability.metrics_path
+            );
+            let metrics_app = metrics_app(&state, &config.observability.metrics_path);
+            tokio::spawn(async move {
+                if let Err(e) = Server::bind(&metrics_addr).serve(metrics_app.into_make_service()).await {
+                    tracing::error!("❌ Metrics server failed: {}", e);
+                }
+            });
+        }
+
+        info!("🌐 Server listening on http://{}", addr);
+        info!("💬 Web UI available at http://{}", addr);
+        match frontend::embedded_files() {
+            0 => info!("🗂️ Serving the web UI from {}", frontend::DIST_DIR),
+            n => info!("🗂️ Serving the web UI from {} embedded files", n),
+        }
+        if config.security.enable_auth {
+            info!("🔐 API authentication enabled");
+        }
+
+        let incoming = listener::bind(addr, listener::Limits::from_config(&config.server)).await?;
+        let mut server = Server::builder(incoming);
+        if let Some(timeout) = listener::header_read_timeout(&config.server) {
+            server = server.http1_header_read_timeout(timeout);
+        }
+        // On a signal, stop accepting connections and give open ones (including long-lived
+        // streams such as /events) a grace period to finish
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = server
+            .serve(app.into_make_service())
+            .with_graceful_shutdown(async {
+                let _ = stopped.await;
+            });
+        tokio::pin!(server);
+        tokio::select! {
+            result = &mut server => result?,
+            _ = shutdown_signal() => {
+                let _ = stop.send(());
+                match tokio::time::timeout(SHUTDOWN_GRACE, &mut server).await {
+                    Ok(result) => result?,
+                    Err(_) => tracing::warn!(
+                        "⚠️ Connections still open after {}s; stopping anyway",
+                        SHUTDOWN_GRACE.as_secs()
+                    ),
+                }
+            }
+        }
+        // Keep what was counted since the last periodic save
+        state.flush_limits().await;
+        info!("👋 Server stopped");
+    } else {
+        anyhow::bail!("Metrics must be enabled");
+    }
+
+    Ok(())
+}
+
+/// How long open connections may take to finish after a shutdown signal
+const SHUTDOWN_GRACE: std::time::Duration = std::time::Duration::from_secs(30);
+
+// Resolves on Ctrl-C, or SIGTERM on Unix
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::warn!("⚠️ Could not listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                tracing::warn!("⚠️ Could not install SIGTERM handler: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
+    info!("🛑 Shutting down");
+}
+
+// Build the mistral.rs engine and pre-warm every configured model
+async fn load_engine(config: &Config) -> M1EngineAdapter {
+    // Load available models from config
+    let available_models = config.models.available_models.clone();
+    let model_labels: Vec<String> = available_models
+        .iter()
+        .map(|m| format!("{} ({})", m.name, m.id))
+        .collect();
+
+    info!("📦 Available models: {:?}", model_labels);
+
+    let engine = M1EngineAdapter::new(available_models.clone());
+
+    // Pre-warm all models
+    let device = if cfg!(feature = "cuda") {
+        "cuda"
+    } else {
+        "cpu"
+    };
+    info!(
+        "🔥 Pre-warming {} models on {}",
+        available_models.len(),
+        device
+    );
+    for model in &available_models {
+        info!("🔥 Loading model: {} ({})", model.name, model.id);
+        if let Err(e) = engine.warmup(&model.id, device).await {
+            tracing::warn!("⚠️ Failed to pre-warm model {}: {:?}", model.name, e);
+        } else {
+            info!("✅ Model cached: {}", model.name);
+        }
+    }
+    engine
+}
+
+// The metrics endpoint on its own port. Scrapes still go through the rate limiter and its auth
+// checks, and are counted in the HTTP metrics like requests on the API port.
+fn metrics_app(state: &AppState, path: &str) -> Router {
+    routes::metrics_router(path)
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            routes::rate_limit,
+        ))
+        .route_layer(axum::middleware::from_fn(request_metrics::record))
+        .with_state(state.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_metrics_port_counts_scrapes() {
+        let handle = PrometheusBuilder::new().install_recorder().unwrap();
+        let state = AppState::new_in_memory(Arc::new(MockEngine::new()), handle, Config::default())
+            .await
+            .unwrap();
+        let app = metrics_app(&state, "/metrics");
+
+        let scrape = || Request::get("/metrics").body(Body::empty()).unwrap();
+        let resp = app.clone().oneshot(scrape()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = app.oneshot(scrape()).await.unwrap();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(
+            body.contains(r#"http_requests_total{method="GET",route="/metrics",status="2xx"} 1"#),
+            "{}",
+            body
+        );
+    }
+}
@@ -0,0 +1,228 @@
+//! `sessions`: inspect, export and delete chat sessions, either in a local session database or
+//! on a running server.
+
+use anyhow::{bail, Context, Result};
+use clap::{Args, Subcommand, ValueEnum};
+#[cfg(feature = "client")]
+use llm_inference::client::Client;
+use llm_inference::config::Config;
+use llm_inference::export::ExportFormat;
+use llm_inference::models::ChatMessage;
+use llm_inference::state::{SessionStore, SESSIONS_DB};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Args)]
+pub struct SessionsArgs {
+    #[command(subcommand)]
+    command: SessionsCommand,
+
+    /// Talk to a running server instead of opening the database (use this while it is running;
+    /// the server keeps sessions in memory and would write its own copy back)
+    #[arg(long, value_name = "URL", global = true)]
+    url: Option<String>,
+
+    /// API key for --url
+    #[arg(long, env = "LLM_API_KEY", global = true)]
+    api_key: Option<String>,
+
+    /// Session database to open without --url
+    #[arg(long, value_name = "PATH", default_value = SESSIONS_DB, global = true)]
+    db: String,
+}
+
+#[derive(Debug, Subcommand)]
+enum SessionsCommand {
+    /// Print every session id
+    List,
+    /// Print one conversation
+    Show { id: String },
+    /// Delete a session
+    Delete { id: String },
+    /// Write sessions as JSON, `{"<id>": [messages...]}`, or as Markdown or HTML documents
+    Export {
+        /// Only this session (defaults to all)
+        id: Option<String>,
+        /// Output format
+        #[arg(long, value_enum, default_value = "json")]
+        format: SessionsFormat,
+        /// Write to a file instead of stdout
+        #[arg(long, value_name = "PATH")]
+        output: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+enum SessionsFormat {
+    Json,
+    Markdown,
+    Html,
+}
+
+enum Backend {
+    Local(SessionStore),
+    #[cfg(feature = "client")]
+    Remote(Client),
+}
+
+impl Backend {
+    async fn open(args: &SessionsArgs, config: &Config) -> Result<Self> {
+        Ok(match &args.url {
+            #[cfg(feature = "client")]
+            Some(url) => {
+                let client = Client::new(url);
+                Backend::Remote(match &args.api_key {
+                    Some(key) => client.with_api_key(key),
+                    None => client,
+                })
+            }
+            #[cfg(not(feature = "client"))]
+            Some(_) => bail!("--url needs a build with the `client` feature"),
+            None => {
+                if !std::path::Path::new(&args.db).exists() {
+                    bail!("{} does not exist (pass --db or --url)", args.db);
+                }
+                Backend::Local(SessionStore::new(&args.db, &config.session_store, None).await?)
+            }
+        })
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        let mut ids: Vec<String> = match self {
+            Backend::Local(store) => store.load_sessions().await?.into_keys().collect(),
+            #[cfg(feature = "client")]
+            Backend::Remote(client) => client.sessions().await?,
+        };
+        ids.sort();
+        Ok(ids)
+    }
+
+    async fn history(&self, id: &str) -> Result<Vec<ChatMessage>> {
+        let history = match self {
+            Backend::Local(store) => store.load_sessions().await?.remove(id),
+            // The server answers unknown ids with an empty history
+            #[cfg(feature = "client")]
+            Backend::Remote(client) => Some(client.history(id).await?).filter(|h| !h.is_empty()),
+        };
+        history.with_context(|| format!("session {} not found", id))
+    }
+
+    /// One session, or every session read in a single pass
+    async fn export(&self, id: Option<String>) -> Result<BTreeMap<String, Vec<ChatMessage>>> {
+        match (self, id) {
+            (Backend::Local(store), None) => Ok(store.load_sessions().await?.into_iter().collect()),
+            (backend, Some(id)) => {
+                let history = backend.history(&id).await?;
+                Ok(BTreeMap::from([(id, history)]))
+            }
+            #[cfg(feature = "client")]
+            (Backend::Remote(client), None) => {
+                let mut sessions = BTreeMap::new();
+                for id in client.sessions().await? {
+                    let history = client.history(&id).await?;
+                    sessions.insert(id, history);
+                }
+                Ok(sessions)
+            }
+        }
+    }
+
+    async fn delete(&self, id: &str) -> Result<()> {
+        match self {
+            Backend::Local(store) => store.delete_session(id).await,
+            #[cfg(feature = "client")]
+            Backend::Remote(client) => Ok(client.delete_session(id).await?),
+        }
+    }
+}
+
+fn print_history(history: &[ChatMessage]) {
+    for message in history {
+        println!("[{}]\n{}\n", message.role, message.content);
+    }
+}
+
+// Exported sessions as one document; Markdown and HTML put one conversation after another
+fn render(sessions: &BTreeMap<String, Vec<ChatMessage>>, format: SessionsFormat) -> Result<String> {
+    let format = match format {
+        SessionsFormat::Json => return Ok(serde_json::to_string_pretty(sessions)? + "\n"),
+        SessionsFormat::Markdown => ExportFormat::Md,
+        SessionsFormat::Html => ExportFormat::Html,
+    };
+    let documents: Vec<String> = sessions.iter().map(|(id, history)| format.render(id, history)).collect();
+    Ok(documents.join("\n"))
+}
+
+pub async fn run(args: SessionsArgs, config: Config) -> Result<()> {
+    let backend = Backend::open(&args, &config).await?;
+    match args.command {
+        SessionsCommand::List => {
+            for id in backend.list().await? {
+                println!("{}", id);
+            }
+        }
+        SessionsCommand::Show { id } => print_history(&backend.history(&id).await?),
+        SessionsCommand::Delete { id } => {
+            backend.delete(&id).await?;
+            eprintln!("🗑️ Deleted session {}", id);
+        }
+        SessionsCommand::Export { id, format, output } => {
+            let sessions = backend.export(id).await?;
+            let document = render(&sessions, format)?;
+            match output {
+                Some(path) => {
+                    std::fs::write(&path, document)?;
+                    eprintln!("✅ Exported {} sessions to {}", sessions.len(), path);
+                }
+                None => print!("{}", document),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use llm_inference::state::IN_MEMORY_DB;
+
+    fn message(role: &str, content: &str) -> ChatMessage {
+        ChatMessage {
+            role: role.to_string(),
+            content: content.to_string(),
+            truncated: false,
+            partial: false,
+            timestamp: None,
+            continues: false,
+        }
+    }
+
+    async fn exported(id: Option<&str>) -> BTreeMap<String, Vec<ChatMessage>> {
+        let store = SessionStore::new(IN_MEMORY_DB, &Default::default(), None).await.unwrap();
+        store.upsert_session("a", &[message("user", "Hi"), message("assistant", "Hello")]).await.unwrap();
+        store.upsert_session("b", &[message("user", "Bye")]).await.unwrap();
+        Backend::Local(store).export(id.map(str::to_string)).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_export_json() {
+        let sessions = exported(None).await;
+        let json: serde_json::Value = serde_json::from_str(&render(&sessions, SessionsFormat::Json).unwrap()).unwrap();
+        assert_eq!(json["a"][1]["content"], "Hello");
+        assert_eq!(json["b"][0]["role"], "user");
+
+        let sessions = exported(Some("b")).await;
+        let json: serde_json::Value = serde_json::from_str(&render(&sessions, SessionsFormat::Json).unwrap()).unwrap();
+        assert_eq!(json.as_object().unwrap().keys().collect::<Vec<_>>(), ["b"]);
+    }
+
+    #[tokio::test]
+    async fn test_export_markdown() {
+        let sessions = exported(None).await;
+        let markdown = render(&sessions, SessionsFormat::Markdown).unwrap();
+        let a = markdown.find("# Conversation a").unwrap();
+        let b = markdown.find("# Conversation b").unwrap();
+        assert!(a < b, "{}", markdown);
+        assert!(markdown[a..b].contains("Hello"));
+        assert!(markdown[b..].contains("Bye"));
+    }
+}
@@ -0,0 +1,143 @@
+//! `tokens`: tokenize text with a configured model's tokenizer, without loading the model or
+//! talking to the server.
+
+use crate::infer::read_input;
+use anyhow::{Context, Result};
+use clap::{Args, Subcommand};
+use llm_inference::config::{Config, ModelConfig};
+use llm_inference::engine_tokenizer::ModelTokenizer;
+use std::path::PathBuf;
+use tokenizers::Tokenizer;
+
+#[derive(Debug, Args)]
+pub struct TokensArgs {
+    #[command(subcommand)]
+    command: TokensCommand,
+}
+
+#[derive(Debug, Subcommand)]
+enum TokensCommand {
+    /// Print the token count and whether the text fits the model's context
+    Count(TokenInput),
+    /// Print every token id and the text it stands for
+    Encode(TokenInput),
+}
+
+#[derive(Debug, Args)]
+struct TokenInput {
+    /// Model name or id from the config (defaults to the first configured model)
+    #[arg(short, long, env = "LLM_MODEL")]
+    model: Option<String>,
+
+    /// Read the text from a file (`-` for stdin, the default)
+    #[arg(short, long, value_name = "FILE", default_value = "-")]
+    file: String,
+
+    /// Count this text instead of reading --file
+    #[arg(short, long, conflicts_with = "file")]
+    text: Option<String>,
+
+    /// tokenizer.json to use instead of the model's own
+    #[arg(long, value_name = "PATH")]
+    tokenizer: Option<PathBuf>,
+
+    /// Tokens to keep free for the answer when checking the context length
+    #[arg(long, default_value_t = 0)]
+    max_tokens: usize,
+}
+
+impl TokenInput {
+    fn model<'a>(&self, config: &'a Config) -> Result<&'a ModelConfig> {
+        let models = &config.models.available_models;
+        match &self.model {
+            Some(wanted) => models
+                .iter()
+                .find(|m| m.answers_to(wanted))
+                .with_context(|| format!("model {} is not configured", wanted)),
+            None => models
+                .first()
+                .context("no models configured; pass --model or add [[models.available_models]]"),
+        }
+    }
+
+    fn text(&self) -> Result<String> {
+        match &self.text {
+            Some(text) => Ok(text.clone()),
+            None => read_input(&self.file),
+        }
+    }
+}
+
+pub async fn run(args: TokensArgs, config: Config) -> Result<()> {
+    let (TokensCommand::Count(input) | TokensCommand::Encode(input)) = &args.command;
+    let model = input.model(&config)?;
+    let tokenizer = match &input.tokenizer {
+        Some(path) => Tokenizer::from_file(path).map_err(anyhow::Error::msg)?,
+        None => ModelTokenizer::load(model)
+            .await
+            .with_context(|| format!("no tokenizer for {}", model.name))?
+            .tokenizer,
+    };
+    let text = input.text()?;
+    let encoding = tokenizer
+        .encode(text.as_str(), false)
+        .map_err(anyhow::Error::msg)?;
+
+    match args.command {
+        TokensCommand::Count(input) => {
+            let tokens = encoding.len();
+            println!("model: {} ({})", model.name, model.id);
+            println!("characters: {}", text.chars().count());
+            println!("tokens: {}", tokens);
+            match model.context_length {
+                Some(context) => {
+                    let needed = tokens + input.max_tokens;
+                    if needed <= context {
+                        println!(
+                            "context: {} (fits, {} tokens to spare)",
+                            context,
+                            context - needed
+                        );
+                    } else {
+                        println!(
+                            "context: {} (does not fit, {} tokens over)",
+                            context,
+                            needed - context
+                        );
+                    }
+                }
+                None => println!("context: unknown (set context_length for this model)"),
+            }
+        }
+        TokensCommand::Encode(_) => {
+            for (id, token) in encoding.get_ids().iter().zip(encoding.get_tokens()) {
+                println!("{}\t{:?}", id, token);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Cli, Command};
+    use clap::Parser;
+
+    #[test]
+    fn test_text_conflicts_with_file() {
+        let cli = Cli::try_parse_from([
+            "llm-inference",
+            "tokens",
+            "count",
+            "--text",
+            "hi",
+            "--file",
+            "prompt.txt",
+        ]);
+        assert!(cli.is_err());
+
+        let cli =
+            Cli::try_parse_from(["llm-inference", "tokens", "count", "-m", "qwen", "-t", "hi"]);
+        assert!(matches!(cli.unwrap().command, Command::Tokens(_)));
+    }
+}
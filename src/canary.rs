@@ -0,0 +1,119 @@
+//! Canary checks for loaded models (`[canary]`).
+//!
+//! A background task runs a tiny generation against every model the engine reports, every
+//! `canary.interval_seconds`. After `canary.failure_threshold` failures in a row a model is
+//! marked unhealthy: requests for it are refused (or forwarded to a federation peer), and
+//! `/readiness` lists it. Checks keep running, and the first one that succeeds brings the model
+//! back. This catches faults such as a corrupted CUDA context that only surface when the
+//! model actually generates.
+
+use chrono::{DateTime, Utc};
+use metrics::gauge;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ModelStatus {
+    pub healthy: bool,
+    pub consecutive_failures: u32,
+    pub last_check: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+/// Canary results by model name
+#[derive(Default)]
+pub struct ModelHealth {
+    models: RwLock<BTreeMap<String, ModelStatus>>,
+}
+
+impl ModelHealth {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Models that have not been checked yet count as healthy
+    pub fn is_healthy(&self, model: &str) -> bool {
+        self.models
+            .read()
+            .unwrap()
+            .get(model)
+            .is_none_or(|status| status.healthy)
+    }
+
+    pub fn record_success(&self, model: &str) {
+        self.models.write().unwrap().insert(
+            model.to_string(),
+            ModelStatus {
+                healthy: true,
+                consecutive_failures: 0,
+                last_check: Utc::now(),
+                last_error: None,
+            },
+        );
+        gauge!("model_healthy", 1.0, "model" => model.to_string());
+    }
+
+    /// Count a failed check; returns true when this failure made the model unhealthy
+    pub fn record_failure(&self, model: &str, error: String, threshold: u32) -> bool {
+        let mut models = self.models.write().unwrap();
+        let status = models.entry(model.to_string()).or_insert(ModelStatus {
+            healthy: true,
+            consecutive_failures: 0,
+            last_check: Utc::now(),
+            last_error: None,
+        });
+        status.consecutive_failures += 1;
+        status.last_check = Utc::now();
+        status.last_error = Some(error);
+        let was_healthy = status.healthy;
+        status.healthy = status.consecutive_failures < threshold;
+        gauge!("model_healthy", if status.healthy { 1.0 } else { 0.0 }, "model" => model.to_string());
+        was_healthy && !status.healthy
+    }
+
+    /// Forget models the engine no longer serves
+    pub fn retain(&self, models: &[String]) {
+        self.models
+            .write()
+            .unwrap()
+            .retain(|name, _| models.contains(name));
+    }
+
+    pub fn unhealthy(&self) -> Vec<String> {
+        self.models
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, status)| !status.healthy)
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, ModelStatus> {
+        self.models.read().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_failures_mark_a_model_unhealthy_until_it_recovers() {
+        let health = ModelHealth::new();
+        assert!(health.is_healthy("qwen"));
+
+        assert!(!health.record_failure("qwen", "boom".into(), 2));
+        assert!(health.is_healthy("qwen"));
+        assert!(health.record_failure("qwen", "boom".into(), 2));
+        assert!(!health.record_failure("qwen", "boom".into(), 2));
+        assert!(!health.is_healthy("qwen"));
+        assert_eq!(health.unhealthy(), vec!["qwen"]);
+
+        health.record_success("qwen");
+        assert!(health.is_healthy("qwen"));
+        assert_eq!(health.snapshot()["qwen"].consecutive_failures, 0);
+    }
+}
@@ -0,0 +1,188 @@
+//! Error injection for resilience testing (`--features chaos`, `/debug/chaos`).
+//!
+//! [`ChaosEngine`] wraps the real engine and, for a configurable share of generations, waits
+//! before starting, fails to start, or panics after the first token. That exercises the panic
+//! guard, error reporting, timeouts and client retries in staging without a broken model.
+//! Everything starts at zero; an admin turns it up at runtime with `PUT /debug/chaos`. Builds
+//! without the feature have neither the wrapper nor the endpoint.
+
+use crate::config::ModelConfig;
+use crate::engine::{CachedModel, EngineError, InferenceEngine, LoadedModel, RenderedPrompt, TokenStream};
+use crate::models::InferenceRequest;
+use anyhow::{anyhow, Result as AnyResult};
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use metrics::increment_counter;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use utoipa::ToSchema;
+
+/// Share (0-1) of generations hit by each fault; each is drawn independently
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct ChaosSettings {
+    #[serde(default)]
+    pub delay_rate: f64,
+    /// How long a delayed generation waits before starting
+    #[serde(default)]
+    pub delay_ms: u64,
+    #[serde(default)]
+    pub error_rate: f64,
+    #[serde(default)]
+    pub panic_rate: f64,
+}
+
+impl ChaosSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, rate) in [
+            ("delay_rate", self.delay_rate),
+            ("error_rate", self.error_rate),
+            ("panic_rate", self.panic_rate),
+        ] {
+            if !(0.0..=1.0).contains(&rate) {
+                return Err(format!("{} must be between 0 and 1", name));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The engine with faults injected as the current [`ChaosSettings`] say
+pub struct ChaosEngine {
+    inner: Arc<dyn InferenceEngine>,
+    settings: RwLock<ChaosSettings>,
+}
+
+impl ChaosEngine {
+    pub fn new(inner: Arc<dyn InferenceEngine>) -> Self {
+        Self {
+            inner,
+            settings: RwLock::new(ChaosSettings::default()),
+        }
+    }
+
+    pub fn settings(&self) -> ChaosSettings {
+        *self.settings.read().unwrap()
+    }
+
+    pub fn set_settings(&self, settings: ChaosSettings) {
+        *self.settings.write().unwrap() = settings;
+    }
+}
+
+fn hit(rate: f64) -> bool {
+    rate > 0.0 && (uuid::Uuid::new_v4().as_u64_pair().0 as f64 / u64::MAX as f64) < rate
+}
+
+#[async_trait]
+impl InferenceEngine for ChaosEngine {
+    async fn get_available_models(&self) -> Vec<String> {
+        self.inner.get_available_models().await
+    }
+
+    async fn run_streaming_inference(
+        &self,
+        request: InferenceRequest,
+    ) -> Result<TokenStream, EngineError> {
+        let settings = self.settings();
+        if hit(settings.delay_rate) {
+            increment_counter!("chaos_injections_total", "fault" => "delay");
+            tokio::time::sleep(Duration::from_millis(settings.delay_ms)).await;
+        }
+        if hit(settings.error_rate) {
+            increment_counter!("chaos_injections_total", "fault" => "error");
+            return Err(EngineError::Backend(anyhow!("chaos: injected engine error")));
+        }
+        let mut stream = self.inner.run_streaming_inference(request).await?;
+        if !hit(settings.panic_rate) {
+            return Ok(stream);
+        }
+        increment_counter!("chaos_injections_total", "fault" => "panic");
+        let panicking = async_stream::stream! {
+            if let Some(first) = stream.next().await {
+                yield first;
+            }
+            panic!("chaos: injected engine panic");
+        };
+        let boxed: TokenStream = Box::pin(panicking);
+        Ok(boxed)
+    }
+
+    async fn cached_models(&self) -> Vec<String> {
+        self.inner.cached_models().await
+    }
+
+    async fn loaded_device(&self, model: &str) -> Option<String> {
+        self.inner.loaded_device(model).await
+    }
+
+    async fn loaded_model(&self, model: &str) -> Option<LoadedModel> {
+        self.inner.loaded_model(model).await
+    }
+
+    async fn cache_entries(&self) -> Vec<CachedModel> {
+        self.inner.cache_entries().await
+    }
+
+    async fn evict_model(&self, model: &str) -> Result<bool, EngineError> {
+        self.inner.evict_model(model).await
+    }
+
+    async fn render_prompt(&self, request: &InferenceRequest) -> Result<RenderedPrompt, EngineError> {
+        self.inner.render_prompt(request).await
+    }
+
+    async fn tokenize(&self, model: &str, text: &str, add_special_tokens: bool) -> Result<Vec<u32>, EngineError> {
+        self.inner.tokenize(model, text, add_special_tokens).await
+    }
+
+    fn continues_messages(&self) -> bool {
+        self.inner.continues_messages()
+    }
+
+    async fn embed(&self, model: &str, text: &str) -> AnyResult<Vec<f32>> {
+        self.inner.embed(model, text).await
+    }
+
+    fn embeds(&self) -> bool {
+        self.inner.embeds()
+    }
+
+    async fn reload_models(&self, configs: Vec<ModelConfig>) -> AnyResult<()> {
+        self.inner.reload_models(configs).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine_mock::MockEngine;
+
+    fn request() -> InferenceRequest {
+        serde_json::from_value(serde_json::json!({"model-name": "mock-model", "prompt": "hi"}))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn faults_follow_the_settings() {
+        let engine = ChaosEngine::new(Arc::new(MockEngine::new()));
+        assert!(engine.run_streaming_inference(request()).await.is_ok());
+
+        engine.set_settings(ChaosSettings { error_rate: 1.0, ..Default::default() });
+        let err = engine.run_streaming_inference(request()).await.err().unwrap();
+        assert_eq!(err.to_string(), "chaos: injected engine error");
+
+        engine.set_settings(ChaosSettings { panic_rate: 1.0, ..Default::default() });
+        let mut stream = engine.run_streaming_inference(request()).await.unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap().as_str(), "hello");
+        let panicked = tokio::spawn(async move { stream.next().await.map(|_| ()) }).await;
+        assert!(panicked.unwrap_err().is_panic());
+    }
+
+    #[test]
+    fn rates_must_be_fractions() {
+        assert!(ChaosSettings::default().validate().is_ok());
+        let settings = ChaosSettings { panic_rate: 1.5, ..Default::default() };
+        assert_eq!(settings.validate().unwrap_err(), "panic_rate must be between 0 and 1");
+    }
+}
@@ -0,0 +1,311 @@
+//! Typed async client for a running server (feature `client`, on by default).
+//!
+//! Wraps the HTTP API so Rust callers do not have to build requests or parse SSE themselves:
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), llm_inference::client::ClientError> {
+//! use futures_util::StreamExt;
+//! use llm_inference::client::Client;
+//!
+//! let client = Client::new("http://localhost:3000").with_api_key("sk-...");
+//! let mut stream = client.chat_stream(&Client::chat_request("qwen", "Hello!")).await?;
+//! while let Some(token) = stream.next().await {
+//!     print!("{}", token?);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::engine::Token;
+use crate::models::{ChatMessage, CompletionRequest, InferenceRequest, ModelsList};
+use futures_util::{Stream, StreamExt};
+use serde::Deserialize;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    /// The server answered with an error status
+    #[error("server returned {status}: {message}")]
+    Api {
+        status: u16,
+        /// Machine-readable `code` from the error body, when the server sent one
+        code: Option<String>,
+        message: String,
+    },
+    /// The server reported an error partway through a stream
+    #[error("stream failed: {0}")]
+    Stream(String),
+}
+
+/// Non-streaming `/completions` answer
+#[derive(Debug, Clone, Deserialize)]
+pub struct Completion {
+    pub text: String,
+    pub model: String,
+    pub tokens: u64,
+    pub finish_reason: String,
+    #[serde(default)]
+    pub cached: bool,
+}
+
+/// Tokens of a streamed chat reply. Ends after the last token; named events (`queue`, `warning`,
+/// `finish`) are skipped.
+pub struct ChatStream {
+    /// Id of the session the reply belongs to, when the server created one (`create-session`)
+    pub session_id: Option<String>,
+    inner: Pin<Box<dyn Stream<Item = Result<Token, ClientError>> + Send>>,
+}
+
+impl Stream for ChatStream {
+    type Item = Result<Token, ClientError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.as_mut().poll_next(cx)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+}
+
+impl Client {
+    /// Client for the server at `base_url`, e.g. `http://localhost:3000`
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            api_key: None,
+        }
+    }
+
+    /// Send this key as a bearer token on every request
+    pub fn with_api_key(mut self, key: impl Into<String>) -> Self {
+        self.api_key = Some(key.into());
+        self
+    }
+
+    /// Use a preconfigured `reqwest` client (timeouts, proxies, TLS)
+    pub fn with_http_client(mut self, http: reqwest::Client) -> Self {
+        self.http = http;
+        self
+    }
+
+    /// Chat request with the server's defaults for everything but model and prompt
+    pub fn chat_request(model: &str, prompt: &str) -> InferenceRequest {
+        serde_json::from_value(serde_json::json!({"model-name": model, "prompt": prompt}))
+            .expect("model and prompt are the only required fields")
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        let request = self
+            .http
+            .request(method, format!("{}{}", self.base_url, path));
+        match &self.api_key {
+            Some(key) => request.bearer_auth(key),
+            None => request,
+        }
+    }
+
+    async fn send(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, ClientError> {
+        let response = request.send().await?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let body = response.text().await.unwrap_or_default();
+        let parsed: Option<serde_json::Value> = serde_json::from_str(&body).ok();
+        let field = |name: &str| {
+            parsed
+                .as_ref()
+                .and_then(|v| v[name].as_str())
+                .map(str::to_string)
+        };
+        Err(ClientError::Api {
+            status: status.as_u16(),
+            code: field("code"),
+            message: field("error").unwrap_or(body),
+        })
+    }
+
+    /// Names of the models the server offers
+    pub async fn models(&self) -> Result<Vec<String>, ClientError> {
+        let response = self
+            .send(self.request(reqwest::Method::GET, "/models"))
+            .await?;
+        Ok(response.json::<ModelsList>().await?.models)
+    }
+
+    /// Run a completion to the end; `stream` and `callback_url` are ignored
+    pub async fn complete(&self, request: &CompletionRequest) -> Result<Completion, ClientError> {
+        let mut request = request.clone();
+        request.stream = false;
+        request.callback_url = None;
+        let response = self
+            .send(
+                self.request(reqwest::Method::POST, "/completions")
+                    .json(&request),
+            )
+            .await?;
+        Ok(response.json().await?)
+    }
+
+    /// Start a chat turn and stream the reply
+    pub async fn chat_stream(&self, request: &InferenceRequest) -> Result<ChatStream, ClientError> {
+        let response = self
+            .send(
+                self.request(reqwest::Method::POST, "/chat/completions")
+                    .json(request),
+            )
+            .await?;
+        let session_id = response
+            .headers()
+            .get("x-session-id")
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+
+        let mut body = response.bytes_stream();
+        let inner = async_stream::stream! {
+            let mut parser = SseParser::default();
+            while let Some(chunk) = body.next().await {
+                let chunk = match chunk {
+                    Ok(chunk) => chunk,
+                    Err(e) => {
+                        yield Err(ClientError::Http(e));
+                        return;
+                    }
+                };
+                for event in parser.push(&chunk) {
+                    if event.event.is_some() {
+                        continue;
+                    }
+                    match event.data.strip_prefix("__ERROR__:") {
+                        Some(message) => {
+                            yield Err(ClientError::Stream(message.to_string()));
+                            return;
+                        }
+                        None => yield Ok(Token::from(event.data)),
+                    }
+                }
+            }
+        };
+        Ok(ChatStream {
+            session_id,
+            inner: Box::pin(inner),
+        })
+    }
+
+    /// Ids of the sessions the server holds
+    pub async fn sessions(&self) -> Result<Vec<String>, ClientError> {
+        let response = self
+            .send(self.request(reqwest::Method::GET, "/sessions"))
+            .await?;
+        Ok(response.json().await?)
+    }
+
+    /// Conversation stored under `session_id`; empty for unknown sessions
+    pub async fn history(&self, session_id: &str) -> Result<Vec<ChatMessage>, ClientError> {
+        let path = format!("/chat/history/{}", session_id);
+        let response = self.send(self.request(reqwest::Method::GET, &path)).await?;
+        Ok(response.json().await?)
+    }
+
+    pub async fn delete_session(&self, session_id: &str) -> Result<(), ClientError> {
+        let path = format!("/chat/history/{}", session_id);
+        self.send(self.request(reqwest::Method::DELETE, &path))
+            .await?;
+        Ok(())
+    }
+}
+
+/// One server-sent event
+#[derive(Debug, Clone, PartialEq, Default)]
+struct SseEvent {
+    event: Option<String>,
+    data: String,
+}
+
+/// Incremental SSE parser; chunks may split lines (and multi-byte characters) anywhere
+#[derive(Debug, Default)]
+struct SseParser {
+    buffer: Vec<u8>,
+    event: Option<String>,
+    data: Vec<String>,
+}
+
+impl SseParser {
+    fn push(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        self.buffer.extend_from_slice(chunk);
+        let mut events = Vec::new();
+        while let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line[..end]);
+            let line = line.strip_suffix('\r').unwrap_or(&line);
+            if line.is_empty() {
+                // A blank line ends the event; comment-only blocks (keep-alives) carry no data
+                if !self.data.is_empty() {
+                    events.push(SseEvent {
+                        event: self.event.take(),
+                        data: self.data.join("\n"),
+                    });
+                    self.data.clear();
+                }
+                self.event = None;
+                continue;
+            }
+            let (field, value) = line.split_once(':').unwrap_or((line, ""));
+            let value = value.strip_prefix(' ').unwrap_or(value);
+            match field {
+                "data" => self.data.push(value.to_string()),
+                "event" => self.event = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sse_parser_handles_split_chunks() {
+        let body =
+            "event:session\ndata:abc\n\ndata:Hel\n\n:\n\ndata:multi\ndata:line\n\ndata:你好\n\n";
+        let bytes = body.as_bytes();
+        let mut parser = SseParser::default();
+        // Feed one byte at a time, splitting the CJK characters too
+        let events: Vec<SseEvent> = bytes.chunks(1).flat_map(|c| parser.push(c)).collect();
+        assert_eq!(
+            events,
+            vec![
+                SseEvent {
+                    event: Some("session".to_string()),
+                    data: "abc".to_string()
+                },
+                SseEvent {
+                    event: None,
+                    data: "Hel".to_string()
+                },
+                SseEvent {
+                    event: None,
+                    data: "multi\nline".to_string()
+                },
+                SseEvent {
+                    event: None,
+                    data: "你好".to_string()
+                },
+            ]
+        );
+    }
+}
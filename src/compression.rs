@@ -0,0 +1,175 @@
+//! Prompt compression for long chat histories (`compress_history: true`).
+//!
+//! Unlike pruning, which drops old turns for good, compression only changes what one request
+//! sends to the model: once the conversation fills `chat.compression_threshold` of the model's
+//! context, the older turns are condensed by the model itself into a short summary of about
+//! `chat.compression_ratio` of their size. The system prompt and the latest exchange are sent
+//! verbatim, and the stored session history is left as it was.
+//!
+//! Compaction (`[compaction]`) is the stored counterpart: a background task replaces the older
+//! turns of sessions that have been idle for a while with such a summary for good, keeping the
+//! latest `compaction.keep_messages` verbatim.
+
+use crate::config::ChatConfig;
+use crate::models::{ChatMessage, InferenceRequest};
+use crate::state::CHARS_PER_TOKEN;
+
+// The latest user turn and the reply before it stay verbatim
+const RECENT_MESSAGES: usize = 2;
+
+// Summaries shorter than this are not worth a generation
+const MIN_SUMMARY_TOKENS: usize = 16;
+
+fn estimate_tokens(messages: &[ChatMessage]) -> usize {
+    messages
+        .iter()
+        .map(|m| m.content.chars().count().div_ceil(CHARS_PER_TOKEN))
+        .sum()
+}
+
+/// The turns to condense: everything between a leading system prompt and the latest exchange.
+/// `None` when the conversation does not fill `threshold` of `context_length` (counting room for
+/// `max_tokens` of answer) or has no older turns.
+pub fn older_turns(
+    messages: &[ChatMessage],
+    context_length: usize,
+    max_tokens: usize,
+    chat: &ChatConfig,
+) -> Option<std::ops::Range<usize>> {
+    let needed = estimate_tokens(messages) + max_tokens;
+    if (needed as f64) < context_length as f64 * chat.compression_threshold {
+        return None;
+    }
+    let start = usize::from(messages.first().is_some_and(|m| m.role == "system"));
+    let end = messages.len().saturating_sub(RECENT_MESSAGES);
+    (end > start).then_some(start..end)
+}
+
+/// The turns of a stored session to compact: everything between a leading system prompt and the
+/// latest `keep` messages. `None` with fewer than two such turns, so a compacted session is not
+/// summarized again until it has grown.
+pub fn compactable_turns(messages: &[ChatMessage], keep: usize) -> Option<std::ops::Range<usize>> {
+    let start = usize::from(messages.first().is_some_and(|m| m.role == "system"));
+    let end = messages.len().saturating_sub(keep);
+    (end >= start + 2).then_some(start..end)
+}
+
+/// Request that asks `req`'s model to condense `turns` to about `ratio` of their size
+pub fn condense_request(req: &InferenceRequest, turns: &[ChatMessage], ratio: f64) -> InferenceRequest {
+    let transcript = turns
+        .iter()
+        .map(|m| format!("{}: {}", m.role, m.content))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let budget = ((estimate_tokens(turns) as f64 * ratio).ceil() as usize).max(MIN_SUMMARY_TOKENS);
+    let mut condense = req.clone();
+    condense.messages = Some(vec![
+        ChatMessage {
+            role: "system".to_string(),
+            content: format!(
+                "Condense the conversation below into at most {} words. Keep names, numbers, \
+                 decisions and open questions; drop pleasantries. Answer with the summary only.",
+                budget * 3 / 4
+            ),
+            truncated: false,
+            partial: false,
+            timestamp: None,
+            continues: false,
+        },
+        ChatMessage {
+            role: "user".to_string(),
+            content: transcript,
+            truncated: false,
+            partial: false,
+            timestamp: None,
+            continues: false,
+        },
+    ]);
+    condense.session_id = None;
+    condense.create_session = false;
+    condense.max_token = budget;
+    condense.temperature = 0.0;
+    condense.stop = Vec::new();
+    condense
+}
+
+/// `messages` with `turns` replaced by one system message carrying `summary`
+pub fn with_summary(
+    mut messages: Vec<ChatMessage>,
+    turns: std::ops::Range<usize>,
+    summary: &str,
+) -> Vec<ChatMessage> {
+    messages.splice(
+        turns,
+        [ChatMessage {
+            role: "system".to_string(),
+            content: format!("Summary of the earlier conversation: {}", summary.trim()),
+            truncated: false,
+            partial: false,
+            timestamp: None,
+            continues: false,
+        }],
+    );
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: &str, content: &str) -> ChatMessage {
+        ChatMessage {
+            role: role.to_string(),
+            content: content.to_string(),
+            truncated: false,
+            partial: false,
+            timestamp: None,
+            continues: false,
+        }
+    }
+
+    fn conversation() -> Vec<ChatMessage> {
+        vec![
+            message("system", "Be brief."),
+            message("user", &"a".repeat(400)),
+            message("assistant", &"b".repeat(400)),
+            message("user", &"c".repeat(400)),
+            message("assistant", &"d".repeat(400)),
+            message("user", "and now?"),
+        ]
+    }
+
+    #[test]
+    fn only_long_conversations_are_condensed() {
+        let chat = ChatConfig::default();
+        let messages = conversation();
+        // ~405 estimated tokens plus 100 for the answer
+        assert_eq!(older_turns(&messages, 4096, 100, &chat), None);
+        assert_eq!(older_turns(&messages, 600, 100, &chat), Some(1..4));
+        assert_eq!(older_turns(&messages[..3], 100, 100, &chat), None);
+    }
+
+    #[test]
+    fn compaction_keeps_the_latest_messages() {
+        let messages = conversation();
+        assert_eq!(compactable_turns(&messages, 2), Some(1..4));
+        assert_eq!(compactable_turns(&messages, 4), None);
+        assert_eq!(compactable_turns(&messages[1..], 2), Some(0..3));
+
+        // A compacted session only qualifies again once it has grown
+        let compacted = with_summary(messages, 1..4, "they talked");
+        assert_eq!(compactable_turns(&compacted, 2), None);
+    }
+
+    #[test]
+    fn summary_replaces_older_turns() {
+        let messages = with_summary(conversation(), 1..4, " they talked ");
+        let roles: Vec<&str> = messages.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, vec!["system", "system", "assistant", "user"]);
+        assert_eq!(
+            messages[1].content,
+            "Summary of the earlier conversation: they talked"
+        );
+        assert_eq!(messages[3].content, "and now?");
+    }
+}
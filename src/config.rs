@@ -1,17 +1,145 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+/// Prefix for environment overrides, e.g. `LLM__SERVER__PORT=8080`
+pub const ENV_PREFIX: &str = "LLM__";
+
+pub const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+/// Environment variable selecting a `[profile.<name>]` section when `--profile` is not given
+pub const PROFILE_ENV: &str = "LLM_PROFILE";
+
+// Top-level key holding the profile override tables
+const PROFILE_KEY: &str = "profile";
+
+// Top-level key listing files to merge beneath this one, relative to its directory
+const INCLUDE_KEY: &str = "include";
+
+/// Files looked up by [`Config::load`], in order of preference
+pub const CONFIG_FILE_CANDIDATES: &[&str] = &[
+    DEFAULT_CONFIG_PATH,
+    "config.yaml",
+    "config.yml",
+    "config.json",
+];
+
+/// Devices the inference engine knows how to initialise
+pub const VALID_DEVICES: &[&str] = &["cpu", "cuda", "metal"];
+/// Device name that lets the server place each model itself (see [`crate::device`])
+pub const AUTO_DEVICE: &str = "auto";
+
+pub const DEFAULT_METRICS_PATH: &str = "/metrics";
+
+/// First path segments of the API routes (keep in step with `routes::router`); the metrics
+/// endpoint cannot live under one of them
+pub const API_ROUTE_PREFIXES: &[&str] = &[
+    "models",
+    "sessions",
+    "events",
+    "completions",
+    "chat",
+    "eval",
+    "feedback",
+    "jobs",
+    "requests",
+    "health",
+    "readiness",
+    "stats",
+    "usage",
+    "keys",
+    "admin",
+    "templates",
+    "tokenize",
+    "debug",
+    "openapi.json",
+    "docs",
+];
+
+/// Settings that can change on a running server; everything else needs a restart
+const HOT_RELOADABLE: &[&str] = &[
+    "server.log_level",
+    "server.swagger_ui",
+    "models.available_models",
+    "security.enable_auth",
+    "security.api_keys",
+    "security.key_rotation_grace_seconds",
+    "limits",
+    "overload",
+    "chat",
+    "guardrails",
+    "pricing",
+    "cache",
+    "streaming",
+    "observability.slow_request_threshold_ms",
+    "observability.slow_ttft_threshold_ms",
+    "observability.metrics_token",
+    "experiments",
+    "shadow",
+    "federation",
+    "canary",
+    "eval",
+    "request_log",
+    "jobs",
+    "compaction",
+    "personas",
+];
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct Config {
     pub server: ServerConfig,
     pub models: ModelsConfig,
     pub security: SecurityConfig,
     pub limits: LimitsConfig,
+    #[serde(default)]
+    pub overload: OverloadConfig,
     pub observability: ObservabilityConfig,
+    #[serde(default)]
+    pub webhooks: WebhookConfig,
+    #[serde(default)]
+    pub chat: ChatConfig,
+    #[serde(default)]
+    pub guardrails: GuardrailsConfig,
+    #[serde(default)]
+    pub pricing: PricingConfig,
+    #[serde(default)]
+    pub error_reporting: ErrorReportingConfig,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    #[serde(default)]
+    pub streaming: StreamingConfig,
+    #[serde(default)]
+    pub session_store: SessionStoreConfig,
+    #[serde(default)]
+    pub persistence: PersistenceConfig,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub experiments: Vec<ExperimentConfig>,
+    #[serde(default)]
+    pub shadow: ShadowConfig,
+    #[serde(default)]
+    pub federation: FederationConfig,
+    #[serde(default)]
+    pub canary: CanaryConfig,
+    #[serde(default)]
+    pub eval: EvalConfig,
+    #[serde(default)]
+    pub request_log: RequestLogConfig,
+    #[serde(default)]
+    pub jobs: JobsConfig,
+    #[serde(default)]
+    pub compaction: CompactionConfig,
+    #[serde(default)]
+    pub mock: MockConfig,
+    /// Named system prompts and default parameters, picked per chat request with `persona`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub personas: BTreeMap<String, PersonaConfig>,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct ServerConfig {
     #[serde(default = "default_host")]
     pub host: String,
@@ -19,9 +147,31 @@ pub struct ServerConfig {
     pub port: u16,
     #[serde(default = "default_log_level")]
     pub log_level: String,
+    /// Serve sessions, history, models and stats but refuse generations with 503, e.g. for a
+    /// dashboard instance sharing `sessions.db` with a serving one
+    #[serde(default)]
+    pub read_only: bool,
+    /// Load only each model's tokenizer and chat template, never its weights: `/tokenize` and
+    /// `/debug/render` work and generations get 503. For CI and prompt budgeting services.
+    #[serde(default)]
+    pub tokenizer_only: bool,
+    /// Close connections that have not sent complete request headers within this many seconds
+    /// (0 = wait forever)
+    #[serde(default = "default_header_read_timeout")]
+    pub header_read_timeout_seconds: u64,
+    /// Close connections that have sent and received nothing for this many seconds (0 = never);
+    /// keep it above the SSE keep-alive interval so long streams are not cut
+    #[serde(default)]
+    pub idle_timeout_seconds: u64,
+    /// Open connections at most; further ones wait to be accepted (0 = unlimited)
+    #[serde(default)]
+    pub max_connections: usize,
+    /// Serve Swagger UI for `/openapi.json` at `/docs`; the page loads its assets from a CDN
+    #[serde(default)]
+    pub swagger_ui: bool,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct ModelsConfig {
     #[serde(default)]
     pub model_dir: Option<PathBuf>,
@@ -32,7 +182,7 @@ pub struct ModelsConfig {
     pub max_concurrent_requests: usize,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct ModelConfig {
     pub id: String,
     pub name: String,
@@ -42,9 +192,41 @@ pub struct ModelConfig {
     pub quantization: Option<String>,
     #[serde(default)]
     pub context_length: Option<usize>,
+    /// Extra names clients may use for this model, e.g. `gpt-3.5-turbo` for drop-in
+    /// replacement of a hosted API
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    /// Devices for extra copies of this model, e.g. `["cuda:1"]`; requests go to whichever copy
+    /// is least busy
+    #[serde(default)]
+    pub standby: Vec<String>,
+    /// Memory the model needs on a device, in GiB, for `default_device = "auto"`; estimated from
+    /// the weights on disk when unset
+    #[serde(default)]
+    pub memory_gb: Option<f64>,
+}
+
+impl ModelConfig {
+    /// Whether requests may name this model `name`: its id, its name or one of its aliases
+    pub fn answers_to(&self, name: &str) -> bool {
+        self.id == name || self.name == name || self.aliases.iter().any(|a| a == name)
+    }
+}
+
+/// Split a device such as `cuda:1` into its kind and ordinal (0 when omitted); `None` for unknown
+/// kinds or a malformed ordinal
+pub fn parse_device(device: &str) -> Option<(&'static str, usize)> {
+    let (kind, ordinal) = match device.split_once(':') {
+        Some((kind, ordinal)) => (kind, ordinal.parse().ok()?),
+        None => (device, 0),
+    };
+    let kind = VALID_DEVICES
+        .iter()
+        .find(|valid| valid.eq_ignore_ascii_case(kind.trim()))?;
+    Some((kind, ordinal))
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct SecurityConfig {
     #[serde(default)]
     pub enable_auth: bool,
@@ -52,9 +234,11 @@ pub struct SecurityConfig {
     pub api_keys: Vec<ApiKeyConfig>,
     #[serde(default)]
     pub allowed_origins: Vec<String>,
+    #[serde(default = "default_key_rotation_grace")]
+    pub key_rotation_grace_seconds: u64,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
 pub struct ApiKeyConfig {
     pub key: String,
     pub name: String,
@@ -62,9 +246,43 @@ pub struct ApiKeyConfig {
     pub rate_limit_per_minute: Option<u32>,
     #[serde(default)]
     pub enabled: bool,
+    #[serde(default)]
+    pub max_concurrent_requests: Option<usize>,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub admin: bool,
+    #[serde(default)]
+    pub max_priority: Option<i32>,
+    /// Model for requests with this key that name none
+    #[serde(default)]
+    pub default_model: Option<String>,
+    /// Highest `temperature` requests with this key get; higher values are lowered to it
+    #[serde(default)]
+    pub max_temperature: Option<f64>,
+    /// Highest `max_tokens` requests with this key get, below `limits.max_response_tokens`
+    #[serde(default)]
+    pub max_tokens: Option<usize>,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+impl ApiKeyConfig {
+    /// Lower `temperature` and `max_tokens` to this key's caps. Returns the parameters that
+    /// were lowered.
+    pub fn cap_params(&self, temperature: &mut f64, max_tokens: &mut usize) -> Vec<&'static str> {
+        let mut lowered = Vec::new();
+        if let Some(cap) = self.max_temperature.filter(|cap| *temperature > *cap) {
+            *temperature = cap;
+            lowered.push("temperature");
+        }
+        if let Some(cap) = self.max_tokens.filter(|cap| *max_tokens > *cap) {
+            *max_tokens = cap;
+            lowered.push("max_tokens");
+        }
+        lowered
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct LimitsConfig {
     #[serde(default = "default_max_prompt_length")]
     pub max_prompt_length: usize,
@@ -76,149 +294,2466 @@ pub struct LimitsConfig {
     pub session_ttl_seconds: u64,
     #[serde(default = "default_rate_limit")]
     pub default_rate_limit_per_minute: u32,
+    #[serde(default)]
+    pub max_concurrent_per_key: Option<usize>,
+    #[serde(default)]
+    pub max_priority: i32,
+    #[serde(default)]
+    pub max_generation_seconds: Option<u64>,
+    #[serde(default = "default_rate_limit_cleanup")]
+    pub rate_limit_cleanup_seconds: u64,
+    /// Save rate-limit windows and per-account usage to the session database this often, and
+    /// restore them on start, so a restart resets neither (0 = keep them in memory only)
+    #[serde(default = "default_limits_persist_interval")]
+    pub persist_interval_seconds: u64,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct ObservabilityConfig {
     #[serde(default = "default_true")]
     pub enable_metrics: bool,
     #[serde(default = "default_true")]
     pub enable_tracing: bool,
-    #[serde(default)]
+    /// Path of the Prometheus endpoint (restart to apply)
+    #[serde(default = "default_metrics_path")]
     pub metrics_path: String,
+    /// Serve the metrics endpoint on this port instead of the API port, e.g. to keep scrapes
+    /// on an internal interface (restart to apply)
+    #[serde(default)]
+    pub metrics_port: Option<u16>,
+    #[serde(default = "default_latency_buckets")]
+    pub latency_buckets: Vec<f64>,
+    #[serde(default)]
+    pub metric_buckets: BTreeMap<String, Vec<f64>>,
+    #[serde(default = "default_gpu_metrics_interval")]
+    pub gpu_metrics_interval_seconds: u64,
+    #[serde(default)]
+    pub slow_request_threshold_ms: Option<u64>,
+    #[serde(default)]
+    pub slow_ttft_threshold_ms: Option<u64>,
+    #[serde(default = "default_session_store_slow_ms")]
+    pub session_store_slow_ms: u64,
+    #[serde(default)]
+    pub metrics_token: Option<String>,
+    #[serde(default)]
+    pub metrics_push_url: Option<String>,
+    #[serde(default = "default_metrics_push_interval")]
+    pub metrics_push_interval_seconds: u64,
 }
 
-// Default value functions
-fn default_host() -> String {
-    "127.0.0.1".to_string()
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct WebhookConfig {
+    #[serde(default)]
+    pub secret: Option<String>,
+    #[serde(default = "default_webhook_max_retries")]
+    pub max_retries: u32,
+    #[serde(default = "default_webhook_backoff_ms")]
+    pub initial_backoff_ms: u64,
+    #[serde(default = "default_webhook_timeout")]
+    pub timeout_seconds: u64,
+    /// Hosts `callback_url` may point at. Empty allows any host that resolves to a public
+    /// address; private, loopback and link-local targets (cloud metadata included) need listing.
+    #[serde(default)]
+    pub allowed_hosts: Vec<String>,
 }
-fn default_port() -> u16 {
-    3000
+
+/// Splits the traffic for one model between variants that swap the model or system prompt
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ExperimentConfig {
+    pub name: String,
+    /// Model name or id whose requests take part
+    pub model: String,
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    pub variants: Vec<ExperimentVariant>,
 }
-fn default_log_level() -> String {
-    "info".to_string()
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ExperimentVariant {
+    pub name: String,
+    /// Percent of the experiment's traffic; the rest stays unchanged as the `control` variant
+    pub weight: u32,
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub system_prompt: Option<String>,
 }
-fn default_device() -> String {
-    "cuda".to_string()
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ChatConfig {
+    #[serde(default = "default_system_prompt")]
+    pub default_system_prompt: String,
+    #[serde(default = "default_max_history_messages")]
+    pub max_history_messages: usize,
+    #[serde(default)]
+    pub max_history_tokens: Option<usize>,
+    /// Size condensed turns aim for, as a share of their original size (`compress_history`)
+    #[serde(default = "default_compression_ratio")]
+    pub compression_ratio: f64,
+    /// Share of the model's context a prompt must fill before `compress_history` condenses it
+    #[serde(default = "default_compression_threshold")]
+    pub compression_threshold: f64,
 }
-fn default_max_concurrent() -> usize {
-    10
+
+/// What a guardrail rule does with a request that matches it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GuardrailAction {
+    /// Refuse the request with a structured refusal
+    #[default]
+    Block,
+    /// Replace the matched text with `guardrails.replacement` and let the request through
+    Redact,
 }
-fn default_max_prompt_length() -> usize {
-    8192
+
+/// One `[[guardrails.rules]]` entry
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct GuardrailRule {
+    /// Policy id reported in refusals and the request log, e.g. `no-credentials`
+    pub id: String,
+    /// Safety category, e.g. `self_harm`, `violence`, `privacy`
+    pub category: String,
+    /// Phrases that trigger the rule, matched ignoring ASCII case
+    pub patterns: Vec<String>,
+    #[serde(default)]
+    pub action: GuardrailAction,
+    /// Shown to the client; a message naming the category when unset
+    #[serde(default)]
+    pub message: Option<String>,
 }
-fn default_max_response_tokens() -> usize {
-    2048
+
+/// Rules that screen prompts and messages before they reach a model (see
+/// [`guardrails`](crate::guardrails))
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct GuardrailsConfig {
+    /// Text that stands in for what a `redact` rule matched
+    #[serde(default = "default_guardrail_replacement")]
+    pub replacement: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<GuardrailRule>,
 }
-fn default_max_sessions() -> usize {
-    1000
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct PricingConfig {
+    #[serde(default = "default_currency")]
+    pub currency: String,
+    #[serde(default)]
+    pub default: TokenPrice,
+    #[serde(default)]
+    pub models: BTreeMap<String, TokenPrice>,
 }
-fn default_session_ttl() -> u64 {
-    3600
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+pub struct TokenPrice {
+    #[serde(default)]
+    pub prompt_per_1k: f64,
+    #[serde(default)]
+    pub completion_per_1k: f64,
 }
-fn default_rate_limit() -> u32 {
-    60
+
+impl PricingConfig {
+    /// Estimated cost of the given token counts, using the model's price or the default one
+    pub fn cost(&self, model: &str, prompt_tokens: u64, completion_tokens: u64) -> f64 {
+        let price = self.models.get(model).unwrap_or(&self.default);
+        (prompt_tokens as f64 * price.prompt_per_1k
+            + completion_tokens as f64 * price.completion_per_1k)
+            / 1000.0
+    }
 }
-fn default_true() -> bool {
-    true
+
+impl Default for PricingConfig {
+    fn default() -> Self {
+        Self {
+            currency: default_currency(),
+            default: TokenPrice::default(),
+            models: BTreeMap::new(),
+        }
+    }
 }
 
-impl Default for Config {
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ErrorReportingConfig {
+    #[serde(default)]
+    pub webhook_url: Option<String>,
+    #[serde(default)]
+    pub sentry_dsn: Option<String>,
+    #[serde(default = "default_environment")]
+    pub environment: String,
+    #[serde(default = "default_error_reporting_timeout")]
+    pub timeout_seconds: u64,
+}
+
+impl Default for ErrorReportingConfig {
     fn default() -> Self {
         Self {
-            server: ServerConfig {
-                host: default_host(),
-                port: default_port(),
-                log_level: default_log_level(),
-            },
-            models: ModelsConfig {
-                model_dir: None,
-                available_models: vec![
-                    ModelConfig {
-                        id: "qwen".to_string(),
-                        name: "Qwen/Qwen2.5-0.5B-Instruct".to_string(),
-                        path: None,
-                        quantization: None,
-                        context_length: Some(4096),
-                    },
-                    ModelConfig {
-                        id: "phi".to_string(),
-                        name: "microsoft/Phi-3.5-mini-instruct".to_string(),
-                        path: None,
-                        quantization: None,
-                        context_length: Some(4096),
-                    },
-                ],
-                default_device: default_device(),
-                max_concurrent_requests: default_max_concurrent(),
-            },
-            security: SecurityConfig {
-                enable_auth: false,
-                api_keys: vec![],
-                allowed_origins: vec!["*".to_string()],
-            },
-            limits: LimitsConfig {
-                max_prompt_length: default_max_prompt_length(),
-                max_response_tokens: default_max_response_tokens(),
-                max_sessions: default_max_sessions(),
-                session_ttl_seconds: default_session_ttl(),
-                default_rate_limit_per_minute: default_rate_limit(),
-            },
-            observability: ObservabilityConfig {
-                enable_metrics: true,
-                enable_tracing: true,
-                metrics_path: "/metrics".to_string(),
-            },
+            webhook_url: None,
+            sentry_dsn: None,
+            environment: default_environment(),
+            timeout_seconds: default_error_reporting_timeout(),
         }
     }
 }
 
-impl Config {
-    /// Load configuration from a TOML file
-    pub fn from_file(path: &str) -> Result<Self> {
-        let content = std::fs::read_to_string(path)
-            .context(format!("Failed to read config file: {}", path))?;
-        let config: Config = toml::from_str(&content).context("Failed to parse config file")?;
-        config.validate()?;
-        Ok(config)
+/// Which request gives way when the generation queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShedPolicy {
+    /// Refuse the request that found the queue full
+    RejectNewest,
+    /// Push out the newest of the lowest-priority waiters when the newcomer outranks them;
+    /// otherwise refuse the newcomer
+    Priority,
+}
+
+/// Bounds on the queue of requests waiting for a generation slot. Requests beyond them are
+/// refused with 503 and a `Retry-After` estimated from recent throughput.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct OverloadConfig {
+    /// Requests waiting for a slot at most (0 = unlimited)
+    #[serde(default = "default_overload_max_queue_length")]
+    pub max_queue_length: usize,
+    /// Longest a request waits for a slot before it is refused (0 = as long as it takes)
+    #[serde(default)]
+    pub max_wait_seconds: u64,
+    #[serde(default = "default_shed_policy")]
+    pub shed_policy: ShedPolicy,
+    /// Upper bound on the `Retry-After` sent with refusals
+    #[serde(default = "default_overload_max_retry_after")]
+    pub max_retry_after_seconds: u64,
+}
+
+impl Default for OverloadConfig {
+    fn default() -> Self {
+        Self {
+            max_queue_length: default_overload_max_queue_length(),
+            max_wait_seconds: 0,
+            shed_policy: default_shed_policy(),
+            max_retry_after_seconds: default_overload_max_retry_after(),
+        }
     }
+}
 
-    /// Load configuration with fallback to default
-    pub fn load() -> Self {
-        match Self::from_file("config.toml") {
-            Ok(config) => {
-                tracing::info!("✅ Loaded configuration from config.toml");
-                config
-            }
-            Err(e) => {
-                tracing::warn!("⚠️ Failed to load config.toml: {}. Using defaults.", e);
-                Self::default()
-            }
+/// SQLite `PRAGMA synchronous` level for the session database
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SqliteSynchronousMode {
+    Off,
+    Normal,
+    Full,
+    Extra,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct SessionStoreConfig {
+    #[serde(default = "default_session_store_pool_size")]
+    pub pool_size: u32,
+    #[serde(default = "default_session_store_synchronous")]
+    pub synchronous: SqliteSynchronousMode,
+    #[serde(default = "default_session_store_cache_size_kib")]
+    pub cache_size_kib: u64,
+    #[serde(default = "default_session_store_statement_cache")]
+    pub statement_cache_capacity: usize,
+}
+
+impl Default for SessionStoreConfig {
+    fn default() -> Self {
+        Self {
+            pool_size: default_session_store_pool_size(),
+            synchronous: default_session_store_synchronous(),
+            cache_size_kib: default_session_store_cache_size_kib(),
+            statement_cache_capacity: default_session_store_statement_cache(),
         }
     }
+}
 
-    /// Validate configuration
-    pub fn validate(&self) -> Result<()> {
-        if self.server.port == 0 {
-            anyhow::bail!("Server port cannot be 0");
+/// Whether anything is written to `sessions.db`
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct PersistenceConfig {
+    /// `false` keeps sessions, and everything else normally stored in `sessions.db` (rotated
+    /// keys, templates, feedback, the request log, rate-limit counters), in memory only; it is
+    /// all gone on restart
+    #[serde(default = "default_persistence_enabled")]
+    pub enabled: bool,
+}
+
+impl Default for PersistenceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_persistence_enabled(),
         }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct StreamingConfig {
+    #[serde(default)]
+    pub coalesce_window_ms: u64,
+    #[serde(default)]
+    pub coalesce_max_chars: usize,
+    #[serde(default = "default_stream_buffer_tokens")]
+    pub buffer_tokens: usize,
+    #[serde(default)]
+    pub slow_consumer_timeout_ms: u64,
+    #[serde(default = "default_queue_update_interval_ms")]
+    pub queue_update_interval_ms: u64,
+    /// Chat streams for prompts of at least this many tokens get `prefill` progress events
+    /// before the first token (0 = never)
+    #[serde(default = "default_prefill_events_min_tokens")]
+    pub prefill_events_min_tokens: usize,
+    /// Keep what every stream sent, with timestamps, for `GET /requests/:id/transcript`
+    #[serde(default)]
+    pub record_transcripts: bool,
+    /// Recorded transcripts are deleted after this; 0 keeps them forever
+    #[serde(default = "default_transcript_retention_hours")]
+    pub transcript_retention_hours: u64,
+}
 
-        if self.models.available_models.is_empty() {
-            anyhow::bail!("At least one model must be configured");
+impl Default for StreamingConfig {
+    fn default() -> Self {
+        Self {
+            coalesce_window_ms: 0,
+            coalesce_max_chars: 0,
+            buffer_tokens: default_stream_buffer_tokens(),
+            slow_consumer_timeout_ms: 0,
+            queue_update_interval_ms: default_queue_update_interval_ms(),
+            prefill_events_min_tokens: default_prefill_events_min_tokens(),
+            record_transcripts: false,
+            transcript_retention_hours: default_transcript_retention_hours(),
         }
+    }
+}
 
-        if self.security.enable_auth && self.security.api_keys.is_empty() {
-            anyhow::bail!("Authentication enabled but no API keys configured");
+/// Mirrors a sample of requests to a second model in the background and records its metrics,
+/// discarding the answers
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ShadowConfig {
+    /// Model name or id to mirror to; unset turns shadowing off
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default = "default_shadow_fraction")]
+    pub fraction: f64,
+    /// Only mirror requests for these models (names or ids); empty mirrors every model
+    #[serde(default)]
+    pub source_models: Vec<String>,
+}
+
+impl Default for ShadowConfig {
+    fn default() -> Self {
+        Self {
+            model: None,
+            fraction: default_shadow_fraction(),
+            source_models: Vec::new(),
         }
+    }
+}
 
-        Ok(())
+/// Peer instances that serve the models this one does not host
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct FederationConfig {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub peers: Vec<PeerConfig>,
+    #[serde(default = "default_federation_refresh")]
+    pub refresh_seconds: u64,
+}
+
+impl Default for FederationConfig {
+    fn default() -> Self {
+        Self {
+            peers: Vec::new(),
+            refresh_seconds: default_federation_refresh(),
+        }
     }
+}
 
-    /// Save configuration to file
-    pub fn save(&self, path: &str) -> Result<()> {
-        let content = toml::to_string_pretty(self).context("Failed to serialize config")?;
-        std::fs::write(path, content).context(format!("Failed to write config file: {}", path))?;
-        Ok(())
+/// Periodic tiny generations that take failing models out of rotation
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct CanaryConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_canary_interval")]
+    pub interval_seconds: u64,
+    /// Failed checks in a row before a model is marked unhealthy
+    #[serde(default = "default_canary_failure_threshold")]
+    pub failure_threshold: u32,
+    #[serde(default = "default_canary_prompt")]
+    pub prompt: String,
+    #[serde(default = "default_canary_max_tokens")]
+    pub max_tokens: usize,
+    /// A check that takes longer than this counts as failed
+    #[serde(default = "default_canary_timeout")]
+    pub timeout_seconds: u64,
+}
+
+impl Default for CanaryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_seconds: default_canary_interval(),
+            failure_threshold: default_canary_failure_threshold(),
+            prompt: default_canary_prompt(),
+            max_tokens: default_canary_max_tokens(),
+            timeout_seconds: default_canary_timeout(),
+        }
+    }
+}
+
+/// Limits and datasets for `POST /eval`
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct EvalConfig {
+    /// Directory of `<name>.jsonl` datasets a run can name instead of sending items
+    #[serde(default)]
+    pub dataset_dir: Option<PathBuf>,
+    /// Generations one run keeps going at once
+    #[serde(default = "default_eval_max_concurrency")]
+    pub max_concurrency: usize,
+    #[serde(default = "default_eval_max_items")]
+    pub max_items: usize,
+}
+
+impl Default for EvalConfig {
+    fn default() -> Self {
+        Self {
+            dataset_dir: None,
+            max_concurrency: default_eval_max_concurrency(),
+            max_items: default_eval_max_items(),
+        }
+    }
+}
+
+/// Audit log of `/completions` calls, kept in the session database
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RequestLogConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Entries older than this are deleted; 0 keeps them forever
+    #[serde(default = "default_request_log_retention_days")]
+    pub retention_days: u64,
+}
+
+impl Default for RequestLogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            retention_days: default_request_log_retention_days(),
+        }
+    }
+}
+
+/// Background jobs started with `callback_url` or `output` on `/completions`
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct JobsConfig {
+    /// Where `output: {"type": "file"}` jobs write their text
+    #[serde(default = "default_job_output_dir")]
+    pub output_dir: PathBuf,
+    /// Base URL (e.g. an S3-compatible bucket) finished output files are PUT to
+    #[serde(default)]
+    pub upload_url: Option<String>,
+    /// Sent as a bearer token with uploads
+    #[serde(default)]
+    pub upload_token: Option<String>,
+    /// Finished jobs and their files are deleted after this; 0 keeps them forever
+    #[serde(default = "default_job_retention_hours")]
+    pub retention_hours: u64,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        Self {
+            output_dir: default_job_output_dir(),
+            upload_url: None,
+            upload_token: None,
+            retention_hours: default_job_retention_hours(),
+        }
+    }
+}
+
+/// Background summarizing of sessions nobody has written to in a while
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct CompactionConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Sessions whose latest message is older than this are compacted, as are sessions without
+    /// timestamps
+    #[serde(default = "default_compaction_after_hours")]
+    pub after_hours: u64,
+    /// Latest messages of a compacted session kept verbatim
+    #[serde(default = "default_compaction_keep_messages")]
+    pub keep_messages: usize,
+    /// Model that writes the summaries; the first of `models.available_models` when unset
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default = "default_compaction_interval")]
+    pub interval_seconds: u64,
+}
+
+impl Default for CompactionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            after_hours: default_compaction_after_hours(),
+            keep_messages: default_compaction_keep_messages(),
+            model: None,
+            interval_seconds: default_compaction_interval(),
+        }
+    }
+}
+
+/// A persona preset: the system prompt its sessions start with, and parameters used where a
+/// request leaves them at their defaults
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct PersonaConfig {
+    pub system_prompt: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repeat_penalty: Option<f32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+}
+
+/// Simulated latency and failures for `serve --mock`, so clients can be tried against realistic
+/// streaming without a GPU
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, Default)]
+pub struct MockConfig {
+    /// Pace of the tokens after the first; 0 sends them as fast as possible
+    #[serde(default)]
+    pub tokens_per_second: f64,
+    /// Wait before the first token of every generation
+    #[serde(default)]
+    pub time_to_first_token_ms: u64,
+    /// Share of generations (0-1) that end with an error partway through
+    #[serde(default)]
+    pub error_rate: f64,
+    /// Which generations fail, and where, follows from this; the same seed fails the same ones
+    #[serde(default)]
+    pub seed: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct PeerConfig {
+    /// Base URL of the peer, e.g. `http://gpu-2:3000`
+    pub url: String,
+    /// Sent to the peer as a bearer token
+    #[serde(default)]
+    pub api_key: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct CacheConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_cache_max_entries")]
+    pub max_entries: usize,
+    #[serde(default = "default_cache_ttl")]
+    pub ttl_seconds: u64,
+    #[serde(default)]
+    pub semantic_threshold: Option<f32>,
+    /// Run identical sessionless requests from one account that arrive while one is generating
+    /// only once
+    #[serde(default)]
+    pub share_in_flight: bool,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_entries: default_cache_max_entries(),
+            ttl_seconds: default_cache_ttl(),
+            semantic_threshold: None,
+            share_in_flight: false,
+        }
+    }
+}
+
+impl Default for ChatConfig {
+    fn default() -> Self {
+        Self {
+            default_system_prompt: default_system_prompt(),
+            max_history_messages: default_max_history_messages(),
+            max_history_tokens: None,
+            compression_ratio: default_compression_ratio(),
+            compression_threshold: default_compression_threshold(),
+        }
+    }
+}
+
+impl Default for GuardrailsConfig {
+    fn default() -> Self {
+        Self {
+            replacement: default_guardrail_replacement(),
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            secret: None,
+            max_retries: default_webhook_max_retries(),
+            initial_backoff_ms: default_webhook_backoff_ms(),
+            timeout_seconds: default_webhook_timeout(),
+            allowed_hosts: Vec::new(),
+        }
+    }
+}
+
+// Used by `Config::commented_template`; keep in step with config.example.toml
+const TEMPLATE_HEADER: &str = "\
+# Rust LLM Inference Service Configuration
+# Generated with every default value; edit what you need and delete the rest.
+
+# Merge other files beneath this one (paths relative to this file); settings here win.
+# include = [\"models.toml\", \"keys.toml\"]
+
+";
+
+const TEMPLATE_FOOTER: &str = "\
+# A/B experiments: send a share of one model's traffic to variants (tagged with X-Experiment and
+# X-Experiment-Variant headers and the experiment_* metrics); the rest is the `control` variant.
+# [[experiments]]
+# name = \"phi-vs-qwen\"
+# model = \"qwen\"  # Model name or id whose requests take part
+# [[experiments.variants]]
+# name = \"phi\"
+# weight = 10  # Percent of the model's traffic
+# model = \"phi\"  # Optional: answer with this model instead
+# system_prompt = \"You are a concise assistant.\"  # Optional: replace the system prompt
+
+# Personas: chat requests with `persona = \"support-bot\"` start sessions with its system prompt and
+# use its parameters where the request leaves them at their defaults
+# [personas.support-bot]
+# system_prompt = \"You answer questions about our product, politely and briefly.\"
+# temperature = 0.3  # Optional, like max_tokens, top_p, top_k, repeat_penalty and stop
+
+# Profiles: select with `--profile <name>` or LLM_PROFILE=<name>; merged over the settings above
+# [profile.prod.server]
+# host = \"0.0.0.0\"
+";
+
+const TEMPLATE_DOCS: &[(&str, &str)] = &[
+    ("server.host", "Bind address"),
+    ("server.log_level", "trace, debug, info, warn, error"),
+    (
+        "server.read_only",
+        "Refuse generations (503) and load no models; sessions, history and stats are still served",
+    ),
+    (
+        "server.tokenizer_only",
+        "Load tokenizers but no weights: /tokenize and /debug/render work, generations get 503",
+    ),
+    (
+        "server.header_read_timeout_seconds",
+        "Drop connections that send no complete request headers in time (0 = never)",
+    ),
+    (
+        "server.idle_timeout_seconds",
+        "Drop connections silent in both directions this long (0 = never); keep above 15 for SSE",
+    ),
+    (
+        "server.max_connections",
+        "Open connections at most; more wait to be accepted (0 = unlimited)",
+    ),
+    (
+        "server.swagger_ui",
+        "Serve Swagger UI for /openapi.json at /docs (assets load from a CDN)",
+    ),
+    ("models.default_device", "cuda, cpu, metal, or auto to place each model on the best device with room"),
+    (
+        "models.max_concurrent_requests",
+        "Simultaneous generations across all keys",
+    ),
+    ("models.available_models.id", "Short id used in requests"),
+    (
+        "models.available_models.name",
+        "Hugging Face repo or display name",
+    ),
+    (
+        "models.available_models.context_length",
+        "Maximum context window in tokens",
+    ),
+    (
+        "models.available_models.aliases",
+        "Extra names requests may use, e.g. [\"gpt-3.5-turbo\"]",
+    ),
+    (
+        "models.available_models.standby",
+        "Devices for extra copies requests are spread over, e.g. [\"cuda:1\"]",
+    ),
+    ("security.enable_auth", "Set to true to require API keys"),
+    (
+        "security.api_keys",
+        "Keys are [[security.api_keys]] tables, see above",
+    ),
+    ("security.allowed_origins", "CORS configuration"),
+    (
+        "security.key_rotation_grace_seconds",
+        "How long a rotated key keeps working",
+    ),
+    ("limits.max_prompt_length", "Maximum characters in prompt"),
+    ("limits.max_response_tokens", "Maximum tokens in response"),
+    ("limits.max_sessions", "Maximum concurrent sessions"),
+    ("limits.session_ttl_seconds", "Session timeout"),
+    (
+        "limits.default_rate_limit_per_minute",
+        "Rate limit for keys without their own",
+    ),
+    (
+        "limits.max_priority",
+        "Highest request priority allowed by default (keys can raise it)",
+    ),
+    (
+        "limits.rate_limit_cleanup_seconds",
+        "How often idle keys are dropped from the rate limiter",
+    ),
+    (
+        "limits.persist_interval_seconds",
+        "How often rate-limit windows and usage are saved to sessions.db to survive restarts; 0 keeps them in memory",
+    ),
+    (
+        "overload.max_queue_length",
+        "Requests waiting for a generation slot at most; more get 503 with Retry-After (0 = unlimited)",
+    ),
+    (
+        "overload.max_wait_seconds",
+        "Refuse queued requests that have waited this long (0 = wait as long as it takes)",
+    ),
+    (
+        "overload.shed_policy",
+        "reject_newest, or priority to push out a lower-priority waiter instead",
+    ),
+    (
+        "overload.max_retry_after_seconds",
+        "Upper bound on Retry-After, which is estimated from recent throughput",
+    ),
+    ("observability.enable_metrics", "Prometheus metrics"),
+    ("observability.enable_tracing", "Structured logging"),
+    (
+        "observability.metrics_path",
+        "Path of the Prometheus endpoint; cannot overlap the API routes",
+    ),
+    (
+        "observability.latency_buckets",
+        "Histogram bounds for *_seconds metrics; empty for summaries",
+    ),
+    (
+        "observability.metrics_push_interval_seconds",
+        "Push period when metrics_push_url is set",
+    ),
+    (
+        "observability.session_store_slow_ms",
+        "Warn when a session store operation takes longer; 0 disables",
+    ),
+    (
+        "webhooks.max_retries",
+        "Delivery attempts after the first failure",
+    ),
+    (
+        "webhooks.initial_backoff_ms",
+        "Doubles after every failed attempt",
+    ),
+    ("webhooks.timeout_seconds", "Per-attempt HTTP timeout"),
+    (
+        "webhooks.allowed_hosts",
+        "Only these callback hosts (empty = any public address)",
+    ),
+    (
+        "chat.default_system_prompt",
+        "First message of every new session",
+    ),
+    (
+        "chat.max_history_messages",
+        "Messages kept per session, including the system prompt",
+    ),
+    (
+        "chat.compression_ratio",
+        "compress_history condenses older turns to this share of their size",
+    ),
+    (
+        "chat.compression_threshold",
+        "compress_history only runs once the prompt fills this share of the model's context",
+    ),
+    (
+        "guardrails.replacement",
+        "Text that stands in for what a redact rule matched",
+    ),
+    (
+        "pricing.currency",
+        "Label for estimated costs in /stats and /usage",
+    ),
+    (
+        "streaming.coalesce_window_ms",
+        "Buffer streamed tokens this long before sending (e.g. 20); 0 sends each token",
+    ),
+    (
+        "streaming.coalesce_max_chars",
+        "Send early once this many characters are buffered; 0 for no limit",
+    ),
+    (
+        "streaming.buffer_tokens",
+        "Tokens generated ahead of a slow client before generation waits",
+    ),
+    (
+        "streaming.slow_consumer_timeout_ms",
+        "Stop generating if a client stays this far behind this long; 0 waits forever",
+    ),
+    (
+        "streaming.queue_update_interval_ms",
+        "How often queued streams get a `queue` event with their place in line; 0 waits silently",
+    ),
+    (
+        "streaming.prefill_events_min_tokens",
+        "Chat streams for prompts this long get `prefill` progress events before the first token; 0 for never",
+    ),
+    (
+        "streaming.record_transcripts",
+        "Store every chunk streamed to clients for GET /requests/:id/transcript (debugging)",
+    ),
+    (
+        "streaming.transcript_retention_hours",
+        "Delete recorded transcripts after this many hours; 0 keeps them forever",
+    ),
+    (
+        "session_store.pool_size",
+        "SQLite connections for sessions.db",
+    ),
+    (
+        "session_store.synchronous",
+        "off, normal, full, extra; lower is faster but less durable on power loss",
+    ),
+    (
+        "session_store.cache_size_kib",
+        "SQLite page cache per connection",
+    ),
+    (
+        "session_store.statement_cache_capacity",
+        "Prepared statements kept per connection",
+    ),
+    (
+        "persistence.enabled",
+        "false keeps sessions, keys, templates, feedback and counters in memory only; nothing is written to sessions.db",
+    ),
+    (
+        "cache.enabled",
+        "Cache non-streaming /completions responses",
+    ),
+    ("cache.max_entries", "Oldest entries are evicted first"),
+    (
+        "cache.ttl_seconds",
+        "How long a cached response stays valid",
+    ),
+    (
+        "cache.share_in_flight",
+        "Identical sessionless requests from one account in flight share one generation (even with enabled = false)",
+    ),
+    (
+        "error_reporting.environment",
+        "Attached to every error report",
+    ),
+    ("error_reporting.timeout_seconds", "Per-report HTTP timeout"),
+    (
+        "shadow.fraction",
+        "Share of requests mirrored to shadow.model (0.0-1.0)",
+    ),
+    (
+        "shadow.source_models",
+        "Only mirror requests for these models (empty = all)",
+    ),
+    (
+        "federation.refresh_seconds",
+        "How often peers' /models lists are fetched",
+    ),
+    (
+        "canary.enabled",
+        "Run a tiny generation against every loaded model in the background",
+    ),
+    ("canary.interval_seconds", "Time between checks of a model"),
+    (
+        "canary.failure_threshold",
+        "Failed checks in a row before a model is taken out of rotation",
+    ),
+    ("canary.prompt", "Prompt of each check"),
+    ("canary.max_tokens", "Tokens generated per check"),
+    (
+        "canary.timeout_seconds",
+        "A check that takes longer than this counts as failed",
+    ),
+    (
+        "eval.max_concurrency",
+        "Generations one /eval run keeps going at once",
+    ),
+    ("eval.max_items", "Most items one /eval run may have"),
+    (
+        "request_log.enabled",
+        "Save every /completions call (prompt, params, response, usage) for GET /admin/requests",
+    ),
+    (
+        "request_log.retention_days",
+        "Delete logged requests older than this; 0 keeps them forever",
+    ),
+    (
+        "jobs.output_dir",
+        "Where jobs with output {\"type\": \"file\"} write their text",
+    ),
+    (
+        "jobs.retention_hours",
+        "Delete finished jobs and their output files after this; 0 keeps them forever",
+    ),
+    (
+        "compaction.enabled",
+        "Replace the older turns of idle sessions with a summary written by the model",
+    ),
+    (
+        "compaction.after_hours",
+        "Sessions whose latest message is older than this are compacted",
+    ),
+    (
+        "compaction.keep_messages",
+        "Latest messages of a compacted session kept verbatim",
+    ),
+    (
+        "compaction.interval_seconds",
+        "Time between compaction rounds",
+    ),
+    (
+        "mock.tokens_per_second",
+        "serve --mock: pace of tokens after the first (at least one a minute); 0 = as fast as possible",
+    ),
+    (
+        "mock.time_to_first_token_ms",
+        "serve --mock: wait before the first token",
+    ),
+    (
+        "mock.error_rate",
+        "serve --mock: share of generations (0-1) that fail partway through",
+    ),
+    (
+        "mock.seed",
+        "serve --mock: decides which generations fail; the same seed fails the same ones",
+    ),
+    (
+        "pricing.default.prompt_per_1k",
+        "Price per 1K prompt tokens for models without their own",
+    ),
+    (
+        "pricing.default.completion_per_1k",
+        "Price per 1K generated tokens",
+    ),
+];
+
+const TEMPLATE_EXTRAS: &[(&str, &str)] = &[
+    (
+        "models",
+        "# model_dir = \"/path/to/models\"  # Optional: directory containing local model files",
+    ),
+    (
+        "models.available_models",
+        "# path = \"/path/to/local/model\"  # Optional: local model path\n\
+         # quantization = \"q4\"  # Optional: q4, q8, bf16\n\
+         # memory_gb = 6.0  # Optional: memory the model needs, for default_device = \"auto\"",
+    ),
+    (
+        "security",
+        "# [[security.api_keys]]\n\
+         # key = \"sk-your-secret-key-here\"\n\
+         # name = \"default\"\n\
+         # enabled = true\n\
+         # rate_limit_per_minute = 100\n\
+         # max_concurrent_requests = 2\n\
+         # expires_at = \"2026-12-31T23:59:59Z\"\n\
+         # admin = false\n\
+         # max_priority = 10\n\
+         # default_model = \"qwen\"  # Optional: model for requests that name none\n\
+         # max_temperature = 1.0  # Optional: higher temperatures are lowered to this\n\
+         # max_tokens = 512  # Optional: larger max_tokens are lowered to this",
+    ),
+    (
+        "limits",
+        "# max_concurrent_per_key = 4  # Optional: default cap on simultaneous generations per key\n\
+         # max_generation_seconds = 120  # Optional: stop generations that run longer (finish_reason \"time_limit\")",
+    ),
+    (
+        "guardrails",
+        "# [[guardrails.rules]]\n\
+         # id = \"no-credentials\"  # Policy id reported in refusals and the request log\n\
+         # category = \"security\"  # Safety category, e.g. self_harm, violence, privacy\n\
+         # patterns = [\"BEGIN RSA PRIVATE KEY\"]  # Phrases that trigger the rule, ignoring ASCII case\n\
+         # action = \"block\"  # block refuses the request; redact replaces the phrase and continues\n\
+         # message = \"Private keys cannot be sent to the model\"  # Optional: shown to the client",
+    ),
+    (
+        "webhooks",
+        "# secret = \"whsec-change-me\"  # Required to accept `callback_url` on /completions",
+    ),
+    (
+        "jobs",
+        "# upload_url = \"https://s3.example.com/llm-outputs\"  # Optional: PUT finished output files here\n\
+         # upload_token = \"change-me\"  # Optional: bearer token for uploads",
+    ),
+    (
+        "compaction",
+        "# model = \"qwen\"  # Optional: model that writes the summaries (default: the first model)",
+    ),
+    (
+        "shadow",
+        "# model = \"phi\"  # Mirror requests to this model in the background (answers discarded)",
+    ),
+    (
+        "federation",
+        "# [[federation.peers]]  # Forward requests for models this server does not host\n\
+         # url = \"http://gpu-2:3000\"\n\
+         # api_key = \"sk-peer-key\"  # Optional: sent to the peer as a bearer token",
+    ),
+    (
+        "chat",
+        "# max_history_tokens = 3000  # Optional: also trim old turns to this estimated size",
+    ),
+    (
+        "pricing.models",
+        "# qwen = { prompt_per_1k = 0.01, completion_per_1k = 0.03 }  # Per-model prices, keyed by model id",
+    ),
+    (
+        "error_reporting",
+        "# webhook_url = \"https://alerts.example.com/hooks/llm\"  # Optional: POST panics and 5xx errors as JSON\n\
+         # sentry_dsn = \"https://<key>@o0.ingest.sentry.io/<project>\"  # Optional: send them to Sentry",
+    ),
+    (
+        "observability",
+        "# metrics_token = \"change-me\"  # Optional: require this bearer token on /metrics instead of an API key\n\
+         # metrics_push_url = \"http://pushgateway:9091/metrics/job/llm_inference\"  # Optional: push instead of being scraped",
+    ),
+    (
+        "cache",
+        "# semantic_threshold = 0.95  # Optional: also serve answers for prompts this similar (needs embeddings)",
+    ),
+    (
+        "eval",
+        "# dataset_dir = \"/path/to/datasets\"  # Optional: <name>.jsonl files /eval can run by name",
+    ),
+];
+
+// Append the commented-out optional settings for `section` (once), after its last key so
+// uncommenting a table header cannot capture the keys that follow it
+fn push_template_extras(out: &mut String, section: &str, seen: &mut Vec<String>) {
+    if seen.iter().any(|s| s == section) {
+        return;
+    }
+    seen.push(section.to_string());
+
+    let mut extras = TEMPLATE_EXTRAS
+        .iter()
+        .filter(|(s, _)| *s == section)
+        .peekable();
+    if extras.peek().is_none() {
+        return;
+    }
+    out.truncate(out.trim_end().len());
+    out.push('\n');
+    for (_, extra) in extras {
+        out.push_str(extra);
+        out.push('\n');
+    }
+    out.push('\n');
+}
+
+/// Where an effective setting was taken from, as reported by [`Config::value_sources`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfigSource {
+    Default,
+    File,
+    Env,
+}
+
+/// A single configuration problem, e.g. `models.available_models[1].id: cannot be empty`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConfigIssue {
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// Every problem found by [`Config::validate`]
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub struct ConfigValidationError {
+    pub issues: Vec<ConfigIssue>,
+}
+
+impl fmt::Display for ConfigValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid configuration ({} problem(s))", self.issues.len())?;
+        for issue in &self.issues {
+            write!(f, "\n  - {}", issue)?;
+        }
+        Ok(())
+    }
+}
+
+// Default value functions
+fn default_host() -> String {
+    "127.0.0.1".to_string()
+}
+fn default_port() -> u16 {
+    3000
+}
+fn default_header_read_timeout() -> u64 {
+    30
+}
+fn default_log_level() -> String {
+    "info".to_string()
+}
+fn default_device() -> String {
+    "cuda".to_string()
+}
+fn default_max_concurrent() -> usize {
+    10
+}
+fn default_max_prompt_length() -> usize {
+    8192
+}
+fn default_max_response_tokens() -> usize {
+    2048
+}
+fn default_max_sessions() -> usize {
+    1000
+}
+fn default_session_ttl() -> u64 {
+    3600
+}
+fn default_rate_limit() -> u32 {
+    60
+}
+fn default_rate_limit_cleanup() -> u64 {
+    60
+}
+fn default_limits_persist_interval() -> u64 {
+    30
+}
+fn default_key_rotation_grace() -> u64 {
+    3600
+}
+fn default_webhook_max_retries() -> u32 {
+    5
+}
+fn default_webhook_backoff_ms() -> u64 {
+    500
+}
+fn default_webhook_timeout() -> u64 {
+    10
+}
+fn default_system_prompt() -> String {
+    "You are a helpful AI assistant.".to_string()
+}
+fn default_max_history_messages() -> usize {
+    20
+}
+fn default_compression_ratio() -> f64 {
+    0.25
+}
+fn default_compression_threshold() -> f64 {
+    0.75
+}
+fn default_guardrail_replacement() -> String {
+    "[REDACTED]".to_string()
+}
+fn default_latency_buckets() -> Vec<f64> {
+    vec![0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0]
+}
+fn default_gpu_metrics_interval() -> u64 {
+    15
+}
+fn default_cache_max_entries() -> usize {
+    1000
+}
+fn default_cache_ttl() -> u64 {
+    3600
+}
+fn default_federation_refresh() -> u64 {
+    30
+}
+fn default_canary_interval() -> u64 {
+    60
+}
+fn default_canary_failure_threshold() -> u32 {
+    3
+}
+fn default_canary_prompt() -> String {
+    "Hi".to_string()
+}
+fn default_canary_max_tokens() -> usize {
+    4
+}
+fn default_canary_timeout() -> u64 {
+    30
+}
+fn default_eval_max_concurrency() -> usize {
+    4
+}
+fn default_eval_max_items() -> usize {
+    1000
+}
+fn default_request_log_retention_days() -> u64 {
+    30
+}
+fn default_persistence_enabled() -> bool {
+    true
+}
+fn default_job_output_dir() -> PathBuf {
+    PathBuf::from("job_outputs")
+}
+fn default_job_retention_hours() -> u64 {
+    24
+}
+fn default_compaction_after_hours() -> u64 {
+    24
+}
+fn default_compaction_keep_messages() -> usize {
+    4
+}
+fn default_compaction_interval() -> u64 {
+    3600
+}
+fn default_shadow_fraction() -> f64 {
+    0.1
+}
+fn default_stream_buffer_tokens() -> usize {
+    64
+}
+fn default_queue_update_interval_ms() -> u64 {
+    1000
+}
+fn default_prefill_events_min_tokens() -> usize {
+    2048
+}
+fn default_transcript_retention_hours() -> u64 {
+    24
+}
+fn default_overload_max_queue_length() -> usize {
+    256
+}
+fn default_shed_policy() -> ShedPolicy {
+    ShedPolicy::RejectNewest
+}
+fn default_overload_max_retry_after() -> u64 {
+    120
+}
+fn default_session_store_pool_size() -> u32 {
+    5
+}
+fn default_session_store_synchronous() -> SqliteSynchronousMode {
+    SqliteSynchronousMode::Full
+}
+fn default_session_store_cache_size_kib() -> u64 {
+    2000
+}
+fn default_session_store_statement_cache() -> usize {
+    100
+}
+fn default_environment() -> String {
+    "production".to_string()
+}
+fn default_error_reporting_timeout() -> u64 {
+    5
+}
+fn default_currency() -> String {
+    "USD".to_string()
+}
+fn default_metrics_path() -> String {
+    DEFAULT_METRICS_PATH.to_string()
+}
+fn default_metrics_push_interval() -> u64 {
+    15
+}
+fn default_session_store_slow_ms() -> u64 {
+    250
+}
+fn default_true() -> bool {
+    true
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            server: ServerConfig {
+                host: default_host(),
+                port: default_port(),
+                log_level: default_log_level(),
+                read_only: false,
+                tokenizer_only: false,
+                header_read_timeout_seconds: default_header_read_timeout(),
+                idle_timeout_seconds: 0,
+                max_connections: 0,
+                swagger_ui: false,
+            },
+            models: ModelsConfig {
+                model_dir: None,
+                available_models: vec![
+                    ModelConfig {
+                        id: "qwen".to_string(),
+                        name: "Qwen/Qwen2.5-0.5B-Instruct".to_string(),
+                        path: None,
+                        quantization: None,
+                        context_length: Some(4096),
+                        aliases: Vec::new(),
+                        standby: Vec::new(),
+                        memory_gb: None,
+                    },
+                    ModelConfig {
+                        id: "phi".to_string(),
+                        name: "microsoft/Phi-3.5-mini-instruct".to_string(),
+                        path: None,
+                        quantization: None,
+                        context_length: Some(4096),
+                        aliases: Vec::new(),
+                        standby: Vec::new(),
+                        memory_gb: None,
+                    },
+                ],
+                default_device: default_device(),
+                max_concurrent_requests: default_max_concurrent(),
+            },
+            security: SecurityConfig {
+                enable_auth: false,
+                api_keys: vec![],
+                allowed_origins: vec!["*".to_string()],
+                key_rotation_grace_seconds: default_key_rotation_grace(),
+            },
+            limits: LimitsConfig {
+                max_prompt_length: default_max_prompt_length(),
+                max_response_tokens: default_max_response_tokens(),
+                max_sessions: default_max_sessions(),
+                session_ttl_seconds: default_session_ttl(),
+                default_rate_limit_per_minute: default_rate_limit(),
+                max_concurrent_per_key: None,
+                max_priority: 0,
+                max_generation_seconds: None,
+                rate_limit_cleanup_seconds: default_rate_limit_cleanup(),
+                persist_interval_seconds: default_limits_persist_interval(),
+            },
+            overload: OverloadConfig::default(),
+            observability: ObservabilityConfig {
+                enable_metrics: true,
+                enable_tracing: true,
+                metrics_path: default_metrics_path(),
+                metrics_port: None,
+                latency_buckets: default_latency_buckets(),
+                metric_buckets: BTreeMap::new(),
+                gpu_metrics_interval_seconds: default_gpu_metrics_interval(),
+                slow_request_threshold_ms: None,
+                slow_ttft_threshold_ms: None,
+                session_store_slow_ms: default_session_store_slow_ms(),
+                metrics_token: None,
+                metrics_push_url: None,
+                metrics_push_interval_seconds: default_metrics_push_interval(),
+            },
+            webhooks: WebhookConfig::default(),
+            chat: ChatConfig::default(),
+            guardrails: GuardrailsConfig::default(),
+            pricing: PricingConfig::default(),
+            error_reporting: ErrorReportingConfig::default(),
+            cache: CacheConfig::default(),
+            streaming: StreamingConfig::default(),
+            session_store: SessionStoreConfig::default(),
+            persistence: PersistenceConfig::default(),
+            experiments: Vec::new(),
+            shadow: ShadowConfig::default(),
+            federation: FederationConfig::default(),
+            canary: CanaryConfig::default(),
+            eval: EvalConfig::default(),
+            request_log: RequestLogConfig::default(),
+            jobs: JobsConfig::default(),
+            compaction: CompactionConfig::default(),
+            mock: MockConfig::default(),
+            personas: BTreeMap::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Load configuration from a TOML, YAML or JSON file, chosen by extension (TOML otherwise)
+    pub fn from_file(path: &str) -> Result<Self> {
+        Self::from_file_with_profile(path, None)
+    }
+
+    /// Load configuration from `path`, merging `[profile.<name>]` over the base settings.
+    ///
+    /// Files listed in a top-level `include = [...]` (resolved relative to the including file)
+    /// are merged first, so the including file wins on conflicts. Profile tables are merged
+    /// recursively; arrays and scalar values replace the base value.
+    pub fn from_file_with_profile(path: &str, profile: Option<&str>) -> Result<Self> {
+        let mut value = read_config_value(path)?;
+        let profiles = value
+            .as_object_mut()
+            .and_then(|root| root.remove(PROFILE_KEY));
+
+        if let Some(name) = profile {
+            let overlay = profiles
+                .as_ref()
+                .and_then(|p| p.get(name))
+                .cloned()
+                .ok_or_else(|| anyhow!("Profile '{}' is not defined in {}", name, path))?;
+            merge_values(&mut value, overlay);
+        }
+
+        let config: Config = serde_json::from_value(value)
+            .context(format!("Failed to parse config file: {}", path))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Profile named by `--profile <name>` (or `--profile=<name>`) in `args`, else `LLM_PROFILE`
+    pub fn selected_profile<I>(args: I) -> Option<String>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "--profile" {
+                return args.next();
+            }
+            if let Some(name) = arg.strip_prefix("--profile=") {
+                return Some(name.to_string());
+            }
+        }
+        std::env::var(PROFILE_ENV).ok().filter(|p| !p.is_empty())
+    }
+
+    /// The first of [`CONFIG_FILE_CANDIDATES`] that exists, falling back to `config.toml`
+    pub fn default_path() -> &'static str {
+        CONFIG_FILE_CANDIDATES
+            .iter()
+            .copied()
+            .find(|path| std::path::Path::new(path).exists())
+            .unwrap_or(DEFAULT_CONFIG_PATH)
+    }
+
+    /// Load configuration from `path` with `LLM__*` environment overrides, without fallback
+    pub fn load_from(path: &str, profile: Option<&str>) -> Result<Self> {
+        Self::from_file_with_profile(path, profile)?.with_env_overrides(std::env::vars())
+    }
+
+    /// Load configuration with fallback to default, then apply `LLM__*` environment overrides
+    pub fn load(profile: Option<&str>) -> Self {
+        let path = Self::default_path();
+        let config = match Self::from_file_with_profile(path, profile) {
+            Ok(config) => {
+                match profile {
+                    Some(name) => {
+                        tracing::info!("✅ Loaded configuration from {} (profile: {})", path, name)
+                    }
+                    None => tracing::info!("✅ Loaded configuration from {}", path),
+                }
+                config
+            }
+            Err(e) => {
+                tracing::warn!("⚠️ Failed to load {}: {}. Using defaults.", path, e);
+                Self::default()
+            }
+        };
+
+        match config.with_env_overrides(std::env::vars()) {
+            Ok(config) => config,
+            Err(e) => {
+                tracing::warn!("⚠️ Ignoring environment overrides: {:#}", e);
+                config
+            }
+        }
+    }
+
+    /// Apply `LLM__SECTION__FIELD=value` overrides on top of this configuration.
+    ///
+    /// Path segments are matched case-insensitively and numeric segments index into arrays
+    /// (`LLM__MODELS__AVAILABLE_MODELS__0__CONTEXT_LENGTH=8192`). Values are parsed as TOML
+    /// (numbers, booleans, arrays) and fall back to plain strings.
+    pub fn with_env_overrides<I>(&self, vars: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut value = toml::Value::try_from(self).context("Failed to serialize config")?;
+
+        let mut applied = 0;
+        for (name, raw) in vars {
+            let Some(path) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            let segments: Vec<String> = path.split("__").map(|s| s.to_lowercase()).collect();
+            set_path(&mut value, &segments, parse_env_value(&raw))
+                .with_context(|| format!("Invalid override {}", name))?;
+            applied += 1;
+        }
+
+        if applied == 0 {
+            return Ok(self.clone());
+        }
+
+        let config: Config = value
+            .try_into()
+            .context("Failed to apply environment overrides")?;
+        config.validate()?;
+        tracing::info!("✅ Applied {} environment override(s)", applied);
+        Ok(config)
+    }
+
+    /// A complete config.toml with every default value, annotated with what each setting does
+    pub fn commented_template() -> Result<String> {
+        let body =
+            toml::to_string_pretty(&Config::default()).context("Failed to serialize config")?;
+
+        let mut out = String::from(TEMPLATE_HEADER);
+        let mut section = String::new();
+        let mut seen_sections = Vec::new();
+        for line in body.lines() {
+            let trimmed = line.trim();
+            let header = trimmed
+                .strip_prefix("[[")
+                .and_then(|l| l.strip_suffix("]]"))
+                .or_else(|| trimmed.strip_prefix('[').and_then(|l| l.strip_suffix(']')));
+            if let Some(name) = header {
+                push_template_extras(&mut out, &section, &mut seen_sections);
+                section = name.to_string();
+                out.push_str(line);
+                out.push('\n');
+                continue;
+            }
+            // Keys are documented as commented-out tables instead
+            if section == "security" && trimmed == "api_keys = []" {
+                continue;
+            }
+
+            let doc = line.split_once(" = ").and_then(|(key, _)| {
+                let path = format!("{}.{}", section, key.trim());
+                TEMPLATE_DOCS
+                    .iter()
+                    .find(|(p, _)| *p == path)
+                    .map(|(_, doc)| doc)
+            });
+            match doc {
+                Some(doc) => out.push_str(&format!("{}  # {}\n", line, doc)),
+                None => {
+                    out.push_str(line);
+                    out.push('\n');
+                }
+            }
+        }
+        push_template_extras(&mut out, &section, &mut seen_sections);
+        out.push_str(TEMPLATE_FOOTER);
+        Ok(out)
+    }
+
+    /// Copy of this configuration that is safe to show: API keys (including peers'), tokens, the
+    /// webhook secret and error reporting endpoints are masked down to their last four characters.
+    pub fn redacted(&self) -> Config {
+        let mut config = self.clone();
+        for key in &mut config.security.api_keys {
+            key.key = mask_secret(&key.key);
+        }
+        config.webhooks.secret = config.webhooks.secret.as_deref().map(mask_secret);
+        config.observability.metrics_token = config
+            .observability
+            .metrics_token
+            .as_deref()
+            .map(mask_secret);
+        config.error_reporting.webhook_url = config
+            .error_reporting
+            .webhook_url
+            .as_deref()
+            .map(mask_secret);
+        config.error_reporting.sentry_dsn = config
+            .error_reporting
+            .sentry_dsn
+            .as_deref()
+            .map(mask_secret);
+        for peer in &mut config.federation.peers {
+            peer.api_key = peer.api_key.as_deref().map(mask_secret);
+        }
+        config.jobs.upload_token = config.jobs.upload_token.as_deref().map(mask_secret);
+        config
+    }
+
+    /// Where each effective setting comes from, keyed by dotted path (arrays count as one
+    /// setting). Values set by an `LLM__*` variable in `vars` are `env`; values that differ
+    /// from the built-in defaults are `file`; everything else is `default`.
+    pub fn value_sources<I>(&self, vars: I) -> BTreeMap<String, ConfigSource>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let env_paths: Vec<String> = vars
+            .into_iter()
+            .filter_map(|(name, _)| {
+                let path = name.strip_prefix(ENV_PREFIX)?;
+                Some(
+                    path.split("__")
+                        .map(|s| s.to_lowercase())
+                        .collect::<Vec<_>>()
+                        .join("."),
+                )
+            })
+            .collect();
+
+        let mut current = Vec::new();
+        flatten_values(
+            "",
+            &serde_json::to_value(self).unwrap_or_default(),
+            &mut current,
+        );
+        let mut defaults = Vec::new();
+        flatten_values(
+            "",
+            &serde_json::to_value(Config::default()).unwrap_or_default(),
+            &mut defaults,
+        );
+        let defaults: HashMap<String, serde_json::Value> = defaults.into_iter().collect();
+
+        current
+            .into_iter()
+            .map(|(path, value)| {
+                let from_env = env_paths.iter().any(|env| {
+                    env == &path
+                        || env.starts_with(&format!("{}.", path))
+                        || path.starts_with(&format!("{}.", env))
+                });
+                let source = if from_env {
+                    ConfigSource::Env
+                } else if defaults.get(&path) != Some(&value) {
+                    ConfigSource::File
+                } else {
+                    ConfigSource::Default
+                };
+                (path, source)
+            })
+            .collect()
+    }
+
+    /// Validate configuration, reporting every problem at once.
+    ///
+    /// The error is a [`ConfigValidationError`] listing each violation with its field path.
+    pub fn validate(&self) -> Result<()> {
+        let issues = self.validation_issues();
+        if issues.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigValidationError { issues }.into())
+        }
+    }
+
+    /// Every validation problem in this configuration, in field order
+    pub fn validation_issues(&self) -> Vec<ConfigIssue> {
+        let mut issues = Vec::new();
+        let mut issue = |path: String, message: String| issues.push(ConfigIssue { path, message });
+
+        if self.server.port == 0 {
+            issue("server.port".into(), "cannot be 0".into());
+        }
+
+        let models = &self.models.available_models;
+        if models.is_empty() {
+            issue(
+                "models.available_models".into(),
+                "at least one model must be configured".into(),
+            );
+        }
+        // alias (id, name or extra alias) -> (model index, field it came from)
+        let mut aliases: HashMap<&str, (usize, &str)> = HashMap::new();
+        for (i, model) in models.iter().enumerate() {
+            let path = format!("models.available_models[{}]", i);
+            if model.id.trim().is_empty() {
+                issue(format!("{}.id", path), "cannot be empty".into());
+            }
+            if model.name.trim().is_empty() {
+                issue(format!("{}.name", path), "cannot be empty".into());
+            }
+            if model.context_length == Some(0) {
+                issue(format!("{}.context_length", path), "must be greater than 0".into());
+            }
+            if model.memory_gb.is_some_and(|gb| gb.is_nan() || gb <= 0.0) {
+                issue(format!("{}.memory_gb", path), "must be greater than 0".into());
+            }
+            if model.aliases.iter().any(|a| a.trim().is_empty()) {
+                issue(format!("{}.aliases", path), "cannot contain empty names".into());
+            }
+            for device in &model.standby {
+                if parse_device(device).is_none() {
+                    issue(
+                        format!("{}.standby", path),
+                        format!(
+                            "unknown device '{}' (expected one of: {}, optionally with an ordinal such as cuda:1)",
+                            device,
+                            VALID_DEVICES.join(", ")
+                        ),
+                    );
+                }
+            }
+
+            let names = [("id", model.id.as_str()), ("name", model.name.as_str())]
+                .into_iter()
+                .chain(model.aliases.iter().map(|a| ("aliases", a.as_str())));
+            for (field, alias) in names {
+                if alias.trim().is_empty() {
+                    continue;
+                }
+                match aliases.get(alias) {
+                    Some(&(j, _)) if j == i => {}
+                    Some(&(j, "id")) if field == "id" => issue(
+                        format!("{}.id", path),
+                        format!("duplicate model id '{}' (also models.available_models[{}])", alias, j),
+                    ),
+                    Some(&(j, other)) => issue(
+                        format!("{}.{}", path, field),
+                        format!(
+                            "'{}' overlaps with models.available_models[{}].{}",
+                            alias, j, other
+                        ),
+                    ),
+                    None => {
+                        aliases.insert(alias, (i, field));
+                    }
+                }
+            }
+        }
+        let default_device = self.models.default_device.to_lowercase();
+        if !VALID_DEVICES.contains(&default_device.as_str()) && default_device != AUTO_DEVICE {
+            issue(
+                "models.default_device".into(),
+                format!(
+                    "unknown device '{}' (expected one of: {}, {})",
+                    self.models.default_device,
+                    VALID_DEVICES.join(", "),
+                    AUTO_DEVICE
+                ),
+            );
+        }
+        if self.models.max_concurrent_requests == 0 {
+            issue(
+                "models.max_concurrent_requests".into(),
+                "must be greater than 0".into(),
+            );
+        }
+
+        if self.security.enable_auth && self.security.api_keys.is_empty() {
+            issue(
+                "security.api_keys".into(),
+                "authentication enabled but no API keys configured".into(),
+            );
+        }
+        let mut keys: HashMap<&str, usize> = HashMap::new();
+        for (i, key) in self.security.api_keys.iter().enumerate() {
+            let path = format!("security.api_keys[{}]", i);
+            if key.key.is_empty() {
+                issue(format!("{}.key", path), "cannot be empty".into());
+            } else if let Some(j) = keys.insert(&key.key, i) {
+                issue(
+                    format!("{}.key", path),
+                    format!("duplicate of security.api_keys[{}].key", j),
+                );
+            }
+            if key.rate_limit_per_minute == Some(0) {
+                issue(
+                    format!("{}.rate_limit_per_minute", path),
+                    "must be greater than 0".into(),
+                );
+            }
+            if key.max_concurrent_requests == Some(0) {
+                issue(
+                    format!("{}.max_concurrent_requests", path),
+                    "must be greater than 0".into(),
+                );
+            }
+            if let Some(model) = &key.default_model {
+                if !aliases.contains_key(model.as_str()) {
+                    issue(format!("{}.default_model", path), format!("unknown model '{}'", model));
+                }
+            }
+            if key.max_temperature.is_some_and(|t| t.is_nan() || t < 0.0) {
+                issue(format!("{}.max_temperature", path), "must be 0 or greater".into());
+            }
+            if key.max_tokens == Some(0) {
+                issue(format!("{}.max_tokens", path), "must be greater than 0".into());
+            }
+        }
+
+        let limits = &self.limits;
+        for (field, value) in [
+            ("max_prompt_length", limits.max_prompt_length as u64),
+            ("max_response_tokens", limits.max_response_tokens as u64),
+            ("max_sessions", limits.max_sessions as u64),
+            ("session_ttl_seconds", limits.session_ttl_seconds),
+            (
+                "default_rate_limit_per_minute",
+                limits.default_rate_limit_per_minute as u64,
+            ),
+            (
+                "rate_limit_cleanup_seconds",
+                limits.rate_limit_cleanup_seconds,
+            ),
+        ] {
+            if value == 0 {
+                issue(format!("limits.{}", field), "must be greater than 0".into());
+            }
+        }
+        match limits.max_concurrent_per_key {
+            Some(0) => issue(
+                "limits.max_concurrent_per_key".into(),
+                "must be greater than 0".into(),
+            ),
+            Some(n) if n > self.models.max_concurrent_requests => issue(
+                "limits.max_concurrent_per_key".into(),
+                format!(
+                    "{} exceeds models.max_concurrent_requests ({})",
+                    n, self.models.max_concurrent_requests
+                ),
+            ),
+            _ => {}
+        }
+
+        if self.limits.max_generation_seconds == Some(0) {
+            issue(
+                "limits.max_generation_seconds".into(),
+                "must be greater than 0".into(),
+            );
+        }
+        if self.overload.max_retry_after_seconds == 0 {
+            issue(
+                "overload.max_retry_after_seconds".into(),
+                "must be greater than 0".into(),
+            );
+        }
+
+        if self.chat.max_history_messages == 0 {
+            issue(
+                "chat.max_history_messages".into(),
+                "must be greater than 0".into(),
+            );
+        }
+        if self.chat.max_history_tokens == Some(0) {
+            issue(
+                "chat.max_history_tokens".into(),
+                "must be greater than 0".into(),
+            );
+        }
+        for (path, value) in [
+            ("chat.compression_ratio", self.chat.compression_ratio),
+            ("chat.compression_threshold", self.chat.compression_threshold),
+        ] {
+            if value == 0.0 || !(0.0..=1.0).contains(&value) {
+                issue(path.into(), "must be greater than 0 and at most 1".into());
+            }
+        }
+
+        let mut guardrail_ids: HashMap<&str, usize> = HashMap::new();
+        for (i, rule) in self.guardrails.rules.iter().enumerate() {
+            let path = format!("guardrails.rules[{}]", i);
+            if rule.id.trim().is_empty() {
+                issue(format!("{}.id", path), "cannot be empty".into());
+            } else if let Some(j) = guardrail_ids.insert(&rule.id, i) {
+                issue(
+                    format!("{}.id", path),
+                    format!("duplicate rule '{}' (also guardrails.rules[{}])", rule.id, j),
+                );
+            }
+            if rule.category.trim().is_empty() {
+                issue(format!("{}.category", path), "cannot be empty".into());
+            }
+            if rule.patterns.is_empty() || rule.patterns.iter().any(|p| p.is_empty()) {
+                issue(
+                    format!("{}.patterns", path),
+                    "needs at least one pattern and no empty ones".into(),
+                );
+            }
+        }
+
+        let buckets = std::iter::once((
+            "observability.latency_buckets".to_string(),
+            &self.observability.latency_buckets,
+        ))
+        .chain(
+            self.observability
+                .metric_buckets
+                .iter()
+                .map(|(metric, b)| (format!("observability.metric_buckets.{}", metric), b)),
+        );
+        for (path, bounds) in buckets {
+            let ascending = bounds.windows(2).all(|w| w[0] < w[1]);
+            if !ascending || bounds.iter().any(|b| !b.is_finite()) {
+                issue(
+                    path,
+                    "bucket bounds must be finite and strictly increasing".into(),
+                );
+            } else if bounds.is_empty() && path != "observability.latency_buckets" {
+                issue(path, "needs at least one bucket bound".into());
+            }
+        }
+
+        let prices = std::iter::once(("pricing.default".to_string(), &self.pricing.default)).chain(
+            self.pricing
+                .models
+                .iter()
+                .map(|(model, price)| (format!("pricing.models.{}", model), price)),
+        );
+        for (path, price) in prices {
+            for (field, value) in [
+                ("prompt_per_1k", price.prompt_per_1k),
+                ("completion_per_1k", price.completion_per_1k),
+            ] {
+                if !value.is_finite() || value < 0.0 {
+                    issue(
+                        format!("{}.{}", path, field),
+                        "must be a non-negative number".into(),
+                    );
+                }
+            }
+        }
+
+        if let Some(url) = &self.observability.metrics_push_url {
+            if !(url.starts_with("http://") || url.starts_with("https://")) {
+                issue(
+                    "observability.metrics_push_url".into(),
+                    "must be an http(s) URL".into(),
+                );
+            }
+            if self.observability.metrics_push_interval_seconds == 0 {
+                issue(
+                    "observability.metrics_push_interval_seconds".into(),
+                    "must be greater than 0 when metrics_push_url is set".into(),
+                );
+            }
+        }
+        let metrics_path = &self.observability.metrics_path;
+        let first_segment = metrics_path.trim_start_matches('/').split('/').next().unwrap_or("");
+        if !metrics_path.starts_with('/')
+            || first_segment.is_empty()
+            || metrics_path.contains(['*', ':', '?', '#'])
+        {
+            issue(
+                "observability.metrics_path".into(),
+                "must be a plain absolute path such as /metrics".into(),
+            );
+        } else if API_ROUTE_PREFIXES.contains(&first_segment) {
+            issue(
+                "observability.metrics_path".into(),
+                format!("collides with the API routes under /{}", first_segment),
+            );
+        }
+        match self.observability.metrics_port {
+            Some(0) => issue(
+                "observability.metrics_port".into(),
+                "must be greater than 0".into(),
+            ),
+            Some(port) if port == self.server.port => issue(
+                "observability.metrics_port".into(),
+                "must differ from server.port".into(),
+            ),
+            _ => {}
+        }
+        if self.observability.metrics_token.as_deref() == Some("") {
+            issue(
+                "observability.metrics_token".into(),
+                "cannot be empty".into(),
+            );
+        }
+
+        if self.session_store.pool_size == 0 {
+            issue(
+                "session_store.pool_size".into(),
+                "must be greater than 0".into(),
+            );
+        }
+        if self.server.read_only && !self.persistence.enabled {
+            issue(
+                "server.read_only".into(),
+                "needs persistence.enabled to read the sessions of another instance".into(),
+            );
+        }
+        if self.server.read_only && self.server.tokenizer_only {
+            issue(
+                "server.tokenizer_only".into(),
+                "cannot be combined with server.read_only".into(),
+            );
+        }
+
+        if self.streaming.buffer_tokens == 0 {
+            issue(
+                "streaming.buffer_tokens".into(),
+                "must be greater than 0".into(),
+            );
+        }
+
+        if self.cache.max_entries == 0 {
+            issue("cache.max_entries".into(), "must be greater than 0".into());
+        }
+        if let Some(threshold) = self.cache.semantic_threshold {
+            if !(threshold > 0.0 && threshold <= 1.0) {
+                issue(
+                    "cache.semantic_threshold".into(),
+                    "must be in (0, 1]".into(),
+                );
+            }
+        }
+
+        let reporting = &self.error_reporting;
+        if let Some(url) = &reporting.webhook_url {
+            if !(url.starts_with("http://") || url.starts_with("https://")) {
+                issue(
+                    "error_reporting.webhook_url".into(),
+                    "must be an http(s) URL".into(),
+                );
+            }
+        }
+        if let Some(dsn) = &reporting.sentry_dsn {
+            let valid = dsn
+                .split_once("://")
+                .and_then(|(_, rest)| rest.split_once('@'))
+                .is_some_and(|(key, location)| !key.is_empty() && location.contains('/'));
+            if !valid {
+                issue(
+                    "error_reporting.sentry_dsn".into(),
+                    "expected https://<key>@<host>/<project_id>".into(),
+                );
+            }
+        }
+        if reporting.timeout_seconds == 0 {
+            issue(
+                "error_reporting.timeout_seconds".into(),
+                "must be greater than 0".into(),
+            );
+        }
+
+        if self.webhooks.timeout_seconds == 0 {
+            issue("webhooks.timeout_seconds".into(), "must be greater than 0".into());
+        }
+        if let Some(url) = &self.jobs.upload_url {
+            if !(url.starts_with("http://") || url.starts_with("https://")) {
+                issue("jobs.upload_url".into(), "must be an http(s) URL".into());
+            }
+        }
+
+        for (name, persona) in &self.personas {
+            let path = format!("personas.{}", name);
+            if persona.system_prompt.trim().is_empty() {
+                issue(format!("{}.system_prompt", path), "cannot be empty".into());
+            }
+            if persona.max_tokens == Some(0) {
+                issue(format!("{}.max_tokens", path), "must be greater than 0".into());
+            }
+            if persona.temperature.is_some_and(|t| t.is_nan() || t < 0.0) {
+                issue(format!("{}.temperature", path), "must be 0 or greater".into());
+            }
+            if persona.top_p.is_some_and(|p| p.is_nan() || p <= 0.0 || p > 1.0) {
+                issue(format!("{}.top_p", path), "must be greater than 0 and at most 1".into());
+            }
+        }
+
+        let mock = &self.mock;
+        if !(mock.tokens_per_second.is_finite() && mock.tokens_per_second >= 0.0) {
+            issue(
+                "mock.tokens_per_second".into(),
+                "must be 0 or greater".into(),
+            );
+        }
+        if !(0.0..=1.0).contains(&mock.error_rate) {
+            issue("mock.error_rate".into(), "must be between 0 and 1".into());
+        }
+
+        let shadow = &self.shadow;
+        if !(0.0..=1.0).contains(&shadow.fraction) {
+            issue("shadow.fraction".into(), "must be between 0 and 1".into());
+        }
+        if let Some(model) = &shadow.model {
+            if !aliases.contains_key(model.as_str()) {
+                issue("shadow.model".into(), format!("unknown model '{}'", model));
+            }
+        }
+        for (i, model) in shadow.source_models.iter().enumerate() {
+            if !aliases.contains_key(model.as_str()) {
+                issue(
+                    format!("shadow.source_models[{}]", i),
+                    format!("unknown model '{}'", model),
+                );
+            }
+        }
+
+        if self.federation.refresh_seconds == 0 {
+            issue(
+                "federation.refresh_seconds".into(),
+                "must be greater than 0".into(),
+            );
+        }
+        for (i, peer) in self.federation.peers.iter().enumerate() {
+            if !(peer.url.starts_with("http://") || peer.url.starts_with("https://")) {
+                issue(
+                    format!("federation.peers[{}].url", i),
+                    "must be an http(s) URL".into(),
+                );
+            }
+        }
+
+        for (path, value) in [
+            ("canary.interval_seconds", self.canary.interval_seconds),
+            ("canary.failure_threshold", self.canary.failure_threshold as u64),
+            ("canary.max_tokens", self.canary.max_tokens as u64),
+            ("canary.timeout_seconds", self.canary.timeout_seconds),
+        ] {
+            if value == 0 {
+                issue(path.into(), "must be greater than 0".into());
+            }
+        }
+
+        let compaction = &self.compaction;
+        for (path, value) in [
+            ("compaction.after_hours", compaction.after_hours),
+            ("compaction.interval_seconds", compaction.interval_seconds),
+        ] {
+            if value == 0 {
+                issue(path.into(), "must be greater than 0".into());
+            }
+        }
+        if let Some(model) = &compaction.model {
+            if !aliases.contains_key(model.as_str()) {
+                issue("compaction.model".into(), format!("unknown model '{}'", model));
+            }
+        }
+
+        for (path, value) in [
+            ("eval.max_concurrency", self.eval.max_concurrency),
+            ("eval.max_items", self.eval.max_items),
+        ] {
+            if value == 0 {
+                issue(path.into(), "must be greater than 0".into());
+            }
+        }
+
+        // experiment name -> index, and model index -> enabled experiment splitting it
+        let mut experiment_names: HashMap<&str, usize> = HashMap::new();
+        let mut experiment_models: HashMap<usize, usize> = HashMap::new();
+        for (i, experiment) in self.experiments.iter().enumerate() {
+            let path = format!("experiments[{}]", i);
+            if experiment.name.trim().is_empty() {
+                issue(format!("{}.name", path), "cannot be empty".into());
+            } else if let Some(j) = experiment_names.insert(&experiment.name, i) {
+                issue(
+                    format!("{}.name", path),
+                    format!(
+                        "duplicate experiment '{}' (also experiments[{}])",
+                        experiment.name, j
+                    ),
+                );
+            }
+            match aliases.get(experiment.model.as_str()) {
+                None => issue(
+                    format!("{}.model", path),
+                    format!("unknown model '{}'", experiment.model),
+                ),
+                Some(&(model, _)) if experiment.enabled => {
+                    if let Some(j) = experiment_models.insert(model, i) {
+                        issue(
+                            format!("{}.model", path),
+                            format!("experiments[{}] already splits this model", j),
+                        );
+                    }
+                }
+                Some(_) => {}
+            }
+            if experiment.variants.is_empty() {
+                issue(
+                    format!("{}.variants", path),
+                    "at least one variant is required".into(),
+                );
+            }
+
+            let mut variant_names = Vec::new();
+            for (v, variant) in experiment.variants.iter().enumerate() {
+                let path = format!("{}.variants[{}]", path, v);
+                if variant.name.trim().is_empty() || variant.name == "control" {
+                    issue(
+                        format!("{}.name", path),
+                        "cannot be empty or 'control' (the untouched remainder)".into(),
+                    );
+                } else if variant_names.contains(&variant.name.as_str()) {
+                    issue(
+                        format!("{}.name", path),
+                        format!("duplicate variant '{}'", variant.name),
+                    );
+                }
+                variant_names.push(&variant.name);
+                if variant.weight == 0 {
+                    issue(format!("{}.weight", path), "must be greater than 0".into());
+                }
+                if let Some(model) = &variant.model {
+                    if !aliases.contains_key(model.as_str()) {
+                        issue(
+                            format!("{}.model", path),
+                            format!("unknown model '{}'", model),
+                        );
+                    }
+                }
+                if variant.model.is_none() && variant.system_prompt.is_none() {
+                    issue(path, "set model, system_prompt or both".into());
+                }
+            }
+            let total: u32 = experiment.variants.iter().map(|v| v.weight).sum();
+            if total > 100 {
+                issue(
+                    format!("{}.variants", path),
+                    format!("weights add up to {}%, more than 100%", total),
+                );
+            }
+        }
+
+        issues
+    }
+
+    /// The configured model with this id, name or alias
+    pub fn find_model(&self, alias: &str) -> Option<&ModelConfig> {
+        self.models
+            .available_models
+            .iter()
+            .find(|m| m.answers_to(alias))
+    }
+
+    /// Dotted paths of every setting that differs between `self` and `other`
+    pub fn changed_fields(&self, other: &Config) -> Vec<String> {
+        let old = serde_json::to_value(self).unwrap_or_default();
+        let new = serde_json::to_value(other).unwrap_or_default();
+        let mut changed = Vec::new();
+        diff_values("", &old, &new, &mut changed);
+        changed
+    }
+
+    /// Changed settings that cannot be applied without restarting the server
+    pub fn restart_required_changes(&self, other: &Config) -> Vec<String> {
+        self.changed_fields(other)
+            .into_iter()
+            .filter(|path| {
+                !HOT_RELOADABLE
+                    .iter()
+                    .any(|hot| path == hot || path.starts_with(&format!("{}.", hot)))
+            })
+            .collect()
+    }
+
+    /// Save configuration to file, in the format implied by its extension
+    pub fn save(&self, path: &str) -> Result<()> {
+        let content = match ConfigFormat::from_path(path) {
+            ConfigFormat::Toml => toml::to_string_pretty(self).context("Failed to serialize config")?,
+            ConfigFormat::Yaml => serde_yaml::to_string(self).context("Failed to serialize config")?,
+            ConfigFormat::Json => {
+                serde_json::to_string_pretty(self).context("Failed to serialize config")?
+            }
+        };
+        std::fs::write(path, content).context(format!("Failed to write config file: {}", path))?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConfigFormat {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl ConfigFormat {
+    fn from_path(path: &str) -> Self {
+        let ext = std::path::Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase());
+        match ext.as_deref() {
+            Some("yaml") | Some("yml") => ConfigFormat::Yaml,
+            Some("json") => ConfigFormat::Json,
+            _ => ConfigFormat::Toml,
+        }
+    }
+}
+
+// Parse any supported format into a JSON value, resolving `include` files first
+fn read_config_value(path: &str) -> Result<serde_json::Value> {
+    read_config_value_inner(Path::new(path), &mut Vec::new())
+}
+
+// `stack` holds the files currently being read, to reject include cycles
+fn read_config_value_inner(path: &Path, stack: &mut Vec<PathBuf>) -> Result<serde_json::Value> {
+    let display = path.display();
+    let content = std::fs::read_to_string(path)
+        .context(format!("Failed to read config file: {}", display))?;
+    let mut value = match ConfigFormat::from_path(&path.to_string_lossy()) {
+        ConfigFormat::Toml => toml_to_json(
+            toml::from_str(&content)
+                .context(format!("Failed to parse config file: {}", display))?,
+        ),
+        ConfigFormat::Yaml => serde_yaml::from_str(&content)
+            .context(format!("Failed to parse YAML config file: {}", display))?,
+        ConfigFormat::Json => serde_json::from_str(&content)
+            .context(format!("Failed to parse JSON config file: {}", display))?,
+    };
+
+    let includes = match value
+        .as_object_mut()
+        .and_then(|root| root.remove(INCLUDE_KEY))
+    {
+        None => return Ok(value),
+        Some(serde_json::Value::Array(items)) => items,
+        Some(_) => anyhow::bail!("{}: `include` must be an array of paths", display),
+    };
+
+    let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    if stack.contains(&canonical) {
+        anyhow::bail!("Config include cycle involving {}", display);
+    }
+    stack.push(canonical);
+
+    // Included files are merged in order, then the including file overrides them
+    let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut merged = serde_json::Value::Object(Default::default());
+    for item in includes {
+        let include = item
+            .as_str()
+            .ok_or_else(|| anyhow!("{}: `include` entries must be strings", display))?;
+        let included = read_config_value_inner(&base_dir.join(include), stack)
+            .with_context(|| format!("Failed to include {} from {}", include, display))?;
+        merge_values(&mut merged, included);
+    }
+    merge_values(&mut merged, value);
+
+    stack.pop();
+    Ok(merged)
+}
+
+// TOML datetimes become RFC 3339 strings, which is what chrono deserializes from
+fn toml_to_json(value: toml::Value) -> serde_json::Value {
+    match value {
+        toml::Value::String(s) => serde_json::Value::String(s),
+        toml::Value::Integer(i) => i.into(),
+        toml::Value::Float(f) => f.into(),
+        toml::Value::Boolean(b) => b.into(),
+        toml::Value::Datetime(dt) => serde_json::Value::String(dt.to_string()),
+        toml::Value::Array(items) => items.into_iter().map(toml_to_json).collect(),
+        toml::Value::Table(table) => table
+            .into_iter()
+            .map(|(k, v)| (k, toml_to_json(v)))
+            .collect::<serde_json::Map<_, _>>()
+            .into(),
+    }
+}
+
+// Objects merge key by key; anything else in `overlay` replaces the base value
+fn merge_values(base: &mut serde_json::Value, overlay: serde_json::Value) {
+    match (base, overlay) {
+        (serde_json::Value::Object(base), serde_json::Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_values(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+// Leaf settings by dotted path; arrays are kept whole, like `diff_values`
+fn flatten_values(
+    prefix: &str,
+    value: &serde_json::Value,
+    out: &mut Vec<(String, serde_json::Value)>,
+) {
+    match value {
+        serde_json::Value::Object(map) if !map.is_empty() => {
+            for (key, value) in map {
+                let path = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                flatten_values(&path, value, out);
+            }
+        }
+        _ => out.push((prefix.to_string(), value.clone())),
+    }
+}
+
+fn mask_secret(secret: &str) -> String {
+    let chars: Vec<char> = secret.chars().collect();
+    if chars.len() < 12 {
+        return "****".to_string();
+    }
+    let tail: String = chars[chars.len() - 4..].iter().collect();
+    format!("****{}", tail)
+}
+
+// Arrays are compared as a whole so a reordered model list reports a single path
+fn diff_values(prefix: &str, old: &serde_json::Value, new: &serde_json::Value, out: &mut Vec<String>) {
+    match (old, new) {
+        (serde_json::Value::Object(a), serde_json::Value::Object(b)) => {
+            let mut keys: Vec<&String> = a.keys().chain(b.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                let path = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                let null = serde_json::Value::Null;
+                diff_values(&path, a.get(key).unwrap_or(&null), b.get(key).unwrap_or(&null), out);
+            }
+        }
+        _ if old != new => out.push(prefix.to_string()),
+        _ => {}
+    }
+}
+
+fn parse_env_value(raw: &str) -> toml::Value {
+    toml::from_str::<toml::Table>(&format!("v = {}", raw))
+        .ok()
+        .and_then(|mut t| t.remove("v"))
+        .unwrap_or_else(|| toml::Value::String(raw.to_string()))
+}
+
+fn set_path(value: &mut toml::Value, path: &[String], new: toml::Value) -> Result<()> {
+    let (head, rest) = path
+        .split_first()
+        .ok_or_else(|| anyhow!("empty config path"))?;
+
+    match value {
+        toml::Value::Table(table) => {
+            if rest.is_empty() {
+                table.insert(head.clone(), new);
+                return Ok(());
+            }
+            let child = table
+                .entry(head.clone())
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+            set_path(child, rest, new)
+        }
+        toml::Value::Array(items) => {
+            let index: usize = head
+                .parse()
+                .map_err(|_| anyhow!("'{}' is not an array index", head))?;
+            let item = items
+                .get_mut(index)
+                .ok_or_else(|| anyhow!("index {} out of range", index))?;
+            if rest.is_empty() {
+                *item = new;
+                Ok(())
+            } else {
+                set_path(item, rest, new)
+            }
+        }
+        _ => anyhow::bail!("cannot set '{}' on a scalar value", head),
     }
 }
 
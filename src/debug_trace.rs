@@ -0,0 +1,168 @@
+//! Per-request debug tracing (`X-Debug-Trace: true`).
+//!
+//! An admin request carrying the header runs inside a `debug_trace` span, and [`filter`] lets
+//! every `DEBUG` event and span under it through the log output regardless of the global level.
+//! The response carries the span's `X-Trace-Id`, so one misbehaving prompt can be followed in
+//! production logs without raising `observability.log_level` for everyone. Streamed bodies stay
+//! inside the span until the last chunk is sent.
+
+use axum::body::{BoxBody, Bytes, HttpBody};
+use axum::http::{HeaderMap, Method};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use tracing::{Level, Span, Subscriber};
+use tracing_subscriber::filter;
+use tracing_subscriber::layer::Filter;
+use tracing_subscriber::registry::LookupSpan;
+
+pub const HEADER: &str = "x-debug-trace";
+pub const TRACE_ID_HEADER: &str = "x-trace-id";
+const SPAN_NAME: &str = "debug_trace";
+
+// Traces in flight; while zero the filter rejects without looking at the span stack
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether a request asked to be traced
+pub fn requested(headers: &HeaderMap) -> bool {
+    headers
+        .get(HEADER)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.trim().eq_ignore_ascii_case("true"))
+}
+
+/// Log filter to combine with the global one (`global.or(debug_trace::filter())`): enables
+/// `DEBUG` and above inside a `debug_trace` span
+pub fn filter<S>() -> impl Filter<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    filter::dynamic_filter_fn(|metadata, cx| {
+        if metadata.name() == SPAN_NAME {
+            return true;
+        }
+        if ACTIVE.load(Ordering::Relaxed) == 0 || *metadata.level() > Level::DEBUG {
+            return false;
+        }
+        cx.lookup_current()
+            .is_some_and(|span| span.scope().any(|span| span.name() == SPAN_NAME))
+    })
+}
+
+/// A traced request; events are elevated while its span is entered
+pub struct DebugTrace {
+    id: String,
+    span: Span,
+}
+
+impl DebugTrace {
+    pub fn start(method: &Method, path: &str) -> Self {
+        ACTIVE.fetch_add(1, Ordering::Relaxed);
+        let id = uuid::Uuid::new_v4().to_string();
+        let span = tracing::info_span!("debug_trace", trace_id = %id, %method, path);
+        Self { id, span }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+}
+
+impl Drop for DebugTrace {
+    fn drop(&mut self) {
+        ACTIVE.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Response body that polls its inner body inside the trace's span
+pub struct TracedBody {
+    inner: BoxBody,
+    trace: DebugTrace,
+}
+
+impl TracedBody {
+    pub fn new(inner: BoxBody, trace: DebugTrace) -> Self {
+        Self { inner, trace }
+    }
+}
+
+impl HttpBody for TracedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let this = &mut *self;
+        let _entered = this.trace.span.enter();
+        Pin::new(&mut this.inner).poll_data(cx)
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        let this = &mut *self;
+        let _entered = this.trace.span.enter();
+        Pin::new(&mut this.inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tracing_subscriber::filter::{FilterExt, LevelFilter};
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::Layer;
+
+    struct CountEvents(Arc<AtomicUsize>);
+
+    impl<S: Subscriber> Layer<S> for CountEvents {
+        fn on_event(&self, _event: &tracing::Event<'_>, _cx: tracing_subscriber::layer::Context<'_, S>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn debug_events_pass_only_inside_a_trace() {
+        let events = Arc::new(AtomicUsize::new(0));
+        let subscriber = tracing_subscriber::registry()
+            .with(CountEvents(events.clone()).with_filter(LevelFilter::WARN.or(filter())));
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::debug!("untraced");
+            assert_eq!(events.load(Ordering::Relaxed), 0);
+
+            let trace = DebugTrace::start(&Method::POST, "/completions");
+            trace.span().in_scope(|| {
+                tracing::debug!("traced");
+                tracing::debug_span!("engine").in_scope(|| tracing::debug!("nested"));
+                tracing::trace!("too verbose");
+            });
+            assert_eq!(events.load(Ordering::Relaxed), 2);
+
+            tracing::warn!("global level still applies");
+            assert_eq!(events.load(Ordering::Relaxed), 3);
+        });
+    }
+
+    #[test]
+    fn header_must_be_true() {
+        let mut headers = HeaderMap::new();
+        assert!(!requested(&headers));
+        headers.insert(HEADER, "TRUE".parse().unwrap());
+        assert!(requested(&headers));
+        headers.insert(HEADER, "1".parse().unwrap());
+        assert!(!requested(&headers));
+    }
+}
@@ -0,0 +1,291 @@
+//! Device placement.
+//!
+//! `device = "auto"` probes the devices this build can use and places each model on the best one
+//! with room for it: a CUDA GPU with the most free memory first, then Metal, then the CPU. A
+//! model needs its `memory_gb` when configured, otherwise the size of its weights on disk plus
+//! headroom for the KV cache and activations. Every decision is logged with its reason.
+//!
+//! Explicitly named devices are opened here too, so an unusable GPU falls back to the CPU the
+//! same way (with a warning) whichever way it was chosen.
+
+use crate::config::ModelConfig;
+use mistralrs::Device;
+
+/// Multiplier on the weights' size for what a loaded model needs beyond them
+const MEMORY_HEADROOM: f64 = 1.2;
+
+const GIB: f64 = (1u64 << 30) as f64;
+
+/// A device a model could be placed on
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    pub kind: &'static str,
+    pub ordinal: usize,
+    /// Free memory, when the device reports it
+    pub free_bytes: Option<u64>,
+}
+
+impl Candidate {
+    pub fn cpu() -> Self {
+        Self {
+            kind: "cpu",
+            ordinal: 0,
+            free_bytes: None,
+        }
+    }
+
+    /// Name as reported for loaded models, e.g. `cuda` or `cuda:1`
+    pub fn name(&self) -> String {
+        device_name(self.kind, self.ordinal)
+    }
+
+    // Higher is better: any GPU before the CPU
+    fn rank(&self) -> u8 {
+        match self.kind {
+            "cuda" => 2,
+            "metal" => 1,
+            _ => 0,
+        }
+    }
+
+    fn fits(&self, required: Option<u64>) -> bool {
+        match (self.free_bytes, required) {
+            (Some(free), Some(required)) => free >= required,
+            _ => true,
+        }
+    }
+}
+
+/// Where `auto` put a model, and why
+#[derive(Debug, Clone, PartialEq)]
+pub struct Placement {
+    pub kind: &'static str,
+    pub ordinal: usize,
+    pub reason: String,
+}
+
+/// `cuda:1`, or just the kind for ordinal 0 and the CPU
+pub fn device_name(kind: &str, ordinal: usize) -> String {
+    match (kind, ordinal) {
+        ("cpu", _) | (_, 0) => kind.to_string(),
+        (_, ordinal) => format!("{}:{}", kind, ordinal),
+    }
+}
+
+/// Memory `model` needs on a device: its `memory_gb`, else its weights' size plus headroom.
+/// `None` when neither is known. Reads weight headers from disk.
+pub fn required_bytes(model: &ModelConfig) -> Option<u64> {
+    if let Some(gb) = model.memory_gb {
+        return Some((gb * GIB) as u64);
+    }
+    crate::weights::summarize_model(model.path.as_deref(), &model.name)
+        .map(|weights| (weights.bytes as f64 * MEMORY_HEADROOM) as u64)
+}
+
+/// Pick the best of `candidates` for a model needing `required` bytes: the highest ranked kind
+/// with enough free memory, the most free among equals. The CPU is always acceptable.
+pub fn choose(candidates: &[Candidate], required: Option<u64>) -> Placement {
+    let needs = match required {
+        Some(bytes) => format!("needs {}", gib(bytes)),
+        None => "size unknown".to_string(),
+    };
+    let best = candidates
+        .iter()
+        .filter(|c| c.kind != "cpu" && c.fits(required))
+        .max_by_key(|c| (c.rank(), c.free_bytes.unwrap_or(0), std::cmp::Reverse(c.ordinal)));
+    if let Some(best) = best {
+        let free = match best.free_bytes {
+            Some(free) => format!("{} free", gib(free)),
+            None => "free memory unknown".to_string(),
+        };
+        return Placement {
+            kind: best.kind,
+            ordinal: best.ordinal,
+            reason: format!("{}, {}", free, needs),
+        };
+    }
+
+    let gpus: Vec<String> = candidates
+        .iter()
+        .filter(|c| c.kind != "cpu")
+        .map(|c| match c.free_bytes {
+            Some(free) => format!("{} has {} free", c.name(), gib(free)),
+            None => c.name(),
+        })
+        .collect();
+    let reason = if gpus.is_empty() {
+        "no GPU available".to_string()
+    } else {
+        format!("no GPU has room ({}; {})", needs, gpus.join(", "))
+    };
+    Placement {
+        kind: "cpu",
+        ordinal: 0,
+        reason,
+    }
+}
+
+/// Probe the devices and place `model`, logging the decision. Blocks on device and disk I/O.
+pub fn place(model: &ModelConfig) -> Placement {
+    let placement = choose(&probe(), required_bytes(model));
+    tracing::info!(
+        "🧭 Auto device for {}: {} ({})",
+        model.name,
+        device_name(placement.kind, placement.ordinal),
+        placement.reason
+    );
+    metrics::increment_counter!(
+        "device_auto_placements_total",
+        "model" => model.id.clone(),
+        "device" => device_name(placement.kind, placement.ordinal)
+    );
+    placement
+}
+
+/// Devices this build can use, GPUs first; the CPU is always last
+pub fn probe() -> Vec<Candidate> {
+    let mut candidates = cuda_devices();
+    if cfg!(feature = "metal") && Device::new_metal(0).is_ok() {
+        candidates.push(Candidate {
+            kind: "metal",
+            ordinal: 0,
+            free_bytes: None,
+        });
+    }
+    candidates.push(Candidate::cpu());
+    candidates
+}
+
+#[cfg(feature = "cuda")]
+fn cuda_devices() -> Vec<Candidate> {
+    match nvml_wrapper::Nvml::init() {
+        Ok(nvml) => (0..nvml.device_count().unwrap_or(0))
+            .map(|index| Candidate {
+                kind: "cuda",
+                ordinal: index as usize,
+                free_bytes: nvml
+                    .device_by_index(index)
+                    .and_then(|device| device.memory_info())
+                    .ok()
+                    .map(|memory| memory.free),
+            })
+            .collect(),
+        // Without NVML, a device that opens is usable; its free memory is unknown
+        Err(e) => {
+            tracing::debug!("NVML unavailable for device probing: {}", e);
+            match Device::cuda_if_available(0) {
+                Ok(device) if device.is_cuda() => vec![Candidate {
+                    kind: "cuda",
+                    ordinal: 0,
+                    free_bytes: None,
+                }],
+                _ => Vec::new(),
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "cuda"))]
+fn cuda_devices() -> Vec<Candidate> {
+    Vec::new()
+}
+
+/// Open a device, falling back to the CPU with a warning when it is unusable. Returns the device
+/// and the kind it actually is.
+pub fn open(kind: &str, ordinal: usize) -> (Device, &'static str) {
+    let opened = match kind {
+        "cuda" => {
+            if !cfg!(feature = "cuda") {
+                tracing::warn!("⚠️ 'cuda' device requested but 'cuda' feature is NOT enabled. Run with '--features cuda'.");
+            }
+            Device::cuda_if_available(ordinal).map_err(|e| format!("{:?}", e))
+        }
+        "metal" => {
+            if !cfg!(feature = "metal") {
+                tracing::warn!("⚠️ 'metal' device requested but 'metal' feature is NOT enabled. Run with '--features metal'.");
+            }
+            Device::new_metal(ordinal).map_err(|e| format!("{:?}", e))
+        }
+        _ => return (Device::Cpu, "cpu"),
+    };
+    match opened {
+        Ok(device) if device.is_cuda() => {
+            tracing::info!("✅ Successfully initialized CUDA device {}.", ordinal);
+            (device, "cuda")
+        }
+        Ok(device) if device.is_metal() => {
+            tracing::info!("✅ Successfully initialized Metal device {}.", ordinal);
+            (device, "metal")
+        }
+        Ok(_) => {
+            tracing::warn!(
+                "⚠️ {} requested but not available. Falling back to CPU.",
+                device_name(kind, ordinal)
+            );
+            (Device::Cpu, "cpu")
+        }
+        Err(e) => {
+            tracing::warn!(
+                "⚠️ {} requested but not available: {}. Falling back to CPU.",
+                device_name(kind, ordinal),
+                e
+            );
+            (Device::Cpu, "cpu")
+        }
+    }
+}
+
+fn gib(bytes: u64) -> String {
+    format!("{:.1} GiB", bytes as f64 / GIB)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gpu(kind: &'static str, ordinal: usize, free_gib: Option<u64>) -> Candidate {
+        Candidate {
+            kind,
+            ordinal,
+            free_bytes: free_gib.map(|g| g << 30),
+        }
+    }
+
+    #[test]
+    fn picks_the_gpu_with_room_and_the_most_free_memory() {
+        let candidates = [
+            gpu("cuda", 0, Some(2)),
+            gpu("cuda", 1, Some(10)),
+            gpu("cuda", 2, Some(6)),
+            Candidate::cpu(),
+        ];
+        let placement = choose(&candidates, Some(4 << 30));
+        assert_eq!((placement.kind, placement.ordinal), ("cuda", 1));
+        assert_eq!(placement.reason, "10.0 GiB free, needs 4.0 GiB");
+
+        // Metal reports no free memory and is taken when no CUDA device fits
+        let candidates = [gpu("cuda", 0, Some(2)), gpu("metal", 0, None), Candidate::cpu()];
+        assert_eq!(choose(&candidates, Some(4 << 30)).kind, "metal");
+    }
+
+    #[test]
+    fn falls_back_to_the_cpu_when_no_gpu_fits() {
+        let candidates = [gpu("cuda", 0, Some(2)), Candidate::cpu()];
+        let placement = choose(&candidates, Some(4 << 30));
+        assert_eq!((placement.kind, placement.ordinal), ("cpu", 0));
+        assert_eq!(
+            placement.reason,
+            "no GPU has room (needs 4.0 GiB; cuda has 2.0 GiB free)"
+        );
+
+        let placement = choose(&[Candidate::cpu()], None);
+        assert_eq!((placement.kind, placement.reason.as_str()), ("cpu", "no GPU available"));
+    }
+
+    #[test]
+    fn configured_memory_wins_over_the_weights() {
+        let mut model = crate::config::Config::default().models.available_models.remove(0);
+        model.memory_gb = Some(1.5);
+        assert_eq!(required_bytes(&model), Some(3 << 29));
+    }
+}
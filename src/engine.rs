@@ -1,13 +1,199 @@
 use crate::config::ModelConfig;
-use crate::models::InferenceRequest;
+use crate::models::{DeviceFallback, InferenceRequest};
 use anyhow::Result as AnyResult;
 use anyhow::{anyhow, Context};
 use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
 use futures_util::Stream;
+use serde::Serialize;
+use std::fmt;
+use std::ops::Deref;
 use std::sync::Arc;
+use thiserror::Error;
+
+/// One piece of generated text. Built from a `String` or `&'static str` without copying and
+/// cheap to clone. Always valid UTF-8.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Token(Bytes);
+
+impl Token {
+    pub fn as_str(&self) -> &str {
+        // SAFETY: a Token is only ever built from `String` or `&str`, so the bytes are UTF-8
+        unsafe { std::str::from_utf8_unchecked(&self.0) }
+    }
+
+    pub fn into_bytes(self) -> Bytes {
+        self.0
+    }
+}
+
+impl Deref for Token {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl AsRef<str> for Token {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl From<String> for Token {
+    fn from(text: String) -> Self {
+        Self(Bytes::from(text))
+    }
+}
+
+impl From<&'static str> for Token {
+    fn from(text: &'static str) -> Self {
+        Self(Bytes::from_static(text.as_bytes()))
+    }
+}
+
+impl From<Token> for String {
+    fn from(token: Token) -> Self {
+        token.as_str().to_string()
+    }
+}
 
 // another type name for TokenStream
-pub type TokenStream = std::pin::Pin<Box<dyn Stream<Item = AnyResult<String>> + Send>>;
+pub type TokenStream = std::pin::Pin<Box<dyn Stream<Item = AnyResult<Token>> + Send>>;
+
+/// Why an engine could not serve a request. The layers in between carry it inside
+/// `anyhow::Error`; the routes downcast it to pick the HTTP status. Engines may also end a
+/// stream with one of these.
+#[derive(Debug, Error)]
+pub enum EngineError {
+    #[error("model '{0}' not found")]
+    ModelNotFound(String),
+    #[error("prompt does not fit the model's context: {0}")]
+    ContextOverflow(String),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("CUDA error: {0}")]
+    Cuda(String),
+    #[error(transparent)]
+    Backend(#[from] anyhow::Error),
+    #[error("generation cancelled")]
+    Cancelled,
+    #[error("{}", .0.message())]
+    DeviceUnavailable(DeviceFallback),
+    /// No generation slot is free and the queue is full (or gave up waiting); carries the
+    /// seconds after which a retry is likely to get in
+    #[error("server is overloaded; retry after {0} seconds")]
+    Overloaded(u64),
+    /// Ends a stream that ran past `limits.max_generation_seconds`. Handlers treat it as a normal
+    /// finish with `finish_reason: "time_limit"` rather than a failure.
+    #[error("generation stopped after the {0}s time limit")]
+    Timeout(u64),
+}
+
+impl EngineError {
+    /// Stable identifier sent to clients as the error `code`
+    pub fn code(&self) -> &'static str {
+        match self {
+            EngineError::ModelNotFound(_) => "model_not_found",
+            EngineError::ContextOverflow(_) => "context_overflow",
+            EngineError::Io(_) | EngineError::Cuda(_) | EngineError::Backend(_) => "backend_error",
+            EngineError::Cancelled => "cancelled",
+            EngineError::DeviceUnavailable(_) => "device_unavailable",
+            EngineError::Overloaded(_) => "overloaded",
+            EngineError::Timeout(_) => "timeout",
+        }
+    }
+
+    /// Whether `err` is a generation stopped at its time limit
+    pub fn is_timeout(err: &anyhow::Error) -> bool {
+        matches!(err.downcast_ref::<EngineError>(), Some(EngineError::Timeout(_)))
+    }
+}
+
+/// what a loaded model actually is, as opposed to what its config asked for
+#[derive(Debug, Clone, Serialize)]
+pub struct LoadedModel {
+    pub device: String,
+    pub loaded_at: DateTime<Utc>,
+    pub load_seconds: f64,
+    /// precision of the weights in memory, e.g. `bf16`; `None` when it could not be read
+    pub quantization: Option<String>,
+    pub parameters: Option<u64>,
+    /// size of the weights in bytes
+    pub memory_bytes: Option<u64>,
+    /// devices of standby copies serving requests alongside this one
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub standby: Vec<String>,
+}
+
+/// a model held in the engine's memory, for `GET /admin/cache`
+#[derive(Debug, Clone, Serialize)]
+pub struct CachedModel {
+    pub model: String,
+    /// device of each loaded copy, the primary first
+    pub devices: Vec<String>,
+    pub loaded_at: DateTime<Utc>,
+    /// weights of every copy together, in bytes; `None` when a copy's size could not be read
+    pub memory_bytes: Option<u64>,
+    /// when a generation or render last used the model
+    pub last_used: DateTime<Utc>,
+    /// generations streaming from it right now
+    pub active_generations: usize,
+}
+
+/// a request's prompt exactly as the model would receive it, for `POST /debug/render`
+#[derive(Debug, Clone, Serialize)]
+pub struct RenderedPrompt {
+    /// messages after the model's chat template, special tokens included
+    pub prompt: String,
+    pub prompt_tokens: usize,
+    /// sequences that end the generation: the request's `stop` and the model's end-of-sequence
+    /// token
+    pub stop: Vec<String>,
+}
+
+/// how far an engine has read a prompt before the first token, both counts in tokens
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Prefill {
+    pub processed: usize,
+    pub total: usize,
+}
+
+/// where an engine reports [`Prefill`] progress for a request (`InferenceRequest::prefill`);
+/// engines that cannot measure it may report only the start and the end, or nothing at all
+pub type PrefillSender = tokio::sync::mpsc::UnboundedSender<Prefill>;
+
+/// how an engine tells the server which device a request's model copy is on
+/// (`InferenceRequest::placement`). Engines that keep copies on several devices call it once they
+/// have leased one and before generating; an error refuses the request with that error.
+#[derive(Clone)]
+pub struct Placement(Arc<PlacementCheck>);
+
+type PlacementCheck = dyn Fn(&str) -> Result<(), EngineError> + Send + Sync;
+
+impl Placement {
+    pub fn new(check: impl Fn(&str) -> Result<(), EngineError> + Send + Sync + 'static) -> Self {
+        Self(Arc::new(check))
+    }
+
+    pub fn check(&self, device: &str) -> Result<(), EngineError> {
+        (self.0)(device)
+    }
+}
+
+impl fmt::Debug for Placement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Placement")
+    }
+}
 
 /// inference engine abtract between service and base
 #[async_trait]
@@ -16,27 +202,91 @@ pub trait InferenceEngine: Send + Sync {
     async fn get_available_models(&self) -> Vec<String>;
 
     /// run streaming inference and return TokenStream
-    async fn run_streaming_inference(&self, request: InferenceRequest) -> AnyResult<TokenStream>;
+    async fn run_streaming_inference(
+        &self,
+        request: InferenceRequest,
+    ) -> Result<TokenStream, EngineError>;
+
+    /// ids of models currently loaded in memory
+    async fn cached_models(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// device a loaded model actually runs on, which may differ from the one requested
+    async fn loaded_device(&self, _model: &str) -> Option<String> {
+        None
+    }
+
+    /// device, load time and weights of a model currently loaded in memory
+    async fn loaded_model(&self, _model: &str) -> Option<LoadedModel> {
+        None
+    }
+
+    /// loaded models with their memory estimates and when each was last used
+    async fn cache_entries(&self) -> Vec<CachedModel> {
+        Vec::new()
+    }
+
+    /// drop every copy of a loaded model from memory; generations already running on it keep
+    /// their copy until they finish. `Ok(false)` when the model is not loaded.
+    async fn evict_model(&self, _model: &str) -> Result<bool, EngineError> {
+        Err(EngineError::Backend(anyhow!("this engine does not cache models")))
+    }
+
+    /// the prompt a request would be turned into, without generating anything
+    async fn render_prompt(&self, _request: &InferenceRequest) -> Result<RenderedPrompt, EngineError> {
+        Err(EngineError::Backend(anyhow!("this engine cannot render prompts")))
+    }
+
+    /// token ids of `text` under the model's tokenizer, with its special tokens when asked for
+    async fn tokenize(&self, _model: &str, _text: &str, _add_special_tokens: bool) -> Result<Vec<u32>, EngineError> {
+        Err(EngineError::Backend(anyhow!("this engine cannot tokenize")))
+    }
+
+    /// whether a conversation may end with an assistant message marked `continue`, which the
+    /// model then carries on instead of starting a new turn
+    fn continues_messages(&self) -> bool {
+        false
+    }
+
+    /// embedding vector for `text`, used by the semantic response cache
+    async fn embed(&self, _model: &str, _text: &str) -> AnyResult<Vec<f32>> {
+        Err(anyhow!("this engine does not support embeddings"))
+    }
+
+    /// whether [`embed`](Self::embed) works, which `cache.semantic_threshold` needs
+    fn embeds(&self) -> bool {
+        false
+    }
+
+    /// replace the configured model list at runtime (used by config hot reload)
+    async fn reload_models(&self, _configs: Vec<ModelConfig>) -> AnyResult<()> {
+        Err(anyhow!("this engine does not support reloading models"))
+    }
 }
 
-use mistralrs::{Device, Model, PagedAttentionMetaBuilder, TextModelBuilder};
+use crate::config::{parse_device, AUTO_DEVICE};
+use crate::engine_tokenizer::TokenizerCache;
+use either::Either;
+use mistralrs::{Model, PagedAttentionMetaBuilder, TextModelBuilder};
 use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+use std::sync::RwLock;
 use tokio::sync::Mutex;
 
-/// M1 engine adapter realization
-pub struct M1EngineAdapter {
-    // cache loaded model canonical_id -> TextModel
-    models: Mutex<HashMap<String, Arc<Model>>>,
+/// configured models and their lookup tables; swapped as a whole on reload
+struct ModelCatalog {
     // canonical id -> ModelConfig
     model_configs: HashMap<String, ModelConfig>,
-    // alias (id/name) -> canonical id
+    // alias (id/name/configured alias) -> canonical id
     model_aliases: HashMap<String, String>,
     // model name list for display
     model_names: Vec<String>,
 }
 
-impl M1EngineAdapter {
-    pub fn new(configs: Vec<ModelConfig>) -> Self {
+impl ModelCatalog {
+    fn new(configs: Vec<ModelConfig>) -> Self {
         let mut model_configs = HashMap::new();
         let mut model_aliases = HashMap::new();
         let mut model_names = Vec::new();
@@ -44,17 +294,141 @@ impl M1EngineAdapter {
         for config in configs {
             model_aliases.insert(config.id.clone(), config.id.clone());
             model_aliases.insert(config.name.clone(), config.id.clone());
+            for alias in &config.aliases {
+                model_aliases.insert(alias.clone(), config.id.clone());
+            }
             model_names.push(config.name.clone());
             model_configs.insert(config.id.clone(), config);
         }
 
         Self {
-            models: Mutex::new(HashMap::new()),
             model_configs,
             model_aliases,
             model_names,
         }
     }
+}
+
+/// one loaded copy of a model
+struct ModelSlot<M = Model> {
+    model: Arc<M>,
+    loaded: LoadedModel,
+    // generations currently streaming from this copy
+    active: Arc<AtomicUsize>,
+    // when this copy was last leased, in Unix milliseconds
+    last_used: AtomicI64,
+}
+
+/// a generation's claim on a model copy, released when its stream ends or is dropped
+struct SlotLease {
+    active: Arc<AtomicUsize>,
+    // device of the leased copy
+    device: String,
+}
+
+impl Drop for SlotLease {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// pick the copy with the fewest generations in flight, preferring the primary on a tie
+fn lease_slot<M>(model_id: &str, slots: &[ModelSlot<M>]) -> (Arc<M>, SlotLease) {
+    let (index, slot) = slots
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, slot)| slot.active.load(Ordering::SeqCst))
+        .expect("a loaded model has at least one copy");
+    slot.active.fetch_add(1, Ordering::SeqCst);
+    slot.last_used.store(Utc::now().timestamp_millis(), Ordering::SeqCst);
+    if slots.len() > 1 {
+        metrics::increment_counter!(
+            "model_slot_requests_total",
+            "model" => model_id.to_string(),
+            "slot" => index.to_string()
+        );
+    }
+    let lease = SlotLease {
+        active: slot.active.clone(),
+        device: slot.loaded.device.clone(),
+    };
+    (slot.model.clone(), lease)
+}
+
+/// loaded copies by canonical model id, the primary first and then standby copies
+type SlotMap<M = Model> = Mutex<HashMap<String, Vec<ModelSlot<M>>>>;
+
+/// one lock per model, held while its copies load
+type LoadLocks = Mutex<HashMap<String, Arc<Mutex<()>>>>;
+
+/// lease a copy of `model_id`, running `load` to build its copies when none are loaded. Loads
+/// of one model take turns, so requests that arrive together while it is not loaded wait for the
+/// first one's copies instead of building their own.
+async fn lease_or_load<M, F, Fut>(
+    models: &SlotMap<M>,
+    loading: &LoadLocks,
+    model_id: &str,
+    load: F,
+) -> Result<(Arc<M>, SlotLease), EngineError>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<Vec<ModelSlot<M>>, EngineError>>,
+{
+    if let Some(slots) = models.lock().await.get(model_id) {
+        return Ok(lease_slot(model_id, slots));
+    }
+    let lock = loading.lock().await.entry(model_id.to_string()).or_default().clone();
+    let _loading = lock.lock().await;
+    if let Some(slots) = models.lock().await.get(model_id) {
+        return Ok(lease_slot(model_id, slots));
+    }
+
+    let slots = load().await?;
+    let mut guard = models.lock().await;
+    guard.entry(model_id.to_string()).or_insert(slots);
+    metrics::gauge!("models_cached", guard.len() as f64);
+    Ok(lease_slot(model_id, &guard[model_id]))
+}
+
+/// the conversation a request sends: its messages, or its prompt as a single user turn
+fn text_messages(request: &InferenceRequest) -> mistralrs::TextMessages {
+    let mut messages = mistralrs::TextMessages::new();
+
+    if let Some(msgs) = &request.messages {
+        for msg in msgs {
+            let role = match msg.role.to_lowercase().as_str() {
+                "user" => mistralrs::TextMessageRole::User,
+                "assistant" => mistralrs::TextMessageRole::Assistant,
+                "system" => mistralrs::TextMessageRole::System,
+                _ => mistralrs::TextMessageRole::User,
+            };
+            messages = messages.add_message(role, &msg.content);
+        }
+    } else {
+        messages = messages.add_message(mistralrs::TextMessageRole::User, &request.prompt);
+    }
+    messages
+}
+
+/// M1 engine adapter realization
+pub struct M1EngineAdapter {
+    // cache loaded model canonical_id -> its copies, the primary first and then standby copies
+    models: SlotMap,
+    loading: LoadLocks,
+    catalog: RwLock<ModelCatalog>,
+    // tokenizers for models that are not loaded, so tokenizing never loads weights
+    tokenizers: TokenizerCache,
+}
+
+impl M1EngineAdapter {
+    pub fn new(configs: Vec<ModelConfig>) -> Self {
+        Self {
+            models: Mutex::new(HashMap::new()),
+            loading: Mutex::new(HashMap::new()),
+            catalog: RwLock::new(ModelCatalog::new(configs)),
+            tokenizers: TokenizerCache::default(),
+        }
+    }
 
     /// Pre-warm the model by loading it into cache
     pub async fn warmup(&self, model_id: &str, device: &str) -> AnyResult<()> {
@@ -70,41 +444,55 @@ impl M1EngineAdapter {
         Ok(())
     }
 
-    /// load model and cache
-    async fn get_or_load_model(&self, model_id: &str, device: &str) -> AnyResult<Arc<Model>> {
+    /// loaded copy of the model that is least busy, loading the model (with its standby copies)
+    /// on first use
+    async fn get_or_load_model(
+        &self,
+        model_id: &str,
+        device: &str,
+    ) -> Result<(Arc<Model>, SlotLease), EngineError> {
         let (canonical_id, config) = self.resolve_model(model_id)?;
+        lease_or_load(&self.models, &self.loading, &canonical_id, || self.load_copies(&config, device)).await
+    }
 
-        // check cache first
-        {
-            let guard = self.models.lock().await;
-            if let Some(m) = guard.get(&canonical_id) {
-                return Ok(m.clone());
+    /// build the primary copy of a model on `device`, then any standby copies; a standby copy
+    /// that fails to load is skipped
+    async fn load_copies(&self, config: &ModelConfig, device: &str) -> Result<Vec<ModelSlot>, EngineError> {
+        let (kind, ordinal) = if device.eq_ignore_ascii_case(AUTO_DEVICE) {
+            let model = config.clone();
+            let placement = tokio::task::spawn_blocking(move || crate::device::place(&model))
+                .await
+                .map_err(|e| EngineError::Backend(anyhow!(e)))?;
+            (placement.kind, placement.ordinal)
+        } else {
+            parse_device(device).unwrap_or(("cpu", 0))
+        };
+        let mut slots = vec![self.load_copy(config, kind, ordinal).await?];
+        for standby in &config.standby {
+            let Some((kind, ordinal)) = parse_device(standby) else {
+                continue;
+            };
+            match self.load_copy(config, kind, ordinal).await {
+                Ok(slot) => slots.push(slot),
+                Err(e) => tracing::warn!(
+                    "⚠️ Failed to load standby copy of {} on {}: {}",
+                    config.name,
+                    standby,
+                    e
+                ),
             }
         }
+        Ok(slots)
+    }
 
-        // not found -> build
-        let dev = match device.to_lowercase().as_str() {
-            "cuda" => {
-                #[cfg(not(feature = "cuda"))]
-                tracing::warn!("⚠️ 'cuda' device requested but 'cuda' feature is NOT enabled. This will likely cause CPU fallback. Run with '--features cuda'.");
-
-                match Device::cuda_if_available(0) {
-                    Ok(d) => {
-                        tracing::info!("✅ Successfully initialized CUDA device.");
-                        d
-                    }
-                    Err(e) => {
-                        tracing::warn!(
-                            "⚠️ CUDA requested but not available: {:?}. Falling back to CPU.",
-                            e
-                        );
-                        Device::Cpu
-                    }
-                }
-            }
-            "metal" => Device::new_metal(0).unwrap_or(Device::Cpu),
-            _ => Device::Cpu,
-        };
+    /// build one copy of a model on the given device
+    async fn load_copy(
+        &self,
+        config: &ModelConfig,
+        kind: &str,
+        ordinal: usize,
+    ) -> Result<ModelSlot, EngineError> {
+        let (dev, device_name) = crate::device::open(kind, ordinal);
 
         let identifier = config
             .path
@@ -112,6 +500,12 @@ impl M1EngineAdapter {
             .map(|p| p.to_string_lossy().into_owned())
             .unwrap_or_else(|| config.name.clone());
 
+        // weights already on disk load warm; a Hub model without a local snapshot downloads first
+        let on_disk =
+            config.path.is_some() || crate::weights::hub_snapshot(&config.name).is_some();
+        let start = if on_disk { "warm" } else { "cold" };
+        let started = std::time::Instant::now();
+        let loaded_at = Utc::now();
         let builder = TextModelBuilder::new(&identifier)
             .with_device(dev)
             .with_logging()
@@ -120,24 +514,58 @@ impl M1EngineAdapter {
         let model = builder
             .build()
             .await
-            .context("failed to build/load model")?;
-        let arc = Arc::new(model);
-        let mut guard = self.models.lock().await;
-        guard.insert(canonical_id, arc.clone());
-        Ok(arc)
+            .context("failed to build/load model")
+            .map_err(|e| match device_name {
+                "cuda" => EngineError::Cuda(format!("{:#}", e)),
+                _ => EngineError::Backend(e),
+            })?;
+        let load_seconds = started.elapsed().as_secs_f64();
+
+        // No ISQ is applied, so the weights' own dtype is the precision in effect
+        let (path, name) = (config.path.clone(), config.name.clone());
+        let weights = tokio::task::spawn_blocking(move || {
+            crate::weights::summarize_model(path.as_deref(), &name)
+        })
+        .await
+        .ok()
+        .flatten();
+        let device = crate::device::device_name(device_name, ordinal);
+        metrics::histogram!(
+            "model_load_duration_seconds",
+            load_seconds,
+            "model" => config.id.clone(),
+            "device" => device.clone(),
+            "start" => start
+        );
+        Ok(ModelSlot {
+            model: Arc::new(model),
+            loaded: LoadedModel {
+                device,
+                loaded_at,
+                load_seconds,
+                quantization: weights.as_ref().map(|w| w.dtype.clone()),
+                parameters: weights.as_ref().map(|w| w.parameters),
+                memory_bytes: weights.as_ref().map(|w| w.bytes),
+                standby: Vec::new(),
+            },
+            active: Arc::new(AtomicUsize::new(0)),
+            last_used: AtomicI64::new(loaded_at.timestamp_millis()),
+        })
     }
 
-    fn resolve_model(&self, model_id: &str) -> AnyResult<(String, ModelConfig)> {
-        let canonical_id = self
+    fn resolve_model(&self, model_id: &str) -> Result<(String, ModelConfig), EngineError> {
+        let catalog = self.catalog.read().unwrap();
+        let not_found = || EngineError::ModelNotFound(model_id.to_string());
+        let canonical_id = catalog
             .model_aliases
             .get(model_id)
             .cloned()
-            .ok_or_else(|| anyhow!("Model '{}' not configured", model_id))?;
-        let config = self
+            .ok_or_else(not_found)?;
+        let config = catalog
             .model_configs
             .get(&canonical_id)
             .cloned()
-            .ok_or_else(|| anyhow!("Model '{}' not configured", model_id))?;
+            .ok_or_else(not_found)?;
         Ok((canonical_id, config))
     }
 }
@@ -145,34 +573,142 @@ impl M1EngineAdapter {
 #[async_trait]
 impl InferenceEngine for M1EngineAdapter {
     async fn get_available_models(&self) -> Vec<String> {
-        self.model_names.clone()
+        self.catalog.read().unwrap().model_names.clone()
+    }
+
+    async fn cached_models(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self.models.lock().await.keys().cloned().collect();
+        ids.sort();
+        ids
+    }
+
+    async fn loaded_device(&self, model: &str) -> Option<String> {
+        self.loaded_model(model).await.map(|loaded| loaded.device)
+    }
+
+    async fn loaded_model(&self, model: &str) -> Option<LoadedModel> {
+        let (canonical_id, _) = self.resolve_model(model).ok()?;
+        let guard = self.models.lock().await;
+        let (primary, standby) = guard.get(&canonical_id)?.split_first()?;
+        let mut loaded = primary.loaded.clone();
+        loaded.standby = standby.iter().map(|slot| slot.loaded.device.clone()).collect();
+        Some(loaded)
+    }
+
+    async fn cache_entries(&self) -> Vec<CachedModel> {
+        let guard = self.models.lock().await;
+        let mut entries: Vec<CachedModel> = guard
+            .iter()
+            .filter_map(|(id, slots)| {
+                let primary = slots.first()?;
+                let last_used = slots.iter().map(|slot| slot.last_used.load(Ordering::SeqCst)).max()?;
+                Some(CachedModel {
+                    model: id.clone(),
+                    devices: slots.iter().map(|slot| slot.loaded.device.clone()).collect(),
+                    loaded_at: primary.loaded.loaded_at,
+                    memory_bytes: slots.iter().map(|slot| slot.loaded.memory_bytes).sum(),
+                    last_used: DateTime::from_timestamp_millis(last_used).unwrap_or(primary.loaded.loaded_at),
+                    active_generations: slots.iter().map(|slot| slot.active.load(Ordering::SeqCst)).sum(),
+                })
+            })
+            .collect();
+        entries.sort_by(|a, b| a.model.cmp(&b.model));
+        entries
+    }
+
+    async fn evict_model(&self, model: &str) -> Result<bool, EngineError> {
+        let (canonical_id, _) = self.resolve_model(model)?;
+        let mut guard = self.models.lock().await;
+        let evicted = guard.remove(&canonical_id).is_some();
+        metrics::gauge!("models_cached", guard.len() as f64);
+        Ok(evicted)
     }
 
-    async fn run_streaming_inference(&self, request: InferenceRequest) -> AnyResult<TokenStream> {
+    async fn render_prompt(&self, request: &InferenceRequest) -> Result<RenderedPrompt, EngineError> {
+        let (_, config) = self.resolve_model(&request.model_name)?;
+        let (model, _lease) = self.get_or_load_model(&request.model_name, &request.device).await?;
+
+        // The template is applied by tokenizing the way a generation would, generation prompt
+        // and special tokens included, and decoding without skipping special tokens
+        let tokens = model
+            .tokenize(Either::Left(text_messages(request)), None, true, true, None)
+            .await?;
+        let prompt = model.detokenize(tokens.clone(), false).await?;
+
+        let mut stop = request.stop.clone();
+        let eos = tokio::task::spawn_blocking(move || {
+            crate::weights::model_dir(config.path.as_deref(), &config.name)
+                .and_then(|dir| crate::weights::eos_token(&dir))
+        })
+        .await
+        .ok()
+        .flatten();
+        stop.extend(eos);
+        Ok(RenderedPrompt {
+            prompt,
+            prompt_tokens: tokens.len(),
+            stop,
+        })
+    }
+
+    async fn tokenize(&self, model: &str, text: &str, add_special_tokens: bool) -> Result<Vec<u32>, EngineError> {
+        let (canonical_id, config) = self.resolve_model(model)?;
+        let loaded = {
+            let guard = self.models.lock().await;
+            guard
+                .get(&canonical_id)
+                .and_then(|slots| slots.first())
+                .map(|slot| slot.model.clone())
+        };
+        match loaded {
+            Some(model) => Ok(model
+                .tokenize(Either::Right(text.to_string()), None, add_special_tokens, false, None)
+                .await?),
+            None => {
+                let tokenizer = self.tokenizers.get(&config).await?;
+                tokenizer.encode(text, add_special_tokens).map_err(EngineError::Backend)
+            }
+        }
+    }
+
+    async fn reload_models(&self, configs: Vec<ModelConfig>) -> AnyResult<()> {
+        self.tokenizers.clear().await;
+        let catalog = ModelCatalog::new(configs);
+
+        // Drop cached weights for models that were removed or whose source changed; in-flight
+        // generations keep their own Arc and finish normally.
+        let mut guard = self.models.lock().await;
+        let mut current = self.catalog.write().unwrap();
+        guard.retain(|id, _| {
+            match (current.model_configs.get(id), catalog.model_configs.get(id)) {
+                (Some(old), Some(new)) => {
+                    old.name == new.name && old.path == new.path && old.quantization == new.quantization
+                        && old.standby == new.standby
+                }
+                _ => false,
+            }
+        });
+        metrics::gauge!("models_cached", guard.len() as f64);
+        *current = catalog;
+        Ok(())
+    }
+
+    async fn run_streaming_inference(
+        &self,
+        request: InferenceRequest,
+    ) -> Result<TokenStream, EngineError> {
         // Use cached model (or load) and create a stream using the model directly. This avoids
         // rebuilding models for every request and makes `get_or_load_model` actually used.
         let model_id = request.model_name.clone();
         let device = request.device.clone();
 
-        let model = self.get_or_load_model(&model_id, &device).await?;
-
-        let mut messages = mistralrs::TextMessages::new();
-
-        if let Some(msgs) = &request.messages {
-            for msg in msgs {
-                let role = match msg.role.to_lowercase().as_str() {
-                    "user" => mistralrs::TextMessageRole::User,
-                    "assistant" => mistralrs::TextMessageRole::Assistant,
-                    "system" => mistralrs::TextMessageRole::System,
-                    _ => mistralrs::TextMessageRole::User,
-                };
-                messages = messages.add_message(role, &msg.content);
-            }
-        } else {
-            messages = messages.add_message(mistralrs::TextMessageRole::User, &request.prompt);
+        let (model, lease) = self.get_or_load_model(&model_id, &device).await?;
+        // The copy is known now; a request refused there gives it back right away
+        if let Some(placement) = &request.placement {
+            placement.check(&lease.device)?;
         }
 
-        let mut req = mistralrs::RequestBuilder::from(messages)
+        let mut req = mistralrs::RequestBuilder::from(text_messages(&request))
             .set_sampler_max_len(request.max_token)
             .set_sampler_temperature(request.temperature);
 
@@ -201,22 +737,57 @@ impl InferenceEngine for M1EngineAdapter {
             req = req.set_sampler_stop_toks(mistralrs::StopTokens::Seqs(request.stop.clone()));
         }
 
+        // The prompt is only counted when its length is checked or reported
+        let context_length = self.resolve_model(&model_id)?.1.context_length;
+        let prompt_tokens = if context_length.is_some() || request.prefill.is_some() {
+            model
+                .tokenize(Either::Left(text_messages(&request)), None, true, true, None)
+                .await
+                .map_or(0, |tokens| tokens.len())
+        } else {
+            0
+        };
+        if let Some(limit) = context_length.filter(|limit| prompt_tokens >= *limit) {
+            return Err(EngineError::ContextOverflow(format!(
+                "prompt of {} tokens, context of {}",
+                prompt_tokens, limit
+            )));
+        }
+
+        // mistralrs does not report prefill as it goes, so progress is the start and the end
+        let prefill = request.prefill.clone().map(|sender| {
+            let _ = sender.send(Prefill { processed: 0, total: prompt_tokens });
+            (sender, prompt_tokens)
+        });
+
         use async_stream::try_stream;
 
         let model_clone = model.clone();
         let req_clone = req;
 
         let s = try_stream! {
+            let _lease = lease;
+            let mut prefill = prefill;
             let mut inner = model_clone.stream_chat_request(req_clone).await?;
             while let Some(chunk) = inner.next().await {
+                if let Some((sender, total)) = prefill.take() {
+                    let _ = sender.send(Prefill { processed: total, total });
+                }
                 match chunk {
                     mistralrs::Response::Chunk(mistralrs::ChatCompletionChunkResponse { choices, .. }) => {
-                        if let Some(mistralrs::ChunkChoice { delta: mistralrs::Delta { content: Some(c), .. }, .. }) = choices.first() {
-                            yield c.clone();
+                        // Take the text out of the response rather than cloning it
+                        if let Some(mistralrs::ChunkChoice { delta: mistralrs::Delta { content: Some(c), .. }, .. }) = choices.into_iter().next() {
+                            yield Token::from(c);
                         } else {
-                            yield String::new();
+                            yield Token::default();
                         }
                     }
+                    mistralrs::Response::ValidationError(e) if is_sequence_length_error(&e.to_string()) => {
+                        Err(EngineError::ContextOverflow(e.to_string()))?;
+                    }
+                    mistralrs::Response::ValidationError(e) => Err(anyhow!("invalid request: {}", e))?,
+                    mistralrs::Response::InternalError(e) => Err(anyhow!("engine error: {}", e))?,
+                    mistralrs::Response::ModelError(message, _) => Err(anyhow!("model error: {}", message))?,
                     _ => continue,
                 }
             }
@@ -226,3 +797,108 @@ impl InferenceEngine for M1EngineAdapter {
         Ok(boxed)
     }
 }
+
+// mistral.rs refuses a prompt longer than the model allows with a `ValidationError` naming the
+// sequence length; the error has no kind to match on, only its message
+const SEQUENCE_LENGTH_ERROR: &str = "sequence length";
+
+fn is_sequence_length_error(message: &str) -> bool {
+    message.contains(SEQUENCE_LENGTH_ERROR)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn slot(device: &str) -> ModelSlot<()> {
+        ModelSlot {
+            model: Arc::new(()),
+            loaded: LoadedModel {
+                device: device.to_string(),
+                loaded_at: Utc::now(),
+                load_seconds: 0.0,
+                quantization: None,
+                parameters: None,
+                memory_bytes: None,
+                standby: Vec::new(),
+            },
+            active: Arc::new(AtomicUsize::new(0)),
+            last_used: AtomicI64::new(0),
+        }
+    }
+
+    fn active(slots: &[ModelSlot<()>]) -> Vec<usize> {
+        slots.iter().map(|slot| slot.active.load(Ordering::SeqCst)).collect()
+    }
+
+    #[test]
+    fn test_lease_goes_to_the_least_busy_copy() {
+        let slots = vec![slot("cuda"), slot("cuda:1")];
+        let (_, first) = lease_slot("qwen", &slots);
+        assert_eq!(first.device, "cuda");
+
+        // With the primary busy the standby copy takes over, and the primary again once free
+        let (_, second) = lease_slot("qwen", &slots);
+        assert_eq!(second.device, "cuda:1");
+        assert_eq!(active(&slots), [1, 1]);
+        drop(first);
+        assert_eq!(active(&slots), [0, 1]);
+        let (_, third) = lease_slot("qwen", &slots);
+        assert_eq!(third.device, "cuda");
+
+        drop((second, third));
+        assert_eq!(active(&slots), [0, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_first_requests_load_once() {
+        let models = SlotMap::default();
+        let loading = LoadLocks::default();
+        let loads = &AtomicUsize::new(0);
+        let load = move || async move {
+            loads.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            Ok(vec![slot("cuda"), slot("cuda:1")])
+        };
+
+        let (a, b) = tokio::join!(
+            lease_or_load(&models, &loading, "qwen", load),
+            lease_or_load(&models, &loading, "qwen", load)
+        );
+        let (a, b) = (a.unwrap().1, b.unwrap().1);
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        assert_eq!((a.device.as_str(), b.device.as_str()), ("cuda", "cuda:1"));
+        assert_eq!(active(&models.lock().await["qwen"]), [1, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_failed_load_is_retried() {
+        let models = SlotMap::default();
+        let loading = LoadLocks::default();
+        let failed = lease_or_load(&models, &loading, "qwen", || async {
+            Err(EngineError::Cuda("out of memory".to_string()))
+        })
+        .await;
+        assert!(matches!(failed, Err(EngineError::Cuda(_))));
+        assert!(models.lock().await.is_empty());
+
+        // The next request loads again, and the one after that reuses its copy
+        let (_, lease) = lease_or_load(&models, &loading, "qwen", || async { Ok(vec![slot("cpu")]) })
+            .await
+            .unwrap();
+        assert_eq!(lease.device, "cpu");
+        let (_, lease) = lease_or_load(&models, &loading, "qwen", || async { Ok(vec![slot("cuda")]) })
+            .await
+            .unwrap();
+        assert_eq!(lease.device, "cpu");
+    }
+
+    #[test]
+    fn test_sequence_length_errors_are_recognised() {
+        // As mistral.rs words a prompt over the model's limit
+        let message = "Prompt sequence length is greater than 4096, perhaps consider using `truncate_sequence`?";
+        assert!(is_sequence_length_error(message));
+        assert!(!is_sequence_length_error("temperature must be positive"));
+    }
+}
@@ -1,38 +1,495 @@
-use crate::engine::{InferenceEngine, TokenStream};
+//! Engine for tests, benchmarks and `serve --mock`.
+//!
+//! By default it answers every prompt with `hello <prompt>\ndone`. The `with_*` methods script
+//! other behaviour: a fixed token sequence, a delay before each token, a slow model load, a load
+//! failure, or an error or panic partway through the stream. A request asking for prefill
+//! progress gets reports spread over the wait for its first token. Everything is deterministic,
+//! so tests can drive timeout, cancellation and error paths as easily as the happy path.
+//!
+//! [`MockEngine::from_config`] applies the `[mock]` section for `serve --mock`: a time to first
+//! token, a token rate, and a share of generations failing partway through. Which generations
+//! fail is derived from `mock.seed` and the order requests arrive in, so a run can be repeated.
+
+use crate::config::{MockConfig, ModelConfig};
+use crate::engine::{
+    CachedModel, EngineError, InferenceEngine, LoadedModel, Prefill, RenderedPrompt, Token, TokenStream,
+};
 use crate::models::InferenceRequest;
-use anyhow::Result as AnyResult;
+use anyhow::{anyhow, Result as AnyResult};
 use async_trait::async_trait;
-use futures_util::stream;
-use std::sync::Arc;
+use chrono::{DateTime, Utc};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Prefill progress reports spread over the wait for the first token, after the one at 0
+const PREFILL_STEPS: usize = 4;
+
+/// Longest pause between tokens `[mock]` sets; slower rates are clamped to it
+const MAX_TOKEN_DELAY: Duration = Duration::from_secs(60);
+
+/// How a scripted stream ends early
+#[derive(Debug, Clone)]
+enum Failure {
+    Error(String),
+    Panic(String),
+}
 
-pub struct MockEngine {}
+pub struct MockEngine {
+    models: Vec<String>,
+    tokens: Option<Vec<String>>,
+    token_delay: Duration,
+    load_delay: Duration,
+    load_error: Option<String>,
+    failure: Option<(usize, Failure)>,
+    first_token_delay: Option<Duration>,
+    error_rate: Option<(f64, u64)>,
+    requests: AtomicU64,
+    generated: Arc<AtomicUsize>,
+    created_at: DateTime<Utc>,
+    // models evicted from the cache and not generated with since
+    evicted: Mutex<HashSet<String>>,
+}
 
 impl MockEngine {
     pub fn new() -> Self {
-        Self {}
+        Self {
+            models: vec!["mock-model".to_string()],
+            tokens: None,
+            token_delay: Duration::ZERO,
+            load_delay: Duration::ZERO,
+            load_error: None,
+            failure: None,
+            first_token_delay: None,
+            error_rate: None,
+            requests: AtomicU64::new(0),
+            generated: Arc::new(AtomicUsize::new(0)),
+            created_at: Utc::now(),
+            evicted: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// The engine `serve --mock` runs with the `[mock]` latency and failure settings
+    pub fn from_config(config: &MockConfig) -> Self {
+        let mut engine = Self::new();
+        if config.tokens_per_second > 0.0 {
+            let delay = Duration::try_from_secs_f64(1.0 / config.tokens_per_second).unwrap_or(MAX_TOKEN_DELAY);
+            engine = engine.with_token_delay(delay.min(MAX_TOKEN_DELAY));
+        }
+        if config.time_to_first_token_ms > 0 {
+            engine = engine.with_first_token_delay(Duration::from_millis(config.time_to_first_token_ms));
+        }
+        if config.error_rate > 0.0 {
+            engine = engine.with_error_rate(config.error_rate, config.seed);
+        }
+        engine
+    }
+
+    /// Models reported by `get_available_models` (default `mock-model`)
+    pub fn with_models<I, S>(mut self, models: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.models = models.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Answer every request with these tokens instead of echoing the prompt
+    pub fn with_tokens<I, S>(mut self, tokens: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.tokens = Some(tokens.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Wait this long before each token
+    pub fn with_token_delay(mut self, delay: Duration) -> Self {
+        self.token_delay = delay;
+        self
+    }
+
+    /// Wait this long before the first token instead of the token delay, like prompt processing
+    pub fn with_first_token_delay(mut self, delay: Duration) -> Self {
+        self.first_token_delay = Some(delay);
+        self
+    }
+
+    /// Wait this long before the stream is returned, like a model being loaded
+    pub fn with_load_delay(mut self, delay: Duration) -> Self {
+        self.load_delay = delay;
+        self
+    }
+
+    /// Fail every request before any token, like a model that cannot be loaded
+    pub fn with_load_error(mut self, message: impl Into<String>) -> Self {
+        self.load_error = Some(message.into());
+        self
+    }
+
+    /// End the stream with an error after `tokens` tokens (or after the last one, if fewer)
+    pub fn with_error_after(mut self, tokens: usize, message: impl Into<String>) -> Self {
+        self.failure = Some((tokens, Failure::Error(message.into())));
+        self
+    }
+
+    /// Panic while producing the stream after `tokens` tokens (or after the last one, if fewer)
+    pub fn with_panic_after(mut self, tokens: usize, message: impl Into<String>) -> Self {
+        self.failure = Some((tokens, Failure::Panic(message.into())));
+        self
+    }
+
+    /// End this share (0-1) of generations with an error partway through. Which ones, and after
+    /// how many tokens, depends only on `seed` and the order requests arrive in.
+    pub fn with_error_rate(mut self, rate: f64, seed: u64) -> Self {
+        self.error_rate = Some((rate, seed));
+        self
+    }
+
+    /// Tokens handed out so far, across all requests. A cancelled generation stops counting.
+    pub fn tokens_generated(&self) -> usize {
+        self.generated.load(Ordering::SeqCst)
+    }
+
+    // The scripted failure, else a drawn one for the `request`th generation of `tokens` tokens
+    fn failure_for(&self, request: u64, tokens: usize) -> Option<(usize, Failure)> {
+        if self.failure.is_some() {
+            return self.failure.clone();
+        }
+        let (rate, seed) = self.error_rate?;
+        let draw = splitmix64(seed ^ request);
+        let roll = (draw >> 11) as f64 / (1u64 << 53) as f64;
+        (roll < rate).then(|| {
+            let at = (draw % (tokens as u64 + 1)) as usize;
+            (at, Failure::Error("mock engine: injected failure".to_string()))
+        })
+    }
+
+    fn reply(&self, request: InferenceRequest) -> Vec<Token> {
+        match &self.tokens {
+            Some(tokens) => tokens.iter().cloned().map(Token::from).collect(),
+            None => vec![
+                "hello".into(),
+                " ".into(),
+                request.prompt.into(),
+                "\n".into(),
+                "done".into(),
+            ],
+        }
+    }
+}
+
+impl Default for MockEngine {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 #[async_trait]
 impl InferenceEngine for MockEngine {
     async fn get_available_models(&self) -> Vec<String> {
-        vec!["mock-model".to_string()]
-    }
-
-    async fn run_streaming_inference(&self, request: InferenceRequest) -> AnyResult<TokenStream> {
-        let replies: Vec<String> = vec![
-            "hello".to_string(),
-            " ".to_string(),
-            request.prompt.clone(),
-            "\n".to_string(),
-            "done".to_string(),
-        ];
-        let s = stream::iter(replies.into_iter().map(|s| Ok(s)));
+        self.models.clone()
+    }
+
+    async fn cached_models(&self) -> Vec<String> {
+        let evicted = self.evicted.lock().unwrap();
+        self.models.iter().filter(|m| !evicted.contains(*m)).cloned().collect()
+    }
+
+    /// Every model counts as loaded on the CPU since the engine was created, until it is evicted;
+    /// there are no weights
+    async fn loaded_model(&self, model: &str) -> Option<LoadedModel> {
+        let loaded = self.models.iter().any(|m| m == model) && !self.evicted.lock().unwrap().contains(model);
+        loaded.then(|| LoadedModel {
+            device: "cpu".to_string(),
+            loaded_at: self.created_at,
+            load_seconds: 0.0,
+            quantization: None,
+            parameters: None,
+            memory_bytes: None,
+            standby: Vec::new(),
+        })
+    }
+
+    async fn cache_entries(&self) -> Vec<CachedModel> {
+        self.cached_models()
+            .await
+            .into_iter()
+            .map(|model| CachedModel {
+                model,
+                devices: vec!["cpu".to_string()],
+                loaded_at: self.created_at,
+                memory_bytes: None,
+                last_used: self.created_at,
+                active_generations: 0,
+            })
+            .collect()
+    }
+
+    async fn evict_model(&self, model: &str) -> Result<bool, EngineError> {
+        if !self.models.iter().any(|m| m == model) {
+            return Err(EngineError::ModelNotFound(model.to_string()));
+        }
+        Ok(self.evicted.lock().unwrap().insert(model.to_string()))
+    }
+
+    async fn run_streaming_inference(
+        &self,
+        request: InferenceRequest,
+    ) -> Result<TokenStream, EngineError> {
+        // A generation loads an evicted model again
+        self.evicted.lock().unwrap().remove(&request.model_name);
+        if !self.load_delay.is_zero() {
+            tokio::time::sleep(self.load_delay).await;
+        }
+        if let Some(message) = &self.load_error {
+            return Err(EngineError::Backend(anyhow!("{}", message)));
+        }
+
+        let prefill = match request.prefill.clone() {
+            Some(sender) => {
+                let total = self.render_prompt(&request).await.map_or(0, |r| r.prompt_tokens);
+                Some((sender, total))
+            }
+            None => None,
+        };
+        let replies = self.reply(request);
+        let delay = self.token_delay;
+        let first_delay = self.first_token_delay.unwrap_or(delay);
+        let failure = self.failure_for(self.requests.fetch_add(1, Ordering::SeqCst), replies.len());
+        let fail_at = failure.as_ref().map_or(usize::MAX, |(at, _)| *at);
+        let generated = self.generated.clone();
+        let s = async_stream::stream! {
+            let mut prefill = prefill;
+            for (index, token) in replies.into_iter().take(fail_at).enumerate() {
+                let delay = if index == 0 { first_delay } else { delay };
+                // The wait for the first token stands in for reading the prompt
+                if let Some((sender, total)) = prefill.take() {
+                    for step in 0..PREFILL_STEPS {
+                        let _ = sender.send(Prefill { processed: total * step / PREFILL_STEPS, total });
+                        tokio::time::sleep(delay / PREFILL_STEPS as u32).await;
+                    }
+                    let _ = sender.send(Prefill { processed: total, total });
+                } else if !delay.is_zero() {
+                    tokio::time::sleep(delay).await;
+                }
+                generated.fetch_add(1, Ordering::SeqCst);
+                yield Ok(token);
+            }
+            match failure {
+                Some((_, Failure::Error(message))) => yield Err(anyhow!("{}", message)),
+                Some((_, Failure::Panic(message))) => panic!("{}", message),
+                None => {}
+            }
+        };
         let boxed: TokenStream = Box::pin(s);
         Ok(boxed)
     }
+
+    fn continues_messages(&self) -> bool {
+        true
+    }
+
+    /// `<|role|>` headed turns ending in an open assistant turn (the last message itself when it
+    /// is continued), one token per word, and `</s>` as the end-of-sequence token
+    async fn render_prompt(&self, request: &InferenceRequest) -> Result<RenderedPrompt, EngineError> {
+        if !self.models.contains(&request.model_name) {
+            return Err(EngineError::ModelNotFound(request.model_name.clone()));
+        }
+        if let Some(message) = &self.load_error {
+            return Err(EngineError::Backend(anyhow!("{}", message)));
+        }
+        let mut turns: Vec<(&str, &str)> = match &request.messages {
+            Some(messages) => messages.iter().map(|m| (m.role.as_str(), m.content.as_str())).collect(),
+            None => vec![("user", request.prompt.as_str())],
+        };
+        let continued = request
+            .messages
+            .as_ref()
+            .and_then(|messages| messages.last())
+            .filter(|m| m.continues)
+            .map(|m| m.content.as_str());
+        if continued.is_some() {
+            turns.pop();
+        }
+        let mut prompt = String::new();
+        for (role, content) in turns {
+            prompt.push_str(&format!("<|{}|>\n{}\n", role, content));
+        }
+        prompt.push_str("<|assistant|>\n");
+        prompt.push_str(continued.unwrap_or_default());
+        let mut stop = request.stop.clone();
+        stop.push("</s>".to_string());
+        Ok(RenderedPrompt {
+            prompt_tokens: prompt.split_whitespace().count(),
+            prompt,
+            stop,
+        })
+    }
+
+    /// One id per whitespace-separated word, hashed into a 32000 token vocabulary; BOS is 1
+    async fn tokenize(&self, model: &str, text: &str, add_special_tokens: bool) -> Result<Vec<u32>, EngineError> {
+        if !self.models.iter().any(|m| m == model) {
+            return Err(EngineError::ModelNotFound(model.to_string()));
+        }
+        if let Some(message) = &self.load_error {
+            return Err(EngineError::Backend(anyhow!("{}", message)));
+        }
+        let mut ids = Vec::new();
+        if add_special_tokens {
+            ids.push(1);
+        }
+        ids.extend(text.split_whitespace().map(|word| {
+            let mut hasher = DefaultHasher::new();
+            word.hash(&mut hasher);
+            (hasher.finish() % 32000) as u32
+        }));
+        Ok(ids)
+    }
+
+    async fn reload_models(&self, _configs: Vec<ModelConfig>) -> AnyResult<()> {
+        Ok(())
+    }
+
+    /// Hashed bag of lowercase words: prompts sharing most words come out similar
+    async fn embed(&self, _model: &str, text: &str) -> AnyResult<Vec<f32>> {
+        let mut vector = vec![0.0; 64];
+        for word in text
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|w| !w.is_empty())
+        {
+            let mut hasher = DefaultHasher::new();
+            word.to_lowercase().hash(&mut hasher);
+            vector[(hasher.finish() % 64) as usize] += 1.0;
+        }
+        Ok(vector)
+    }
+
+    fn embeds(&self) -> bool {
+        true
+    }
+}
+
+// Well-mixed 64 bits from a counter, so consecutive requests draw independently
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
 }
 
 pub fn boxed(engine: Arc<dyn InferenceEngine>) -> Arc<dyn InferenceEngine> {
     engine
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+
+    fn request(prompt: &str) -> InferenceRequest {
+        serde_json::from_value(serde_json::json!({"model-name": "mock-model", "prompt": prompt}))
+            .unwrap()
+    }
+
+    async fn collect(engine: &MockEngine) -> Vec<Result<String, String>> {
+        let stream = engine.run_streaming_inference(request("hi")).await.unwrap();
+        stream
+            .map(|item| item.map(String::from).map_err(|e| e.to_string()))
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_scripted_tokens_and_error() {
+        assert_eq!(
+            collect(&MockEngine::new()).await,
+            vec![
+                Ok("hello".to_string()),
+                Ok(" ".to_string()),
+                Ok("hi".to_string()),
+                Ok("\n".to_string()),
+                Ok("done".to_string()),
+            ]
+        );
+
+        let engine = MockEngine::new()
+            .with_tokens(["a", "b", "c"])
+            .with_error_after(2, "boom");
+        assert_eq!(
+            collect(&engine).await,
+            vec![
+                Ok("a".to_string()),
+                Ok("b".to_string()),
+                Err("boom".to_string())
+            ]
+        );
+        assert_eq!(engine.tokens_generated(), 2);
+
+        let engine = MockEngine::new().with_load_error("no weights");
+        let err = engine.run_streaming_inference(request("hi")).await.err();
+        assert_eq!(err.unwrap().to_string(), "no weights");
+    }
+
+    #[tokio::test]
+    async fn test_delays_and_panic() {
+        let engine = MockEngine::new()
+            .with_tokens(["a", "b"])
+            .with_load_delay(Duration::from_millis(50))
+            .with_token_delay(Duration::from_millis(30))
+            .with_panic_after(1, "engine crashed");
+        let start = std::time::Instant::now();
+        let mut stream = engine.run_streaming_inference(request("hi")).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(stream.next().await.unwrap().unwrap().as_str(), "a");
+        assert!(start.elapsed() >= Duration::from_millis(80));
+
+        let panicked = tokio::spawn(async move { stream.next().await.map(|_| ()) }).await;
+        assert!(panicked.unwrap_err().is_panic());
+    }
+
+    #[test]
+    fn test_slow_token_rates_are_clamped() {
+        for tokens_per_second in [1e-3, 1e-300, f64::MIN_POSITIVE] {
+            let config = MockConfig { tokens_per_second, ..MockConfig::default() };
+            assert_eq!(MockEngine::from_config(&config).token_delay, MAX_TOKEN_DELAY);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_configured_latency_and_repeatable_failures() {
+        let config = MockConfig {
+            tokens_per_second: 50.0,
+            time_to_first_token_ms: 100,
+            error_rate: 0.0,
+            seed: 0,
+        };
+        let engine = MockEngine::from_config(&config).with_tokens(["a", "b", "c"]);
+        let start = std::time::Instant::now();
+        let mut stream = engine.run_streaming_inference(request("hi")).await.unwrap();
+        stream.next().await.unwrap().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        while stream.next().await.is_some() {}
+        assert!(start.elapsed() >= Duration::from_millis(140));
+
+        let outcomes = |seed| async move {
+            let config = MockConfig { error_rate: 0.5, seed, ..MockConfig::default() };
+            let engine = MockEngine::from_config(&config);
+            let mut outcomes = Vec::new();
+            for _ in 0..32 {
+                outcomes.push(collect(&engine).await);
+            }
+            outcomes
+        };
+        let first = outcomes(7).await;
+        assert_eq!(first, outcomes(7).await);
+        assert_ne!(first, outcomes(8).await);
+        let failed = first.iter().filter(|o| o.last().is_some_and(|t| t.is_err())).count();
+        assert!((4..=28).contains(&failed), "{} of 32 failed", failed);
+    }
+}
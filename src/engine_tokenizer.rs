@@ -0,0 +1,293 @@
+//! Engine for `server.tokenizer_only`.
+//!
+//! Loads each model's tokenizer and chat template but never its weights, so `/tokenize`,
+//! `/debug/render` and `/models` work on a machine with no GPU and little memory: CI, or a prompt
+//! budgeting service built on this crate. The routes refuse generations before they get here.
+//!
+//! A model's `tokenizer.json`, `tokenizer_config.json` (chat template, BOS and EOS tokens) and
+//! `chat_template.jinja` come from its local `path` or its snapshot in the Hugging Face cache,
+//! and are otherwise downloaded from the Hub (set `HF_TOKEN` for gated models). Chat templates are
+//! rendered with minijinja plus the Python string methods Hub templates rely on.
+
+use crate::config::ModelConfig;
+use crate::engine::{EngineError, InferenceEngine, RenderedPrompt, TokenStream};
+use crate::models::InferenceRequest;
+use anyhow::{anyhow, bail, Context, Result as AnyResult};
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use tokenizers::Tokenizer;
+use tokio::sync::Mutex;
+
+/// A model's tokenizer and what its chat template needs
+pub struct ModelTokenizer {
+    pub tokenizer: Tokenizer,
+    pub chat_template: Option<String>,
+    pub bos_token: Option<String>,
+    pub eos_token: Option<String>,
+}
+
+impl ModelTokenizer {
+    /// The files a model would load: its local directory, then the Hugging Face cache, then the
+    /// Hub itself
+    pub async fn load(model: &ModelConfig) -> AnyResult<Self> {
+        if let Some(dir) = crate::weights::model_dir(model.path.as_deref(), &model.name) {
+            return Self::from_dir(&dir);
+        }
+        if let Some(path) = &model.path {
+            bail!("{} is not a directory", path.display());
+        }
+        let tokenizer = hub_file(&model.name, "tokenizer.json").await?;
+        let config = hub_file(&model.name, "tokenizer_config.json").await.ok();
+        let template = hub_file(&model.name, "chat_template.jinja")
+            .await
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok());
+        Self::from_parts(&tokenizer, config.as_deref(), template)
+    }
+
+    /// `tokenizer.json` in `dir`, with `tokenizer_config.json` and `chat_template.jinja` when
+    /// present
+    pub fn from_dir(dir: &Path) -> AnyResult<Self> {
+        let tokenizer = std::fs::read(dir.join("tokenizer.json"))
+            .with_context(|| format!("{} has no tokenizer.json", dir.display()))?;
+        let config = std::fs::read(dir.join("tokenizer_config.json")).ok();
+        let template = std::fs::read_to_string(dir.join("chat_template.jinja")).ok();
+        Self::from_parts(&tokenizer, config.as_deref(), template)
+    }
+
+    fn from_parts(tokenizer: &[u8], config: Option<&[u8]>, template: Option<String>) -> AnyResult<Self> {
+        let tokenizer = Tokenizer::from_bytes(tokenizer).map_err(anyhow::Error::msg)?;
+        let config: Value = config
+            .and_then(|config| serde_json::from_slice(config).ok())
+            .unwrap_or_default();
+        // Special tokens are either plain strings or `{"content": ...}`
+        let special = |name: &str| {
+            let token = &config[name];
+            token
+                .as_str()
+                .or_else(|| token["content"].as_str())
+                .map(str::to_string)
+        };
+        // A list of named templates is used through its `default` one
+        let chat_template = template.or_else(|| match &config["chat_template"] {
+            Value::String(template) => Some(template.clone()),
+            Value::Array(templates) => templates
+                .iter()
+                .find(|t| t["name"] == "default")
+                .or(templates.first())
+                .and_then(|t| t["template"].as_str())
+                .map(str::to_string),
+            _ => None,
+        });
+        Ok(Self {
+            tokenizer,
+            chat_template,
+            bos_token: special("bos_token"),
+            eos_token: special("eos_token"),
+        })
+    }
+
+    /// Token ids of `text`, with the tokenizer's special tokens (e.g. BOS) when asked for
+    pub fn encode(&self, text: &str, add_special_tokens: bool) -> AnyResult<Vec<u32>> {
+        let encoding = self
+            .tokenizer
+            .encode(text, add_special_tokens)
+            .map_err(anyhow::Error::msg)?;
+        Ok(encoding.get_ids().to_vec())
+    }
+
+    /// `(role, content)` turns through the chat template, ending with an open assistant turn
+    pub fn apply_chat_template(&self, turns: &[(String, String)]) -> AnyResult<String> {
+        let template = self
+            .chat_template
+            .as_deref()
+            .context("the tokenizer has no chat template")?;
+        let mut env = minijinja::Environment::new();
+        env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+        env.add_function("raise_exception", |message: String| -> Result<String, minijinja::Error> {
+            Err(minijinja::Error::new(minijinja::ErrorKind::InvalidOperation, message))
+        });
+        let messages: Vec<Value> = turns
+            .iter()
+            .map(|(role, content)| json!({ "role": role, "content": content }))
+            .collect();
+        let prompt = env.render_str(
+            template,
+            minijinja::context! {
+                messages => messages,
+                add_generation_prompt => true,
+                bos_token => self.bos_token.as_deref().unwrap_or_default(),
+                eos_token => self.eos_token.as_deref().unwrap_or_default(),
+            },
+        )?;
+        Ok(prompt)
+    }
+}
+
+// One file of a Hub repo's main revision
+async fn hub_file(repo: &str, file: &str) -> AnyResult<Vec<u8>> {
+    let url = format!("https://huggingface.co/{}/resolve/main/{}", repo, file);
+    let mut request = reqwest::Client::new().get(&url);
+    if let Ok(token) = std::env::var("HF_TOKEN") {
+        request = request.bearer_auth(token);
+    }
+    let body = request
+        .send()
+        .await?
+        .error_for_status()
+        .with_context(|| format!("failed to download {} (set HF_TOKEN for gated models)", url))?
+        .bytes()
+        .await?;
+    Ok(body.to_vec())
+}
+
+/// Tokenizers loaded so far, by model id; each is loaded once on first use
+#[derive(Default)]
+pub struct TokenizerCache(Mutex<HashMap<String, Arc<ModelTokenizer>>>);
+
+impl TokenizerCache {
+    pub async fn get(&self, model: &ModelConfig) -> Result<Arc<ModelTokenizer>, EngineError> {
+        let mut cache = self.0.lock().await;
+        if let Some(tokenizer) = cache.get(&model.id) {
+            return Ok(tokenizer.clone());
+        }
+        let tokenizer = ModelTokenizer::load(model)
+            .await
+            .with_context(|| format!("no tokenizer for {}", model.name))
+            .map_err(EngineError::Backend)?;
+        let tokenizer = Arc::new(tokenizer);
+        cache.insert(model.id.clone(), tokenizer.clone());
+        Ok(tokenizer)
+    }
+
+    /// Forget every tokenizer, e.g. after the model list changed
+    pub async fn clear(&self) {
+        self.0.lock().await.clear();
+    }
+}
+
+/// Tokenizers and chat templates only; every generation fails
+pub struct TokenizerEngine {
+    models: RwLock<Vec<ModelConfig>>,
+    tokenizers: TokenizerCache,
+}
+
+impl TokenizerEngine {
+    pub fn new(models: Vec<ModelConfig>) -> Self {
+        Self {
+            models: RwLock::new(models),
+            tokenizers: TokenizerCache::default(),
+        }
+    }
+
+    fn resolve(&self, model: &str) -> Result<ModelConfig, EngineError> {
+        self.models
+            .read()
+            .unwrap()
+            .iter()
+            .find(|m| m.answers_to(model))
+            .cloned()
+            .ok_or_else(|| EngineError::ModelNotFound(model.to_string()))
+    }
+}
+
+#[async_trait]
+impl InferenceEngine for TokenizerEngine {
+    async fn get_available_models(&self) -> Vec<String> {
+        self.models.read().unwrap().iter().map(|m| m.name.clone()).collect()
+    }
+
+    async fn run_streaming_inference(&self, _request: InferenceRequest) -> Result<TokenStream, EngineError> {
+        Err(EngineError::Backend(anyhow!(
+            "this server loads tokenizers only and cannot generate"
+        )))
+    }
+
+    async fn render_prompt(&self, request: &InferenceRequest) -> Result<RenderedPrompt, EngineError> {
+        let tokenizer = self.tokenizers.get(&self.resolve(&request.model_name)?).await?;
+        let turns: Vec<(String, String)> = match &request.messages {
+            Some(messages) => messages
+                .iter()
+                .map(|m| (m.role.to_lowercase(), m.content.clone()))
+                .collect(),
+            None => vec![("user".to_string(), request.prompt.clone())],
+        };
+        let prompt = tokenizer.apply_chat_template(&turns).map_err(EngineError::Backend)?;
+        // The template already wrote out any BOS token
+        let prompt_tokens = tokenizer.encode(&prompt, false).map_err(EngineError::Backend)?.len();
+        let mut stop = request.stop.clone();
+        stop.extend(tokenizer.eos_token.clone());
+        Ok(RenderedPrompt {
+            prompt,
+            prompt_tokens,
+            stop,
+        })
+    }
+
+    async fn tokenize(&self, model: &str, text: &str, add_special_tokens: bool) -> Result<Vec<u32>, EngineError> {
+        let tokenizer = self.tokenizers.get(&self.resolve(model)?).await?;
+        tokenizer.encode(text, add_special_tokens).map_err(EngineError::Backend)
+    }
+
+    async fn reload_models(&self, configs: Vec<ModelConfig>) -> AnyResult<()> {
+        *self.models.write().unwrap() = configs;
+        self.tokenizers.clear().await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokenizer(template: &str) -> ModelTokenizer {
+        // A word-level vocabulary is enough to build a tokenizer without any files
+        let tokenizer = json!({
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": [],
+            "normalizer": null,
+            "post_processor": null,
+            "decoder": null,
+            "model": { "type": "WordLevel", "vocab": { "<unk>": 0, "hello": 1 }, "unk_token": "<unk>" },
+            "pre_tokenizer": { "type": "Whitespace" }
+        });
+        let config = json!({
+            "chat_template": template,
+            "bos_token": "<s>",
+            "eos_token": { "content": "</s>" }
+        });
+        ModelTokenizer::from_parts(
+            tokenizer.to_string().as_bytes(),
+            Some(config.to_string().as_bytes()),
+            None,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn renders_the_chat_template_with_special_tokens() {
+        let tokenizer = tokenizer(
+            "{{ bos_token }}{% for m in messages %}[{{ m['role'] | upper }}] {{ m.content.strip() }}\n{% endfor %}{% if add_generation_prompt %}[ASSISTANT]{% endif %}",
+        );
+        let turns = [
+            ("system".to_string(), "Be brief.".to_string()),
+            ("user".to_string(), " hello ".to_string()),
+        ];
+        let prompt = tokenizer.apply_chat_template(&turns).unwrap();
+        assert_eq!(prompt, "<s>[SYSTEM] Be brief.\n[USER] hello\n[ASSISTANT]");
+        assert_eq!(tokenizer.eos_token.as_deref(), Some("</s>"));
+        assert_eq!(tokenizer.encode("hello there", false).unwrap(), vec![1, 0]);
+    }
+
+    #[test]
+    fn template_exceptions_are_errors() {
+        let tokenizer = tokenizer("{{ raise_exception('roles must alternate') }}");
+        let error = tokenizer.apply_chat_template(&[]).unwrap_err();
+        assert!(format!("{:#}", error).contains("roles must alternate"));
+    }
+}
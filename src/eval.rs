@@ -0,0 +1,229 @@
+//! Evaluation runs (`POST /eval`).
+//!
+//! A run sends a list of prompts to one model, at most `eval.max_concurrency` at a time, and
+//! returns every output next to its expected answer, with aggregate latency and token figures.
+//! Items come with the request or from a dataset: `<dataset>.jsonl` in `eval.dataset_dir`, one
+//! `{"prompt": ..., "expected": ...}` object per line. Meant for regression-testing model or
+//! sampler changes from CI.
+
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use thiserror::Error;
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, ToSchema)]
+pub struct EvalItem {
+    pub prompt: String,
+    /// Items without an expected answer are run but not scored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expected: Option<String>,
+}
+
+/// How an output is compared with the expected answer; surrounding whitespace never counts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchMode {
+    #[default]
+    Contains,
+    Exact,
+}
+
+impl MatchMode {
+    pub fn matches(self, output: &str, expected: &str) -> bool {
+        match self {
+            MatchMode::Contains => output.contains(expected.trim()),
+            MatchMode::Exact => output.trim() == expected.trim(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EvalResult {
+    pub index: usize,
+    pub prompt: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected: Option<String>,
+    pub output: String,
+    /// `None` for items without an expected answer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub passed: Option<bool>,
+    pub tokens: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finish_reason: Option<&'static str>,
+    pub duration_seconds: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttft_seconds: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LatencyStats {
+    pub mean: f64,
+    pub p50: f64,
+    pub p95: f64,
+    pub max: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EvalSummary {
+    pub items: usize,
+    /// Items with an expected answer that generated without error
+    pub scored: usize,
+    pub passed: usize,
+    pub errors: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pass_rate: Option<f64>,
+    pub total_tokens: u64,
+    /// Time the whole run took, items overlapping
+    pub wall_seconds: f64,
+    /// Per-item generation time, over items that did not fail
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_seconds: Option<LatencyStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mean_ttft_seconds: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tokens_per_second: Option<f64>,
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum EvalError {
+    #[error("send either `items` or `dataset`")]
+    NoItems,
+    #[error("`items` and `dataset` cannot be combined")]
+    BothSources,
+    #[error("{0} items is more than eval.max_items ({1})")]
+    TooManyItems(usize, usize),
+    #[error("datasets are disabled; set eval.dataset_dir")]
+    DatasetsDisabled,
+    #[error("invalid dataset name '{0}'")]
+    InvalidDataset(String),
+    #[error("dataset '{0}' not found")]
+    DatasetNotFound(String),
+    #[error("dataset '{0}' line {1}: {2}")]
+    Malformed(String, usize, String),
+}
+
+/// Dataset names become file names, so keep them to a safe set of characters
+pub fn valid_dataset_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+        && !name.starts_with('.')
+}
+
+/// Read `<name>.jsonl` from `dir`; blank lines are skipped
+pub async fn load_dataset(dir: &Path, name: &str) -> Result<Vec<EvalItem>, EvalError> {
+    if !valid_dataset_name(name) {
+        return Err(EvalError::InvalidDataset(name.to_string()));
+    }
+    let contents = tokio::fs::read_to_string(dir.join(format!("{}.jsonl", name)))
+        .await
+        .map_err(|_| EvalError::DatasetNotFound(name.to_string()))?;
+    parse_dataset(name, &contents)
+}
+
+fn parse_dataset(name: &str, contents: &str) -> Result<Vec<EvalItem>, EvalError> {
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line)
+                .map_err(|e| EvalError::Malformed(name.to_string(), i + 1, e.to_string()))
+        })
+        .collect()
+}
+
+/// Aggregate figures for a finished run
+pub fn summarize(results: &[EvalResult], wall_seconds: f64) -> EvalSummary {
+    let completed: Vec<&EvalResult> = results.iter().filter(|r| r.error.is_none()).collect();
+    let scored = completed.iter().filter(|r| r.passed.is_some()).count();
+    let passed = completed.iter().filter(|r| r.passed == Some(true)).count();
+    let total_tokens = results.iter().map(|r| r.tokens).sum();
+
+    let mut durations: Vec<f64> = completed.iter().map(|r| r.duration_seconds).collect();
+    durations.sort_by(f64::total_cmp);
+    let generation_seconds: f64 = durations.iter().sum();
+    let latency_seconds = (!durations.is_empty()).then(|| LatencyStats {
+        mean: generation_seconds / durations.len() as f64,
+        p50: percentile(&durations, 0.5),
+        p95: percentile(&durations, 0.95),
+        max: durations[durations.len() - 1],
+    });
+    let ttfts: Vec<f64> = completed.iter().filter_map(|r| r.ttft_seconds).collect();
+    let completed_tokens: u64 = completed.iter().map(|r| r.tokens).sum();
+
+    EvalSummary {
+        items: results.len(),
+        scored,
+        passed,
+        errors: results.len() - completed.len(),
+        pass_rate: (scored > 0).then(|| passed as f64 / scored as f64),
+        total_tokens,
+        wall_seconds,
+        latency_seconds,
+        mean_ttft_seconds: (!ttfts.is_empty()).then(|| ttfts.iter().sum::<f64>() / ttfts.len() as f64),
+        tokens_per_second: (generation_seconds > 0.0).then(|| completed_tokens as f64 / generation_seconds),
+    }
+}
+
+// Nearest-rank percentile of sorted, non-empty values
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = (p * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(duration: f64, passed: Option<bool>, error: Option<&str>) -> EvalResult {
+        EvalResult {
+            index: 0,
+            prompt: "q".into(),
+            expected: passed.map(|_| "a".into()),
+            output: "a".into(),
+            passed,
+            tokens: 2,
+            finish_reason: Some("stop"),
+            duration_seconds: duration,
+            ttft_seconds: Some(duration / 2.0),
+            error: error.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn summary_counts_scored_items_and_latency() {
+        let results = vec![
+            result(1.0, Some(true), None),
+            result(3.0, Some(false), None),
+            result(2.0, None, None),
+            result(9.0, None, Some("boom")),
+        ];
+        let summary = summarize(&results, 4.0);
+        assert_eq!((summary.items, summary.scored, summary.passed, summary.errors), (4, 2, 1, 1));
+        assert_eq!(summary.pass_rate, Some(0.5));
+        let latency = summary.latency_seconds.unwrap();
+        assert_eq!((latency.mean, latency.p50, latency.max), (2.0, 2.0, 3.0));
+        assert_eq!(summary.total_tokens, 8);
+        assert_eq!(summary.tokens_per_second, Some(1.0));
+    }
+
+    #[test]
+    fn datasets_are_jsonl_with_safe_names() {
+        let items = parse_dataset("qa", "{\"prompt\":\"2+2\",\"expected\":\"4\"}\n\n{\"prompt\":\"hi\"}\n").unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].expected.as_deref(), Some("4"));
+        assert!(matches!(
+            parse_dataset("qa", "{\"prompt\":\"ok\"}\n{\"prompt\":1}"),
+            Err(EvalError::Malformed(_, 2, _))
+        ));
+        assert!(valid_dataset_name("smoke-v2.1"));
+        assert!(!valid_dataset_name("../secrets"));
+        assert!(!valid_dataset_name(".hidden"));
+        assert!(MatchMode::Contains.matches("The answer is 4.", " 4\n"));
+        assert!(!MatchMode::Exact.matches("The answer is 4.", "4"));
+    }
+}
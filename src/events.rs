@@ -0,0 +1,154 @@
+//! Server-wide lifecycle events (`GET /events`).
+//!
+//! Sessions being created and deleted, generations starting and finishing, and models being
+//! loaded and unloaded are broadcast to every subscriber, so dashboards and sidecars can react
+//! without polling `/sessions` and `/stats`. Nothing is kept for clients that are not connected,
+//! and a subscriber that falls too far behind misses events rather than holding up the server.
+//!
+//! Engines load models lazily and evict them on their own, so model events come from comparing
+//! the engine's cached models with the last known set: after every generation starts, after an
+//! eviction or reload, and when a subscriber connects.
+
+use metrics::increment_counter;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+
+/// Events buffered per subscriber before a slow one starts missing them
+const CHANNEL_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
+    SessionCreated {
+        session_id: String,
+    },
+    SessionDeleted {
+        session_id: String,
+    },
+    GenerationStarted {
+        model: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        session_id: Option<String>,
+    },
+    /// `outcome` is `completed`, `failed`, or `stopped` when the stream was dropped early, e.g.
+    /// because its client went away
+    GenerationFinished {
+        model: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        session_id: Option<String>,
+        tokens: usize,
+        outcome: &'static str,
+    },
+    ModelLoaded {
+        model: String,
+    },
+    ModelUnloaded {
+        model: String,
+    },
+}
+
+impl ServerEvent {
+    /// SSE event name, the same as the `type` field
+    pub fn name(&self) -> &'static str {
+        match self {
+            ServerEvent::SessionCreated { .. } => "session_created",
+            ServerEvent::SessionDeleted { .. } => "session_deleted",
+            ServerEvent::GenerationStarted { .. } => "generation_started",
+            ServerEvent::GenerationFinished { .. } => "generation_finished",
+            ServerEvent::ModelLoaded { .. } => "model_loaded",
+            ServerEvent::ModelUnloaded { .. } => "model_unloaded",
+        }
+    }
+}
+
+pub struct EventBus {
+    sender: broadcast::Sender<ServerEvent>,
+    // Models the engine had cached at the last sync
+    loaded: Mutex<BTreeSet<String>>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Self {
+            sender,
+            loaded: Mutex::new(BTreeSet::new()),
+        }
+    }
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send `event` to every subscriber; dropped when there are none
+    pub fn publish(&self, event: ServerEvent) {
+        if self.has_subscribers() {
+            increment_counter!("server_events_total", "type" => event.name());
+            let _ = self.sender.send(event);
+        }
+    }
+
+    pub fn has_subscribers(&self) -> bool {
+        self.sender.receiver_count() > 0
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Publish `model_loaded` and `model_unloaded` for the difference between `cached` and the
+    /// models cached at the last sync
+    pub fn sync_models(&self, cached: Vec<String>) {
+        let cached: BTreeSet<String> = cached.into_iter().collect();
+        let mut loaded = self.loaded.lock().unwrap();
+        for model in cached.difference(&loaded) {
+            self.publish(ServerEvent::ModelLoaded { model: model.clone() });
+        }
+        for model in loaded.difference(&cached) {
+            self.publish(ServerEvent::ModelUnloaded { model: model.clone() });
+        }
+        *loaded = cached;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn model_events_follow_the_cached_set() {
+        let bus = EventBus::new();
+        let mut events = bus.subscribe();
+
+        bus.sync_models(vec!["qwen".to_string()]);
+        bus.sync_models(vec!["qwen".to_string()]);
+        bus.sync_models(vec!["phi".to_string()]);
+
+        let loaded = |model: &str| ServerEvent::ModelLoaded { model: model.to_string() };
+        assert_eq!(events.try_recv().unwrap(), loaded("qwen"));
+        assert_eq!(events.try_recv().unwrap(), loaded("phi"));
+        assert_eq!(
+            events.try_recv().unwrap(),
+            ServerEvent::ModelUnloaded { model: "qwen".to_string() }
+        );
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn events_serialize_with_their_type() {
+        let event = ServerEvent::GenerationFinished {
+            model: "qwen".to_string(),
+            session_id: None,
+            tokens: 3,
+            outcome: "completed",
+        };
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::json!({"type": "generation_finished", "model": "qwen", "tokens": 3, "outcome": "completed"})
+        );
+    }
+}
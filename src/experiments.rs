@@ -0,0 +1,178 @@
+//! A/B experiments from `[[experiments]]`.
+//!
+//! Each experiment splits the requests for one model between weighted variants that swap in
+//! another model and/or system prompt; whatever the weights leave over is the `control`
+//! variant, served unchanged. Requests with a session are bucketed by session id so a
+//! conversation keeps its variant; others are bucketed at random.
+
+use crate::config::{Config, ExperimentConfig};
+use crate::models::ChatMessage;
+use sha2::{Digest, Sha256};
+
+/// Variant name for the share of traffic no variant claims
+pub const CONTROL: &str = "control";
+
+/// The variant one request was assigned to
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assignment {
+    pub experiment: String,
+    pub variant: String,
+    /// Model to use instead of the requested one
+    pub model: Option<String>,
+    /// System prompt to use instead of the configured or stored one
+    pub system_prompt: Option<String>,
+}
+
+// A configured model's id, looked up by id or name
+fn model_id<'a>(config: &'a Config, alias: &str) -> Option<&'a str> {
+    config.find_model(alias).map(|m| m.id.as_str())
+}
+
+// Stable bucket in 0..100 for a sticky key, random otherwise
+fn bucket(experiment: &ExperimentConfig, sticky_key: Option<&str>) -> u32 {
+    let value = match sticky_key {
+        Some(key) => {
+            let digest = Sha256::digest(format!("{}:{}", experiment.name, key));
+            u64::from_be_bytes(digest[..8].try_into().unwrap())
+        }
+        None => uuid::Uuid::new_v4().as_u64_pair().0,
+    };
+    (value % 100) as u32
+}
+
+/// Pick a variant for a request to `model`, if an enabled experiment covers it
+pub fn assign(config: &Config, model: &str, sticky_key: Option<&str>) -> Option<Assignment> {
+    let requested = model_id(config, model)?;
+    let experiment = config
+        .experiments
+        .iter()
+        .find(|e| e.enabled && model_id(config, &e.model) == Some(requested))?;
+
+    let mut remaining = bucket(experiment, sticky_key);
+    for variant in &experiment.variants {
+        if remaining < variant.weight {
+            return Some(Assignment {
+                experiment: experiment.name.clone(),
+                variant: variant.name.clone(),
+                model: variant.model.clone(),
+                system_prompt: variant.system_prompt.clone(),
+            });
+        }
+        remaining -= variant.weight;
+    }
+    Some(Assignment {
+        experiment: experiment.name.clone(),
+        variant: CONTROL.to_string(),
+        model: None,
+        system_prompt: None,
+    })
+}
+
+/// Conversation to send with a replaced system prompt: `messages` when the request has them
+/// (e.g. session history), otherwise just `prompt` as the user turn
+pub fn with_system_prompt(
+    messages: Option<Vec<ChatMessage>>,
+    prompt: &str,
+    system_prompt: &str,
+) -> Vec<ChatMessage> {
+    let mut messages = messages.unwrap_or_else(|| {
+        vec![ChatMessage {
+            role: "user".to_string(),
+            content: prompt.to_string(),
+            truncated: false,
+            partial: false,
+            timestamp: None,
+            continues: false,
+        }]
+    });
+    messages.retain(|m| m.role != "system");
+    messages.insert(
+        0,
+        ChatMessage {
+            role: "system".to_string(),
+            content: system_prompt.to_string(),
+            truncated: false,
+            partial: false,
+            timestamp: None,
+            continues: false,
+        },
+    );
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ExperimentVariant;
+
+    fn config(weight: u32) -> Config {
+        let mut config = Config::default();
+        config.experiments.push(ExperimentConfig {
+            name: "phi-vs-qwen".to_string(),
+            model: "qwen".to_string(),
+            enabled: true,
+            variants: vec![ExperimentVariant {
+                name: "phi".to_string(),
+                weight,
+                model: Some("phi".to_string()),
+                system_prompt: None,
+            }],
+        });
+        config
+    }
+
+    #[test]
+    fn test_assignment_follows_weights() {
+        let all = config(100);
+        let assignment = assign(&all, "Qwen/Qwen2.5-0.5B-Instruct", None).unwrap();
+        assert_eq!(assignment.variant, "phi");
+        assert_eq!(assignment.model.as_deref(), Some("phi"));
+        assert_eq!(assign(&all, "phi", None), None);
+
+        let half = config(50);
+        let variants: Vec<String> = (0..200)
+            .map(|i| assign(&half, "qwen", Some(&i.to_string())).unwrap().variant)
+            .collect();
+        let phi = variants.iter().filter(|v| *v == "phi").count();
+        assert!((60..140).contains(&phi), "{} of 200 assigned to phi", phi);
+        assert!(variants.iter().any(|v| v == CONTROL));
+    }
+
+    #[test]
+    fn test_sessions_keep_their_variant() {
+        let config = config(50);
+        let first = assign(&config, "qwen", Some("session-1"));
+        for _ in 0..20 {
+            assert_eq!(assign(&config, "qwen", Some("session-1")), first);
+        }
+    }
+
+    #[test]
+    fn test_system_prompt_replaces_existing() {
+        let history = vec![
+            ChatMessage {
+                role: "system".to_string(),
+                content: "old".to_string(),
+                truncated: false,
+                partial: false,
+                timestamp: None,
+                continues: false,
+            },
+            ChatMessage {
+                role: "user".to_string(),
+                content: "hi".to_string(),
+                truncated: false,
+                partial: false,
+                timestamp: None,
+                continues: false,
+            },
+        ];
+        let messages = with_system_prompt(Some(history), "hi", "new");
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].content, "new");
+
+        let messages = with_system_prompt(None, "hello", "new");
+        assert_eq!(messages[1].role, "user");
+        assert_eq!(messages[1].content, "hello");
+    }
+}
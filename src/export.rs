@@ -0,0 +1,231 @@
+//! Conversation export (`GET /chat/history/:id/export`).
+//!
+//! Renders a session as Markdown or as a standalone HTML page for sharing or archiving, with
+//! each turn's role and time. Message text goes out as written, so fenced code blocks survive in
+//! Markdown and become `<pre><code>` blocks in HTML; everything else in HTML is escaped. A fence
+//! left open at the end of a message is closed there in both formats.
+
+use crate::models::ChatMessage;
+use serde::Deserialize;
+use std::fmt::Write;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    #[serde(alias = "markdown")]
+    Md,
+    Html,
+}
+
+impl ExportFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ExportFormat::Md => "md",
+            ExportFormat::Html => "html",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Md => "text/markdown; charset=utf-8",
+            ExportFormat::Html => "text/html; charset=utf-8",
+        }
+    }
+
+    pub fn render(self, session_id: &str, history: &[ChatMessage]) -> String {
+        match self {
+            ExportFormat::Md => to_markdown(session_id, history),
+            ExportFormat::Html => to_html(session_id, history),
+        }
+    }
+}
+
+fn role_title(role: &str) -> String {
+    let mut chars = role.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+// "User · 2026-10-16 09:12 UTC (interrupted)"
+fn heading(message: &ChatMessage) -> String {
+    let mut heading = role_title(&message.role);
+    if let Some(timestamp) = message.timestamp {
+        let _ = write!(heading, " · {}", timestamp.format("%Y-%m-%d %H:%M UTC"));
+    }
+    if message.truncated {
+        heading.push_str(" (stopped)");
+    } else if message.partial {
+        heading.push_str(" (interrupted)");
+    }
+    heading
+}
+
+pub fn to_markdown(session_id: &str, history: &[ChatMessage]) -> String {
+    let mut out = format!("# Conversation {}\n", session_id);
+    for message in history {
+        let content = message.content.trim_end();
+        let _ = write!(out, "\n### {}\n\n{}\n", heading(message), content);
+        // Close a fence the message left open (e.g. a cut-off answer), as the HTML export does,
+        // so it does not swallow the turns after it
+        if content.lines().filter(|line| line.trim_start().starts_with("```")).count() % 2 == 1 {
+            out.push_str("```\n");
+        }
+    }
+    out
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Paragraphs of escaped text with inline `code`, line breaks kept
+fn push_text(out: &mut String, text: &str) {
+    for paragraph in text.split("\n\n").map(str::trim).filter(|p| !p.is_empty()) {
+        out.push_str("<p>");
+        for (i, part) in paragraph.split('`').enumerate() {
+            // Odd parts sit between backticks; an unmatched backtick leaves the rest as text
+            if i % 2 == 1 && paragraph.matches('`').count() % 2 == 0 {
+                let _ = write!(out, "<code>{}</code>", escape_html(part));
+            } else {
+                if i > 0 && paragraph.matches('`').count() % 2 == 1 {
+                    out.push('`');
+                }
+                out.push_str(&escape_html(part).replace('\n', "<br>\n"));
+            }
+        }
+        out.push_str("</p>\n");
+    }
+}
+
+// Message body with ``` fences turned into code blocks; an unclosed fence runs to the end
+fn push_body(out: &mut String, content: &str) {
+    let mut text = String::new();
+    let mut code: Option<(String, String)> = None;
+    for line in content.lines() {
+        match (line.trim_start().strip_prefix("```"), code.as_mut()) {
+            (Some(_), Some(_)) => push_code(out, code.take()),
+            (Some(language), None) => {
+                push_text(out, &text);
+                text.clear();
+                code = Some((language.trim().to_string(), String::new()));
+            }
+            (None, Some((_, body))) => {
+                body.push_str(line);
+                body.push('\n');
+            }
+            (None, None) => {
+                text.push_str(line);
+                text.push('\n');
+            }
+        }
+    }
+    push_code(out, code);
+    push_text(out, &text);
+}
+
+fn push_code(out: &mut String, code: Option<(String, String)>) {
+    let Some((language, body)) = code else {
+        return;
+    };
+    if language.is_empty() {
+        out.push_str("<pre><code>");
+    } else {
+        let _ = write!(out, "<pre><code class=\"language-{}\">", escape_html(&language));
+    }
+    out.push_str(&escape_html(&body));
+    out.push_str("</code></pre>\n");
+}
+
+pub fn to_html(session_id: &str, history: &[ChatMessage]) -> String {
+    let title = escape_html(&format!("Conversation {}", session_id));
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n\
+         <style>\n\
+         body {{ font-family: system-ui, sans-serif; max-width: 48rem; margin: 2rem auto; padding: 0 1rem; line-height: 1.5; }}\n\
+         section {{ border-left: 4px solid #ccc; padding: 0 1rem; margin: 1.5rem 0; }}\n\
+         section.user {{ border-color: #2f6fde; }}\n\
+         section.assistant {{ border-color: #2a9d55; }}\n\
+         h2 {{ font-size: 0.9rem; color: #555; }}\n\
+         pre {{ background: #f4f4f4; padding: 0.75rem; overflow-x: auto; }}\n\
+         </style>\n</head>\n<body>\n<h1>{title}</h1>\n"
+    );
+    for message in history {
+        let _ = writeln!(out, "<section class=\"{}\">", escape_html(&message.role));
+        let _ = writeln!(out, "<h2>{}</h2>", escape_html(&heading(message)));
+        push_body(&mut out, &message.content);
+        out.push_str("</section>\n");
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn history() -> Vec<ChatMessage> {
+        let message = |role: &str, content: &str| ChatMessage {
+            role: role.to_string(),
+            content: content.to_string(),
+            truncated: false,
+            partial: false,
+            timestamp: Some(chrono::Utc.with_ymd_and_hms(2026, 10, 16, 9, 12, 0).unwrap()),
+            continues: false,
+        };
+        vec![
+            message("user", "Sort <a> & `b`?"),
+            ChatMessage {
+                partial: true,
+                ..message("assistant", "Like this:\n\n```rust\nv.sort();\nlet x = a < b;\n```\nDone.")
+            },
+        ]
+    }
+
+    #[test]
+    fn markdown_keeps_content_verbatim() {
+        let md = to_markdown("s1", &history());
+        assert!(md.starts_with("# Conversation s1\n"));
+        assert!(md.contains("### User · 2026-10-16 09:12 UTC\n\nSort <a> & `b`?\n"));
+        assert!(md.contains("### Assistant · 2026-10-16 09:12 UTC (interrupted)"));
+        assert!(md.contains("```rust\nv.sort();\nlet x = a < b;\n```"));
+    }
+
+    #[test]
+    fn markdown_closes_unclosed_fences() {
+        let mut history = history();
+        history[1].content = "Like this:\n\n```rust\nv.sort();".to_string();
+        history.push(ChatMessage {
+            role: "user".to_string(),
+            content: "Thanks".to_string(),
+            ..history[0].clone()
+        });
+
+        let md = to_markdown("s1", &history);
+        assert!(md.contains("```rust\nv.sort();\n```\n\n### User"));
+        assert_eq!(md.matches("```").count(), 2);
+    }
+
+    #[test]
+    fn html_escapes_text_and_renders_code_blocks() {
+        let html = to_html("s1", &history());
+        assert!(html.contains("<p>Sort &lt;a&gt; &amp; <code>b</code>?</p>"));
+        assert!(html.contains("<pre><code class=\"language-rust\">v.sort();\nlet x = a &lt; b;\n</code></pre>"));
+        assert!(html.contains("<p>Done.</p>"));
+        assert!(!html.contains("<a>"));
+    }
+}
@@ -0,0 +1,167 @@
+//! Federation: requests for models this server does not host are forwarded to peer instances
+//! (`[[federation.peers]]`), so a few servers with different models can sit behind one
+//! endpoint. Which peer serves which model comes from the peers' own `/models` listings,
+//! fetched every `federation.refresh_seconds`.
+//!
+//! Everything sent to a peer carries [`FORWARDED_HEADER`]. A server answers such requests with
+//! its own models only: `/models` leaves out its peers' models and a request for a model it
+//! would forward again is refused, so servers listing each other cannot pass a request around.
+
+use crate::config::PeerConfig;
+use crate::models::ModelsList;
+use crate::protocol::{self, Protocol};
+use axum::body::StreamBody;
+use axum::http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use metrics::{gauge, increment_counter};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+use std::time::Duration;
+use tracing::warn;
+
+// Headers describing the connection rather than the response; hyper sets its own
+const HOP_BY_HOP: &[&str] = &["connection", "content-length", "keep-alive", "transfer-encoding"];
+
+/// Marks requests sent by a federation peer
+pub const FORWARDED_HEADER: &str = "x-federation-forwarded";
+
+/// Whether a request came from a federation peer
+pub fn is_forwarded(headers: &HeaderMap) -> bool {
+    headers.contains_key(FORWARDED_HEADER)
+}
+
+pub struct Federation {
+    http: reqwest::Client,
+    // Model -> peer serving it, as of the last refresh
+    routes: RwLock<BTreeMap<String, PeerConfig>>,
+}
+
+impl Federation {
+    pub fn new() -> Self {
+        let http = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(5))
+            .build()
+            .unwrap_or_default();
+        Self {
+            http,
+            routes: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    /// Peer that listed `model` at the last refresh. The first peer wins when several do.
+    pub fn peer_for(&self, model: &str) -> Option<PeerConfig> {
+        self.routes.read().unwrap().get(model).cloned()
+    }
+
+    /// Models offered by peers
+    pub fn models(&self) -> Vec<String> {
+        self.routes.read().unwrap().keys().cloned().collect()
+    }
+
+    /// Fetch every peer's model list and rebuild the routing table. Models of peers that cannot
+    /// be reached are dropped until they answer again.
+    pub async fn refresh(&self, peers: &[PeerConfig]) {
+        let mut routes = BTreeMap::new();
+        for peer in peers {
+            match self.fetch_models(peer).await {
+                Ok(models) => {
+                    gauge!("federation_peer_up", 1.0, "peer" => peer.url.clone());
+                    for model in models {
+                        routes.entry(model).or_insert_with(|| peer.clone());
+                    }
+                }
+                Err(e) => {
+                    gauge!("federation_peer_up", 0.0, "peer" => peer.url.clone());
+                    warn!("⚠️ Federation peer {} is unavailable: {}", peer.url, e);
+                }
+            }
+        }
+        *self.routes.write().unwrap() = routes;
+    }
+
+    async fn fetch_models(&self, peer: &PeerConfig) -> reqwest::Result<Vec<String>> {
+        let list: ModelsList = self
+            .request(peer, reqwest::Method::GET, "/models")
+            .timeout(Duration::from_secs(10))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(list.models)
+    }
+
+    fn request(&self, peer: &PeerConfig, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        let url = format!("{}{}", peer.url.trim_end_matches('/'), path);
+        let request = self.http.request(method, url).header(FORWARDED_HEADER, "1");
+        match &peer.api_key {
+            Some(key) => request.bearer_auth(key),
+            None => request,
+        }
+    }
+
+    /// POST `body` to `path` on `peer` and relay the answer: status, headers and a body that
+    /// streams as the peer produces it. An unreachable peer is a `502`.
+    pub async fn forward(&self, peer: &PeerConfig, path: &str, body: &impl Serialize, protocol: Protocol) -> Response {
+        increment_counter!("federation_requests_total", "peer" => peer.url.clone(), "path" => path.to_string());
+        let upstream = match self
+            .request(peer, reqwest::Method::POST, path)
+            .header(protocol::HEADER, protocol.version())
+            .json(body)
+            .send()
+            .await
+        {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                increment_counter!("federation_errors_total", "peer" => peer.url.clone());
+                warn!("⚠️ Forwarding {} to {} failed: {}", path, peer.url, e);
+                let body = Json(json!({
+                    "error": format!("peer {} is unavailable", peer.url),
+                    "code": "peer_unavailable",
+                }));
+                return (StatusCode::BAD_GATEWAY, body).into_response();
+            }
+        };
+
+        let status = StatusCode::from_u16(upstream.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
+        let mut headers = Vec::new();
+        for (name, value) in upstream.headers() {
+            if HOP_BY_HOP.contains(&name.as_str()) {
+                continue;
+            }
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_str().as_bytes()),
+                HeaderValue::from_bytes(value.as_bytes()),
+            ) {
+                headers.push((name, value));
+            }
+        }
+
+        let mut upstream = upstream;
+        let body = async_stream::stream! {
+            loop {
+                match upstream.chunk().await {
+                    Ok(Some(chunk)) => yield Ok::<_, std::io::Error>(chunk),
+                    Ok(None) => break,
+                    Err(e) => {
+                        yield Err(std::io::Error::other(e));
+                        break;
+                    }
+                }
+            }
+        };
+        let mut response = StreamBody::new(body).into_response();
+        *response.status_mut() = status;
+        response.headers_mut().extend(headers);
+        response
+    }
+}
+
+impl Default for Federation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
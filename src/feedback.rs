@@ -0,0 +1,133 @@
+//! Thumbs up/down feedback on chat replies.
+//!
+//! Feedback is posted against an assistant message in a session's history and stored in the
+//! session database together with the conversation up to that message, so an export stays a
+//! complete example even after the session is rolled back or continued. Rating the same message
+//! again replaces the earlier feedback; deleting the session deletes its feedback.
+
+use crate::models::ChatMessage;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use utoipa::ToSchema;
+
+/// Longest accepted feedback comment, in characters
+pub const MAX_COMMENT_LENGTH: usize = 2000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Rating {
+    Up,
+    Down,
+}
+
+impl Rating {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Rating::Up => "up",
+            Rating::Down => "down",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct MessageFeedback {
+    pub session_id: String,
+    pub message_index: usize,
+    pub rating: Rating,
+    #[serde(default)]
+    pub comment: Option<String>,
+    /// The conversation up to and including the rated message
+    pub messages: Vec<ChatMessage>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum FeedbackError {
+    #[error("session '{0}' not found")]
+    SessionNotFound(String),
+    #[error("session has {len} messages; there is no message {index}")]
+    MessageNotFound { index: usize, len: usize },
+    #[error("message {0} is a '{1}' message; only assistant replies can be rated")]
+    NotAssistant(usize, String),
+    #[error("comment is longer than {} characters", MAX_COMMENT_LENGTH)]
+    CommentTooLong,
+}
+
+/// Build the feedback record for `history[index]`
+pub fn for_message(
+    session_id: &str,
+    history: &[ChatMessage],
+    index: usize,
+    rating: Rating,
+    comment: Option<String>,
+) -> Result<MessageFeedback, FeedbackError> {
+    let message = history.get(index).ok_or(FeedbackError::MessageNotFound {
+        index,
+        len: history.len(),
+    })?;
+    if message.role != "assistant" {
+        return Err(FeedbackError::NotAssistant(index, message.role.clone()));
+    }
+    // Blank comments are the same as none
+    let comment = comment.filter(|c| !c.trim().is_empty());
+    if comment
+        .as_ref()
+        .is_some_and(|c| c.chars().count() > MAX_COMMENT_LENGTH)
+    {
+        return Err(FeedbackError::CommentTooLong);
+    }
+    Ok(MessageFeedback {
+        session_id: session_id.to_string(),
+        message_index: index,
+        rating,
+        comment,
+        messages: history[..=index].to_vec(),
+        created_at: Utc::now(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: &str, content: &str) -> ChatMessage {
+        ChatMessage {
+            role: role.to_string(),
+            content: content.to_string(),
+            truncated: false,
+            partial: false,
+            timestamp: None,
+            continues: false,
+        }
+    }
+
+    #[test]
+    fn test_feedback_keeps_conversation_prefix() {
+        let history = vec![
+            message("system", "Be brief."),
+            message("user", "Hi"),
+            message("assistant", "Hello!"),
+            message("user", "Bye"),
+            message("assistant", "Goodbye!"),
+        ];
+        let feedback = for_message("s1", &history, 2, Rating::Up, Some("  ".to_string())).unwrap();
+        assert_eq!(feedback.messages.len(), 3);
+        assert_eq!(feedback.messages[2].content, "Hello!");
+        assert_eq!(feedback.comment, None);
+
+        assert_eq!(
+            for_message("s1", &history, 1, Rating::Down, None),
+            Err(FeedbackError::NotAssistant(1, "user".to_string()))
+        );
+        assert_eq!(
+            for_message("s1", &history, 5, Rating::Down, None),
+            Err(FeedbackError::MessageNotFound { index: 5, len: 5 })
+        );
+        let long = "x".repeat(MAX_COMMENT_LENGTH + 1);
+        assert_eq!(
+            for_message("s1", &history, 4, Rating::Down, Some(long)),
+            Err(FeedbackError::CommentTooLong)
+        );
+    }
+}
@@ -0,0 +1,55 @@
+//! Web UI assets. With the `embed-frontend` feature the contents of `frontend/dist` at build time
+//! are compiled into the binary, so a lone executable still serves the UI; paths that are not
+//! embedded (or every path, without the feature) are served from `frontend/dist` on disk.
+
+use axum::body::{boxed, Body};
+use axum::http::Request;
+use axum::response::Response;
+use tower::ServiceExt;
+use tower_http::services::ServeDir;
+
+/// Directory the UI is built into and served from when not embedded
+pub const DIST_DIR: &str = "frontend/dist";
+
+#[cfg(feature = "embed-frontend")]
+#[derive(rust_embed::RustEmbed)]
+#[folder = "frontend/dist/"]
+#[allow_missing = true]
+struct Assets;
+
+/// Number of embedded files; 0 when built without the feature or without a built UI
+pub fn embedded_files() -> usize {
+    #[cfg(feature = "embed-frontend")]
+    return Assets::iter().count();
+    #[cfg(not(feature = "embed-frontend"))]
+    0
+}
+
+/// Fallback handler for everything the API routes do not match
+pub async fn serve(request: Request<Body>) -> Response {
+    #[cfg(feature = "embed-frontend")]
+    if let Some(response) = embedded(request.uri().path()) {
+        return response;
+    }
+    match ServeDir::new(DIST_DIR).oneshot(request).await {
+        Ok(response) => response.map(boxed),
+        // `ServeDir` reports missing files as responses; its error type is `Infallible`
+        Err(never) => match never {},
+    }
+}
+
+#[cfg(feature = "embed-frontend")]
+fn embedded(path: &str) -> Option<Response> {
+    use axum::http::header;
+    use axum::response::IntoResponse;
+
+    let path = path.trim_start_matches('/');
+    let path = match path {
+        "" => "index.html".to_string(),
+        dir if dir.ends_with('/') => format!("{}index.html", dir),
+        file => file.to_string(),
+    };
+    let file = Assets::get(&path)?;
+    let headers = [(header::CONTENT_TYPE, file.metadata.mimetype().to_string())];
+    Some((headers, file.data.into_owned()).into_response())
+}
@@ -0,0 +1,91 @@
+//! Background GPU telemetry for Prometheus.
+//!
+//! With the `cuda` feature, NVML is polled for every visible device and published as
+//! `gpu_memory_used_bytes`, `gpu_memory_total_bytes`, `gpu_utilization_percent` and
+//! `gpu_temperature_celsius`, each labelled `device="cuda:<index>"`. Other builds (including
+//! Metal, which has no equivalent public counters) publish nothing.
+
+use std::time::Duration;
+
+/// Start polling GPU stats every `interval` on a dedicated thread (NVML calls block).
+/// Returns `false` when no collector could be started.
+pub fn spawn_collector(interval: Duration) -> bool {
+    imp::spawn_collector(interval)
+}
+
+#[cfg(feature = "cuda")]
+mod imp {
+    use metrics::gauge;
+    use nvml_wrapper::enum_wrappers::device::TemperatureSensor;
+    use nvml_wrapper::Nvml;
+    use std::time::Duration;
+
+    pub fn spawn_collector(interval: Duration) -> bool {
+        let nvml = match Nvml::init() {
+            Ok(nvml) => nvml,
+            Err(e) => {
+                tracing::warn!("⚠️ NVML unavailable, GPU metrics disabled: {}", e);
+                return false;
+            }
+        };
+
+        let spawned = std::thread::Builder::new()
+            .name("gpu-metrics".to_string())
+            .spawn(move || loop {
+                collect(&nvml);
+                std::thread::sleep(interval);
+            });
+        match spawned {
+            Ok(_) => {
+                tracing::info!("📈 GPU metrics collector started (every {:?})", interval);
+                true
+            }
+            Err(e) => {
+                tracing::warn!("⚠️ Failed to start GPU metrics collector: {}", e);
+                false
+            }
+        }
+    }
+
+    fn collect(nvml: &Nvml) {
+        let count = match nvml.device_count() {
+            Ok(count) => count,
+            Err(e) => {
+                tracing::debug!("NVML device_count failed: {}", e);
+                return;
+            }
+        };
+
+        for index in 0..count {
+            let Ok(device) = nvml.device_by_index(index) else {
+                continue;
+            };
+            let label = format!("cuda:{}", index);
+
+            if let Ok(memory) = device.memory_info() {
+                gauge!("gpu_memory_used_bytes", memory.used as f64, "device" => label.clone());
+                gauge!("gpu_memory_total_bytes", memory.total as f64, "device" => label.clone());
+            }
+            if let Ok(utilization) = device.utilization_rates() {
+                gauge!("gpu_utilization_percent", utilization.gpu as f64, "device" => label.clone());
+            }
+            if let Ok(temperature) = device.temperature(TemperatureSensor::Gpu) {
+                gauge!("gpu_temperature_celsius", temperature as f64, "device" => label);
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "cuda"))]
+mod imp {
+    use std::time::Duration;
+
+    pub fn spawn_collector(_interval: Duration) -> bool {
+        if cfg!(feature = "metal") {
+            tracing::info!("GPU metrics are not available on Metal; skipping collector");
+        } else {
+            tracing::debug!("Built without the cuda feature; GPU metrics disabled");
+        }
+        false
+    }
+}
@@ -0,0 +1,287 @@
+//! Sharing of identical in-flight generations.
+//!
+//! When several clients send the exact same sessionless request at the same time (a page that
+//! retries, or a frontend fanning one prompt out), only the first runs on the engine. Later
+//! arrivals attach to it: they are replayed the tokens produced so far and then follow the live
+//! stream. Generation keeps going, holding the generation slot of the request that started it,
+//! while at least one attached client is still reading and stops once the last one goes away.
+//! Requests from different accounts never share. Finished generations are not kept; that is
+//! the response cache's job.
+
+use crate::engine::{EngineError, Token, TokenStream};
+use crate::models::InferenceRequest;
+use crate::state::EnginePanic;
+use anyhow::{anyhow, Result};
+use async_stream::stream;
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use futures_util::StreamExt;
+use sha2::{Digest, Sha256};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+/// Identifies requests that may share a generation: the account that sent them and everything
+/// but the queue priority and whether the answer is streamed. Accounts never share.
+pub fn request_key(account: &str, req: &InferenceRequest) -> String {
+    let mut req = req.clone();
+    req.priority = None;
+    req.stream = true;
+    let body = serde_json::to_vec(&req).expect("InferenceRequest always serializes");
+    let mut hasher = Sha256::new();
+    hasher.update(account.as_bytes());
+    hasher.update([0]);
+    hasher.update(body);
+    hex::encode(hasher.finalize())
+}
+
+type Item = Result<Token, Arc<anyhow::Error>>;
+
+#[derive(Default)]
+struct SharedState {
+    history: Vec<Item>,
+    followers: Vec<mpsc::UnboundedSender<Result<Token>>>,
+    finished: bool,
+}
+
+/// One generation and the clients reading it
+#[derive(Default)]
+pub struct Shared {
+    state: Mutex<SharedState>,
+}
+
+impl Shared {
+    // None once the generation has finished; the caller should start a new one instead
+    fn subscribe(&self) -> Option<TokenStream> {
+        let mut state = self.state.lock().unwrap();
+        if state.finished {
+            return None;
+        }
+        // Unbounded, but never longer than the generation itself (bounded by max tokens)
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        for item in &state.history {
+            let _ = tx.send(replay(item));
+        }
+        state.followers.push(tx);
+        Some(Box::pin(stream! {
+            while let Some(item) = rx.recv().await {
+                yield item;
+            }
+        }))
+    }
+
+    // Returns false, and stops accepting followers, once nobody is reading any more
+    fn publish(&self, item: Result<Token>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let item = item.map_err(Arc::new);
+        state.followers.retain(|tx| tx.send(replay(&item)).is_ok());
+        state.history.push(item);
+        if state.followers.is_empty() {
+            state.finished = true;
+        }
+        !state.finished
+    }
+
+    fn finish(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.finished = true;
+        // Dropping the senders ends every follower's stream
+        state.followers.clear();
+    }
+}
+
+fn replay(item: &Item) -> Result<Token> {
+    match item {
+        Ok(token) => Ok(token.clone()),
+        Err(e) => Err(clone_error(e)),
+    }
+}
+
+/// Rebuild an error for another reader, keeping the types handlers check for
+pub fn clone_error(err: &anyhow::Error) -> anyhow::Error {
+    if let Some(EngineError::Timeout(secs)) = err.downcast_ref::<EngineError>() {
+        EngineError::Timeout(*secs).into()
+    } else if err.is::<EnginePanic>() {
+        EnginePanic.into()
+    } else {
+        anyhow!("{:#}", err)
+    }
+}
+
+/// Result of [`InFlight::claim`]
+pub enum Claim {
+    /// Nothing identical is running: start the engine and feed it to [`InFlight::drive`]
+    Leader(Arc<Shared>, TokenStream),
+    /// Attached to a running generation
+    Follower(TokenStream),
+}
+
+/// Generations currently running, keyed by [`request_key`]
+#[derive(Default)]
+pub struct InFlight {
+    entries: DashMap<String, Arc<Shared>>,
+}
+
+impl InFlight {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Attach to a running generation, if there is one
+    pub fn join(&self, key: &str) -> Option<TokenStream> {
+        let shared = self.entries.get(key)?.clone();
+        shared.subscribe()
+    }
+
+    /// Attach to a running generation, or register a new one that the caller must drive
+    pub fn claim(&self, key: &str) -> Claim {
+        let shared = Arc::new(Shared::default());
+        let stream = shared.subscribe().expect("new generations are open");
+        match self.entries.entry(key.to_string()) {
+            Entry::Occupied(mut entry) => {
+                if let Some(follower) = entry.get().subscribe() {
+                    return Claim::Follower(follower);
+                }
+                entry.insert(shared.clone());
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(shared.clone());
+            }
+        }
+        Claim::Leader(shared, stream)
+    }
+
+    /// Pass an engine stream on to everyone attached to `shared`. Returns when it ends or the
+    /// last reader leaves; dropping `source` then stops the engine.
+    pub async fn drive(&self, key: &str, shared: Arc<Shared>, mut source: TokenStream) {
+        while let Some(item) = source.next().await {
+            if !shared.publish(item) {
+                break;
+            }
+        }
+        self.finish(key, &shared);
+    }
+
+    /// End a generation that failed before producing a stream
+    pub fn fail(&self, key: &str, shared: &Arc<Shared>, err: &anyhow::Error) {
+        shared.publish(Err(clone_error(err)));
+        self.finish(key, shared);
+    }
+
+    fn finish(&self, key: &str, shared: &Arc<Shared>) {
+        shared.finish();
+        self.entries
+            .remove_if(key, |_, current| Arc::ptr_eq(current, shared));
+    }
+
+    /// Number of generations currently shared out
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::stream;
+
+    fn request(prompt: &str) -> InferenceRequest {
+        InferenceRequest {
+            model_name: "mock-model".to_string(),
+            model_dir: None,
+            prompt: prompt.to_string(),
+            messages: None,
+            session_id: None,
+            create_session: false,
+            stream: true,
+            max_token: 16,
+            auto_fit: false,
+            temperature: 0.7,
+            top_p: 0.95,
+            top_k: 10,
+            repeat_penalty: 1.0,
+            stop: vec![],
+            device: "cpu".to_string(),
+            strict_device: false,
+            priority: None,
+            compress_history: false,
+            template: None,
+            variables: Default::default(),
+            persona: None,
+            prefill: None,
+            placement: None,
+            sent: Default::default(),
+        }
+    }
+
+    fn tokens(items: Vec<&'static str>) -> TokenStream {
+        Box::pin(stream::iter(items.into_iter().map(|t| Ok(Token::from(t)))))
+    }
+
+    async fn collect(stream: TokenStream) -> Vec<String> {
+        stream.map(|t| t.unwrap().to_string()).collect().await
+    }
+
+    #[test]
+    fn test_request_key_ignores_priority_and_streaming_only() {
+        let base = request("hi");
+        let mut urgent = base.clone();
+        urgent.priority = Some(5);
+        assert_eq!(request_key("alice", &base), request_key("alice", &urgent));
+        let mut collected = base.clone();
+        collected.stream = false;
+        assert_eq!(request_key("alice", &base), request_key("alice", &collected));
+        assert_ne!(request_key("alice", &base), request_key("alice", &request("hello")));
+        assert_ne!(request_key("alice", &base), request_key("bob", &base));
+    }
+
+    #[tokio::test]
+    async fn test_followers_get_replay_and_live_tokens() {
+        let in_flight = InFlight::new();
+        let (shared, leader) = match in_flight.claim("k") {
+            Claim::Leader(shared, stream) => (shared, stream),
+            Claim::Follower(_) => panic!("nothing was running"),
+        };
+        shared.publish(Ok(Token::from("a")));
+
+        let follower = match in_flight.claim("k") {
+            Claim::Follower(stream) => stream,
+            Claim::Leader(..) => panic!("should attach to the running generation"),
+        };
+        in_flight.drive("k", shared, tokens(vec!["b", "c"])).await;
+
+        assert_eq!(collect(leader).await, vec!["a", "b", "c"]);
+        assert_eq!(collect(follower).await, vec!["a", "b", "c"]);
+        assert!(in_flight.is_empty());
+        assert!(in_flight.join("k").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_errors_keep_their_type() {
+        let in_flight = InFlight::new();
+        let Claim::Leader(shared, leader) = in_flight.claim("k") else {
+            panic!("nothing was running");
+        };
+        let source: TokenStream = Box::pin(stream::iter(vec![Err(EngineError::Timeout(3).into())]));
+        in_flight.drive("k", shared, source).await;
+
+        let items: Vec<_> = leader.collect().await;
+        assert!(EngineError::is_timeout(items[0].as_ref().unwrap_err()));
+    }
+
+    #[tokio::test]
+    async fn test_generation_stops_when_everyone_leaves() {
+        let in_flight = InFlight::new();
+        let Claim::Leader(shared, leader) = in_flight.claim("k") else {
+            panic!("nothing was running");
+        };
+        drop(leader);
+        let source: TokenStream = Box::pin(stream::repeat_with(|| Ok(Token::from("t"))));
+        // Would never return if the endless source kept being polled
+        in_flight.drive("k", shared, source).await;
+        assert!(in_flight.is_empty());
+    }
+}
@@ -0,0 +1,230 @@
+//! Background completion jobs (`callback_url` and `output` on `/completions`).
+//!
+//! A job answers `202` with its id right away and generates in the background. Its status is kept
+//! in memory for `GET /jobs/:id`. With `output: {"type": "file"}` the tokens are appended to
+//! `<jobs.output_dir>/<job_id>.txt` as they arrive, so even huge batch outputs are never held in
+//! memory or in a database row; the finished file is optionally PUT to `jobs.upload_url` (any
+//! S3-compatible endpoint that accepts plain or bearer-authenticated PUTs), and the job status
+//! carries a `download_url`. Finished jobs and their files are deleted after
+//! `jobs.retention_hours`.
+
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use utoipa::ToSchema;
+
+/// Where a job's generated text goes instead of the callback payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JobOutput {
+    File,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Running,
+    Completed,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Job {
+    pub job_id: String,
+    pub status: JobState,
+    pub model: String,
+    /// API key name that started the job; only it (or an admin) may see the job
+    #[serde(skip)]
+    pub account: String,
+    pub created_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<DateTime<Utc>>,
+    pub tokens: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<String>)]
+    pub finish_reason: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Bytes written to the output file so far
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_bytes: Option<u64>,
+    /// Set once a file output is complete: `jobs.upload_url` location or `/jobs/:id/output`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download_url: Option<String>,
+    #[serde(skip)]
+    pub output_path: Option<PathBuf>,
+}
+
+impl Job {
+    pub fn new(job_id: &str, account: &str, model: &str) -> Self {
+        Self {
+            job_id: job_id.to_string(),
+            status: JobState::Running,
+            model: model.to_string(),
+            account: account.to_string(),
+            created_at: Utc::now(),
+            finished_at: None,
+            tokens: 0,
+            finish_reason: None,
+            error: None,
+            output_bytes: None,
+            download_url: None,
+            output_path: None,
+        }
+    }
+}
+
+/// Status of every job since startup (or since its retention ran out)
+#[derive(Default)]
+pub struct JobRegistry {
+    jobs: DashMap<String, Job>,
+}
+
+impl JobRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&self, job: Job) {
+        self.jobs.insert(job.job_id.clone(), job);
+    }
+
+    pub fn get(&self, job_id: &str) -> Option<Job> {
+        self.jobs.get(job_id).map(|job| job.clone())
+    }
+
+    pub fn update(&self, job_id: &str, f: impl FnOnce(&mut Job)) {
+        if let Some(mut job) = self.jobs.get_mut(job_id) {
+            f(&mut job);
+        }
+    }
+
+    /// Forget jobs that finished before `cutoff` and delete their output files
+    pub async fn prune(&self, cutoff: DateTime<Utc>) -> usize {
+        let expired: Vec<Job> = self
+            .jobs
+            .iter()
+            .filter(|job| job.finished_at.is_some_and(|at| at < cutoff))
+            .map(|job| job.clone())
+            .collect();
+        for job in &expired {
+            self.jobs.remove(&job.job_id);
+            if let Some(path) = &job.output_path {
+                if let Err(e) = tokio::fs::remove_file(path).await {
+                    tracing::debug!("Could not delete job output {}: {}", path.display(), e);
+                }
+            }
+        }
+        expired.len()
+    }
+}
+
+/// Output file a job streams its tokens into
+pub struct OutputFile {
+    file: tokio::io::BufWriter<tokio::fs::File>,
+    path: PathBuf,
+    bytes: u64,
+}
+
+impl OutputFile {
+    /// Create `<dir>/<job_id>.txt`, and `dir` if needed
+    pub async fn create(dir: &Path, job_id: &str) -> std::io::Result<Self> {
+        tokio::fs::create_dir_all(dir).await?;
+        let path = dir.join(format!("{}.txt", job_id));
+        let file = tokio::fs::File::create(&path).await?;
+        Ok(Self {
+            file: tokio::io::BufWriter::new(file),
+            path,
+            bytes: 0,
+        })
+    }
+
+    pub async fn write(&mut self, text: &str) -> std::io::Result<()> {
+        self.file.write_all(text.as_bytes()).await?;
+        self.bytes += text.len() as u64;
+        Ok(())
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Flush everything to disk
+    pub async fn finish(mut self) -> std::io::Result<(PathBuf, u64)> {
+        self.file.flush().await?;
+        Ok((self.path, self.bytes))
+    }
+}
+
+/// PUT a finished output file to `<base_url>/<file name>` without reading it into memory and
+/// return where it now lives
+pub async fn upload(
+    client: &reqwest::Client,
+    base_url: &str,
+    token: Option<&str>,
+    path: &Path,
+) -> anyhow::Result<String> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow::anyhow!("output file has no name"))?;
+    let url = format!("{}/{}", base_url.trim_end_matches('/'), name);
+    let file = tokio::fs::File::open(path).await?;
+    let length = file.metadata().await?.len();
+    let body = reqwest::Body::wrap_stream(tokio_util::io::ReaderStream::new(file));
+
+    let mut request = client
+        .put(&url)
+        .header(reqwest::header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .header(reqwest::header::CONTENT_LENGTH, length)
+        .body(body);
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    request.send().await?.error_for_status()?;
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn output_files_are_pruned_with_their_jobs() {
+        let dir = std::env::temp_dir().join(format!("llm_inference_jobs_{}", std::process::id()));
+        let mut output = OutputFile::create(&dir, "job-1").await.unwrap();
+        output.write("hello ").await.unwrap();
+        output.write("world").await.unwrap();
+        let (path, bytes) = output.finish().await.unwrap();
+        assert_eq!(bytes, 11);
+        assert_eq!(tokio::fs::read_to_string(&path).await.unwrap(), "hello world");
+
+        let registry = JobRegistry::new();
+        let mut job = Job::new("job-1", "ci", "qwen");
+        job.status = JobState::Completed;
+        job.finished_at = Some(Utc::now());
+        job.output_path = Some(path.clone());
+        registry.insert(job);
+        registry.insert(Job::new("job-2", "ci", "qwen"));
+
+        assert_eq!(registry.prune(Utc::now() - chrono::Duration::hours(1)).await, 0);
+        assert_eq!(registry.prune(Utc::now() + chrono::Duration::hours(1)).await, 1);
+        assert!(registry.get("job-1").is_none());
+        assert!(registry.get("job-2").is_some());
+        assert!(!path.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn output_is_tagged_by_type() {
+        let output: JobOutput = serde_json::from_value(serde_json::json!({"type": "file"})).unwrap();
+        assert_eq!(output, JobOutput::File);
+        assert!(serde_json::from_value::<JobOutput>(serde_json::json!({"type": "s3"})).is_err());
+    }
+}
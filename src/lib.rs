@@ -6,13 +6,49 @@
 // - Added helper test utilities under tests/ for consistent request construction
 // - Added configuration system with TOML support
 // - Added API key authentication and rate limiting middleware
+pub mod api_error;
+pub mod canary;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+#[cfg(feature = "client")]
+pub mod client;
+pub mod compression;
 pub mod config;
+pub mod debug_trace;
+pub mod device;
 pub mod engine;
 pub mod engine_mock;
+pub mod engine_tokenizer;
+pub mod error_reporting;
+pub mod eval;
+pub mod events;
+pub mod experiments;
+pub mod export;
+pub mod federation;
+pub mod feedback;
+pub mod frontend;
+pub mod gpu_metrics;
+pub mod guardrails;
+pub mod inflight;
+pub mod jobs;
+pub mod listener;
+pub mod metrics_push;
 pub mod middleware;
 pub mod models;
+pub mod observers;
+pub mod protocol;
+pub mod request_log;
+pub mod request_metrics;
+pub mod response_cache;
 pub mod routes;
+pub mod session_stats;
 pub mod state;
+pub mod stats;
+pub mod streaming;
+pub mod templates;
+pub mod transcript;
+pub mod webhook;
+pub mod weights;
 
 #[cfg(test)]
 mod tests {
@@ -31,7 +67,7 @@ mod tests {
         let handle = recorder.handle();
 
         let config = config::Config::default();
-        let state = state::AppState::new(
+        let state = state::AppState::new_in_memory(
             std::sync::Arc::new(engine_mock::MockEngine::new()),
             handle,
             config,
@@ -50,9 +86,6 @@ mod tests {
 
     #[tokio::test]
     async fn test_persistence_flow() {
-        // Clean up previous test file if exists
-        let _ = std::fs::remove_file("sessions.db");
-
         use metrics_exporter_prometheus::PrometheusBuilder;
 
         let builder = PrometheusBuilder::new();
@@ -61,7 +94,7 @@ mod tests {
 
         let engine = std::sync::Arc::new(engine_mock::MockEngine::new());
         let config = config::Config::default();
-        let state = state::AppState::new(engine.clone(), handle.clone(), config.clone())
+        let state = state::AppState::new_in_memory(engine, handle, config)
             .await
             .unwrap();
 
@@ -73,6 +106,10 @@ mod tests {
                 vec![models::ChatMessage {
                     role: "user".to_string(),
                     content: "hello".to_string(),
+                    truncated: false,
+                    partial: false,
+                    timestamp: None,
+                    continues: false,
                 }],
             );
         }
@@ -80,16 +117,52 @@ mod tests {
         // Trigger save
         state.save_sessions().await;
 
-        // Verify file exists
-        assert!(std::path::Path::new("sessions.db").exists());
+        // The session and its message reached the store
+        let stats = state.session_stats(10).await.unwrap();
+        assert_eq!(stats.sessions, 1);
+        assert_eq!(stats.messages, 1);
+        assert_eq!(stats.top_sessions[0].session_id, "test-session");
+    }
+
+    #[tokio::test]
+    async fn test_sessions_stay_in_memory_without_persistence() {
+        use metrics_exporter_prometheus::PrometheusBuilder;
+
+        let handle = PrometheusBuilder::new().build_recorder().handle();
+        let engine = std::sync::Arc::new(engine_mock::MockEngine::new());
+        let mut config = config::Config::default();
+        config.persistence.enabled = false;
+        let state = state::AppState::new(engine.clone(), handle.clone(), config.clone())
+            .await
+            .unwrap();
+        state.sessions.lock().await.insert("ephemeral".to_string(), Vec::new());
+        state.save_sessions().await;
 
-        // Create new state and verify load
+        // A restart starts from nothing
         let state2 = state::AppState::new(engine, handle, config).await.unwrap();
-        let sessions = state2.sessions.lock().await;
-        assert!(sessions.contains_key("test-session"));
-        assert_eq!(sessions.get("test-session").unwrap()[0].content, "hello");
+        assert!(!state2.sessions.lock().await.contains_key("ephemeral"));
+    }
+
+    #[tokio::test]
+    async fn test_old_transcripts_are_pruned() {
+        let store = state::SessionStore::new(state::IN_MEMORY_DB, &Default::default(), None)
+            .await
+            .unwrap();
+        let now = chrono::Utc::now();
+        for (request_id, age_hours) in [("old", 30), ("recent", 1)] {
+            let transcript = transcript::Transcript {
+                request_id: request_id.to_string(),
+                endpoint: "chat".to_string(),
+                started_at: now - chrono::Duration::hours(age_hours),
+                completed: true,
+                chunks: Vec::new(),
+            };
+            store.insert_transcript(&transcript).await.unwrap();
+        }
 
-        // Cleanup
-        let _ = std::fs::remove_file("sessions.db");
+        let pruned = store.prune_transcripts(now - chrono::Duration::hours(24)).await.unwrap();
+        assert_eq!(pruned, 1);
+        assert!(store.load_transcript("old").await.unwrap().is_none());
+        assert!(store.load_transcript("recent").await.unwrap().is_some());
     }
 }
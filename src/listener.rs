@@ -0,0 +1,223 @@
+//! Accepting HTTP connections under the `[server]` connection limits.
+//!
+//! hyper's own listener accepts without bound and keeps idle connections forever. This one waits
+//! for a free slot once `server.max_connections` are open, and closes a connection that has
+//! neither sent nor received a byte for `server.idle_timeout_seconds`. A streaming response
+//! counts as activity with every chunk (and every SSE keep-alive), so hour-long streams stay open
+//! while abandoned sockets behind a proxy are reclaimed.
+
+use crate::config::ServerConfig;
+use metrics::{decrement_gauge, increment_counter, increment_gauge};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{Instant, Sleep};
+
+/// Connection limits from `[server]`; zero disables each
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Limits {
+    pub idle_timeout: Option<Duration>,
+    pub max_connections: Option<usize>,
+}
+
+impl Limits {
+    pub fn from_config(config: &ServerConfig) -> Self {
+        Self {
+            idle_timeout: (config.idle_timeout_seconds > 0)
+                .then(|| Duration::from_secs(config.idle_timeout_seconds)),
+            max_connections: (config.max_connections > 0).then_some(config.max_connections),
+        }
+    }
+}
+
+/// `server.header_read_timeout_seconds` as hyper's header read timeout
+pub fn header_read_timeout(config: &ServerConfig) -> Option<Duration> {
+    (config.header_read_timeout_seconds > 0)
+        .then(|| Duration::from_secs(config.header_read_timeout_seconds))
+}
+
+/// Listen on `addr`, handing out connections within `limits`, for `Server::builder`
+pub async fn bind(
+    addr: SocketAddr,
+    limits: Limits,
+) -> io::Result<impl hyper::server::accept::Accept<Conn = Connection, Error = io::Error>> {
+    let listener = TcpListener::bind(addr).await?;
+    let slots = limits.max_connections.map(|max| Arc::new(Semaphore::new(max)));
+    let connections = async_stream::stream! {
+        loop {
+            let slot = match &slots {
+                Some(slots) => Some(slots.clone().acquire_owned().await.expect("semaphore is never closed")),
+                None => None,
+            };
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let _ = stream.set_nodelay(true);
+                    yield Ok(Connection::new(stream, slot, limits.idle_timeout));
+                }
+                // Running out of file descriptors and the like passes; an error here would stop
+                // the server
+                Err(e) => {
+                    tracing::warn!("⚠️ Failed to accept a connection: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            }
+        }
+    };
+    Ok(hyper::server::accept::from_stream(connections))
+}
+
+/// An accepted connection holding its slot until it closes
+pub struct Connection {
+    stream: TcpStream,
+    idle: Option<(Duration, Pin<Box<Sleep>>)>,
+    _slot: Option<OwnedSemaphorePermit>,
+}
+
+impl Connection {
+    fn new(stream: TcpStream, slot: Option<OwnedSemaphorePermit>, idle_timeout: Option<Duration>) -> Self {
+        increment_gauge!("http_open_connections", 1.0);
+        Self {
+            stream,
+            idle: idle_timeout.map(|timeout| (timeout, Box::pin(tokio::time::sleep(timeout)))),
+            _slot: slot,
+        }
+    }
+
+    fn active(&mut self) {
+        if let Some((timeout, deadline)) = &mut self.idle {
+            deadline.as_mut().reset(Instant::now() + *timeout);
+        }
+    }
+
+    // Called while the socket has nothing to do; fails once it has been quiet too long
+    fn poll_idle(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some((_, deadline)) = &mut self.idle {
+            if deadline.as_mut().poll(cx).is_ready() {
+                increment_counter!("http_idle_connections_closed_total");
+                return Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, "connection idle")));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        decrement_gauge!("http_open_connections", 1.0);
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        match Pin::new(&mut this.stream).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) => {
+                if buf.filled().len() > before {
+                    this.active();
+                }
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => this.poll_idle(cx),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.stream).poll_write(cx, buf) {
+            Poll::Ready(result) => {
+                if matches!(result, Ok(n) if n > 0) {
+                    this.active();
+                }
+                Poll::Ready(result)
+            }
+            // A peer that stops reading is as idle as one that stops writing
+            Poll::Pending => this.poll_idle(cx).map(|result| result.map(|()| 0)),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.stream).poll_write_vectored(cx, bufs) {
+            Poll::Ready(result) => {
+                if matches!(result, Ok(n) if n > 0) {
+                    this.active();
+                }
+                Poll::Ready(result)
+            }
+            Poll::Pending => this.poll_idle(cx).map(|result| result.map(|()| 0)),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn pair(limits: Limits) -> (Connection, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap());
+        let ((server, _), client) = tokio::join!(async { listener.accept().await.unwrap() }, client);
+        (Connection::new(server, None, limits.idle_timeout), client.unwrap())
+    }
+
+    #[tokio::test]
+    async fn quiet_connections_time_out_and_traffic_keeps_them_open() {
+        let limits = Limits {
+            idle_timeout: Some(Duration::from_millis(500)),
+            max_connections: None,
+        };
+        let (mut server, mut client) = pair(limits).await;
+        let mut buf = [0u8; 4];
+
+        // Each pause alone is within the timeout, both together are not
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        client.write_all(b"ping").await.unwrap();
+        server.read_exact(&mut buf).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        server.write_all(b"pong").await.unwrap();
+
+        let started = std::time::Instant::now();
+        let err = server.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() >= Duration::from_millis(400));
+    }
+
+    #[test]
+    fn zero_disables_each_limit() {
+        let mut config = crate::config::Config::default().server;
+        assert_eq!(Limits::from_config(&config), Limits::default());
+        assert_eq!(header_read_timeout(&config), Some(Duration::from_secs(30)));
+        config.max_connections = 2;
+        config.header_read_timeout_seconds = 0;
+        assert_eq!(Limits::from_config(&config).max_connections, Some(2));
+        assert_eq!(header_read_timeout(&config), None);
+    }
+}
@@ -0,0 +1,46 @@
+//! Push mode for environments where Prometheus can't scrape the server.
+//!
+//! The rendered text exposition is sent with `PUT` to `observability.metrics_push_url` on a
+//! fixed interval. That URL is normally a Pushgateway group
+//! (`http://pushgateway:9091/metrics/job/llm_inference`), but any endpoint that accepts the
+//! text format works, e.g. VictoriaMetrics' `/api/v1/import/prometheus`. Protobuf
+//! remote-write is not supported.
+
+use metrics::increment_counter;
+use metrics_exporter_prometheus::PrometheusHandle;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+/// Push the current metrics to `url` every `interval` until the task is aborted
+pub fn spawn_pusher(handle: PrometheusHandle, url: String, interval: Duration) -> JoinHandle<()> {
+    let client = reqwest::Client::builder()
+        .timeout(interval.max(Duration::from_secs(1)))
+        .build()
+        .unwrap_or_default();
+
+    tracing::info!("📤 Pushing metrics to {} every {:?}", url, interval);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let result = client
+                .put(&url)
+                .header("content-type", "text/plain; version=0.0.4")
+                .body(handle.render())
+                .send()
+                .await;
+            let failure = match result {
+                Ok(resp) if resp.status().is_success() => {
+                    increment_counter!("metrics_pushes_total");
+                    continue;
+                }
+                Ok(resp) => format!("push target returned {}", resp.status()),
+                Err(err) => err.to_string(),
+            };
+            increment_counter!("metrics_push_failures_total");
+            warn!("⚠️ Failed to push metrics to {}: {}", url, failure);
+        }
+    })
+}
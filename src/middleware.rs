@@ -1,6 +1,17 @@
+use crate::config::{ApiKeyConfig, OverloadConfig, ShedPolicy};
+use crate::engine::{EngineError, TokenStream};
+use chrono::Utc;
 use dashmap::DashMap;
-use std::sync::Arc;
+use futures_util::StreamExt;
+use metrics::{gauge, histogram, increment_counter};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::{oneshot, OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
 
 /// Rate limiting state
 pub struct RateLimiter {
@@ -21,7 +32,7 @@ impl RateLimiter {
         let mut entry = self
             .requests
             .entry(key.to_string())
-            .or_insert_with(Vec::new);
+            .or_default();
 
         // Remove old entries
         entry.retain(|&time| now.duration_since(time) < window);
@@ -36,7 +47,8 @@ impl RateLimiter {
         true
     }
 
-    /// Clean up old entries periodically
+    /// Forget keys without requests in the current window and publish how many are left.
+    /// `AppState` runs this every `limits.rate_limit_cleanup_seconds`.
     pub fn cleanup(&self) {
         let now = Instant::now();
         let window = Duration::from_secs(60);
@@ -45,6 +57,51 @@ impl RateLimiter {
             times.retain(|&time| now.duration_since(time) < window);
             !times.is_empty()
         });
+        gauge!("rate_limiter_tracked_keys", self.tracked_keys() as f64);
+    }
+
+    /// Keys with a request window in memory, including ones not cleaned up yet
+    pub fn tracked_keys(&self) -> usize {
+        self.requests.len()
+    }
+
+    /// Request times in the current window per key, as Unix milliseconds, for saving across
+    /// restarts
+    pub fn snapshot(&self) -> Vec<(String, Vec<i64>)> {
+        let now = Instant::now();
+        let wall_now = Utc::now().timestamp_millis();
+        let window = Duration::from_secs(60);
+
+        self.requests
+            .iter()
+            .filter_map(|entry| {
+                let times: Vec<i64> = entry
+                    .value()
+                    .iter()
+                    .map(|&time| now.duration_since(time))
+                    .filter(|&age| age < window)
+                    .map(|age| wall_now - age.as_millis() as i64)
+                    .collect();
+                (!times.is_empty()).then(|| (entry.key().clone(), times))
+            })
+            .collect()
+    }
+
+    /// Count request times saved by [`snapshot`](Self::snapshot) against `key` again; times
+    /// already outside the window are dropped
+    pub fn restore(&self, key: &str, times: &[i64]) {
+        let now = Instant::now();
+        let wall_now = Utc::now().timestamp_millis();
+        let window = Duration::from_secs(60);
+
+        let mut entry = self.requests.entry(key.to_string()).or_default();
+        for &time in times {
+            let age = Duration::from_millis(wall_now.saturating_sub(time).max(0) as u64);
+            if let Some(at) = now.checked_sub(age).filter(|_| age < window) {
+                entry.push(at);
+            }
+        }
+        entry.sort();
     }
 
     /// Return the remaining allowed requests for `key` under `limit` in the current window.
@@ -69,6 +126,12 @@ impl RateLimiter {
     }
 }
 
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Clone for RateLimiter {
     fn clone(&self) -> Self {
         Self {
@@ -77,6 +140,849 @@ impl Clone for RateLimiter {
     }
 }
 
+/// Concurrency limiting state: a global cap on simultaneous generations shared by everyone,
+/// plus optional per-key caps so one tenant cannot hold every slot.
+///
+/// Requests that have to wait are not served first-come first-served. Freed slots go to the
+/// highest waiting priority, and within a priority the keys take turns, so a key with a deep
+/// backlog (a batch job, say) cannot starve interactive traffic from other keys.
+///
+/// Saturation is published as the `generations_in_flight`, `generations_queued` and
+/// `generation_permits_available` gauges, with queue time in `generation_queue_wait_seconds`.
+/// [`jobs`](Self::jobs) lists every running and waiting request, and [`cancel`](Self::cancel)
+/// stops one.
+///
+/// The queue is bounded by an [`OverloadConfig`]: past `max_queue_length` a request is refused
+/// (or, with [`ShedPolicy::Priority`], pushes out a lower-priority waiter), and waiters give up
+/// after `max_wait_seconds`. Both count toward `overload_shed_total`.
+pub struct ConcurrencyLimiter {
+    global: Arc<Semaphore>,
+    per_key: Arc<DashMap<String, Arc<Semaphore>>>,
+    max_concurrent: usize,
+    queued: Arc<AtomicUsize>,
+    waiters: Arc<Mutex<WaitQueue>>,
+    // Moving average of how long a slot is held, in milliseconds; 0 until one is released
+    average_hold_ms: Arc<AtomicU64>,
+    jobs: Arc<DashMap<u64, Arc<Job>>>,
+    next_job: Arc<AtomicU64>,
+    overload: Arc<Mutex<OverloadConfig>>,
+}
+
+// A request from reservation until it gives up its slot (or its place in the queue)
+struct Job {
+    id: u64,
+    key: String,
+    priority: i32,
+    model: Mutex<Option<String>>,
+    enqueued: Instant,
+    started: OnceLock<Instant>,
+    tokens: AtomicU64,
+    cancel: CancellationToken,
+    // Id in the wait queue, for requests that had to queue
+    waiter: Option<u64>,
+}
+
+impl Job {
+    fn set_model(&self, model: &str) {
+        *self.model.lock().unwrap() = Some(model.to_string());
+    }
+}
+
+/// A running or waiting request, as listed by [`ConcurrencyLimiter::jobs`]
+#[derive(Debug, Clone)]
+pub struct JobStatus {
+    pub id: u64,
+    /// Limiter key: the API key, or the client address without auth
+    pub key: String,
+    pub model: Option<String>,
+    pub priority: i32,
+    /// Time since the slot was granted, or spent waiting so far while queued
+    pub elapsed: Duration,
+    /// Tokens streamed so far; counted for streams passed through [`GenerationPermit::track`]
+    pub tokens: u64,
+    /// Place in line while queued, `None` once running
+    pub position: Option<usize>,
+}
+
+/// Held for the lifetime of a generation; dropping it frees both the global and per-key slot.
+pub struct GenerationPermit {
+    global: Option<OwnedSemaphorePermit>,
+    _key: Option<OwnedSemaphorePermit>,
+    limiter: ConcurrencyLimiter,
+    acquired: Instant,
+    job: Arc<Job>,
+}
+
+impl GenerationPermit {
+    /// Id of this generation in [`ConcurrencyLimiter::jobs`]
+    pub fn id(&self) -> u64 {
+        self.job.id
+    }
+
+    /// Model shown for this generation in the job list
+    pub fn set_model(&self, model: &str) {
+        self.job.set_model(model);
+    }
+
+    /// Count the tokens of `stream` towards this generation, and end it with
+    /// [`EngineError::Cancelled`] once the generation is cancelled. Dropping the engine's stream
+    /// stops the generation unless others share it.
+    pub fn track(&self, mut stream: TokenStream) -> TokenStream {
+        let job = self.job.clone();
+        Box::pin(async_stream::stream! {
+            loop {
+                tokio::select! {
+                    item = stream.next() => match item {
+                        Some(item) => {
+                            if item.is_ok() {
+                                job.tokens.fetch_add(1, Ordering::Relaxed);
+                            }
+                            yield item;
+                        }
+                        None => break,
+                    },
+                    _ = job.cancel.cancelled() => {
+                        yield Err(EngineError::Cancelled.into());
+                        break;
+                    }
+                }
+            }
+        })
+    }
+
+    /// [`track`](Self::track) `stream` and keep the slot until it ends or is dropped, for
+    /// generations that outlive the request that started them
+    pub fn hold(self, stream: TokenStream) -> TokenStream {
+        let mut stream = self.track(stream);
+        Box::pin(async_stream::stream! {
+            let _permit = self;
+            while let Some(item) = stream.next().await {
+                yield item;
+            }
+        })
+    }
+}
+
+impl Drop for GenerationPermit {
+    fn drop(&mut self) {
+        // Release before publishing so the gauges reflect the freed slot
+        self.global.take();
+        self.limiter.jobs.remove(&self.job.id);
+        self.limiter.record_hold(self.acquired.elapsed());
+        self.limiter.dispatch();
+        self.limiter.publish_gauges();
+    }
+}
+
+/// Why [`ConcurrencyLimiter::reserve`] refused a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refusal {
+    /// The key already has its `key_limit` generations running
+    KeyLimit,
+    /// The queue is at `max_queue_length` and the request could not take anyone's place
+    QueueFull,
+}
+
+/// Outcome of asking for a slot: one right away, or a place in the queue
+pub enum Reservation {
+    Ready(GenerationPermit),
+    Queued(QueueTicket),
+}
+
+impl Reservation {
+    /// Model shown for this request in the job list, while queued and once running
+    pub fn set_model(&self, model: &str) {
+        match self {
+            Reservation::Ready(permit) => permit.set_model(model),
+            Reservation::Queued(ticket) => ticket.guard.job.set_model(model),
+        }
+    }
+}
+
+/// A request waiting for a global slot. Dropping the ticket leaves the queue.
+pub struct QueueTicket {
+    guard: QueuedGuard,
+    key_permit: Option<OwnedSemaphorePermit>,
+    wait_start: Instant,
+    // Give up at this point (`max_wait_seconds`)
+    deadline: Option<tokio::time::Instant>,
+    shed: bool,
+}
+
+impl QueueTicket {
+    /// Wait until a slot is handed over. Cancel-safe, so it can be polled alongside a timer.
+    /// Returns `None` when the request was [cancelled](ConcurrencyLimiter::cancel) or
+    /// [shed](Self::is_shed) while queued.
+    pub async fn wait(&mut self) -> Option<GenerationPermit> {
+        let granted = match self.deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, &mut self.guard.granted).await {
+                Ok(granted) => granted,
+                Err(_) => {
+                    if !self.shed {
+                        increment_counter!("overload_shed_total", "reason" => "max_wait");
+                    }
+                    Ok(None)
+                }
+            },
+            None => (&mut self.guard.granted).await,
+        };
+        let global = match granted.ok()? {
+            Some(global) => global,
+            None => {
+                self.shed = true;
+                return None;
+            }
+        };
+        let limiter = &self.guard.limiter;
+        histogram!(
+            "generation_queue_wait_seconds",
+            self.wait_start.elapsed().as_secs_f64()
+        );
+        let job = self.guard.job.clone();
+        let acquired = *job.started.get_or_init(Instant::now);
+        limiter.publish_gauges();
+        Some(GenerationPermit {
+            global: Some(global),
+            _key: self.key_permit.take(),
+            limiter: limiter.clone(),
+            acquired,
+            job,
+        })
+    }
+
+    /// Whether the request left the queue because it was cancelled
+    pub fn is_cancelled(&self) -> bool {
+        self.guard.job.cancel.is_cancelled()
+    }
+
+    /// Whether the request left the queue to relieve overload: it waited past
+    /// `max_wait_seconds`, or a higher-priority request took its place in a full queue
+    pub fn is_shed(&self) -> bool {
+        self.shed
+    }
+
+    /// Suggested wait before retrying, see [`ConcurrencyLimiter::retry_after`]
+    pub fn retry_after(&self) -> Duration {
+        self.guard.limiter.retry_after()
+    }
+
+    /// Place in line, starting at 1 for the request served next. Later arrivals with a higher
+    /// priority, or from keys whose turn comes first, can still move ahead.
+    pub fn position(&self) -> usize {
+        let guard = &self.guard;
+        let waiters = guard.limiter.waiters.lock().unwrap();
+        waiters.ahead_of(&guard.job.key, guard.job.priority, guard.id) + 1
+    }
+
+    /// Rough time until a slot frees up for this request, from how long generations have
+    /// recently held their slots. `None` until a generation has finished.
+    pub fn estimated_wait(&self) -> Option<Duration> {
+        let limiter = &self.guard.limiter;
+        let average = limiter.average_hold_ms.load(Ordering::Relaxed);
+        if average == 0 {
+            return None;
+        }
+        let rounds = self.position().div_ceil(limiter.max_concurrent.max(1));
+        Some(Duration::from_millis(average * rounds as u64))
+    }
+}
+
+// A request let in by `take_or_enqueue`: with a free slot, or queued under a waiter id
+enum Admission {
+    Slot(OwnedSemaphorePermit),
+    Queued(u64, oneshot::Receiver<Option<OwnedSemaphorePermit>>),
+}
+
+// A waiter is handed a slot, or `None` when it is shed to make room
+struct Waiter {
+    id: u64,
+    grant: oneshot::Sender<Option<OwnedSemaphorePermit>>,
+}
+
+// Waiters at one priority: a FIFO per key, with the keys served round-robin
+#[derive(Default)]
+struct PriorityLevel {
+    turns: VecDeque<String>,
+    queues: HashMap<String, VecDeque<Waiter>>,
+}
+
+#[derive(Default)]
+struct WaitQueue {
+    levels: BTreeMap<i32, PriorityLevel>,
+    next_id: u64,
+}
+
+impl WaitQueue {
+    fn is_empty(&self) -> bool {
+        self.levels.is_empty()
+    }
+
+    fn len(&self) -> usize {
+        self.levels
+            .values()
+            .flat_map(|level| level.queues.values())
+            .map(VecDeque::len)
+            .sum()
+    }
+
+    fn push(
+        &mut self,
+        key: &str,
+        priority: i32,
+        grant: oneshot::Sender<Option<OwnedSemaphorePermit>>,
+    ) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        let level = self.levels.entry(priority).or_default();
+        let queue = level.queues.entry(key.to_string()).or_default();
+        if queue.is_empty() {
+            level.turns.push_back(key.to_string());
+        }
+        queue.push_back(Waiter { id, grant });
+        id
+    }
+
+    /// Next waiter: highest priority first, then the key whose turn it is
+    fn pop(&mut self) -> Option<Waiter> {
+        let mut entry = self.levels.last_entry()?;
+        let level = entry.get_mut();
+        let key = level.turns.pop_front()?;
+        let queue = level.queues.get_mut(&key)?;
+        let waiter = queue.pop_front();
+        if queue.is_empty() {
+            level.queues.remove(&key);
+        } else {
+            level.turns.push_back(key);
+        }
+        if level.turns.is_empty() {
+            entry.remove();
+        }
+        waiter
+    }
+
+    /// Waiters that would be served before waiter `id`, if nobody else arrives
+    fn ahead_of(&self, key: &str, priority: i32, id: u64) -> usize {
+        let higher: usize = self
+            .levels
+            .range(priority + 1..)
+            .flat_map(|(_, level)| level.queues.values())
+            .map(VecDeque::len)
+            .sum();
+        let Some(level) = self.levels.get(&priority) else {
+            return higher;
+        };
+        let Some(rounds) = level
+            .queues
+            .get(key)
+            .and_then(|queue| queue.iter().position(|w| w.id == id))
+        else {
+            return higher;
+        };
+        // Every other key gets one turn per round; keys ahead in the rotation also get a turn
+        // in the round this waiter is served
+        let mut ahead = higher + rounds;
+        let mut before_us = true;
+        for turn in &level.turns {
+            if turn == key {
+                before_us = false;
+                continue;
+            }
+            let len = level.queues.get(turn).map_or(0, VecDeque::len);
+            ahead += len.min(rounds + usize::from(before_us));
+        }
+        ahead
+    }
+
+    /// Take out the newest waiter of the lowest priority, when that priority is below `priority`
+    fn evict_below(&mut self, priority: i32) -> Option<Waiter> {
+        let (&lowest, level) = self.levels.first_key_value()?;
+        if lowest >= priority {
+            return None;
+        }
+        let (key, id) = level
+            .queues
+            .iter()
+            .filter_map(|(key, queue)| Some((key.clone(), queue.back()?.id)))
+            .max_by_key(|(_, id)| *id)?;
+        let waiter = self.levels.get_mut(&lowest)?.queues.get_mut(&key)?.pop_back();
+        // Tidies up the key and level if they are now empty
+        self.remove(&key, lowest, id);
+        waiter
+    }
+
+    /// Forget a waiter that gave up before being served
+    fn remove(&mut self, key: &str, priority: i32, id: u64) {
+        let Some(level) = self.levels.get_mut(&priority) else {
+            return;
+        };
+        if let Some(queue) = level.queues.get_mut(key) {
+            queue.retain(|w| w.id != id);
+            if queue.is_empty() {
+                level.queues.remove(key);
+                level.turns.retain(|k| k != key);
+            }
+        }
+        if level.turns.is_empty() {
+            self.levels.remove(&priority);
+        }
+    }
+}
+
+// Counts a request as queued until it gets a slot or its future is dropped. A slot granted
+// after the request gave up is released and handed on to the next waiter.
+struct QueuedGuard {
+    limiter: ConcurrencyLimiter,
+    job: Arc<Job>,
+    id: u64,
+    granted: oneshot::Receiver<Option<OwnedSemaphorePermit>>,
+}
+
+impl Drop for QueuedGuard {
+    fn drop(&mut self) {
+        self.granted.close();
+        drop(self.granted.try_recv());
+        self.limiter
+            .waiters
+            .lock()
+            .unwrap()
+            .remove(&self.job.key, self.job.priority, self.id);
+        // Once granted, the job belongs to the permit
+        if self.job.started.get().is_none() {
+            self.limiter.jobs.remove(&self.job.id);
+        }
+        self.limiter.queued.fetch_sub(1, Ordering::SeqCst);
+        self.limiter.dispatch();
+    }
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(max_concurrent: usize) -> Self {
+        Self {
+            global: Arc::new(Semaphore::new(max_concurrent)),
+            per_key: Arc::new(DashMap::new()),
+            max_concurrent,
+            queued: Arc::new(AtomicUsize::new(0)),
+            waiters: Arc::new(Mutex::new(WaitQueue::default())),
+            average_hold_ms: Arc::new(AtomicU64::new(0)),
+            jobs: Arc::new(DashMap::new()),
+            next_job: Arc::new(AtomicU64::new(1)),
+            overload: Arc::new(Mutex::new(OverloadConfig::default())),
+        }
+    }
+
+    /// Bounds on the queue for requests from now on; waiters keep the deadline they queued with
+    pub fn set_overload(&self, overload: OverloadConfig) {
+        *self.overload.lock().unwrap() = overload;
+    }
+
+    /// Reserve a generation slot for `key`.
+    ///
+    /// Returns `None` immediately when the key already has `key_limit` generations running or
+    /// the queue is full. Otherwise waits (queues) until a global slot is handed to it; higher
+    /// `priority` values are served first.
+    pub async fn acquire(
+        &self,
+        key: &str,
+        key_limit: Option<usize>,
+        priority: i32,
+    ) -> Option<GenerationPermit> {
+        match self.reserve(key, key_limit, priority).ok()? {
+            Reservation::Ready(permit) => Some(permit),
+            Reservation::Queued(mut ticket) => ticket.wait().await,
+        }
+    }
+
+    /// Like [`acquire`](Self::acquire), but hands back a [`QueueTicket`] instead of waiting
+    /// when no slot is free, so the caller can report progress while it waits.
+    pub fn reserve(
+        &self,
+        key: &str,
+        key_limit: Option<usize>,
+        priority: i32,
+    ) -> Result<Reservation, Refusal> {
+        let key_permit = match key_limit {
+            Some(limit) => {
+                let sem = self
+                    .per_key
+                    .entry(key.to_string())
+                    .or_insert_with(|| Arc::new(Semaphore::new(limit)))
+                    .clone();
+                Some(sem.try_acquire_owned().map_err(|_| Refusal::KeyLimit)?)
+            }
+            None => None,
+        };
+
+        let overload = self.overload.lock().unwrap().clone();
+        match self.take_or_enqueue(key, priority, &overload)? {
+            Admission::Slot(global_permit) => {
+                histogram!("generation_queue_wait_seconds", 0.0);
+                let job = self.register_job(key, priority, None);
+                let acquired = *job.started.get_or_init(Instant::now);
+                self.publish_gauges();
+                Ok(Reservation::Ready(GenerationPermit {
+                    global: Some(global_permit),
+                    _key: key_permit,
+                    limiter: self.clone(),
+                    acquired,
+                    job,
+                }))
+            }
+            Admission::Queued(id, granted) => {
+                self.queued.fetch_add(1, Ordering::SeqCst);
+                let guard = QueuedGuard {
+                    limiter: self.clone(),
+                    job: self.register_job(key, priority, Some(id)),
+                    id,
+                    granted,
+                };
+                self.publish_gauges();
+                let max_wait = Duration::from_secs(overload.max_wait_seconds);
+                Ok(Reservation::Queued(QueueTicket {
+                    guard,
+                    key_permit,
+                    wait_start: Instant::now(),
+                    deadline: (!max_wait.is_zero()).then(|| tokio::time::Instant::now() + max_wait),
+                    shed: false,
+                }))
+            }
+        }
+    }
+
+    fn register_job(&self, key: &str, priority: i32, waiter: Option<u64>) -> Arc<Job> {
+        let job = Arc::new(Job {
+            id: self.next_job.fetch_add(1, Ordering::Relaxed),
+            key: key.to_string(),
+            priority,
+            model: Mutex::new(None),
+            enqueued: Instant::now(),
+            started: OnceLock::new(),
+            tokens: AtomicU64::new(0),
+            cancel: CancellationToken::new(),
+            waiter,
+        });
+        self.jobs.insert(job.id, job.clone());
+        job
+    }
+
+    /// Running generations (by id) followed by waiting requests (in serving order)
+    pub fn jobs(&self) -> Vec<JobStatus> {
+        let jobs: Vec<Arc<Job>> = self
+            .jobs
+            .iter()
+            .map(|entry| entry.value().clone())
+            .collect();
+        let waiters = self.waiters.lock().unwrap();
+        let mut statuses: Vec<JobStatus> = jobs
+            .iter()
+            .map(|job| {
+                let (elapsed, position) = match (job.started.get(), job.waiter) {
+                    (Some(started), _) => (started.elapsed(), None),
+                    (None, waiter) => (
+                        job.enqueued.elapsed(),
+                        waiter.map(|id| waiters.ahead_of(&job.key, job.priority, id) + 1),
+                    ),
+                };
+                JobStatus {
+                    id: job.id,
+                    key: job.key.clone(),
+                    model: job.model.lock().unwrap().clone(),
+                    priority: job.priority,
+                    elapsed,
+                    tokens: job.tokens.load(Ordering::Relaxed),
+                    position,
+                }
+            })
+            .collect();
+        statuses.sort_by_key(|status| (status.position.is_some(), status.position, status.id));
+        statuses
+    }
+
+    /// Cancel a running generation or take a request out of the queue. Returns `false` for
+    /// unknown ids, including requests that already finished.
+    pub fn cancel(&self, id: u64) -> bool {
+        let Some(job) = self.jobs.get(&id).map(|entry| entry.value().clone()) else {
+            return false;
+        };
+        job.cancel.cancel();
+        if job.started.get().is_none() {
+            if let Some(waiter) = job.waiter {
+                // Dropping the waiter closes its channel, which ends `QueueTicket::wait`
+                self.waiters
+                    .lock()
+                    .unwrap()
+                    .remove(&job.key, job.priority, waiter);
+            }
+        }
+        true
+    }
+
+    // Take a free slot right away when nobody is waiting, otherwise join the queue if it has
+    // room (or room can be made under the shed policy)
+    fn take_or_enqueue(
+        &self,
+        key: &str,
+        priority: i32,
+        overload: &OverloadConfig,
+    ) -> Result<Admission, Refusal> {
+        let mut waiters = self.waiters.lock().unwrap();
+        if waiters.is_empty() {
+            if let Ok(permit) = self.global.clone().try_acquire_owned() {
+                return Ok(Admission::Slot(permit));
+            }
+        }
+        if overload.max_queue_length > 0 && waiters.len() >= overload.max_queue_length {
+            let evicted = match overload.shed_policy {
+                ShedPolicy::RejectNewest => None,
+                ShedPolicy::Priority => waiters.evict_below(priority),
+            };
+            match evicted {
+                // Its ticket sees the `None` and leaves the queue
+                Some(waiter) => {
+                    let _ = waiter.grant.send(None);
+                    increment_counter!("overload_shed_total", "reason" => "evicted");
+                }
+                None => {
+                    increment_counter!("overload_shed_total", "reason" => "queue_full");
+                    return Err(Refusal::QueueFull);
+                }
+            }
+        }
+        let (grant, granted) = oneshot::channel();
+        Ok(Admission::Queued(waiters.push(key, priority, grant), granted))
+    }
+
+    // Hand free slots to waiters in scheduling order
+    fn dispatch(&self) {
+        let mut waiters = self.waiters.lock().unwrap();
+        let mut spare = None;
+        while !waiters.is_empty() {
+            let Some(permit) = spare
+                .take()
+                .or_else(|| self.global.clone().try_acquire_owned().ok())
+            else {
+                break;
+            };
+            let Some(waiter) = waiters.pop() else {
+                break;
+            };
+            // A closed channel means the waiter gave up; the permit goes to the next one
+            spare = waiter.grant.send(Some(permit)).err().flatten();
+        }
+    }
+
+    /// Take a free global slot without queueing. Returns `None` when every slot is busy or
+    /// requests are waiting, so background work never delays real traffic.
+    pub fn try_acquire(&self) -> Option<GenerationPermit> {
+        let permit = {
+            let waiters = self.waiters.lock().unwrap();
+            if !waiters.is_empty() {
+                return None;
+            }
+            self.global.clone().try_acquire_owned().ok()?
+        };
+        let job = self.register_job("", 0, None);
+        let acquired = *job.started.get_or_init(Instant::now);
+        self.publish_gauges();
+        Some(GenerationPermit {
+            global: Some(permit),
+            _key: None,
+            limiter: self.clone(),
+            acquired,
+            job,
+        })
+    }
+
+    // Fold a released slot's hold time into the moving average (weight 1/5)
+    fn record_hold(&self, held: Duration) {
+        let held = (held.as_millis() as u64).max(1);
+        let _ =
+            self.average_hold_ms
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |average| {
+                    Some(match average {
+                        0 => held,
+                        _ => (average * 4 + held) / 5,
+                    })
+                });
+    }
+
+    /// Suggested wait before retrying a refused request: how long the queue would take to drain
+    /// at the pace slots have recently been freed, between one second and
+    /// `max_retry_after_seconds`
+    pub fn retry_after(&self) -> Duration {
+        let max = self.overload.lock().unwrap().max_retry_after_seconds.max(1);
+        let average = self.average_hold_ms.load(Ordering::Relaxed);
+        let rounds = (self.queued() + 1).div_ceil(self.max_concurrent.max(1));
+        let seconds = (average * rounds as u64).div_ceil(1000);
+        Duration::from_secs(seconds.clamp(1, max))
+    }
+
+    /// Forget per-key semaphores so changed caps apply to new requests. Generations already
+    /// running keep their permits on the old semaphores.
+    pub fn reset_key_limits(&self) {
+        self.per_key.clear();
+    }
+
+    /// Global slots currently free
+    pub fn available(&self) -> usize {
+        self.global.available_permits()
+    }
+
+    /// Generations currently holding a global slot
+    pub fn in_flight(&self) -> usize {
+        self.max_concurrent.saturating_sub(self.available())
+    }
+
+    /// Requests waiting for a global slot
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    fn publish_gauges(&self) {
+        gauge!("generations_in_flight", self.in_flight() as f64);
+        gauge!("generations_queued", self.queued() as f64);
+        gauge!("generation_permits_available", self.available() as f64);
+    }
+}
+
+impl Clone for ConcurrencyLimiter {
+    fn clone(&self) -> Self {
+        Self {
+            global: self.global.clone(),
+            per_key: self.per_key.clone(),
+            max_concurrent: self.max_concurrent,
+            queued: self.queued.clone(),
+            waiters: self.waiters.clone(),
+            average_hold_ms: self.average_hold_ms.clone(),
+            jobs: self.jobs.clone(),
+            next_job: self.next_job.clone(),
+            overload: self.overload.clone(),
+        }
+    }
+}
+
+/// Reasons an API key is refused by the auth middleware
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum ApiKeyError {
+    #[error("Invalid API key")]
+    Unknown,
+    #[error("API key is disabled")]
+    Disabled,
+    #[error("API key has expired")]
+    Expired,
+}
+
+impl ApiKeyError {
+    /// Machine-readable code returned alongside 401 responses
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiKeyError::Unknown => "invalid_api_key",
+            ApiKeyError::Disabled => "api_key_disabled",
+            ApiKeyError::Expired => "api_key_expired",
+        }
+    }
+}
+
+/// API keys known at runtime: the configured keys plus any issued through rotation
+pub struct ApiKeyRegistry {
+    keys: Arc<DashMap<String, ApiKeyConfig>>,
+}
+
+impl ApiKeyRegistry {
+    /// Later entries win; see [`with_rotated_keys`] for combining configured and stored keys.
+    pub fn new(keys: impl IntoIterator<Item = ApiKeyConfig>) -> Self {
+        let map = DashMap::new();
+        for key in keys {
+            map.insert(key.key.clone(), key);
+        }
+        Self {
+            keys: Arc::new(map),
+        }
+    }
+
+    /// Replace every known key, e.g. after a config reload
+    pub fn replace_all(&self, keys: impl IntoIterator<Item = ApiKeyConfig>) {
+        self.keys.clear();
+        for key in keys {
+            self.keys.insert(key.key.clone(), key);
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<ApiKeyConfig> {
+        self.keys.get(key).map(|k| k.clone())
+    }
+
+    /// Check that `key` exists, is enabled and has not expired
+    pub fn validate(&self, key: &str) -> Result<ApiKeyConfig, ApiKeyError> {
+        let entry = self.get(key).ok_or(ApiKeyError::Unknown)?;
+        if !entry.enabled {
+            return Err(ApiKeyError::Disabled);
+        }
+        if entry.expires_at.is_some_and(|exp| exp <= Utc::now()) {
+            return Err(ApiKeyError::Expired);
+        }
+        Ok(entry)
+    }
+
+    /// Issue a replacement for `key` with the same settings. The old key keeps working for
+    /// `grace` (or until its own expiry, if sooner). Returns `(old, new)` for persistence.
+    pub fn rotate(
+        &self,
+        key: &str,
+        grace: Duration,
+    ) -> Result<(ApiKeyConfig, ApiKeyConfig), ApiKeyError> {
+        let mut old = self.validate(key)?;
+
+        let mut new = old.clone();
+        new.key = format!("sk-{}", uuid::Uuid::new_v4().simple());
+        new.expires_at = None;
+
+        let grace_end = Utc::now()
+            + chrono::Duration::from_std(grace).unwrap_or_else(|_| chrono::Duration::zero());
+        old.expires_at = Some(old.expires_at.map_or(grace_end, |exp| exp.min(grace_end)));
+
+        self.keys.insert(old.key.clone(), old.clone());
+        self.keys.insert(new.key.clone(), new.clone());
+        Ok((old, new))
+    }
+}
+
+/// The configured keys followed by the keys issued through rotation, for [`ApiKeyRegistry`].
+/// A stored entry for a configured key only carries its rotation expiry over: the config
+/// decides every other setting, and a stored entry that disagrees with it is logged.
+pub fn with_rotated_keys(
+    configured: &[ApiKeyConfig],
+    stored: impl IntoIterator<Item = ApiKeyConfig>,
+) -> Vec<ApiKeyConfig> {
+    let mut keys = configured.to_vec();
+    for rotated in stored {
+        let Some(key) = keys.iter_mut().find(|k| k.key == rotated.key) else {
+            keys.push(rotated);
+            continue;
+        };
+        let expires_at = match (key.expires_at, rotated.expires_at) {
+            (Some(configured), Some(rotated)) => Some(configured.min(rotated)),
+            (configured, rotated) => configured.or(rotated),
+        };
+        let rotated = ApiKeyConfig { expires_at: key.expires_at, ..rotated };
+        if rotated != *key {
+            warn!(
+                "Stored settings of rotated API key {} differ from the config; using the config",
+                key.name
+            );
+        }
+        key.expires_at = expires_at;
+    }
+    keys
+}
+
+impl Clone for ApiKeyRegistry {
+    fn clone(&self) -> Self {
+        Self {
+            keys: self.keys.clone(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -106,4 +1012,336 @@ mod tests {
         // Second key should still work
         assert!(!limiter.check_rate_limit("key2", 1));
     }
+
+    #[tokio::test]
+    async fn test_jobs_are_listed_and_cancellable() {
+        let limiter = ConcurrencyLimiter::new(1);
+        let Ok(Reservation::Ready(running)) = limiter.reserve("a", None, 0) else {
+            panic!("the only slot should be free");
+        };
+        running.set_model("m1");
+        let Ok(Reservation::Queued(mut ticket)) = limiter.reserve("b", None, 2) else {
+            panic!("the second request should queue");
+        };
+
+        let jobs = limiter.jobs();
+        assert_eq!(jobs.len(), 2);
+        assert_eq!(jobs[0].id, running.id());
+        assert_eq!(jobs[0].model.as_deref(), Some("m1"));
+        assert_eq!(jobs[0].position, None);
+        assert_eq!((jobs[1].key.as_str(), jobs[1].priority), ("b", 2));
+        assert_eq!(jobs[1].position, Some(1));
+
+        // A cancelled request leaves the queue without a slot
+        assert!(limiter.cancel(jobs[1].id));
+        assert!(ticket.wait().await.is_none());
+        assert!(ticket.is_cancelled());
+        drop(ticket);
+        assert_eq!(limiter.queued(), 0);
+        assert_eq!(limiter.jobs().len(), 1);
+
+        // A running generation stops at the next poll, token or not
+        let tokens = futures_util::stream::iter([Ok::<_, anyhow::Error>("a".into())]);
+        let mut stream = running.track(Box::pin(tokens.chain(futures_util::stream::pending())));
+        assert!(stream.next().await.unwrap().is_ok());
+        assert_eq!(limiter.jobs()[0].tokens, 1);
+        assert!(limiter.cancel(running.id()));
+        let err = stream.next().await.unwrap().unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(EngineError::Cancelled)));
+        assert!(stream.next().await.is_none());
+
+        drop(running);
+        assert!(limiter.jobs().is_empty());
+        assert!(!limiter.cancel(jobs[0].id));
+    }
+
+    #[test]
+    fn test_rate_limiter_cleanup_drops_idle_keys() {
+        let limiter = RateLimiter::new();
+        assert!(limiter.check_rate_limit("active", 5));
+        let stale = Instant::now() - Duration::from_secs(61);
+        limiter.requests.insert("idle".to_string(), vec![stale]);
+        assert_eq!(limiter.tracked_keys(), 2);
+
+        limiter.cleanup();
+        assert_eq!(limiter.tracked_keys(), 1);
+        assert_eq!(limiter.remaining("active", 5), 4);
+    }
+
+    #[test]
+    fn test_rate_limiter_snapshot_restores_the_window() {
+        let limiter = RateLimiter::new();
+        assert!(limiter.check_rate_limit("busy", 3));
+        assert!(limiter.check_rate_limit("busy", 3));
+        let stale = Instant::now() - Duration::from_secs(61);
+        limiter.requests.insert("idle".to_string(), vec![stale]);
+
+        let snapshot = limiter.snapshot();
+        assert_eq!(snapshot.len(), 1);
+        let restarted = RateLimiter::new();
+        for (key, times) in &snapshot {
+            restarted.restore(key, times);
+        }
+        assert_eq!(restarted.remaining("busy", 3), 1);
+        let expired = Utc::now().timestamp_millis() - 61_000;
+        restarted.restore("idle", &[expired]);
+        assert_eq!(restarted.remaining("idle", 3), 3);
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_limiter_per_key() {
+        let limiter = ConcurrencyLimiter::new(10);
+
+        let first = limiter.acquire("key1", Some(1), 0).await;
+        assert!(first.is_some());
+        assert!(limiter.acquire("key1", Some(1), 0).await.is_none());
+
+        // Releasing the permit frees the slot again
+        drop(first);
+        assert!(limiter.acquire("key1", Some(1), 0).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_limiter_saturation() {
+        let limiter = ConcurrencyLimiter::new(1);
+
+        let first = limiter.acquire("key1", None, 0).await.unwrap();
+        assert_eq!(limiter.in_flight(), 1);
+        assert_eq!(limiter.available(), 0);
+
+        let waiter = {
+            let limiter = limiter.clone();
+            tokio::spawn(async move { limiter.acquire("key2", None, 0).await.is_some() })
+        };
+        while limiter.queued() == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        drop(first);
+        assert!(waiter.await.unwrap());
+        assert_eq!(limiter.queued(), 0);
+        assert_eq!(limiter.in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_queue_ticket_position() {
+        let limiter = ConcurrencyLimiter::new(1);
+        let held = limiter.acquire("busy", None, 0).await.unwrap();
+        let ticket = |key: &str, priority: i32| match limiter.reserve(key, None, priority) {
+            Ok(Reservation::Queued(ticket)) => ticket,
+            _ => panic!("expected to queue"),
+        };
+
+        let a1 = ticket("a", 0);
+        let a2 = ticket("a", 0);
+        let b1 = ticket("b", 0);
+        assert_eq!((a1.position(), a2.position(), b1.position()), (1, 3, 2));
+        assert_eq!(a1.estimated_wait(), None);
+
+        // Higher priorities go first; leaving the queue moves everyone behind up, and key "a"
+        // keeps its turn
+        let urgent = ticket("c", 5);
+        assert_eq!((urgent.position(), a1.position()), (1, 2));
+        drop(a1);
+        assert_eq!((a2.position(), b1.position()), (2, 3));
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        drop(held);
+        let estimate = b1.estimated_wait().unwrap();
+        assert!(estimate >= Duration::from_millis(20));
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_sheds_by_policy() {
+        let limiter = ConcurrencyLimiter::new(1);
+        let overload = |shed_policy| OverloadConfig {
+            max_queue_length: 1,
+            shed_policy,
+            ..Default::default()
+        };
+        limiter.set_overload(overload(ShedPolicy::RejectNewest));
+        let _held = limiter.acquire("a", None, 0).await.unwrap();
+        let Ok(Reservation::Queued(mut low)) = limiter.reserve("b", None, 0) else {
+            panic!("the first waiter should queue");
+        };
+        assert!(matches!(limiter.reserve("c", None, 5), Err(Refusal::QueueFull)));
+
+        // Under the priority policy only a newcomer that outranks a waiter takes its place
+        limiter.set_overload(overload(ShedPolicy::Priority));
+        assert!(matches!(limiter.reserve("c", None, 0), Err(Refusal::QueueFull)));
+        let Ok(Reservation::Queued(high)) = limiter.reserve("c", None, 5) else {
+            panic!("the urgent request should take the queued one's place");
+        };
+        assert!(low.wait().await.is_none());
+        assert!(low.is_shed() && !low.is_cancelled());
+        assert_eq!(high.position(), 1);
+        // Nothing has finished yet to estimate from
+        assert_eq!(limiter.retry_after(), Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_waiters_give_up_after_max_wait() {
+        let limiter = ConcurrencyLimiter::new(1);
+        limiter.set_overload(OverloadConfig {
+            max_wait_seconds: 1,
+            ..Default::default()
+        });
+        let _held = limiter.acquire("a", None, 0).await.unwrap();
+        let started = Instant::now();
+        let Ok(Reservation::Queued(mut ticket)) = limiter.reserve("b", None, 0) else {
+            panic!("the second request should queue");
+        };
+        assert!(ticket.wait().await.is_none());
+        assert!(ticket.is_shed());
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        drop(ticket);
+        assert_eq!(limiter.queued(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_try_acquire_never_queues() {
+        let limiter = ConcurrencyLimiter::new(2);
+        let background = limiter.try_acquire().unwrap();
+        let held = limiter.acquire("key1", None, 0).await.unwrap();
+        assert!(limiter.try_acquire().is_none());
+
+        // A waiting request keeps background work out even once a slot frees up
+        let waiter = {
+            let limiter = limiter.clone();
+            tokio::spawn(async move { limiter.acquire("key2", None, 0).await })
+        };
+        while limiter.queued() == 0 {
+            tokio::task::yield_now().await;
+        }
+        drop(background);
+        let served = waiter.await.unwrap();
+        assert!(served.is_some());
+        assert!(limiter.try_acquire().is_none());
+
+        drop(held);
+        assert!(limiter.try_acquire().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_limiter_fair_priority_order() {
+        let limiter = ConcurrencyLimiter::new(1);
+        let held = limiter.acquire("batch", None, 0).await.unwrap();
+
+        let (order_tx, mut order_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut waiters = Vec::new();
+        // A deep batch backlog queues first, then one chat request and one urgent request
+        for (key, priority) in [
+            ("batch", 0),
+            ("batch", 0),
+            ("batch", 0),
+            ("chat", 0),
+            ("ops", 5),
+        ] {
+            let expected = limiter.queued() + 1;
+            let waiting = limiter.clone();
+            let order_tx = order_tx.clone();
+            waiters.push(tokio::spawn(async move {
+                let _permit = waiting.acquire(key, None, priority).await.unwrap();
+                order_tx.send(key).unwrap();
+            }));
+            while limiter.queued() < expected {
+                tokio::task::yield_now().await;
+            }
+        }
+
+        drop(held);
+        for waiter in waiters {
+            waiter.await.unwrap();
+        }
+        let mut order = Vec::new();
+        while let Ok(key) = order_rx.try_recv() {
+            order.push(key);
+        }
+        assert_eq!(order, vec!["ops", "batch", "chat", "batch", "batch"]);
+        assert_eq!(limiter.queued(), 0);
+        assert_eq!(limiter.in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_waiter_passes_its_slot_on() {
+        let limiter = ConcurrencyLimiter::new(1);
+        let held = limiter.acquire("a", None, 0).await.unwrap();
+
+        let abandoned = {
+            let limiter = limiter.clone();
+            tokio::spawn(async move { limiter.acquire("b", None, 0).await.is_some() })
+        };
+        while limiter.queued() == 0 {
+            tokio::task::yield_now().await;
+        }
+        let patient = {
+            let limiter = limiter.clone();
+            tokio::spawn(async move { limiter.acquire("c", None, 0).await.is_some() })
+        };
+        while limiter.queued() < 2 {
+            tokio::task::yield_now().await;
+        }
+
+        abandoned.abort();
+        let _ = abandoned.await;
+        drop(held);
+        assert!(patient.await.unwrap());
+        assert_eq!(limiter.in_flight(), 0);
+    }
+
+    fn api_key(key: &str) -> ApiKeyConfig {
+        ApiKeyConfig {
+            key: key.to_string(),
+            name: "test".to_string(),
+            enabled: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_api_key_registry_validation() {
+        let mut expired = api_key("expired");
+        expired.expires_at = Some(Utc::now() - chrono::Duration::seconds(1));
+        let mut disabled = api_key("disabled");
+        disabled.enabled = false;
+        let registry = ApiKeyRegistry::new(vec![api_key("valid"), expired, disabled]);
+
+        assert!(registry.validate("valid").is_ok());
+        assert_eq!(registry.validate("expired").unwrap_err(), ApiKeyError::Expired);
+        assert_eq!(registry.validate("disabled").unwrap_err(), ApiKeyError::Disabled);
+        assert_eq!(registry.validate("missing").unwrap_err(), ApiKeyError::Unknown);
+    }
+
+    #[test]
+    fn test_api_key_rotation_keeps_old_key_in_grace_window() {
+        let registry = ApiKeyRegistry::new(vec![api_key("old")]);
+
+        let (old, new) = registry.rotate("old", Duration::from_secs(60)).unwrap();
+        assert_ne!(new.key, old.key);
+        assert!(old.expires_at.is_some());
+        assert!(registry.validate("old").is_ok());
+        assert!(registry.validate(&new.key).is_ok());
+
+        // Zero grace expires the old key immediately
+        let (_, newer) = registry.rotate(&new.key, Duration::ZERO).unwrap();
+        assert_eq!(registry.validate(&new.key).unwrap_err(), ApiKeyError::Expired);
+        assert!(registry.validate(&newer.key).is_ok());
+    }
+
+    #[test]
+    fn test_configured_keys_win_over_stored_ones() {
+        let mut configured = api_key("old");
+        configured.admin = true;
+        let registry = ApiKeyRegistry::new(vec![configured.clone()]);
+        let (old, new) = registry.rotate("old", Duration::from_secs(60)).unwrap();
+
+        // The key was demoted in the config since it was rotated
+        configured.admin = false;
+        let keys = with_rotated_keys(&[configured], vec![old.clone(), new.clone()]);
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].key, "old");
+        assert!(!keys[0].admin);
+        assert_eq!(keys[0].expires_at, old.expires_at);
+        assert_eq!(keys[1], new);
+    }
 }
@@ -1,25 +1,65 @@
+use crate::config::{parse_device, PersonaConfig, AUTO_DEVICE, VALID_DEVICES};
+use crate::engine::{Placement, PrefillSender};
+use crate::eval::{EvalItem, MatchMode};
+use crate::feedback::Rating;
+use crate::guardrails::Refusal;
+use crate::jobs::JobOutput;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
 use std::path::PathBuf;
+use utoipa::ToSchema;
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, ToSchema)]
 pub struct ChatMessage {
     pub role: String,
     pub content: String,
+    /// An assistant reply cut short, e.g. by a WebSocket `stop` message
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+    /// An assistant reply interrupted by an engine error or a client disconnect
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub partial: bool,
+    /// When the message was added to a session; absent for client-side and older history
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<DateTime<Utc>>,
+    /// `continue`: have the model carry on this (last, assistant) message instead of starting a
+    /// new turn, e.g. after a reply was cut off at `max_token`
+    #[serde(default, rename = "continue", skip_serializing_if = "std::ops::Not::not")]
+    pub continues: bool,
 }
 
 /// Inference request from original parse::Args
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 #[serde(rename_all = "kebab-case")]
 pub struct InferenceRequest {
+    /// Optional when the API key has a `default_model`
+    #[serde(default)]
     pub model_name: String,
+    #[schema(value_type = Option<String>)]
     pub model_dir: Option<PathBuf>,
+    /// Optional when `template` is set; the rendered template replaces it
+    #[serde(default)]
     pub prompt: String,
+    /// Full conversation to send instead of `prompt`. Chat requests that carry only `messages`
+    /// are taken as caller-managed history and bypass server-side sessions.
     #[serde(default)]
     pub messages: Option<Vec<ChatMessage>>,
     #[serde(default)]
     pub session_id: Option<String>,
+    /// Start a new session with a generated id when `session_id` is not given
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub create_session: bool,
+    /// `false` answers `/chat/completions` with one JSON body holding the whole reply instead
+    /// of server-sent events; WebSockets always stream
+    #[serde(default = "default_stream")]
+    pub stream: bool,
     #[serde(default = "default_max_token")]
     pub max_token: usize,
+    /// Ignore `max_token` and allow whatever the model's context leaves after the prompt
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub auto_fit: bool,
     #[serde(default = "default_temperature")]
     pub temperature: f64,
     #[serde(default = "default_top_p")]
@@ -32,15 +72,105 @@ pub struct InferenceRequest {
     pub stop: Vec<String>,
     #[serde(default = "default_device")]
     pub device: String,
+    /// Refuse to run on another device than `device` instead of falling back
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub strict_device: bool,
+    /// Queue priority when waiting for a generation slot; higher is served first
+    #[serde(default)]
+    pub priority: Option<i32>,
+    /// Condense older turns through the model when the conversation nears the context limit
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub compress_history: bool,
+    /// Saved prompt template to render into `prompt`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    /// Values for the template's `{{variables}}`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub variables: HashMap<String, Value>,
+    /// Persona preset from `[personas]`: its system prompt and default parameters
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub persona: Option<String>,
+    /// Receives the engine's prompt processing progress; set by the server for streams that
+    /// show it, never read from or written to JSON
+    #[serde(skip)]
+    pub prefill: Option<PrefillSender>,
+    /// Checks the device of the model copy the engine picked before it generates; set by the
+    /// server, never read from or written to JSON
+    #[serde(skip)]
+    pub placement: Option<Placement>,
+    /// Sampling parameters the client set itself; filled in when a [`ClientRequest`] is parsed,
+    /// never read from or written to JSON
+    #[serde(skip)]
+    pub sent: SentParams,
+}
+
+/// Sampling parameters present in a request body, whatever their value
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SentParams {
+    pub max_token: Option<usize>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub top_k: Option<i32>,
+    pub repeat_penalty: Option<f32>,
+    pub stop: Option<Vec<String>>,
+}
+
+impl InferenceRequest {
+    /// Take `persona`'s parameters wherever the client did not set its own
+    pub fn apply_persona(&mut self, persona: &PersonaConfig) {
+        let sent = &self.sent;
+        if let Some(max_tokens) = persona.max_tokens.filter(|_| sent.max_token.is_none()) {
+            self.max_token = max_tokens;
+        }
+        if let Some(temperature) = persona.temperature.filter(|_| sent.temperature.is_none()) {
+            self.temperature = temperature;
+        }
+        if let Some(top_p) = persona.top_p.filter(|_| sent.top_p.is_none()) {
+            self.top_p = top_p;
+        }
+        if let Some(top_k) = persona.top_k.filter(|_| sent.top_k.is_none()) {
+            self.top_k = top_k;
+        }
+        if let Some(penalty) = persona.repeat_penalty.filter(|_| sent.repeat_penalty.is_none()) {
+            self.repeat_penalty = penalty;
+        }
+        if sent.stop.is_none() {
+            self.stop = persona.stop.clone();
+        }
+    }
+}
+
+/// An [`InferenceRequest`] as a client sent it, with [`InferenceRequest::sent`] recorded
+#[derive(Debug, Clone)]
+pub struct ClientRequest(pub InferenceRequest);
+
+impl<'de> Deserialize<'de> for ClientRequest {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        let body = Value::deserialize(deserializer)?;
+        let sent = SentParams::deserialize(&body).map_err(D::Error::custom)?;
+        let mut request = InferenceRequest::deserialize(body).map_err(D::Error::custom)?;
+        request.sent = sent;
+        Ok(Self(request))
+    }
 }
 
 /// Completion request (non-chat, raw completion)
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 pub struct CompletionRequest {
+    /// Optional when the API key has a `default_model`
+    #[serde(default)]
     pub model: String,
+    /// Optional when `template` is set; the rendered template replaces it
+    #[serde(default)]
     pub prompt: String,
     #[serde(default = "default_max_token")]
     pub max_tokens: usize,
+    /// Ignore `max_tokens` and allow whatever the model's context leaves after the prompt
+    #[serde(default)]
+    pub auto_fit: bool,
     #[serde(default = "default_temperature")]
     pub temperature: f64,
     #[serde(default = "default_top_p")]
@@ -49,6 +179,288 @@ pub struct CompletionRequest {
     pub stop: Vec<String>,
     #[serde(default)]
     pub stream: bool,
+    /// When set, the request is accepted immediately and the result is POSTed here
+    #[serde(default)]
+    pub callback_url: Option<String>,
+    /// Run as a background job writing its text to this output, e.g. `{"type": "file"}`
+    #[serde(default)]
+    pub output: Option<JobOutput>,
+    /// Refuse to run on another device than `models.default_device` instead of falling back
+    #[serde(default)]
+    pub strict_device: bool,
+    /// Queue priority when waiting for a generation slot; higher is served first
+    #[serde(default)]
+    pub priority: Option<i32>,
+    /// Saved prompt template to render into `prompt`
+    #[serde(default)]
+    pub template: Option<String>,
+    /// Values for the template's `{{variables}}`
+    #[serde(default)]
+    pub variables: HashMap<String, Value>,
+}
+
+/// Body of `POST /eval`: `items`, or the name of a dataset in `eval.dataset_dir`
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct EvalRequest {
+    /// Optional when the API key has a `default_model`
+    #[serde(default)]
+    pub model: String,
+    #[serde(default)]
+    pub items: Vec<EvalItem>,
+    #[serde(default)]
+    pub dataset: Option<String>,
+    #[serde(default = "default_max_token")]
+    pub max_tokens: usize,
+    #[serde(default = "default_temperature")]
+    pub temperature: f64,
+    #[serde(default = "default_top_p")]
+    pub top_p: f64,
+    #[serde(default)]
+    pub stop: Vec<String>,
+    /// Items generated at once; capped by `eval.max_concurrency`
+    #[serde(default)]
+    pub concurrency: Option<usize>,
+    #[serde(default)]
+    pub match_mode: MatchMode,
+}
+
+/// Body of `POST /tokenize`
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct TokenizeRequest {
+    /// Model name, id or alias; the API key's `default_model` when empty
+    #[serde(default)]
+    pub model: String,
+    pub text: String,
+    /// Add the tokenizer's special tokens (e.g. BOS), as a raw prompt would get them
+    #[serde(default)]
+    pub add_special_tokens: bool,
+}
+
+/// Body of `POST /templates` and `PUT /templates/:name`
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct TemplateRequest {
+    /// Required for POST; must match the URL for PUT when given
+    #[serde(default)]
+    pub name: Option<String>,
+    pub template: String,
+    #[serde(default)]
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct FeedbackRequest {
+    pub rating: Rating,
+    #[serde(default)]
+    pub comment: Option<String>,
+}
+
+/// Roles accepted in a caller-supplied conversation
+pub const MESSAGE_ROLES: [&str; 3] = ["system", "user", "assistant"];
+
+/// Check a caller-supplied conversation before it reaches the model
+pub fn validate_messages(messages: &[ChatMessage]) -> Result<(), String> {
+    if messages.is_empty() {
+        return Err("messages must not be empty".to_string());
+    }
+    for (i, message) in messages.iter().enumerate() {
+        if !MESSAGE_ROLES.contains(&message.role.as_str()) {
+            return Err(format!(
+                "messages[{}].role must be one of {} (got '{}')",
+                i,
+                MESSAGE_ROLES.join(", "),
+                message.role
+            ));
+        }
+        if message.continues && i + 1 != messages.len() {
+            return Err(format!("messages[{}].continue is only allowed on the last message", i));
+        }
+        if message.continues && message.role != "assistant" {
+            return Err(format!("messages[{}].continue is only allowed on an assistant message", i));
+        }
+    }
+    Ok(())
+}
+
+/// Query of `GET /chat/history/:session_id`, for loading a long conversation piece by piece.
+/// `from` and `to` are positions in the whole history (`to` exclusive), so they stay valid
+/// whichever roles are asked for.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct HistoryQuery {
+    /// Comma-separated roles to include, e.g. `user,assistant`
+    #[serde(default)]
+    pub roles: Option<String>,
+    #[serde(default)]
+    pub from: Option<usize>,
+    #[serde(default)]
+    pub to: Option<usize>,
+    /// Most messages returned, counted from the start of `order`
+    #[serde(default)]
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub order: HistoryOrder,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HistoryOrder {
+    #[default]
+    Asc,
+    /// Newest first; with `limit`, the latest messages
+    Desc,
+}
+
+impl HistoryQuery {
+    /// The selected messages with their positions in `history`
+    pub fn select<'a>(&self, history: &'a [ChatMessage]) -> Result<Vec<(usize, &'a ChatMessage)>, String> {
+        let roles = match &self.roles {
+            Some(roles) => {
+                let roles: Vec<&str> = roles.split(',').map(str::trim).filter(|r| !r.is_empty()).collect();
+                if let Some(role) = roles.iter().find(|r| !MESSAGE_ROLES.contains(r)) {
+                    return Err(format!("roles must be among {} (got '{}')", MESSAGE_ROLES.join(", "), role));
+                }
+                Some(roles)
+            }
+            None => None,
+        };
+        let from = self.from.unwrap_or(0);
+        let to = self.to.unwrap_or(history.len());
+        if from > to {
+            return Err(format!("from ({}) must not be greater than to ({})", from, to));
+        }
+        let range = from.min(history.len())..to.min(history.len());
+        let mut selected: Vec<(usize, &ChatMessage)> = history[range.clone()]
+            .iter()
+            .zip(range)
+            .filter(|(message, _)| match &roles {
+                Some(roles) => roles.contains(&message.role.as_str()),
+                None => true,
+            })
+            .map(|(message, index)| (index, message))
+            .collect();
+        if self.order == HistoryOrder::Desc {
+            selected.reverse();
+        }
+        if let Some(limit) = self.limit {
+            selected.truncate(limit);
+        }
+        Ok(selected)
+    }
+}
+
+/// Parameters a generation actually runs with, after server-side limits and engine fallbacks.
+/// Returned as JSON in the `X-Effective-Params` response header.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EffectiveParams {
+    pub model: String,
+    pub max_tokens: usize,
+    pub temperature: f64,
+    /// `1.0` (nucleus sampling off) unless the request asked for a value in `[0, 1)`
+    pub top_p: f64,
+    pub top_k: i32,
+    pub repeat_penalty: f32,
+    pub device: String,
+    pub priority: i32,
+    /// Parameters that differ from what the request asked for
+    pub adjusted: Vec<&'static str>,
+    /// Guardrails that redacted the prompt or messages
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub guardrails: Vec<Refusal>,
+}
+
+impl EffectiveParams {
+    /// `req` carries the clamped `max_token`; `requested_max_tokens` is the caller's value and
+    /// `priority` the queue priority after the key's cap
+    pub fn new(req: &InferenceRequest, requested_max_tokens: usize, priority: i32) -> Self {
+        let mut adjusted = Vec::new();
+        if req.max_token != requested_max_tokens {
+            adjusted.push("max_tokens");
+        }
+        let top_p = if (0.0..1.0).contains(&req.top_p) {
+            req.top_p
+        } else {
+            if req.top_p != 1.0 {
+                adjusted.push("top_p");
+            }
+            1.0
+        };
+        if req.top_k < 0 {
+            adjusted.push("top_k");
+        }
+        // The engine builds unknown devices on the CPU
+        let device = req.device.to_lowercase();
+        let device = if VALID_DEVICES.contains(&device.as_str()) || device == AUTO_DEVICE {
+            device
+        } else {
+            adjusted.push("device");
+            "cpu".to_string()
+        };
+        if req.priority.is_some_and(|p| p != priority) {
+            adjusted.push("priority");
+        }
+        Self {
+            model: req.model_name.clone(),
+            max_tokens: req.max_token,
+            temperature: req.temperature,
+            top_p,
+            top_k: req.top_k.max(0),
+            repeat_penalty: req.repeat_penalty,
+            device,
+            priority,
+            adjusted,
+            guardrails: Vec::new(),
+        }
+    }
+
+    /// Record the guardrails that redacted the prompt; it then counts as adjusted
+    pub fn redacted(&mut self, guardrails: Vec<Refusal>) {
+        if !guardrails.is_empty() {
+            self.adjusted.push("prompt");
+        }
+        self.guardrails = guardrails;
+    }
+
+    /// Record the device the engine loaded the model on, when it reports one. Returns the
+    /// fallback when that is not the requested kind of device; a copy on another ordinal
+    /// (`cuda:1` for `cuda`) is not one, and any device is what `auto` asked for.
+    pub fn ran_on(&mut self, device: Option<String>) -> Option<DeviceFallback> {
+        let device = device.filter(|d| *d != self.device)?;
+        let kind = |device: &str| parse_device(device).map(|(kind, _)| kind);
+        if self.device == AUTO_DEVICE || kind(&self.device).is_some_and(|k| kind(&device) == Some(k)) {
+            self.device = device;
+            return None;
+        }
+        let requested = std::mem::replace(&mut self.device, device.clone());
+        if !self.adjusted.contains(&"device") {
+            self.adjusted.push("device");
+        }
+        Some(DeviceFallback { requested, device })
+    }
+}
+
+/// A generation that runs on another device than the request asked for
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DeviceFallback {
+    pub requested: String,
+    pub device: String,
+}
+
+impl DeviceFallback {
+    pub fn message(&self) -> String {
+        format!(
+            "requested device '{}' is not available; running on '{}'",
+            self.requested, self.device
+        )
+    }
+
+    /// Structured warning sent along with the response
+    pub fn warning(&self) -> Value {
+        serde_json::json!({
+            "code": "device_fallback",
+            "message": self.message(),
+            "requested": self.requested,
+            "device": self.device,
+        })
+    }
 }
 
 fn default_max_token() -> usize {
@@ -66,12 +478,15 @@ fn default_top_k() -> i32 {
 fn default_repeat_penalty() -> f32 {
     1.0
 }
+fn default_stream() -> bool {
+    true
+}
 fn default_device() -> String {
     "cpu".to_string()
 }
 
 /// standard API return model list pack
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct ModelsList {
     pub models: Vec<String>,
 }
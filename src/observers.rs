@@ -0,0 +1,140 @@
+//! Read-only observers of session generations (`GET /chat/ws/observe/:session_id`).
+//!
+//! Every chat generation tied to a session (`/chat/completions`, `/chat/ws` and regenerations)
+//! publishes its tokens on a broadcast channel keyed by the session id while it runs. A second
+//! client, such as a supervisor UI, can subscribe to it: it first gets the text generated so far
+//! and then the same tokens as the client that asked. The channel goes away with the generation.
+
+use dashmap::DashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+/// Messages buffered per observer before a slow one is cut off
+const CHANNEL_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Observed {
+    Token(String),
+    Error(String),
+    Done { finish_reason: String },
+}
+
+struct Generation {
+    id: u64,
+    sender: broadcast::Sender<Observed>,
+    // Everything generated so far; held while sending so a new observer sees each token once
+    text: Mutex<String>,
+}
+
+/// In-progress session generations that observers can attach to
+#[derive(Default)]
+pub struct SessionObservers {
+    generations: DashMap<String, Arc<Generation>>,
+    next_id: AtomicU64,
+}
+
+impl SessionObservers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start broadcasting a generation for `session_id`; observers attach to the newest one
+    pub fn publish(self: &Arc<Self>, session_id: &str) -> Broadcast {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        let generation = Arc::new(Generation {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            sender,
+            text: Mutex::new(String::new()),
+        });
+        self.generations.insert(session_id.to_string(), generation.clone());
+        Broadcast {
+            observers: self.clone(),
+            session_id: session_id.to_string(),
+            generation,
+        }
+    }
+
+    /// The text generated so far for `session_id` and a receiver for the rest, if a generation
+    /// is in progress
+    pub fn subscribe(&self, session_id: &str) -> Option<(String, broadcast::Receiver<Observed>)> {
+        let generation = self.generations.get(session_id)?.clone();
+        let text = generation.text.lock().unwrap();
+        Some((text.clone(), generation.sender.subscribe()))
+    }
+}
+
+/// Publishing end of one generation; observers see the channel close when it is dropped
+pub struct Broadcast {
+    observers: Arc<SessionObservers>,
+    session_id: String,
+    generation: Arc<Generation>,
+}
+
+impl Broadcast {
+    pub fn token(&self, text: &str) {
+        let mut so_far = self.generation.text.lock().unwrap();
+        so_far.push_str(text);
+        if self.generation.sender.receiver_count() > 0 {
+            let _ = self.generation.sender.send(Observed::Token(text.to_string()));
+        }
+    }
+
+    pub fn error(&self, message: &str) {
+        let _ = self.generation.sender.send(Observed::Error(message.to_string()));
+    }
+
+    pub fn finish(&self, finish_reason: &str) {
+        let _ = self.generation.sender.send(Observed::Done {
+            finish_reason: finish_reason.to_string(),
+        });
+    }
+}
+
+impl Drop for Broadcast {
+    fn drop(&mut self) {
+        // A newer generation for the same session may have replaced this one
+        let id = self.generation.id;
+        self.observers
+            .generations
+            .remove_if(&self.session_id, |_, generation| generation.id == id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn late_observers_catch_up_then_follow() {
+        let observers = Arc::new(SessionObservers::new());
+        assert!(observers.subscribe("s1").is_none());
+
+        let broadcast = observers.publish("s1");
+        broadcast.token("Hello");
+        let (so_far, mut receiver) = observers.subscribe("s1").unwrap();
+        assert_eq!(so_far, "Hello");
+
+        broadcast.token(" world");
+        broadcast.finish("stop");
+        assert_eq!(receiver.recv().await.unwrap(), Observed::Token(" world".to_string()));
+        assert_eq!(
+            receiver.recv().await.unwrap(),
+            Observed::Done { finish_reason: "stop".to_string() }
+        );
+
+        drop(broadcast);
+        assert!(observers.subscribe("s1").is_none());
+        assert!(matches!(receiver.recv().await, Err(broadcast::error::RecvError::Closed)));
+    }
+
+    #[test]
+    fn an_old_generation_does_not_remove_its_successor() {
+        let observers = Arc::new(SessionObservers::new());
+        let first = observers.publish("s1");
+        let second = observers.publish("s1");
+        drop(first);
+        second.token("still here");
+        assert_eq!(observers.subscribe("s1").unwrap().0, "still here");
+    }
+}
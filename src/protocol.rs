@@ -0,0 +1,243 @@
+//! Streaming protocol versions.
+//!
+//! Clients pick the format of streamed responses with an `X-Protocol-Version` header (SSE and
+//! the WebSocket upgrade) or a WebSocket `hello` message. Version 1, the default, is the legacy
+//! format: raw tokens as `data:` lines or text frames, `__ERROR__:` prefixed errors, and a few
+//! named SSE events. Version 2 sends every frame as a JSON object with a `type` (`session`,
+//! `queue`, `warning`, `prefill`, `token`, `usage`, `error`, `refused`, `done`), which leaves
+//! room for new frame types without breaking clients that ignore types they do not know. A
+//! client asking for a newer version than the server speaks gets the newest one it has; the
+//! response header (or `hello` reply) says which.
+//!
+//! On either version a WebSocket client can send `{"type": "stop"}` while a generation runs to
+//! stop it; version 2 sockets then get a `stopped` frame, legacy ones a close frame.
+
+use crate::guardrails::Refusal;
+use axum::http::HeaderMap;
+use axum::response::sse::Event;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+pub const HEADER: &str = "x-protocol-version";
+
+/// Versions this server speaks, oldest first
+pub const SUPPORTED: &[u32] = &[1, 2];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Protocol {
+    /// Raw tokens and `__ERROR__:` strings
+    #[default]
+    Legacy,
+    /// Typed JSON frames
+    Typed,
+}
+
+/// One piece of a streamed response, before it is encoded for a protocol version
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame<'a> {
+    Session(&'a str),
+    Queue(Value),
+    Warning(Value),
+    /// Prompt tokens the engine has read before the first token
+    Prefill {
+        processed: usize,
+        total: usize,
+    },
+    Token(&'a str),
+    Usage {
+        prompt_tokens: u64,
+        completion_tokens: u64,
+    },
+    Error(&'a str),
+    /// A guardrail refused the request; legacy clients get it as an error
+    Refused(&'a Refusal),
+    /// The client stopped the generation
+    Stopped {
+        completion_tokens: u64,
+    },
+    Done {
+        finish_reason: &'a str,
+    },
+}
+
+/// First WebSocket message of a client that negotiates a version
+#[derive(Debug, Deserialize)]
+pub struct Hello {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub protocol_version: u32,
+}
+
+impl Protocol {
+    /// The newest version not above `requested`; version 0 does not exist
+    pub fn negotiate(requested: u32) -> Result<Self, String> {
+        match requested {
+            0 => Err(format!(
+                "unsupported protocol version 0; supported versions are {:?}",
+                SUPPORTED
+            )),
+            1 => Ok(Protocol::Legacy),
+            _ => Ok(Protocol::Typed),
+        }
+    }
+
+    /// Version asked for in the `X-Protocol-Version` header, legacy when there is none
+    pub fn from_headers(headers: &HeaderMap) -> Result<Self, String> {
+        let Some(value) = headers.get(HEADER) else {
+            return Ok(Protocol::Legacy);
+        };
+        let requested = value
+            .to_str()
+            .ok()
+            .and_then(|v| v.trim().parse().ok())
+            .ok_or_else(|| format!("{} must be a version number", HEADER))?;
+        Self::negotiate(requested)
+    }
+
+    pub fn version(self) -> u32 {
+        match self {
+            Protocol::Legacy => 1,
+            Protocol::Typed => 2,
+        }
+    }
+
+    // Typed JSON encoding of a frame
+    fn typed(frame: &Frame) -> Value {
+        match frame {
+            Frame::Session(id) => json!({"type": "session", "session_id": id}),
+            Frame::Queue(status) => with_type("queue", status),
+            Frame::Warning(warning) => with_type("warning", warning),
+            Frame::Prefill { processed, total } => {
+                json!({"type": "prefill", "processed": processed, "total": total})
+            }
+            Frame::Token(text) => json!({"type": "token", "text": text}),
+            Frame::Usage {
+                prompt_tokens,
+                completion_tokens,
+            } => json!({
+                "type": "usage",
+                "prompt_tokens": prompt_tokens,
+                "completion_tokens": completion_tokens,
+            }),
+            Frame::Error(message) => json!({"type": "error", "message": message}),
+            Frame::Refused(refusal) => with_type("refused", &json!(refusal)),
+            Frame::Stopped { completion_tokens } => json!({"type": "stopped", "completion_tokens": completion_tokens}),
+            Frame::Done { finish_reason } => json!({"type": "done", "finish_reason": finish_reason}),
+        }
+    }
+
+    /// SSE event for a frame, if this version has one
+    pub fn event(self, frame: Frame) -> Option<Event> {
+        match self {
+            Protocol::Typed => Some(Event::default().data(Self::typed(&frame).to_string())),
+            Protocol::Legacy => match frame {
+                Frame::Session(id) => Some(Event::default().event("session").data(id)),
+                Frame::Queue(status) => Some(Event::default().event("queue").data(status.to_string())),
+                Frame::Warning(warning) => Some(Event::default().event("warning").data(warning.to_string())),
+                Frame::Prefill { processed, total } => Some(
+                    Event::default()
+                        .event("prefill")
+                        .data(json!({ "processed": processed, "total": total }).to_string()),
+                ),
+                Frame::Token(text) => Some(Event::default().data(text)),
+                Frame::Error(message) => Some(Event::default().data(format!("__ERROR__:{}", message))),
+                Frame::Refused(refusal) => Some(Event::default().data(format!("__ERROR__:{}", refusal.message))),
+                // Legacy streams only mark the end when the model did not finish by itself
+                Frame::Done {
+                    finish_reason: "time_limit",
+                } => Some(
+                    Event::default()
+                        .event("finish")
+                        .data(json!({ "finish_reason": "time_limit" }).to_string()),
+                ),
+                Frame::Usage { .. } | Frame::Stopped { .. } | Frame::Done { .. } => None,
+            },
+        }
+    }
+
+    /// Encoded SSE queue updates and errors, for the stream a queued request waits in
+    pub fn sse_bytes(self, frame: Frame) -> Option<String> {
+        match self {
+            Protocol::Typed => Some(format!("data:{}\n\n", Self::typed(&frame))),
+            Protocol::Legacy => match frame {
+                Frame::Queue(status) => Some(format!("event:queue\ndata:{}\n\n", status)),
+                Frame::Error(message) => Some(format!("data:__ERROR__:{}\n\n", message)),
+                _ => None,
+            },
+        }
+    }
+
+    /// WebSocket text frame for a frame, if this version has one. Legacy sockets only carry
+    /// tokens and errors; the end of a time-limited generation is a close frame there.
+    pub fn ws_text(self, frame: Frame) -> Option<String> {
+        match self {
+            Protocol::Typed => Some(Self::typed(&frame).to_string()),
+            Protocol::Legacy => match frame {
+                Frame::Token(text) => Some(text.to_string()),
+                Frame::Error(message) => Some(format!("__ERROR__:{}", message)),
+                Frame::Refused(refusal) => Some(format!("__ERROR__:{}", refusal.message)),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Whether a WebSocket message is a `stop` request
+pub fn is_stop(text: &str) -> bool {
+    serde_json::from_str::<Value>(text)
+        .ok()
+        .is_some_and(|message| message["type"] == "stop")
+}
+
+fn with_type(kind: &str, fields: &Value) -> Value {
+    let mut value = json!({"type": kind});
+    if let (Some(out), Some(fields)) = (value.as_object_mut(), fields.as_object()) {
+        for (key, field) in fields {
+            out.insert(key.clone(), field.clone());
+        }
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn negotiates_down_to_the_newest_supported_version() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(Protocol::from_headers(&headers), Ok(Protocol::Legacy));
+        headers.insert(HEADER, HeaderValue::from_static("2"));
+        assert_eq!(Protocol::from_headers(&headers), Ok(Protocol::Typed));
+        headers.insert(HEADER, HeaderValue::from_static("7"));
+        assert_eq!(Protocol::from_headers(&headers).unwrap().version(), 2);
+        headers.insert(HEADER, HeaderValue::from_static("0"));
+        assert!(Protocol::from_headers(&headers).is_err());
+        headers.insert(HEADER, HeaderValue::from_static("latest"));
+        assert!(Protocol::from_headers(&headers).is_err());
+    }
+
+    #[test]
+    fn frames_per_version() {
+        assert_eq!(Protocol::Legacy.ws_text(Frame::Token("hi")).as_deref(), Some("hi"));
+        assert_eq!(
+            Protocol::Legacy.ws_text(Frame::Error("boom")).as_deref(),
+            Some("__ERROR__:boom")
+        );
+        assert_eq!(Protocol::Legacy.ws_text(Frame::Done { finish_reason: "stop" }), None);
+        assert_eq!(Protocol::Legacy.ws_text(Frame::Stopped { completion_tokens: 3 }), None);
+        assert!(is_stop(r#"{"type": "stop"}"#));
+        assert!(!is_stop("stop"));
+        let token: Value = serde_json::from_str(&Protocol::Typed.ws_text(Frame::Token("hi")).unwrap()).unwrap();
+        assert_eq!(token, json!({"type": "token", "text": "hi"}));
+        let queue = Frame::Queue(json!({"position": 2}));
+        let typed = Protocol::Typed.sse_bytes(queue.clone()).unwrap();
+        let typed: Value = serde_json::from_str(typed.strip_prefix("data:").unwrap().trim_end()).unwrap();
+        assert_eq!(typed, json!({"type": "queue", "position": 2}));
+        assert_eq!(
+            Protocol::Legacy.sse_bytes(queue).as_deref(),
+            Some("event:queue\ndata:{\"position\":2}\n\n")
+        );
+    }
+}
@@ -0,0 +1,168 @@
+//! Request log for `/completions` (`[request_log]`).
+//!
+//! With logging on, every `/completions` call is saved to the session database with its prompt,
+//! sampling parameters, response and token usage, apart from chat sessions, so one-off
+//! generations can be audited and replayed. The entry id is returned in `X-Request-Log-Id`, and
+//! entries older than `request_log.retention_days` are pruned in the background.
+
+use crate::guardrails::Refusal;
+use crate::models::CompletionRequest;
+use crate::state::CHARS_PER_TOKEN;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Most entries one query returns
+pub const MAX_QUERY_LIMIT: usize = 500;
+
+/// Sampling parameters a completion ran with, enough to replay it
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct LoggedParams {
+    pub max_tokens: usize,
+    pub temperature: f64,
+    pub top_p: f64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RequestLogEntry {
+    pub id: String,
+    pub created_at: DateTime<Utc>,
+    pub account: String,
+    pub model: String,
+    pub prompt: String,
+    pub params: LoggedParams,
+    pub response: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finish_reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Estimated from the prompt length
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub duration_seconds: f64,
+    /// Answered from the response cache
+    #[serde(default)]
+    pub cached: bool,
+    #[serde(default)]
+    pub stream: bool,
+    /// Guardrail that refused the request; nothing was generated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refusal: Option<Refusal>,
+    /// Guardrails that redacted the prompt before it ran
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub guardrails: Vec<Refusal>,
+}
+
+impl RequestLogEntry {
+    /// Entry for a request about to run with `max_tokens` (after clamping)
+    pub fn new(account: &str, req: &CompletionRequest, max_tokens: usize) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            created_at: Utc::now(),
+            account: account.to_string(),
+            model: req.model.clone(),
+            prompt: req.prompt.clone(),
+            params: LoggedParams {
+                max_tokens,
+                temperature: req.temperature,
+                top_p: req.top_p,
+                stop: req.stop.clone(),
+            },
+            response: String::new(),
+            finish_reason: None,
+            error: None,
+            prompt_tokens: req.prompt.chars().count().div_ceil(CHARS_PER_TOKEN) as u64,
+            completion_tokens: 0,
+            duration_seconds: 0.0,
+            cached: false,
+            stream: req.stream,
+            refusal: None,
+            guardrails: Vec::new(),
+        }
+    }
+
+    /// Record that a guardrail refused the request
+    pub fn refuse(mut self, refusal: Refusal) -> Self {
+        self.error = Some(refusal.message.clone());
+        self.refusal = Some(refusal);
+        self
+    }
+
+    /// Record what was generated and how it ended: the finish reason, or the error that
+    /// stopped it (keeping the text generated before it)
+    pub fn finish(mut self, response: String, tokens: u64, outcome: Result<&str, String>, duration: f64) -> Self {
+        self.response = response;
+        self.completion_tokens = tokens;
+        match outcome {
+            Ok(finish_reason) => self.finish_reason = Some(finish_reason.to_string()),
+            Err(error) => self.error = Some(error),
+        }
+        self.duration_seconds = duration;
+        self
+    }
+
+    /// The original request, to run again
+    pub fn replay_request(&self) -> CompletionRequest {
+        CompletionRequest {
+            model: self.model.clone(),
+            prompt: self.prompt.clone(),
+            max_tokens: self.params.max_tokens,
+            auto_fit: false,
+            temperature: self.params.temperature,
+            top_p: self.params.top_p,
+            stop: self.params.stop.clone(),
+            stream: false,
+            callback_url: None,
+            output: None,
+            strict_device: false,
+            priority: None,
+            template: None,
+            variables: Default::default(),
+        }
+    }
+}
+
+/// Filters for `GET /admin/requests`; newest entries first
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RequestLogQuery {
+    pub account: Option<String>,
+    pub model: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub limit: Option<usize>,
+}
+
+impl RequestLogQuery {
+    pub fn limit(&self) -> usize {
+        self.limit.unwrap_or(100).clamp(1, MAX_QUERY_LIMIT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_replay_with_their_logged_params() {
+        let req: CompletionRequest = serde_json::from_value(serde_json::json!({
+            "model": "qwen",
+            "prompt": "Write a haiku",
+            "max_tokens": 4096,
+            "temperature": 0.2,
+            "stream": true,
+        }))
+        .unwrap();
+        let entry = RequestLogEntry::new("ci", &req, 2048).finish("Leaves".into(), 1, Ok("stop"), 0.5);
+        assert_eq!(entry.prompt_tokens, 4);
+        assert!(entry.stream);
+
+        let replay = entry.replay_request();
+        assert_eq!(replay.max_tokens, 2048);
+        assert_eq!(replay.temperature, 0.2);
+        assert!(!replay.stream);
+
+        let stored: RequestLogEntry = serde_json::from_str(&serde_json::to_string(&entry).unwrap()).unwrap();
+        assert_eq!(stored, entry);
+    }
+}
@@ -0,0 +1,64 @@
+//! Request count, status class and latency for every route (`http_requests_total`,
+//! `http_request_duration_seconds`).
+//!
+//! Added as the outermost route layer, so every endpoint is covered without counters of its own,
+//! including requests the auth and rate-limit middleware turn away. The `route` label is the
+//! route template (`/chat/history/:session_id`) rather than the raw path, which keeps the label
+//! set bounded. Latency runs until the response head is ready: for a stream that is the time to
+//! its first event, and generation timings come from the per-token metrics.
+
+use axum::body::Body;
+use axum::extract::MatchedPath;
+use axum::http::{Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use metrics::{histogram, increment_counter};
+use std::time::Instant;
+
+pub async fn record(req: Request<Body>, next: Next<Body>) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", |path| path.as_str())
+        .to_string();
+    let method = req.method().to_string();
+    let start = Instant::now();
+    let response = next.run(req).await;
+    increment_counter!(
+        "http_requests_total",
+        "method" => method.clone(),
+        "route" => route.clone(),
+        "status" => status_class(response.status())
+    );
+    histogram!(
+        "http_request_duration_seconds",
+        start.elapsed().as_secs_f64(),
+        "method" => method,
+        "route" => route
+    );
+    response
+}
+
+/// `2xx`, `4xx` and so on
+pub fn status_class(status: StatusCode) -> &'static str {
+    match status.as_u16() {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        _ => "5xx",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statuses_are_grouped_by_class() {
+        assert_eq!(status_class(StatusCode::OK), "2xx");
+        assert_eq!(status_class(StatusCode::NO_CONTENT), "2xx");
+        assert_eq!(status_class(StatusCode::TOO_MANY_REQUESTS), "4xx");
+        assert_eq!(status_class(StatusCode::SERVICE_UNAVAILABLE), "5xx");
+    }
+}
@@ -0,0 +1,246 @@
+//! Response cache for non-streaming `/completions`.
+//!
+//! Exact hits need the same account, model, sampling parameters and prompt. With
+//! `cache.semantic_threshold` set and an engine that can embed text, a miss falls back to the
+//! most similar cached prompt for the same model and parameters, provided its cosine
+//! similarity reaches the threshold.
+
+use crate::config::CacheConfig;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedResponse {
+    pub text: String,
+    pub tokens: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CacheHit {
+    Exact(CachedResponse),
+    Semantic {
+        response: CachedResponse,
+        similarity: f32,
+    },
+}
+
+impl CacheHit {
+    pub fn response(&self) -> &CachedResponse {
+        match self {
+            CacheHit::Exact(response) | CacheHit::Semantic { response, .. } => response,
+        }
+    }
+}
+
+struct Entry {
+    scope: String,
+    embedding: Option<Vec<f32>>,
+    response: CachedResponse,
+    inserted: Instant,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<String, Entry>,
+    // insertion order, oldest first, for eviction
+    order: VecDeque<String>,
+}
+
+/// Bounded cache of finished completions. The config is passed on every call so reloads
+/// apply immediately.
+#[derive(Default)]
+pub struct ResponseCache {
+    inner: Mutex<Inner>,
+}
+
+/// Requests only share cached answers when they come from the same account and model and
+/// sampling parameters match
+pub fn cache_scope(
+    account: &str,
+    model: &str,
+    max_tokens: usize,
+    temperature: f64,
+    top_p: f64,
+    stop: &[String],
+) -> String {
+    format!(
+        "{:?}|{}|{}|{}|{}|{:?}",
+        account, model, max_tokens, temperature, top_p, stop
+    )
+}
+
+fn entry_key(scope: &str, prompt: &str) -> String {
+    format!("{}\u{0}{}", scope, prompt)
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let denom = norm(a) * norm(b);
+    if denom == 0.0 {
+        0.0
+    } else {
+        dot / denom
+    }
+}
+
+impl ResponseCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn lookup(
+        &self,
+        config: &CacheConfig,
+        scope: &str,
+        prompt: &str,
+        embedding: Option<&[f32]>,
+    ) -> Option<CacheHit> {
+        if !config.enabled {
+            return None;
+        }
+        let ttl = Duration::from_secs(config.ttl_seconds);
+        let mut inner = self.inner.lock().unwrap();
+        inner.entries.retain(|_, e| e.inserted.elapsed() < ttl);
+        let Inner { entries, order } = &mut *inner;
+        order.retain(|key| entries.contains_key(key));
+
+        if let Some(entry) = inner.entries.get(&entry_key(scope, prompt)) {
+            return Some(CacheHit::Exact(entry.response.clone()));
+        }
+
+        let threshold = config.semantic_threshold?;
+        let embedding = embedding?;
+        inner
+            .entries
+            .values()
+            .filter(|e| e.scope == scope)
+            .filter_map(|e| {
+                let similarity = cosine_similarity(e.embedding.as_deref()?, embedding);
+                Some((similarity, e))
+            })
+            .filter(|(similarity, _)| *similarity >= threshold)
+            .max_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(similarity, e)| CacheHit::Semantic {
+                response: e.response.clone(),
+                similarity,
+            })
+    }
+
+    pub fn insert(
+        &self,
+        config: &CacheConfig,
+        scope: &str,
+        prompt: &str,
+        embedding: Option<Vec<f32>>,
+        response: CachedResponse,
+    ) {
+        if !config.enabled || config.max_entries == 0 {
+            return;
+        }
+        let key = entry_key(scope, prompt);
+        let mut inner = self.inner.lock().unwrap();
+        let entry = Entry {
+            scope: scope.to_string(),
+            embedding,
+            response,
+            inserted: Instant::now(),
+        };
+        if inner.entries.insert(key.clone(), entry).is_none() {
+            inner.order.push_back(key);
+        }
+        while inner.entries.len() > config.max_entries {
+            let Some(oldest) = inner.order.pop_front() else {
+                break;
+            };
+            inner.entries.remove(&oldest);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(semantic_threshold: Option<f32>) -> CacheConfig {
+        CacheConfig {
+            enabled: true,
+            max_entries: 2,
+            semantic_threshold,
+            ..Default::default()
+        }
+    }
+
+    fn response(text: &str) -> CachedResponse {
+        CachedResponse {
+            text: text.to_string(),
+            tokens: 1,
+        }
+    }
+
+    #[test]
+    fn test_exact_and_semantic_hits() {
+        let cache = ResponseCache::new();
+        let config = config(Some(0.9));
+        let scope = cache_scope("alice", "qwen", 64, 0.0, 1.0, &[]);
+        cache.insert(
+            &config,
+            &scope,
+            "what is rust",
+            Some(vec![1.0, 0.0]),
+            response("a language"),
+        );
+
+        assert_eq!(
+            cache.lookup(&config, &scope, "what is rust", None),
+            Some(CacheHit::Exact(response("a language")))
+        );
+        match cache.lookup(&config, &scope, "what's rust?", Some(&[0.99, 0.1])) {
+            Some(CacheHit::Semantic {
+                response: r,
+                similarity,
+            }) => {
+                assert_eq!(r.text, "a language");
+                assert!(similarity > 0.9);
+            }
+            other => panic!("expected a semantic hit, got {:?}", other),
+        }
+        assert!(cache
+            .lookup(&config, &scope, "unrelated", Some(&[0.0, 1.0]))
+            .is_none());
+
+        // Different sampling parameters or accounts never share answers
+        for other_scope in [
+            cache_scope("alice", "qwen", 64, 0.7, 1.0, &[]),
+            cache_scope("bob", "qwen", 64, 0.0, 1.0, &[]),
+        ] {
+            assert!(cache
+                .lookup(&config, &other_scope, "what is rust", Some(&[1.0, 0.0]))
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn test_oldest_entries_are_evicted() {
+        let cache = ResponseCache::new();
+        let config = config(None);
+        for prompt in ["a", "b", "c"] {
+            cache.insert(&config, "scope", prompt, None, response(prompt));
+        }
+        assert_eq!(cache.len(), 2);
+        assert!(cache.lookup(&config, "scope", "a", None).is_none());
+        assert!(cache.lookup(&config, "scope", "c", None).is_some());
+    }
+}
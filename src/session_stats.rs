@@ -0,0 +1,122 @@
+//! Session storage analysis (`GET /admin/sessions/stats`).
+//!
+//! Summarizes what the `sessions` table holds, computed in SQL over the stored history JSON: how
+//! many sessions there are, how their sizes are distributed, when the least and most recently
+//! active ones were last used, and which sessions hold the most text. Meant for choosing
+//! retention settings before the database grows out of hand. Activity is the timestamp of a
+//! session's last message; sessions saved before messages carried one count as undated.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Most sessions `top` may list
+pub const MAX_TOP: usize = 100;
+
+/// Upper bounds of the size buckets, in bytes of stored history; one more bucket holds the rest
+pub const SIZE_BUCKETS: [(u64, &str); 4] = [
+    (1024, "<1KiB"),
+    (10 * 1024, "1-10KiB"),
+    (100 * 1024, "10-100KiB"),
+    (1024 * 1024, "100KiB-1MiB"),
+];
+pub const LARGEST_BUCKET: &str = ">=1MiB";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SessionStatsQuery {
+    /// How many of the largest sessions to list (default 10)
+    #[serde(default = "default_top")]
+    pub top: usize,
+}
+
+fn default_top() -> usize {
+    10
+}
+
+impl SessionStatsQuery {
+    pub fn top(&self) -> usize {
+        self.top.min(MAX_TOP)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SessionStats {
+    pub sessions: u64,
+    pub messages: u64,
+    /// Stored history JSON, in bytes
+    pub total_bytes: u64,
+    pub largest_bytes: u64,
+    pub size_buckets: Vec<SizeBucket>,
+    pub oldest_activity: Option<DateTime<Utc>>,
+    pub newest_activity: Option<DateTime<Utc>>,
+    pub undated_sessions: u64,
+    pub top_sessions: Vec<SessionUsage>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SizeBucket {
+    pub size: &'static str,
+    pub sessions: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SessionUsage {
+    pub session_id: String,
+    pub messages: u64,
+    pub bytes: u64,
+    /// Message text divided by `CHARS_PER_TOKEN`
+    pub estimated_tokens: u64,
+    pub last_active: Option<DateTime<Utc>>,
+}
+
+/// SQL `CASE` mapping `bytes` to the index of its bucket in [`SIZE_BUCKETS`] (the last index
+/// being [`LARGEST_BUCKET`])
+pub fn bucket_case(bytes: &str) -> String {
+    let mut sql = String::from("CASE");
+    for (index, (bound, _)) in SIZE_BUCKETS.iter().enumerate() {
+        sql.push_str(&format!(" WHEN {} < {} THEN {}", bytes, bound, index));
+    }
+    sql.push_str(&format!(" ELSE {} END", SIZE_BUCKETS.len()));
+    sql
+}
+
+/// Every bucket in order, with counts by bucket index
+pub fn size_buckets(counts: &[(i64, i64)]) -> Vec<SizeBucket> {
+    SIZE_BUCKETS
+        .iter()
+        .map(|(_, label)| *label)
+        .chain([LARGEST_BUCKET])
+        .enumerate()
+        .map(|(index, size)| SizeBucket {
+            size,
+            sessions: counts
+                .iter()
+                .find(|(bucket, _)| *bucket == index as i64)
+                .map_or(0, |(_, count)| *count as u64),
+        })
+        .collect()
+}
+
+/// Parse a stored message timestamp; unparseable ones count as undated
+pub fn parse_activity(timestamp: Option<String>) -> Option<DateTime<Utc>> {
+    timestamp.and_then(|t| DateTime::parse_from_rfc3339(&t).ok().map(|t| t.with_timezone(&Utc)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_are_listed_in_order_with_zeroes() {
+        let buckets = size_buckets(&[(0, 3), (4, 1)]);
+        let sizes: Vec<_> = buckets.iter().map(|b| (b.size, b.sessions)).collect();
+        assert_eq!(
+            sizes,
+            vec![("<1KiB", 3), ("1-10KiB", 0), ("10-100KiB", 0), ("100KiB-1MiB", 0), (">=1MiB", 1)]
+        );
+        assert_eq!(
+            bucket_case("b"),
+            "CASE WHEN b < 1024 THEN 0 WHEN b < 10240 THEN 1 WHEN b < 102400 THEN 2 \
+             WHEN b < 1048576 THEN 3 ELSE 4 END"
+        );
+    }
+}
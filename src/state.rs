@@ -1,37 +1,124 @@
-use crate::config::Config;
-use crate::engine::{InferenceEngine, TokenStream};
+use crate::canary::ModelHealth;
+use crate::compression;
+use crate::config::{
+    ApiKeyConfig, CanaryConfig, Config, ObservabilityConfig, PeerConfig, SessionStoreConfig,
+    SqliteSynchronousMode,
+};
+use crate::engine::{EngineError, InferenceEngine, TokenStream};
+use crate::error_reporting::{ErrorReport, ErrorReporter};
+use crate::events::{EventBus, ServerEvent};
+use crate::experiments::{self, Assignment};
+use crate::federation::Federation;
+use crate::feedback::{self, FeedbackError, MessageFeedback, Rating};
+use crate::guardrails::{self, Refusal};
+use crate::inflight::{request_key, Claim, InFlight};
+use crate::jobs::JobRegistry;
 use crate::models::{ChatMessage, InferenceRequest};
-use crate::middleware::RateLimiter;
+use crate::observers::SessionObservers;
+use crate::middleware::{with_rotated_keys, ApiKeyRegistry, ConcurrencyLimiter, GenerationPermit, RateLimiter};
+use crate::request_log::{RequestLogEntry, RequestLogQuery};
+use crate::response_cache::ResponseCache;
+use crate::session_stats::{self, SessionStats, SessionUsage};
+use crate::stats::{RuntimeStats, UsageRecord};
+use crate::templates::{self, PromptTemplate, TemplateError};
+use crate::transcript::{Transcript, TranscriptRecorder};
+use crate::webhook::WebhookSender;
 use anyhow::{anyhow, Result};
 use async_stream::stream;
+use chrono::{DateTime, SecondsFormat, Utc};
+use dashmap::DashMap;
 use futures_util::{FutureExt, StreamExt};
-use metrics_exporter_prometheus::PrometheusHandle;
-use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
-use sqlx::Row;
+use metrics::{counter, gauge, histogram, increment_counter};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions, SqliteSynchronous};
+use sqlx::{QueryBuilder, Row, Sqlite};
 use std::any::Any;
 use std::collections::HashMap;
+use std::future::Future;
 use std::panic::AssertUnwindSafe;
 use std::path::Path;
-use std::sync::Arc;
-use tokio::sync::Mutex;
-use tracing::{error, warn};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::{oneshot, Mutex};
+use tracing::{error, info, warn};
 
-const SESSIONS_DB: &str = "sessions.db";
+/// SQLite database holding chat sessions and rotated API keys
+pub const SESSIONS_DB: &str = "sessions.db";
+/// Database path that keeps everything in memory and writes no file, for tests and throwaway
+/// servers
+pub const IN_MEMORY_DB: &str = ":memory:";
+/// Rough token estimate used for history budgets and prompt accounting
+pub const CHARS_PER_TOKEN: usize = 4;
 
-struct SessionStore {
+/// Prometheus exporter with the configured histogram buckets. `latency_buckets` applies to every
+/// `*_seconds` metric and `metric_buckets` to single metrics; anything else renders as a summary.
+pub fn prometheus_builder(config: &ObservabilityConfig) -> Result<PrometheusBuilder> {
+    let mut builder = PrometheusBuilder::new();
+    if !config.latency_buckets.is_empty() {
+        builder = builder
+            .set_buckets_for_metric(Matcher::Suffix("_seconds".to_string()), &config.latency_buckets)?;
+    }
+    for (metric, buckets) in &config.metric_buckets {
+        builder = builder.set_buckets_for_metric(Matcher::Full(metric.clone()), buckets)?;
+    }
+    Ok(builder)
+}
+
+// Statements run on every chat turn. sqlx prepares each distinct SQL text once per connection
+// and keeps it in the statement cache, so these must stay byte-for-byte identical across calls.
+const UPSERT_SESSION_SQL: &str = "INSERT INTO sessions (session_id, history) VALUES (?, ?)
+     ON CONFLICT(session_id) DO UPDATE SET history = excluded.history";
+const DELETE_SESSION_SQL: &str = "DELETE FROM sessions WHERE session_id = ?";
+const DB_SIZE_SQL: &str =
+    "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()";
+
+/// SQLite persistence for chat sessions, rotated API keys, prompt templates, message feedback,
+/// the request log, and rate-limit and usage counters
+pub struct SessionStore {
     pool: SqlitePool,
+    slow_threshold: Option<Duration>,
 }
 
 impl SessionStore {
-    async fn new(db_path: &str) -> Result<Self> {
-        let connect_opts = SqliteConnectOptions::new()
-            .filename(Path::new(db_path))
-            .create_if_missing(true);
-
-        let pool = SqlitePoolOptions::new()
-            .max_connections(5)
-            .connect_with(connect_opts)
-            .await?;
+    pub async fn new(
+        db_path: &str,
+        config: &SessionStoreConfig,
+        slow_threshold: Option<Duration>,
+    ) -> Result<Self> {
+        let synchronous = match config.synchronous {
+            SqliteSynchronousMode::Off => SqliteSynchronous::Off,
+            SqliteSynchronousMode::Normal => SqliteSynchronous::Normal,
+            SqliteSynchronousMode::Full => SqliteSynchronous::Full,
+            SqliteSynchronousMode::Extra => SqliteSynchronous::Extra,
+        };
+        let in_memory = db_path == IN_MEMORY_DB;
+        let connect_opts = if in_memory {
+            SqliteConnectOptions::from_str("sqlite::memory:")?
+        } else {
+            SqliteConnectOptions::new()
+                .filename(Path::new(db_path))
+                .create_if_missing(true)
+        }
+        .synchronous(synchronous)
+        // Negative sizes are in KiB rather than pages
+        .pragma("cache_size", format!("-{}", config.cache_size_kib))
+        .statement_cache_capacity(config.statement_cache_capacity);
+
+        // Every connection to `:memory:` opens its own empty database, so an in-memory store keeps
+        // exactly one connection open for its whole life
+        let pool_options = if in_memory {
+            SqlitePoolOptions::new()
+                .max_connections(1)
+                .min_connections(1)
+                .idle_timeout(None)
+                .max_lifetime(None)
+        } else {
+            SqlitePoolOptions::new().max_connections(config.pool_size)
+        };
+        let pool = pool_options.connect_with(connect_opts).await?;
 
         sqlx::query(
             "CREATE TABLE IF NOT EXISTS sessions (
@@ -42,13 +129,129 @@ impl SessionStore {
         .execute(&pool)
         .await?;
 
-        Ok(Self { pool })
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS api_keys (
+                key TEXT PRIMARY KEY,
+                config TEXT NOT NULL
+            )",
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS prompt_templates (
+                name TEXT PRIMARY KEY,
+                template TEXT NOT NULL
+            )",
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS message_feedback (
+                session_id TEXT NOT NULL,
+                message_index INTEGER NOT NULL,
+                feedback TEXT NOT NULL,
+                PRIMARY KEY (session_id, message_index)
+            )",
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS stream_transcripts (
+                request_id TEXT PRIMARY KEY,
+                transcript TEXT NOT NULL
+            )",
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS request_log (
+                id TEXT PRIMARY KEY,
+                created_at TEXT NOT NULL,
+                account TEXT NOT NULL,
+                model TEXT NOT NULL,
+                entry TEXT NOT NULL
+            )",
+        )
+        .execute(&pool)
+        .await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS request_log_created_at ON request_log (created_at)")
+            .execute(&pool)
+            .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS rate_limits (
+                key TEXT PRIMARY KEY,
+                requests TEXT NOT NULL
+            )",
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS account_usage (
+                account TEXT NOT NULL,
+                model TEXT NOT NULL,
+                requests INTEGER NOT NULL,
+                prompt_tokens INTEGER NOT NULL,
+                completion_tokens INTEGER NOT NULL,
+                PRIMARY KEY (account, model)
+            )",
+        )
+        .execute(&pool)
+        .await?;
+
+        let store = Self {
+            pool,
+            slow_threshold,
+        };
+        store.refresh_size().await;
+        Ok(store)
+    }
+
+    /// Time a store operation, counting failures and warning when it exceeds the slow threshold
+    async fn observe<T>(
+        &self,
+        operation: &'static str,
+        fut: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let start = Instant::now();
+        let result = fut.await;
+        let elapsed = start.elapsed();
+
+        histogram!("session_store_operation_seconds", elapsed.as_secs_f64(), "operation" => operation);
+        if result.is_err() {
+            increment_counter!("session_store_errors_total", "operation" => operation);
+        }
+        if self.slow_threshold.is_some_and(|threshold| elapsed > threshold) {
+            increment_counter!("session_store_slow_operations_total", "operation" => operation);
+            warn!("🐢 Session store {} took {}ms", operation, elapsed.as_millis());
+        }
+        result
+    }
+
+    /// Publish the database size (pages in use, including free pages)
+    async fn refresh_size(&self) {
+        let size: Result<i64, _> = sqlx::query_scalar(DB_SIZE_SQL)
+            .fetch_one(&self.pool)
+            .await;
+        match size {
+            Ok(bytes) => gauge!("session_store_db_bytes", bytes as f64),
+            Err(err) => warn!("Failed to read session store size: {}", err),
+        }
     }
 
-    async fn load_sessions(&self) -> Result<HashMap<String, Vec<ChatMessage>>> {
+    pub async fn load_sessions(&self) -> Result<HashMap<String, Vec<ChatMessage>>> {
         let mut map = HashMap::new();
-        let rows = sqlx::query("SELECT session_id, history FROM sessions")
-            .fetch_all(&self.pool)
+        let rows = self
+            .observe("load", async {
+                Ok(sqlx::query("SELECT session_id, history FROM sessions")
+                    .fetch_all(&self.pool)
+                    .await?)
+            })
             .await?;
 
         for row in rows {
@@ -67,58 +270,508 @@ impl SessionStore {
         Ok(map)
     }
 
-    async fn upsert_session(&self, session_id: &str, history: &[ChatMessage]) -> Result<()> {
-        let payload = serde_json::to_string(history)?;
+    pub async fn upsert_session(&self, session_id: &str, history: &[ChatMessage]) -> Result<()> {
+        self.observe("upsert", async {
+            let payload = serde_json::to_string(history)?;
+            sqlx::query(UPSERT_SESSION_SQL)
+                .bind(session_id)
+                .bind(payload)
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        })
+        .await?;
+        self.refresh_size().await;
+        Ok(())
+    }
+
+    pub async fn delete_session(&self, session_id: &str) -> Result<()> {
+        self.observe("delete", async {
+            sqlx::query(DELETE_SESSION_SQL)
+                .bind(session_id)
+                .execute(&self.pool)
+                .await?;
+            sqlx::query("DELETE FROM message_feedback WHERE session_id = ?")
+                .bind(session_id)
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        })
+        .await?;
+        self.refresh_size().await;
+        Ok(())
+    }
+
+    async fn load_api_keys(&self) -> Result<Vec<ApiKeyConfig>> {
+        let rows = sqlx::query("SELECT key, config FROM api_keys")
+            .fetch_all(&self.pool)
+            .await?;
+
+        let mut keys = Vec::with_capacity(rows.len());
+        for row in rows {
+            let config_json: String = row.try_get("config")?;
+            match serde_json::from_str::<ApiKeyConfig>(&config_json) {
+                Ok(key) => keys.push(key),
+                Err(err) => {
+                    let key: String = row.try_get("key")?;
+                    warn!("Failed to deserialize stored API key {}: {}", key, err);
+                }
+            }
+        }
+
+        Ok(keys)
+    }
+
+    async fn upsert_api_key(&self, key: &ApiKeyConfig) -> Result<()> {
+        let payload = serde_json::to_string(key)?;
+        sqlx::query(
+            "INSERT INTO api_keys (key, config) VALUES (?, ?)
+             ON CONFLICT(key) DO UPDATE SET config = excluded.config",
+        )
+        .bind(&key.key)
+        .bind(payload)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn load_templates(&self) -> Result<Vec<PromptTemplate>> {
+        let rows = sqlx::query("SELECT name, template FROM prompt_templates")
+            .fetch_all(&self.pool)
+            .await?;
+
+        let mut templates = Vec::with_capacity(rows.len());
+        for row in rows {
+            let template_json: String = row.try_get("template")?;
+            match serde_json::from_str::<PromptTemplate>(&template_json) {
+                Ok(template) => templates.push(template),
+                Err(err) => {
+                    let name: String = row.try_get("name")?;
+                    warn!("Failed to deserialize prompt template {}: {}", name, err);
+                }
+            }
+        }
+
+        Ok(templates)
+    }
+
+    pub async fn upsert_template(&self, template: &PromptTemplate) -> Result<()> {
+        let payload = serde_json::to_string(template)?;
         sqlx::query(
-            "INSERT INTO sessions (session_id, history) VALUES (?, ?)
-             ON CONFLICT(session_id) DO UPDATE SET history = excluded.history",
+            "INSERT INTO prompt_templates (name, template) VALUES (?, ?)
+             ON CONFLICT(name) DO UPDATE SET template = excluded.template",
         )
-        .bind(session_id)
+        .bind(&template.name)
         .bind(payload)
         .execute(&self.pool)
         .await?;
         Ok(())
     }
 
-    async fn delete_session(&self, session_id: &str) -> Result<()> {
-        sqlx::query("DELETE FROM sessions WHERE session_id = ?")
-            .bind(session_id)
+    pub async fn delete_template(&self, name: &str) -> Result<()> {
+        sqlx::query("DELETE FROM prompt_templates WHERE name = ?")
+            .bind(name)
             .execute(&self.pool)
             .await?;
         Ok(())
     }
 
-    async fn replace_all(&self, snapshot: &HashMap<String, Vec<ChatMessage>>) -> Result<()> {
-        let mut tx = self.pool.begin().await?;
-        sqlx::query("DELETE FROM sessions")
-            .execute(&mut *tx)
+    pub async fn upsert_feedback(&self, feedback: &MessageFeedback) -> Result<()> {
+        let payload = serde_json::to_string(feedback)?;
+        sqlx::query(
+            "INSERT INTO message_feedback (session_id, message_index, feedback) VALUES (?, ?, ?)
+             ON CONFLICT(session_id, message_index) DO UPDATE SET feedback = excluded.feedback",
+        )
+        .bind(&feedback.session_id)
+        .bind(feedback.message_index as i64)
+        .bind(payload)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// All stored feedback, oldest first
+    pub async fn load_feedback(&self) -> Result<Vec<MessageFeedback>> {
+        let rows = sqlx::query("SELECT session_id, feedback FROM message_feedback")
+            .fetch_all(&self.pool)
             .await?;
 
-        for (session_id, history) in snapshot.iter() {
-            let payload = serde_json::to_string(history)?;
-            sqlx::query(
-                "INSERT INTO sessions (session_id, history) VALUES (?, ?)
-                 ON CONFLICT(session_id) DO UPDATE SET history = excluded.history",
-            )
-            .bind(session_id)
+        let mut feedback = Vec::with_capacity(rows.len());
+        for row in rows {
+            let feedback_json: String = row.try_get("feedback")?;
+            match serde_json::from_str::<MessageFeedback>(&feedback_json) {
+                Ok(entry) => feedback.push(entry),
+                Err(err) => {
+                    let session_id: String = row.try_get("session_id")?;
+                    warn!("Failed to deserialize feedback for {}: {}", session_id, err);
+                }
+            }
+        }
+        feedback.sort_by_key(|f| f.created_at);
+        Ok(feedback)
+    }
+
+    pub async fn insert_transcript(&self, transcript: &Transcript) -> Result<()> {
+        let payload = serde_json::to_string(transcript)?;
+        sqlx::query("INSERT OR REPLACE INTO stream_transcripts (request_id, transcript) VALUES (?, ?)")
+            .bind(&transcript.request_id)
+            .bind(payload)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Delete transcripts of streams that started before `cutoff`
+    pub async fn prune_transcripts(&self, cutoff: DateTime<Utc>) -> Result<u64> {
+        let result = sqlx::query(
+            "DELETE FROM stream_transcripts
+             WHERE julianday(json_extract(transcript, '$.started_at')) < julianday(?)",
+        )
+        .bind(cutoff.to_rfc3339())
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    pub async fn load_transcript(&self, request_id: &str) -> Result<Option<Transcript>> {
+        let payload: Option<String> =
+            sqlx::query_scalar("SELECT transcript FROM stream_transcripts WHERE request_id = ?")
+                .bind(request_id)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(payload.map(|p| serde_json::from_str(&p)).transpose()?)
+    }
+
+    pub async fn insert_request_log(&self, entry: &RequestLogEntry) -> Result<()> {
+        let payload = serde_json::to_string(entry)?;
+        sqlx::query("INSERT INTO request_log (id, created_at, account, model, entry) VALUES (?, ?, ?, ?, ?)")
+            .bind(&entry.id)
+            .bind(log_timestamp(entry.created_at))
+            .bind(&entry.account)
+            .bind(&entry.model)
             .bind(payload)
-            .execute(&mut *tx)
+            .execute(&self.pool)
             .await?;
+        Ok(())
+    }
+
+    /// Logged requests matching `query`, newest first
+    pub async fn query_request_log(&self, query: &RequestLogQuery) -> Result<Vec<RequestLogEntry>> {
+        let mut sql = QueryBuilder::<Sqlite>::new("SELECT entry FROM request_log WHERE 1 = 1");
+        if let Some(account) = &query.account {
+            sql.push(" AND account = ").push_bind(account.clone());
+        }
+        if let Some(model) = &query.model {
+            sql.push(" AND model = ").push_bind(model.clone());
         }
+        if let Some(since) = query.since {
+            sql.push(" AND created_at >= ").push_bind(log_timestamp(since));
+        }
+        if let Some(until) = query.until {
+            sql.push(" AND created_at < ").push_bind(log_timestamp(until));
+        }
+        sql.push(" ORDER BY created_at DESC LIMIT ")
+            .push_bind(query.limit() as i64);
+
+        let payloads: Vec<String> = sql.build_query_scalar().fetch_all(&self.pool).await?;
+        Ok(payloads
+            .iter()
+            .filter_map(|p| match serde_json::from_str(p) {
+                Ok(entry) => Some(entry),
+                Err(err) => {
+                    warn!("Failed to deserialize request log entry: {}", err);
+                    None
+                }
+            })
+            .collect())
+    }
+
+    pub async fn load_request_log(&self, id: &str) -> Result<Option<RequestLogEntry>> {
+        let payload: Option<String> = sqlx::query_scalar("SELECT entry FROM request_log WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(payload.map(|p| serde_json::from_str(&p)).transpose()?)
+    }
+
+    /// Delete entries logged before `cutoff`; returns how many went
+    pub async fn prune_request_log(&self, cutoff: DateTime<Utc>) -> Result<u64> {
+        let result = sqlx::query("DELETE FROM request_log WHERE created_at < ?")
+            .bind(log_timestamp(cutoff))
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Size and activity summary of the stored sessions, with the `top` holding the most text
+    pub async fn session_stats(&self, top: usize) -> Result<SessionStats> {
+        self.observe("stats", async {
+            let totals = sqlx::query(
+                "SELECT count(*) AS sessions,
+                        coalesce(sum(json_array_length(history)), 0) AS messages,
+                        coalesce(sum(bytes), 0) AS total_bytes,
+                        coalesce(max(bytes), 0) AS largest_bytes,
+                        min(last_active) AS oldest,
+                        max(last_active) AS newest,
+                        coalesce(sum(last_active IS NULL), 0) AS undated
+                 FROM (SELECT history,
+                              length(CAST(history AS BLOB)) AS bytes,
+                              json_extract(history, '$[#-1].timestamp') AS last_active
+                       FROM sessions)",
+            )
+            .fetch_one(&self.pool)
+            .await?;
+
+            let bucket_sql = format!(
+                "SELECT {} AS bucket, count(*) FROM sessions GROUP BY bucket",
+                session_stats::bucket_case("length(CAST(history AS BLOB))")
+            );
+            let buckets: Vec<(i64, i64)> = sqlx::query_as(&bucket_sql).fetch_all(&self.pool).await?;
+
+            let rows = sqlx::query(
+                "SELECT session_id,
+                        json_array_length(history) AS messages,
+                        length(CAST(history AS BLOB)) AS bytes,
+                        (SELECT coalesce(sum(length(json_extract(value, '$.content'))), 0)
+                         FROM json_each(sessions.history)) AS chars,
+                        json_extract(history, '$[#-1].timestamp') AS last_active
+                 FROM sessions
+                 ORDER BY chars DESC, session_id
+                 LIMIT ?",
+            )
+            .bind(top as i64)
+            .fetch_all(&self.pool)
+            .await?;
+            let top_sessions = rows
+                .iter()
+                .map(|row| {
+                    Ok(SessionUsage {
+                        session_id: row.try_get("session_id")?,
+                        messages: row.try_get::<i64, _>("messages")? as u64,
+                        bytes: row.try_get::<i64, _>("bytes")? as u64,
+                        estimated_tokens: (row.try_get::<i64, _>("chars")? as u64)
+                            .div_ceil(CHARS_PER_TOKEN as u64),
+                        last_active: session_stats::parse_activity(row.try_get("last_active")?),
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
 
-        tx.commit().await?;
+            Ok(SessionStats {
+                sessions: totals.try_get::<i64, _>("sessions")? as u64,
+                messages: totals.try_get::<i64, _>("messages")? as u64,
+                total_bytes: totals.try_get::<i64, _>("total_bytes")? as u64,
+                largest_bytes: totals.try_get::<i64, _>("largest_bytes")? as u64,
+                size_buckets: session_stats::size_buckets(&buckets),
+                oldest_activity: session_stats::parse_activity(totals.try_get("oldest")?),
+                newest_activity: session_stats::parse_activity(totals.try_get("newest")?),
+                undated_sessions: totals.try_get::<i64, _>("undated")? as u64,
+                top_sessions,
+            })
+        })
+        .await
+    }
+
+    /// Rate-limit windows (request times in Unix milliseconds per key) and per-account usage
+    /// saved by [`save_limits`](Self::save_limits)
+    pub async fn load_limits(&self) -> Result<(Vec<(String, Vec<i64>)>, Vec<UsageRecord>)> {
+        self.observe("load_limits", async {
+            let mut windows = Vec::new();
+            for row in sqlx::query("SELECT key, requests FROM rate_limits")
+                .fetch_all(&self.pool)
+                .await?
+            {
+                let key: String = row.try_get("key")?;
+                let requests: String = row.try_get("requests")?;
+                match serde_json::from_str::<Vec<i64>>(&requests) {
+                    Ok(times) => windows.push((key, times)),
+                    Err(err) => warn!("Failed to deserialize the stored rate-limit window: {}", err),
+                }
+            }
+            let usage = sqlx::query(
+                "SELECT account, model, requests, prompt_tokens, completion_tokens FROM account_usage",
+            )
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .map(|row| {
+                Ok(UsageRecord {
+                    account: row.try_get("account")?,
+                    model: row.try_get("model")?,
+                    requests: row.try_get::<i64, _>("requests")? as u64,
+                    prompt_tokens: row.try_get::<i64, _>("prompt_tokens")? as u64,
+                    completion_tokens: row.try_get::<i64, _>("completion_tokens")? as u64,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+            Ok((windows, usage))
+        })
+        .await
+    }
+
+    /// Replace the saved rate-limit windows and usage counters with the current ones
+    pub async fn save_limits(&self, windows: &[(String, Vec<i64>)], usage: &[UsageRecord]) -> Result<()> {
+        self.observe("save_limits", async {
+            let mut tx = self.pool.begin().await?;
+            sqlx::query("DELETE FROM rate_limits").execute(&mut *tx).await?;
+            for (key, times) in windows {
+                sqlx::query("INSERT INTO rate_limits (key, requests) VALUES (?, ?)")
+                    .bind(key)
+                    .bind(serde_json::to_string(times)?)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            for record in usage {
+                sqlx::query(
+                    "INSERT INTO account_usage (account, model, requests, prompt_tokens, completion_tokens)
+                     VALUES (?, ?, ?, ?, ?)
+                     ON CONFLICT(account, model) DO UPDATE SET requests = excluded.requests,
+                        prompt_tokens = excluded.prompt_tokens, completion_tokens = excluded.completion_tokens",
+                )
+                .bind(&record.account)
+                .bind(&record.model)
+                .bind(record.requests as i64)
+                .bind(record.prompt_tokens as i64)
+                .bind(record.completion_tokens as i64)
+                .execute(&mut *tx)
+                .await?;
+            }
+            tx.commit().await?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn replace_all(&self, snapshot: &HashMap<String, Vec<ChatMessage>>) -> Result<()> {
+        self.observe("replace_all", async {
+            let mut tx = self.pool.begin().await?;
+            sqlx::query("DELETE FROM sessions")
+                .execute(&mut *tx)
+                .await?;
+
+            for (session_id, history) in snapshot.iter() {
+                let payload = serde_json::to_string(history)?;
+                sqlx::query(UPSERT_SESSION_SQL)
+                    .bind(session_id)
+                    .bind(payload)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+
+            tx.commit().await?;
+            Ok(())
+        })
+        .await?;
+        self.refresh_size().await;
         Ok(())
     }
 }
 
+/// Applies a new log level filter (installed by the server binary)
+pub type LogLevelReloader = Arc<dyn Fn(&str) -> Result<()> + Send + Sync>;
+
+#[derive(Debug, Error)]
+pub enum ConfigReloadError {
+    #[error("configuration changes require a restart: {}", .0.join(", "))]
+    RestartRequired(Vec<String>),
+    #[error("{0:#}")]
+    Failed(anyhow::Error),
+}
+
+// Settings that only work with some engines
+fn check_engine_support(engine: &dyn InferenceEngine, config: &Config) -> Result<()> {
+    if config.cache.semantic_threshold.is_some() && !engine.embeds() {
+        return Err(anyhow!(
+            "cache.semantic_threshold is set, but this engine cannot embed prompts"
+        ));
+    }
+    Ok(())
+}
+
+/// Error returned in place of a caught engine panic. The panic has already been reported,
+/// so request handlers skip reporting it again as a 5xx.
+#[derive(Debug, Error)]
+#[error("Inference engine panicked")]
+pub struct EnginePanic;
+
+/// An `auto_fit` request whose prompt leaves no room in the model's context
+#[derive(Debug, Error)]
+#[error("prompt of {prompt_tokens} tokens leaves no room in the model's {context_length}-token context")]
+pub struct ContextFull {
+    pub prompt_tokens: usize,
+    pub context_length: usize,
+}
+
+/// Set while the server drains for a deploy: new generations are refused, running ones finish
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct Drain {
+    pub since: chrono::DateTime<chrono::Utc>,
+    /// Sent as `Retry-After` to refused requests
+    pub retry_after_seconds: u64,
+}
+
+/// One finished generation, as recorded by [`AppState::record_generation`]
+pub struct RequestTiming<'a> {
+    pub endpoint: &'static str,
+    pub account: &'a str,
+    pub model: &'a str,
+    pub prompt_chars: usize,
+    pub tokens: u64,
+    pub duration: f64,
+    pub ttft: Option<f64>,
+    pub experiment: Option<&'a Assignment>,
+}
+
+/// Aborts a background task once the last `AppState` clone holding it is dropped
+struct BackgroundTask(tokio::task::JoinHandle<()>);
+
+impl Drop for BackgroundTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
 #[derive(Clone)]
 pub struct AppState {
     pub engine: Arc<dyn InferenceEngine>,
     pub sessions: Arc<Mutex<HashMap<String, Vec<ChatMessage>>>>,
+    session_cancellations: Arc<DashMap<String, Arc<AtomicBool>>>,
     pub metrics_handle: PrometheusHandle,
-    pub config: Arc<Config>,
+    config: Arc<RwLock<Arc<Config>>>,
     pub rate_limiter: Arc<RateLimiter>,
+    pub concurrency_limiter: Arc<ConcurrencyLimiter>,
+    pub api_keys: Arc<ApiKeyRegistry>,
+    pub webhooks: Arc<WebhookSender>,
+    pub stats: Arc<RuntimeStats>,
+    pub error_reporter: Arc<ErrorReporter>,
+    pub response_cache: Arc<ResponseCache>,
+    pub in_flight: Arc<InFlight>,
+    /// Saved prompt templates by name, mirrored to the session database
+    pub templates: Arc<DashMap<String, PromptTemplate>>,
+    /// Which peer serves the models this server does not host
+    pub federation: Arc<Federation>,
+    /// Canary check results; unhealthy models get no traffic
+    pub model_health: Arc<ModelHealth>,
+    /// Background `/completions` jobs, for `GET /jobs/:id`
+    pub jobs: Arc<JobRegistry>,
+    /// Session generations in progress, for `GET /chat/ws/observe/:session_id`
+    pub observers: Arc<SessionObservers>,
+    /// Lifecycle events for `GET /events`
+    pub events: Arc<EventBus>,
+    /// Fault injection wrapped around `engine`, for `/debug/chaos`
+    #[cfg(feature = "chaos")]
+    pub chaos: Option<Arc<crate::chaos::ChaosEngine>>,
+    drain: Arc<RwLock<Option<Drain>>>,
+    log_level_reloader: Option<LogLevelReloader>,
+    profile: Option<String>,
+    config_path: Option<String>,
     session_store: Arc<SessionStore>,
+    _rate_limit_cleanup: Arc<BackgroundTask>,
+    _limits_flush: Arc<BackgroundTask>,
+    _federation_refresh: Arc<BackgroundTask>,
+    _canary: Arc<BackgroundTask>,
+    _log_pruning: Arc<BackgroundTask>,
+    _job_pruning: Arc<BackgroundTask>,
+    _compaction: Arc<BackgroundTask>,
 }
 
 impl AppState {
@@ -127,20 +780,263 @@ impl AppState {
         metrics_handle: PrometheusHandle,
         config: Config,
     ) -> Result<Self> {
-        let store = Arc::new(SessionStore::new(SESSIONS_DB).await?);
+        // Without persistence the store lives in memory and nothing reaches the disk
+        let db_path = if config.persistence.enabled {
+            SESSIONS_DB
+        } else {
+            info!("Session persistence disabled; sessions are kept in memory only");
+            IN_MEMORY_DB
+        };
+        Self::with_database(engine, metrics_handle, config, db_path).await
+    }
+
+    /// State backed by an in-memory session database, so tests neither share nor leave behind a
+    /// `sessions.db` and can run in parallel
+    pub async fn new_in_memory(
+        engine: Arc<dyn InferenceEngine>,
+        metrics_handle: PrometheusHandle,
+        config: Config,
+    ) -> Result<Self> {
+        Self::with_database(engine, metrics_handle, config, IN_MEMORY_DB).await
+    }
+
+    async fn with_database(
+        engine: Arc<dyn InferenceEngine>,
+        metrics_handle: PrometheusHandle,
+        config: Config,
+        db_path: &str,
+    ) -> Result<Self> {
+        check_engine_support(engine.as_ref(), &config)?;
+        let slow_threshold = match config.observability.session_store_slow_ms {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        };
+        let store = Arc::new(
+            SessionStore::new(db_path, &config.session_store, slow_threshold).await?,
+        );
         let sessions = store.load_sessions().await.unwrap_or_default();
         let rate_limiter = Arc::new(RateLimiter::new());
+        let stats = Arc::new(RuntimeStats::new());
+        // Pick up where the previous run left off, so a restart does not reset every quota.
+        // Read-only replicas share the primary's database and leave its limits alone.
+        if persists_limits(&config) {
+            match store.load_limits().await {
+                Ok((windows, usage)) => {
+                    for (key, times) in &windows {
+                        rate_limiter.restore(key, times);
+                    }
+                    for record in &usage {
+                        stats.restore_usage(record);
+                    }
+                }
+                Err(err) => warn!("Failed to restore rate limits and usage: {}", err),
+            }
+        }
+        // Keys issued through rotation are added to the configured ones
+        let stored_keys = store.load_api_keys().await.unwrap_or_default();
+        let api_keys = Arc::new(ApiKeyRegistry::new(with_rotated_keys(
+            &config.security.api_keys,
+            stored_keys,
+        )));
+        let templates: DashMap<String, PromptTemplate> = store
+            .load_templates()
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .map(|t| (t.name.clone(), t))
+            .collect();
+        let concurrency_limiter = Arc::new(ConcurrencyLimiter::new(
+            config.models.max_concurrent_requests,
+        ));
+        concurrency_limiter.set_overload(config.overload.clone());
+
+        let webhooks = Arc::new(WebhookSender::new(config.webhooks.clone()));
+        let error_reporter = Arc::new(ErrorReporter::new(&config.error_reporting));
+        let config = Arc::new(RwLock::new(Arc::new(config)));
+        let rate_limit_cleanup = spawn_rate_limit_cleanup(rate_limiter.clone(), config.clone());
+        let limits_flush = spawn_limits_flush(store.clone(), rate_limiter.clone(), stats.clone(), config.clone());
+        let federation = Arc::new(Federation::new());
+        let federation_refresh = spawn_federation_refresh(federation.clone(), config.clone());
+        let model_health = Arc::new(ModelHealth::new());
+        let canary = spawn_canary(
+            engine.clone(),
+            concurrency_limiter.clone(),
+            model_health.clone(),
+            config.clone(),
+        );
+        let log_pruning = spawn_log_pruning(store.clone(), config.clone());
+        let jobs = Arc::new(JobRegistry::new());
+        let job_pruning = spawn_job_pruning(jobs.clone(), config.clone());
+        let sessions = Arc::new(Mutex::new(sessions));
+        let compaction = spawn_compaction(
+            engine.clone(),
+            concurrency_limiter.clone(),
+            sessions.clone(),
+            store.clone(),
+            config.clone(),
+        );
 
         Ok(Self {
             engine,
-            sessions: Arc::new(Mutex::new(sessions)),
+            sessions,
+            session_cancellations: Arc::new(DashMap::new()),
             metrics_handle,
-            config: Arc::new(config),
+            config,
             rate_limiter,
+            concurrency_limiter,
+            api_keys,
+            webhooks,
+            stats,
+            error_reporter,
+            response_cache: Arc::new(ResponseCache::new()),
+            in_flight: Arc::new(InFlight::new()),
+            templates: Arc::new(templates),
+            federation,
+            model_health,
+            jobs,
+            observers: Arc::new(SessionObservers::new()),
+            events: Arc::new(EventBus::new()),
+            #[cfg(feature = "chaos")]
+            chaos: None,
+            drain: Arc::new(RwLock::new(None)),
+            log_level_reloader: None,
+            profile: None,
+            config_path: None,
             session_store: store,
+            _rate_limit_cleanup: Arc::new(rate_limit_cleanup),
+            _limits_flush: Arc::new(limits_flush),
+            _federation_refresh: Arc::new(federation_refresh),
+            _canary: Arc::new(canary),
+            _log_pruning: Arc::new(log_pruning),
+            _job_pruning: Arc::new(job_pruning),
+            _compaction: Arc::new(compaction),
         })
     }
 
+    /// Snapshot of the current configuration (replaced wholesale on reload)
+    pub fn config(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+
+    pub fn with_log_level_reloader(mut self, reloader: LogLevelReloader) -> Self {
+        self.log_level_reloader = Some(reloader);
+        self
+    }
+
+    /// Serve through `chaos`, which must wrap this state's engine, and let `/debug/chaos` tune it
+    #[cfg(feature = "chaos")]
+    pub fn with_chaos(mut self, chaos: Arc<crate::chaos::ChaosEngine>) -> Self {
+        self.engine = chaos.clone();
+        self.chaos = Some(chaos);
+        self
+    }
+
+    /// Config profile to re-apply when the configuration is reloaded
+    pub fn with_profile(mut self, profile: Option<String>) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    pub fn profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
+    /// Config file to re-read on reload, instead of the first of the default candidates
+    pub fn with_config_path(mut self, path: Option<String>) -> Self {
+        self.config_path = path;
+        self
+    }
+
+    /// Re-read the config file (plus environment overrides) and apply it
+    pub async fn reload_config(&self) -> Result<Vec<String>, ConfigReloadError> {
+        let path = match &self.config_path {
+            Some(path) => path.as_str(),
+            None => Config::default_path(),
+        };
+        let config = Config::load_from(path, self.profile.as_deref())
+            .map_err(ConfigReloadError::Failed)?;
+        self.apply_config(config).await
+    }
+
+    /// Apply a new configuration to the running server. Only limits, API keys, auth, log
+    /// level and the model list can change live; anything else is rejected as a whole.
+    /// Returns the changed settings.
+    pub async fn apply_config(&self, new: Config) -> Result<Vec<String>, ConfigReloadError> {
+        new.validate().map_err(ConfigReloadError::Failed)?;
+        check_engine_support(self.engine.as_ref(), &new).map_err(ConfigReloadError::Failed)?;
+
+        let current = self.config();
+        let restart_required = current.restart_required_changes(&new);
+        if !restart_required.is_empty() {
+            return Err(ConfigReloadError::RestartRequired(restart_required));
+        }
+
+        let changed = current.changed_fields(&new);
+        if changed.is_empty() {
+            return Ok(changed);
+        }
+
+        if current.models.available_models != new.models.available_models {
+            self.engine
+                .reload_models(new.models.available_models.clone())
+                .await
+                .map_err(ConfigReloadError::Failed)?;
+            self.sync_loaded_models().await;
+        }
+
+        if current.server.log_level != new.server.log_level {
+            if let Some(reloader) = &self.log_level_reloader {
+                reloader(&new.server.log_level).map_err(ConfigReloadError::Failed)?;
+            }
+        }
+
+        if current.security.api_keys != new.security.api_keys {
+            let stored_keys = self.session_store.load_api_keys().await.unwrap_or_default();
+            self.api_keys
+                .replace_all(with_rotated_keys(&new.security.api_keys, stored_keys));
+        }
+
+        if current.limits != new.limits || current.security.api_keys != new.security.api_keys {
+            self.concurrency_limiter.reset_key_limits();
+        }
+        if current.overload != new.overload {
+            self.concurrency_limiter.set_overload(new.overload.clone());
+        }
+
+        *self.config.write().unwrap() = Arc::new(new);
+        info!("🔄 Configuration reloaded: {}", changed.join(", "));
+        Ok(changed)
+    }
+
+    /// Start draining. Returns `false` if the server was already draining.
+    pub fn start_drain(&self, retry_after_seconds: u64) -> bool {
+        let mut drain = self.drain.write().unwrap();
+        if drain.is_some() {
+            return false;
+        }
+        *drain = Some(Drain {
+            since: chrono::Utc::now(),
+            retry_after_seconds,
+        });
+        gauge!("server_draining", 1.0);
+        info!("🚰 Draining: refusing new generations until resumed");
+        true
+    }
+
+    /// Accept new generations again. Returns `false` if the server was not draining.
+    pub fn resume(&self) -> bool {
+        let resumed = self.drain.write().unwrap().take().is_some();
+        if resumed {
+            gauge!("server_draining", 0.0);
+            info!("Resumed accepting generations");
+        }
+        resumed
+    }
+
+    pub fn draining(&self) -> Option<Drain> {
+        *self.drain.read().unwrap()
+    }
+
     pub async fn save_sessions(&self) {
         let snapshot = {
             let sessions = self.sessions.lock().await;
@@ -169,18 +1065,251 @@ impl AppState {
         }
     }
 
+    /// On a read-only replica, pick up sessions written by the instance sharing the session
+    /// database; serving instances already hold every session in memory
+    pub async fn refresh_replica_sessions(&self) {
+        if !self.config().server.read_only {
+            return;
+        }
+        match self.session_store.load_sessions().await {
+            Ok(sessions) => *self.sessions.lock().await = sessions,
+            Err(err) => error!("Failed to refresh sessions from the store: {}", err),
+        }
+    }
+
+    /// Flag that flips to `true` once `session_id` is deleted, so generations streaming into
+    /// the session can stop without locking the sessions map on every token. Take it while
+    /// holding the sessions lock to avoid racing a concurrent delete.
+    pub fn session_cancellation(&self, session_id: &str) -> Arc<AtomicBool> {
+        self.session_cancellations
+            .entry(session_id.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Peer to forward a request for `model` to. Models this server configures or serves itself
+    /// are never forwarded.
+    pub async fn federated_peer(&self, model: &str) -> Option<PeerConfig> {
+        let peer = self.federation.peer_for(model)?;
+        // A model that failed its canary checks here is better served by a peer
+        if self.model_unhealthy(model) {
+            return Some(peer);
+        }
+        if self.config().find_model(model).is_some() {
+            return None;
+        }
+        if self.engine.get_available_models().await.iter().any(|m| m == model) {
+            return None;
+        }
+        Some(peer)
+    }
+
+    /// Whether `model` (by name or id) failed `canary.failure_threshold` canary checks in a row
+    pub fn model_unhealthy(&self, model: &str) -> bool {
+        let config = self.config();
+        let id = config.find_model(model).map_or(model, |m| m.id.as_str());
+        !self.model_health.is_healthy(id)
+    }
+
+    /// Run one round of canary checks now, as the background task does every
+    /// `canary.interval_seconds` (even with `canary.enabled` off)
+    pub async fn run_canary_checks(&self) {
+        let config = self.config();
+        run_canary_round(
+            &self.engine,
+            &self.concurrency_limiter,
+            &self.model_health,
+            &config.canary,
+            &config.models.default_device,
+        )
+        .await;
+    }
+
+    /// Remove a session from memory and storage and cancel generations still using it
+    pub async fn delete_session(&self, session_id: &str) {
+        {
+            let mut sessions = self.sessions.lock().await;
+            if sessions.remove(session_id).is_some() {
+                self.events.publish(ServerEvent::SessionDeleted {
+                    session_id: session_id.to_string(),
+                });
+            }
+            if let Some((_, flag)) = self.session_cancellations.remove(session_id) {
+                flag.store(true, Ordering::Relaxed);
+            }
+        }
+        self.delete_session_record(session_id).await;
+    }
+
     pub async fn delete_session_record(&self, session_id: &str) {
         if let Err(err) = self.session_store.delete_session(session_id).await {
             error!("Failed to delete session {}: {}", session_id, err);
         }
     }
 
+    /// Save rate-limit windows and per-account usage now rather than at the next
+    /// `limits.persist_interval_seconds`, e.g. on shutdown. Does nothing while persisting is off
+    /// or on read-only replicas.
+    pub async fn flush_limits(&self) {
+        if persists_limits(&self.config()) {
+            flush_limits(&self.session_store, &self.rate_limiter, &self.stats).await;
+        }
+    }
+
+    pub async fn persist_api_key(&self, key: &ApiKeyConfig) {
+        if let Err(err) = self.session_store.upsert_api_key(key).await {
+            error!("Failed to persist API key {}: {}", key.name, err);
+        }
+    }
+
+    /// Store a prompt template, replacing any with the same name
+    pub async fn save_template(&self, template: PromptTemplate) -> Result<()> {
+        self.session_store.upsert_template(&template).await?;
+        self.templates.insert(template.name.clone(), template);
+        Ok(())
+    }
+
+    /// Returns `false` when there was no such template
+    pub async fn delete_template(&self, name: &str) -> Result<bool> {
+        if self.templates.remove(name).is_none() {
+            return Ok(false);
+        }
+        self.session_store.delete_template(name).await?;
+        Ok(true)
+    }
+
+    /// Rate message `index` of a session's history, replacing any earlier rating of it
+    pub async fn record_feedback(
+        &self,
+        session_id: &str,
+        index: usize,
+        rating: Rating,
+        comment: Option<String>,
+    ) -> Result<MessageFeedback> {
+        let feedback = {
+            let sessions = self.sessions.lock().await;
+            let history = sessions
+                .get(session_id)
+                .ok_or_else(|| FeedbackError::SessionNotFound(session_id.to_string()))?;
+            feedback::for_message(session_id, history, index, rating, comment)?
+        };
+        self.session_store.upsert_feedback(&feedback).await?;
+        increment_counter!("message_feedback_total", "rating" => rating.as_str());
+        Ok(feedback)
+    }
+
+    /// Recorder for one streamed response when `streaming.record_transcripts` is on. The
+    /// transcript is saved in the background once the recorder is dropped.
+    pub fn transcript_recorder(&self, endpoint: &str) -> Option<TranscriptRecorder> {
+        if !self.config().streaming.record_transcripts {
+            return None;
+        }
+        let store = self.session_store.clone();
+        Some(TranscriptRecorder::new(endpoint, move |transcript| {
+            increment_counter!("stream_transcripts_recorded_total", "endpoint" => transcript.endpoint.clone());
+            tokio::spawn(async move {
+                if let Err(e) = store.insert_transcript(&transcript).await {
+                    warn!("Failed to save transcript {}: {}", transcript.request_id, e);
+                }
+            });
+        }))
+    }
+
+    pub async fn transcript(&self, request_id: &str) -> Result<Option<Transcript>> {
+        self.session_store.load_transcript(request_id).await
+    }
+
+    /// Run the guardrails over a request's prompt and messages, logging whatever they do to the
+    /// `guardrails` target (see [`guardrails`](crate::guardrails))
+    pub fn screen(
+        &self,
+        endpoint: &str,
+        account: &str,
+        model: &str,
+        texts: &mut [&mut String],
+    ) -> Result<Vec<Refusal>, Refusal> {
+        let outcome = guardrails::screen(&self.config().guardrails, texts);
+        match &outcome {
+            Err(refusal) => warn!(
+                target: "guardrails",
+                endpoint,
+                account,
+                model,
+                policy_id = %refusal.policy_id,
+                category = %refusal.category,
+                "Request refused"
+            ),
+            Ok(applied) => {
+                for redaction in applied {
+                    info!(
+                        target: "guardrails",
+                        endpoint,
+                        account,
+                        model,
+                        policy_id = %redaction.policy_id,
+                        category = %redaction.category,
+                        "Prompt redacted"
+                    );
+                }
+            }
+        }
+        outcome
+    }
+
+    /// Save a finished `/completions` call in the background (see
+    /// [`request_log`](crate::request_log))
+    pub fn log_request(&self, entry: RequestLogEntry) {
+        increment_counter!("request_log_entries_total", "model" => entry.model.clone());
+        let store = self.session_store.clone();
+        tokio::spawn(async move {
+            if let Err(e) = store.insert_request_log(&entry).await {
+                warn!("Failed to log request {}: {}", entry.id, e);
+            }
+        });
+    }
+
+    pub async fn query_request_log(&self, query: &RequestLogQuery) -> Result<Vec<RequestLogEntry>> {
+        self.session_store.query_request_log(query).await
+    }
+
+    pub async fn request_log_entry(&self, id: &str) -> Result<Option<RequestLogEntry>> {
+        self.session_store.load_request_log(id).await
+    }
+
+    pub async fn session_stats(&self, top: usize) -> Result<SessionStats> {
+        self.session_store.session_stats(top).await
+    }
+
+    /// Stored feedback, oldest first, optionally only one rating
+    pub async fn export_feedback(&self, rating: Option<Rating>) -> Result<Vec<MessageFeedback>> {
+        let mut feedback = self.session_store.load_feedback().await?;
+        if let Some(rating) = rating {
+            feedback.retain(|f| f.rating == rating);
+        }
+        Ok(feedback)
+    }
+
+    /// Render a saved template with the request's variables
+    pub fn render_template(
+        &self,
+        name: &str,
+        values: &HashMap<String, serde_json::Value>,
+    ) -> Result<String, TemplateError> {
+        let template = self
+            .templates
+            .get(name)
+            .ok_or_else(|| TemplateError::NotFound(name.to_string()))?;
+        increment_counter!("prompt_template_renders_total", "template" => name.to_string());
+        templates::render(&template.template, values)
+    }
+
     /// Validate prompt length against configured limits
     pub fn validate_prompt_length(&self, prompt: &str) -> Result<()> {
-        if prompt.len() > self.config.limits.max_prompt_length {
+        let max_prompt_length = self.config().limits.max_prompt_length;
+        if prompt.chars().count() > max_prompt_length {
             anyhow::bail!(
                 "Prompt exceeds maximum length of {} characters",
-                self.config.limits.max_prompt_length
+                max_prompt_length
             );
         }
         Ok(())
@@ -188,42 +1317,416 @@ impl AppState {
 
     /// Check session limit
     pub async fn check_session_limit(&self) -> Result<()> {
+        let max_sessions = self.config().limits.max_sessions;
         let sessions = self.sessions.lock().await;
-        if sessions.len() >= self.config.limits.max_sessions {
+        if sessions.len() >= max_sessions {
             anyhow::bail!(
                 "Maximum number of sessions ({}) reached",
-                self.config.limits.max_sessions
+                max_sessions
             );
         }
         Ok(())
     }
 
+    /// Attribute a finished generation's tokens to its account and report it if it was slow
+    pub fn record_generation(&self, timing: RequestTiming<'_>) {
+        let prompt_tokens = timing.prompt_chars.div_ceil(CHARS_PER_TOKEN) as u64;
+        counter!("prompt_tokens_total", prompt_tokens, "account" => timing.account.to_string(), "model" => timing.model.to_string());
+        counter!("completion_tokens_total", timing.tokens, "account" => timing.account.to_string(), "model" => timing.model.to_string());
+        self.stats
+            .record_usage(timing.account, timing.model, prompt_tokens, timing.tokens);
+        if let Some(assignment) = timing.experiment {
+            let labels = [
+                ("experiment", assignment.experiment.clone()),
+                ("variant", assignment.variant.clone()),
+            ];
+            increment_counter!("experiment_requests_total", &labels);
+            counter!("experiment_completion_tokens_total", timing.tokens, &labels);
+            histogram!("experiment_duration_seconds", timing.duration, &labels);
+            if let Some(ttft) = timing.ttft {
+                histogram!("experiment_time_to_first_token_seconds", ttft, &labels);
+            }
+        }
+        self.report_if_slow(&timing);
+    }
+
+    /// Experiment variant for a request to `model`; sessions keep theirs
+    pub fn assign_experiment(&self, model: &str, session_id: Option<&str>) -> Option<Assignment> {
+        experiments::assign(&self.config(), model, session_id)
+    }
+
+    /// Log and count a finished generation that exceeded the configured duration or
+    /// time-to-first-token thresholds (tracing target `slow_requests`)
+    fn report_if_slow(&self, timing: &RequestTiming<'_>) {
+        let config = self.config();
+        let observability = &config.observability;
+        let over = |threshold: Option<u64>, secs: f64| {
+            threshold.is_some_and(|ms| secs * 1000.0 > ms as f64)
+        };
+
+        let reason = if over(observability.slow_request_threshold_ms, timing.duration) {
+            "duration"
+        } else if timing
+            .ttft
+            .is_some_and(|ttft| over(observability.slow_ttft_threshold_ms, ttft))
+        {
+            "ttft"
+        } else {
+            return;
+        };
+
+        increment_counter!("slow_requests_total", "endpoint" => timing.endpoint, "reason" => reason);
+        warn!(
+            target: "slow_requests",
+            endpoint = timing.endpoint,
+            model = timing.model,
+            prompt_chars = timing.prompt_chars,
+            tokens = timing.tokens,
+            duration_ms = (timing.duration * 1000.0) as u64,
+            ttft_ms = ?timing.ttft.map(|t| (t * 1000.0) as u64),
+            reason,
+            "Slow request"
+        );
+    }
+
+    /// Forward an error that is about to become a 5xx response to the error reporter
+    pub fn report_server_error(&self, endpoint: &str, model: &str, err: &anyhow::Error) {
+        if err.is::<EnginePanic>() {
+            return;
+        }
+        self.error_reporter.report(
+            ErrorReport::error(format!("{:#}", err))
+                .with("endpoint", endpoint)
+                .with("model", model),
+        );
+    }
+
     pub async fn run_inference_guarded(&self, req: InferenceRequest) -> Result<TokenStream> {
+        let model = req.model_name.clone();
+        let session_id = req.session_id.clone();
+        self.stats.record_request(&model);
+        self.events.publish(ServerEvent::GenerationStarted {
+            model: model.clone(),
+            session_id: session_id.clone(),
+        });
+        let finished = FinishedEvent {
+            events: self.events.clone(),
+            model: model.clone(),
+            session_id,
+            tokens: 0,
+            outcome: "failed",
+        };
         let fut = AssertUnwindSafe(self.engine.run_streaming_inference(req));
         match fut.catch_unwind().await {
-            Ok(result) => result.map(Self::guard_stream),
+            Ok(Ok(stream)) => {
+                self.sync_loaded_models().await;
+                Ok(self.guard_stream(stream, model, finished))
+            }
+            Ok(Err(e)) => {
+                self.stats.record_error(&model);
+                Err(e.into())
+            }
             Err(payload) => {
+                self.stats.record_error(&model);
                 let reason = panic_message(payload);
                 error!("Inference engine panicked: {}", reason);
-                Err(anyhow!("Inference engine panicked"))
+                self.error_reporter.report(
+                    ErrorReport::panic(reason)
+                        .with("model", model.as_str())
+                        .with("stage", "start"),
+                );
+                Err(EnginePanic.into())
+            }
+        }
+    }
+
+    /// Mirror a sample of requests to `shadow.model` in the background. The answer is thrown
+    /// away and only metrics are kept; requests are skipped when no generation slot is free.
+    pub fn shadow(&self, req: &InferenceRequest) {
+        if self.draining().is_some() {
+            return;
+        }
+        let config = self.config();
+        let shadow = &config.shadow;
+        let Some(target) = shadow.model.as_deref().and_then(|m| config.find_model(m)) else {
+            return;
+        };
+        let Some(source) = config.find_model(&req.model_name) else {
+            return;
+        };
+        let mirrored = shadow.source_models.is_empty()
+            || shadow
+                .source_models
+                .iter()
+                .any(|m| config.find_model(m).is_some_and(|m| m.id == source.id));
+        let sample = uuid::Uuid::new_v4().as_u64_pair().0 as f64 / u64::MAX as f64;
+        if source.id == target.id || !mirrored || sample >= shadow.fraction {
+            return;
+        }
+
+        let model = target.id.clone();
+        let Some(permit) = self.concurrency_limiter.try_acquire() else {
+            increment_counter!("shadow_requests_skipped_total", "model" => model);
+            return;
+        };
+        let mut req = req.clone();
+        req.model_name = model.clone();
+        req.session_id = None;
+        req.priority = None;
+        let state = self.clone();
+        tokio::spawn(async move {
+            permit.set_model(&model);
+            increment_counter!("shadow_requests_total", "model" => model.clone());
+            let start = Instant::now();
+            let mut stream = match state.run_inference_guarded(req).await {
+                Ok(stream) => permit.track(stream),
+                Err(e) => {
+                    increment_counter!("shadow_errors_total", "model" => model.clone());
+                    warn!("Shadow request to {} failed: {}", model, e);
+                    return;
+                }
+            };
+            let mut tokens = 0u64;
+            while let Some(token) = stream.next().await {
+                match token {
+                    Ok(_) => {
+                        if tokens == 0 {
+                            histogram!("shadow_time_to_first_token_seconds", start.elapsed().as_secs_f64(), "model" => model.clone());
+                        }
+                        tokens += 1;
+                    }
+                    Err(e) if EngineError::is_timeout(&e) => break,
+                    Err(e) => {
+                        increment_counter!("shadow_errors_total", "model" => model.clone());
+                        warn!("Shadow request to {} failed: {}", model, e);
+                        return;
+                    }
+                }
+            }
+            histogram!("shadow_duration_seconds", start.elapsed().as_secs_f64(), "model" => model.clone());
+            counter!("shadow_completion_tokens_total", tokens, "model" => model);
+        });
+    }
+
+    /// Condense the older turns of `req.messages` through the model when the conversation fills
+    /// `chat.compression_threshold` of the model's context (see [`compression`]). Models without
+    /// a configured `context_length` are left alone, and a failed condensing run keeps the full
+    /// history.
+    pub async fn compress_history(&self, req: &mut InferenceRequest) {
+        let config = self.config();
+        let Some(context_length) = config
+            .find_model(&req.model_name)
+            .and_then(|m| m.context_length)
+        else {
+            return;
+        };
+        let Some(messages) = req.messages.take() else {
+            return;
+        };
+        let Some(turns) = compression::older_turns(&messages, context_length, req.max_token, &config.chat) else {
+            req.messages = Some(messages);
+            return;
+        };
+
+        let model = req.model_name.clone();
+        let condense = compression::condense_request(req, &messages[turns.clone()], config.chat.compression_ratio);
+        let mut summary = String::new();
+        let result = match self.run_inference_guarded(condense).await {
+            Ok(mut stream) => loop {
+                match stream.next().await {
+                    Some(Ok(token)) => summary.push_str(token.as_str()),
+                    Some(Err(e)) if EngineError::is_timeout(&e) => break Ok(()),
+                    Some(Err(e)) => break Err(e),
+                    None => break Ok(()),
+                }
+            },
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) if !summary.trim().is_empty() => {
+                increment_counter!("history_compressions_total", "model" => model);
+                req.messages = Some(compression::with_summary(messages, turns, &summary));
+            }
+            Ok(()) => req.messages = Some(messages),
+            Err(e) => {
+                increment_counter!("history_compression_failures_total", "model" => model);
+                warn!("History compression failed; sending the full history: {}", e);
+                req.messages = Some(messages);
+            }
+        }
+    }
+
+    /// Run a `[compaction]` round now rather than at the next interval; returns how many
+    /// sessions were compacted
+    pub async fn compact_idle_sessions(&self) -> usize {
+        run_compaction_round(
+            &self.engine,
+            &self.concurrency_limiter,
+            &self.sessions,
+            &self.session_store,
+            &self.config(),
+        )
+        .await
+    }
+
+    /// Largest `max_token` an `auto_fit` request can have: what the model's `context_length`
+    /// leaves after the rendered prompt, within `limits.max_response_tokens`. The prompt is
+    /// counted with the model's tokenizer, or estimated when the engine cannot render it;
+    /// models without a `context_length` just get the limit.
+    pub async fn fit_max_tokens(&self, req: &InferenceRequest) -> Result<usize, ContextFull> {
+        let config = self.config();
+        let limit = config.limits.max_response_tokens;
+        let Some(context_length) = config
+            .find_model(&req.model_name)
+            .and_then(|m| m.context_length)
+        else {
+            return Ok(limit);
+        };
+        let prompt_tokens = match self.engine.render_prompt(req).await {
+            Ok(rendered) => rendered.prompt_tokens,
+            Err(e) => {
+                tracing::debug!("Estimating prompt tokens for auto_fit: {}", e);
+                let chars = match &req.messages {
+                    Some(messages) => messages.iter().map(|m| m.content.chars().count()).sum(),
+                    None => req.prompt.chars().count(),
+                };
+                chars.div_ceil(CHARS_PER_TOKEN)
+            }
+        };
+        match context_length.saturating_sub(prompt_tokens) {
+            0 => Err(ContextFull {
+                prompt_tokens,
+                context_length,
+            }),
+            room => Ok(room.min(limit)),
+        }
+    }
+
+    // Sharing key for requests that may join an identical generation, if enabled
+    fn in_flight_key(&self, account: &str, req: &InferenceRequest) -> Option<String> {
+        if req.session_id.is_some() || !self.config().cache.share_in_flight {
+            return None;
+        }
+        Some(request_key(account, req))
+    }
+
+    /// Attach to an identical generation from the same account that is already running. Callers
+    /// use this before queueing for a generation slot, since followers do not need one.
+    pub fn join_in_flight(&self, account: &str, req: &InferenceRequest) -> Option<TokenStream> {
+        let stream = self.in_flight.join(&self.in_flight_key(account, req)?)?;
+        increment_counter!("inflight_requests_shared_total", "model" => req.model_name.clone());
+        Some(stream)
+    }
+
+    /// Like [`run_inference_guarded`](Self::run_inference_guarded), but with
+    /// `cache.share_in_flight` identical sessionless requests from one account share one
+    /// generation. The returned stream holds `permit` (when given) until it ends. A shared
+    /// generation runs on a separate task that holds the permit instead, so it outlives the
+    /// client that started it while others still read and keeps its slot for as long.
+    pub async fn run_inference_shared(
+        &self,
+        account: &str,
+        req: InferenceRequest,
+        permit: Option<GenerationPermit>,
+    ) -> Result<TokenStream> {
+        let hold = |permit: Option<GenerationPermit>, stream: TokenStream| match permit {
+            Some(permit) => permit.hold(stream),
+            None => stream,
+        };
+        let Some(key) = self.in_flight_key(account, &req) else {
+            let stream = self.run_inference_guarded(req).await?;
+            return Ok(hold(permit, stream));
+        };
+        let (shared, stream) = match self.in_flight.claim(&key) {
+            Claim::Leader(shared, stream) => (shared, stream),
+            Claim::Follower(stream) => {
+                increment_counter!("inflight_requests_shared_total", "model" => req.model_name.clone());
+                return Ok(stream);
+            }
+        };
+
+        let (started_tx, started_rx) = oneshot::channel();
+        let state = self.clone();
+        tokio::spawn(async move {
+            match state.run_inference_guarded(req).await {
+                Ok(source) => {
+                    let _ = started_tx.send(Ok(()));
+                    state.in_flight.drive(&key, shared, hold(permit, source)).await;
+                }
+                Err(e) => {
+                    state.in_flight.fail(&key, &shared, &e);
+                    let _ = started_tx.send(Err(e));
+                }
             }
+        });
+        match started_rx.await {
+            Ok(Ok(())) => Ok(stream),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(anyhow!("generation task ended before starting")),
         }
     }
 
-    fn guard_stream(stream: TokenStream) -> TokenStream {
+    /// Publish `model_loaded`/`model_unloaded` for changes to the engine's cached models since
+    /// the last check; skipped while nobody listens to `/events`
+    pub async fn sync_loaded_models(&self) {
+        if self.events.has_subscribers() {
+            self.events.sync_models(self.engine.cached_models().await);
+        }
+    }
+
+    fn guard_stream(&self, stream: TokenStream, model: String, mut finished: FinishedEvent) -> TokenStream {
+        let stats = self.stats.clone();
+        let reporter = self.error_reporter.clone();
+        let time_limit = self.config().limits.max_generation_seconds;
+        let deadline = time_limit.map(|secs| tokio::time::Instant::now() + Duration::from_secs(secs));
+        // Dropped with the stream, whether it ran to the end or not
+        finished.outcome = "stopped";
         Box::pin(stream! {
             let mut inner = stream;
+            let mut finished = finished;
             loop {
-                let next = AssertUnwindSafe(inner.next()).catch_unwind().await;
+                let next = AssertUnwindSafe(inner.next()).catch_unwind();
+                let next = match deadline {
+                    Some(at) => match tokio::time::timeout_at(at, next).await {
+                        Ok(next) => next,
+                        Err(_) => {
+                            // Dropping the engine stream stops generation
+                            let secs = time_limit.unwrap_or_default();
+                            warn!("⏱️ Generation for {} stopped after the {}s time limit", model, secs);
+                            increment_counter!("generation_time_limit_total", "model" => model.clone());
+                            yield Err(EngineError::Timeout(secs).into());
+                            break;
+                        }
+                    },
+                    None => next.await,
+                };
                 match next {
                     Ok(Some(item)) => {
+                        match &item {
+                            Ok(_) => {
+                                stats.record_tokens(&model, 1);
+                                finished.tokens += 1;
+                            }
+                            Err(_) => {
+                                stats.record_error(&model);
+                                finished.outcome = "failed";
+                            }
+                        }
                         yield item;
                     }
-                    Ok(None) => break,
+                    Ok(None) => {
+                        if finished.outcome != "failed" {
+                            finished.outcome = "completed";
+                        }
+                        break;
+                    }
                     Err(payload) => {
                         let reason = panic_message(payload);
                         error!("Inference stream panicked: {}", reason);
-                        yield Err(anyhow!("Inference engine panicked"));
+                        stats.record_error(&model);
+                        finished.outcome = "failed";
+                        reporter.report(ErrorReport::panic(reason).with("model", model.as_str()).with("stage", "stream"));
+                        yield Err(EnginePanic.into());
                         break;
                     }
                 }
@@ -232,6 +1735,32 @@ impl AppState {
     }
 }
 
+// Publishes `generation_finished` when dropped: with the guarded stream, or right away when the
+// engine fails to start
+struct FinishedEvent {
+    events: Arc<EventBus>,
+    model: String,
+    session_id: Option<String>,
+    tokens: usize,
+    outcome: &'static str,
+}
+
+impl Drop for FinishedEvent {
+    fn drop(&mut self) {
+        self.events.publish(ServerEvent::GenerationFinished {
+            model: std::mem::take(&mut self.model),
+            session_id: self.session_id.take(),
+            tokens: self.tokens,
+            outcome: self.outcome,
+        });
+    }
+}
+
+// Request log timestamps are stored as fixed-width UTC strings, so they sort and compare as text
+fn log_timestamp(at: DateTime<Utc>) -> String {
+    at.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
 fn panic_message(payload: Box<dyn Any + Send>) -> String {
     if let Some(msg) = payload.downcast_ref::<&str>() {
         msg.to_string()
@@ -241,3 +1770,356 @@ fn panic_message(payload: Box<dyn Any + Send>) -> String {
         "unknown panic".to_string()
     }
 }
+
+// Periodically drop rate-limit windows of keys that went quiet. The interval is re-read from
+// the live config, so a reload applies after the current wait.
+fn spawn_rate_limit_cleanup(rate_limiter: Arc<RateLimiter>, config: Arc<RwLock<Arc<Config>>>) -> BackgroundTask {
+    BackgroundTask(tokio::spawn(async move {
+        loop {
+            let interval = config.read().unwrap().limits.rate_limit_cleanup_seconds;
+            tokio::time::sleep(Duration::from_secs(interval)).await;
+            rate_limiter.cleanup();
+        }
+    }))
+}
+
+// Read-only replicas would overwrite the primary's windows and usage with their own
+fn persists_limits(config: &Config) -> bool {
+    config.limits.persist_interval_seconds > 0 && !config.server.read_only
+}
+
+async fn flush_limits(store: &SessionStore, rate_limiter: &RateLimiter, stats: &RuntimeStats) {
+    if let Err(err) = store.save_limits(&rate_limiter.snapshot(), &stats.usage_records()).await {
+        error!("Failed to save rate limits and usage: {}", err);
+    }
+}
+
+// Save rate-limit windows and usage every `limits.persist_interval_seconds`, re-read from the live
+// config; while it is 0, or on a read-only replica, nothing is saved
+fn spawn_limits_flush(
+    store: Arc<SessionStore>,
+    rate_limiter: Arc<RateLimiter>,
+    stats: Arc<RuntimeStats>,
+    config: Arc<RwLock<Arc<Config>>>,
+) -> BackgroundTask {
+    BackgroundTask(tokio::spawn(async move {
+        loop {
+            let interval = config.read().unwrap().limits.persist_interval_seconds;
+            tokio::time::sleep(Duration::from_secs(if interval == 0 { 60 } else { interval })).await;
+            if persists_limits(&config.read().unwrap()) {
+                flush_limits(&store, &rate_limiter, &stats).await;
+            }
+        }
+    }))
+}
+
+// Delete request log entries past `request_log.retention_days` and stream transcripts past
+// `streaming.transcript_retention_hours`, once an hour
+fn spawn_log_pruning(store: Arc<SessionStore>, config: Arc<RwLock<Arc<Config>>>) -> BackgroundTask {
+    BackgroundTask(tokio::spawn(async move {
+        loop {
+            let (retention_days, transcript_hours) = {
+                let config = config.read().unwrap();
+                (config.request_log.retention_days, config.streaming.transcript_retention_hours)
+            };
+            if retention_days > 0 {
+                let cutoff = Utc::now() - chrono::Duration::days(retention_days as i64);
+                match store.prune_request_log(cutoff).await {
+                    Ok(0) => {}
+                    Ok(pruned) => {
+                        counter!("request_log_pruned_total", pruned);
+                        info!("Pruned {} request log entries older than {} days", pruned, retention_days);
+                    }
+                    Err(e) => warn!("Failed to prune the request log: {}", e),
+                }
+            }
+            if transcript_hours > 0 {
+                let cutoff = Utc::now() - chrono::Duration::hours(transcript_hours as i64);
+                match store.prune_transcripts(cutoff).await {
+                    Ok(0) => {}
+                    Ok(pruned) => info!("Pruned {} stream transcripts older than {} hours", pruned, transcript_hours),
+                    Err(e) => warn!("Failed to prune stream transcripts: {}", e),
+                }
+            }
+            tokio::time::sleep(Duration::from_secs(3600)).await;
+        }
+    }))
+}
+
+// Forget finished jobs and delete their output files once `jobs.retention_hours` have passed
+fn spawn_job_pruning(jobs: Arc<JobRegistry>, config: Arc<RwLock<Arc<Config>>>) -> BackgroundTask {
+    BackgroundTask(tokio::spawn(async move {
+        loop {
+            let retention_hours = config.read().unwrap().jobs.retention_hours;
+            if retention_hours > 0 {
+                let cutoff = Utc::now() - chrono::Duration::hours(retention_hours as i64);
+                let pruned = jobs.prune(cutoff).await;
+                if pruned > 0 {
+                    counter!("jobs_pruned_total", pruned as u64);
+                    info!("Pruned {} jobs finished more than {} hours ago", pruned, retention_hours);
+                }
+            }
+            tokio::time::sleep(Duration::from_secs(600)).await;
+        }
+    }))
+}
+
+// Ask `model` to condense `turns` for compaction. Runs on the engine directly, on a slot the
+// caller holds.
+async fn summarize_turns(
+    engine: &Arc<dyn InferenceEngine>,
+    model: &str,
+    device: &str,
+    turns: &[ChatMessage],
+    ratio: f64,
+) -> std::result::Result<String, String> {
+    let base: InferenceRequest = serde_json::from_value(serde_json::json!({
+        "model-name": model,
+        "device": device,
+    }))
+    .map_err(|e| e.to_string())?;
+    let req = compression::condense_request(&base, turns, ratio);
+    let summarize = async {
+        let mut stream = engine
+            .run_streaming_inference(req)
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut summary = String::new();
+        while let Some(token) = stream.next().await {
+            summary.push_str(token.map_err(|e| e.to_string())?.as_str());
+        }
+        Ok::<_, String>(summary)
+    };
+    match AssertUnwindSafe(summarize).catch_unwind().await {
+        Ok(Ok(summary)) if !summary.trim().is_empty() => Ok(summary),
+        Ok(Ok(_)) => Err("the model returned an empty summary".to_string()),
+        Ok(Err(e)) => Err(e),
+        Err(payload) => Err(format!("engine panicked: {}", panic_message(payload))),
+    }
+}
+
+// Replace the older turns of sessions whose latest message is older than
+// `compaction.after_hours` with a summary, and save them. Sessions without timestamps are left
+// alone. Summaries only run on spare generation slots, and are written outside the sessions
+// lock, so a session that changed meanwhile is left for the next round.
+async fn run_compaction_round(
+    engine: &Arc<dyn InferenceEngine>,
+    limiter: &ConcurrencyLimiter,
+    sessions: &Mutex<HashMap<String, Vec<ChatMessage>>>,
+    store: &SessionStore,
+    config: &Config,
+) -> usize {
+    let settings = &config.compaction;
+    let Some(model) = settings
+        .model
+        .clone()
+        .or_else(|| config.models.available_models.first().map(|m| m.id.clone()))
+    else {
+        return 0;
+    };
+    let cutoff = Utc::now() - chrono::Duration::hours(settings.after_hours as i64);
+    // Sessions saved before messages carried timestamps count as the oldest, and idle sessions
+    // are compacted oldest first in case the round runs out of slots
+    let mut candidates: Vec<(Option<DateTime<Utc>>, String, Vec<ChatMessage>)> = sessions
+        .lock()
+        .await
+        .iter()
+        .map(|(session_id, history)| {
+            let last = history.iter().filter_map(|m| m.timestamp).max();
+            (last, session_id, history)
+        })
+        .filter(|(last, _, history)| {
+            !last.is_some_and(|last| last >= cutoff)
+                && compression::compactable_turns(history, settings.keep_messages).is_some()
+        })
+        .map(|(last, session_id, history)| (last, session_id.clone(), history.clone()))
+        .collect();
+    candidates.sort_by_key(|(last, _, _)| *last);
+
+    let mut compacted = 0;
+    for (_, session_id, history) in candidates {
+        let Some(turns) = compression::compactable_turns(&history, settings.keep_messages) else {
+            continue;
+        };
+        let Some(permit) = limiter.try_acquire() else {
+            increment_counter!("session_compactions_skipped_total");
+            break;
+        };
+        permit.set_model(&model);
+        let device = &config.models.default_device;
+        let ratio = config.chat.compression_ratio;
+        let summary = match summarize_turns(engine, &model, device, &history[turns.clone()], ratio).await {
+            Ok(summary) => summary,
+            Err(e) => {
+                increment_counter!("session_compaction_failures_total");
+                warn!("Failed to compact session {}: {}", session_id, e);
+                continue;
+            }
+        };
+        drop(permit);
+
+        let before = history.len();
+        let replacement = compression::with_summary(history.clone(), turns, &summary);
+        // Held while saving, so a newer version of the session cannot be saved first
+        let mut sessions = sessions.lock().await;
+        match sessions.get_mut(&session_id) {
+            Some(current) if *current == history => *current = replacement.clone(),
+            _ => continue,
+        }
+        if let Err(e) = store.upsert_session(&session_id, &replacement).await {
+            error!("Failed to persist compacted session {}: {}", session_id, e);
+        }
+        drop(sessions);
+        increment_counter!("session_compactions_total");
+        info!(
+            "Compacted session {}: {} messages down to {}",
+            session_id,
+            before,
+            replacement.len()
+        );
+        compacted += 1;
+    }
+    compacted
+}
+
+// Compact idle sessions every `compaction.interval_seconds`, re-read from the live config.
+// Replicas load no models, so they never compact.
+fn spawn_compaction(
+    engine: Arc<dyn InferenceEngine>,
+    limiter: Arc<ConcurrencyLimiter>,
+    sessions: Arc<Mutex<HashMap<String, Vec<ChatMessage>>>>,
+    store: Arc<SessionStore>,
+    config: Arc<RwLock<Arc<Config>>>,
+) -> BackgroundTask {
+    BackgroundTask(tokio::spawn(async move {
+        loop {
+            let interval = config.read().unwrap().compaction.interval_seconds;
+            tokio::time::sleep(Duration::from_secs(interval)).await;
+            let config = config.read().unwrap().clone();
+            if config.compaction.enabled
+                && !config.server.read_only
+                && !config.server.tokenizer_only
+            {
+                run_compaction_round(&engine, &limiter, &sessions, &store, &config).await;
+            }
+        }
+    }))
+}
+
+// Keep the federation routing table current. Peers are re-read from the live config, so
+// added or removed peers apply at the next refresh.
+fn spawn_federation_refresh(
+    federation: Arc<Federation>,
+    config: Arc<RwLock<Arc<Config>>>,
+) -> BackgroundTask {
+    BackgroundTask(tokio::spawn(async move {
+        loop {
+            let settings = config.read().unwrap().federation.clone();
+            if !settings.peers.is_empty() || !federation.models().is_empty() {
+                federation.refresh(&settings.peers).await;
+            }
+            tokio::time::sleep(Duration::from_secs(settings.refresh_seconds)).await;
+        }
+    }))
+}
+
+// Check every loaded model with a tiny generation. Models are skipped while no generation slot
+// is free, so canaries never queue ahead of real traffic.
+async fn run_canary_round(
+    engine: &Arc<dyn InferenceEngine>,
+    limiter: &ConcurrencyLimiter,
+    health: &ModelHealth,
+    canary: &CanaryConfig,
+    device: &str,
+) {
+    let models = engine.cached_models().await;
+    health.retain(&models);
+    for model in models {
+        let Some(permit) = limiter.try_acquire() else {
+            increment_counter!("canary_checks_skipped_total", "model" => model);
+            continue;
+        };
+        permit.set_model(&model);
+        match canary_check(engine, &model, canary, device).await {
+            Ok(()) => {
+                if !health.is_healthy(&model) {
+                    info!("✅ Model {} passed its canary check and is back in rotation", model);
+                }
+                increment_counter!("canary_checks_total", "model" => model.clone(), "result" => "ok");
+                health.record_success(&model);
+            }
+            Err(e) => {
+                increment_counter!("canary_checks_total", "model" => model.clone(), "result" => "failed");
+                if health.record_failure(&model, e.clone(), canary.failure_threshold) {
+                    error!(
+                        "❌ Model {} failed {} canary checks in a row and is out of rotation: {}",
+                        model, canary.failure_threshold, e
+                    );
+                } else {
+                    warn!("⚠️ Canary check of {} failed: {}", model, e);
+                }
+            }
+        }
+    }
+}
+
+// One tiny generation; it must produce at least one token within `canary.timeout_seconds`
+async fn canary_check(
+    engine: &Arc<dyn InferenceEngine>,
+    model: &str,
+    canary: &CanaryConfig,
+    device: &str,
+) -> std::result::Result<(), String> {
+    let req: InferenceRequest = serde_json::from_value(serde_json::json!({
+        "model-name": model,
+        "prompt": canary.prompt,
+        "max-token": canary.max_tokens,
+        "temperature": 0.0,
+        "device": device,
+    }))
+    .map_err(|e| e.to_string())?;
+    let check = async {
+        let mut stream = engine
+            .run_streaming_inference(req)
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut tokens = 0;
+        while let Some(token) = stream.next().await {
+            token.map_err(|e| e.to_string())?;
+            tokens += 1;
+        }
+        if tokens == 0 {
+            return Err("no tokens generated".to_string());
+        }
+        Ok(())
+    };
+    let timeout = Duration::from_secs(canary.timeout_seconds);
+    match tokio::time::timeout(timeout, AssertUnwindSafe(check).catch_unwind()).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(payload)) => Err(format!("engine panicked: {}", panic_message(payload))),
+        Err(_) => Err(format!("no answer within {}s", canary.timeout_seconds)),
+    }
+}
+
+fn spawn_canary(
+    engine: Arc<dyn InferenceEngine>,
+    limiter: Arc<ConcurrencyLimiter>,
+    health: Arc<ModelHealth>,
+    config: Arc<RwLock<Arc<Config>>>,
+) -> BackgroundTask {
+    BackgroundTask(tokio::spawn(async move {
+        loop {
+            let interval = config.read().unwrap().canary.interval_seconds;
+            tokio::time::sleep(Duration::from_secs(interval)).await;
+            let config = config.read().unwrap().clone();
+            // Replicas load no models, so there is nothing to check
+            if config.canary.enabled
+                && !config.server.read_only
+                && !config.server.tokenizer_only
+            {
+                let device = &config.models.default_device;
+                run_canary_round(&engine, &limiter, &health, &config.canary, device).await;
+            }
+        }
+    }))
+}
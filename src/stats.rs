@@ -0,0 +1,297 @@
+use crate::config::PricingConfig;
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// In-process request/token/error totals backing `GET /stats`. Prometheus keeps the
+/// authoritative time series; these are the same numbers in a form people can read.
+pub struct RuntimeStats {
+    started: Instant,
+    started_at: DateTime<Utc>,
+    totals: Arc<Counters>,
+    per_model: Arc<DashMap<String, Arc<Counters>>>,
+    per_account: Arc<DashMap<(String, String), Arc<TokenCounters>>>,
+}
+
+#[derive(Default)]
+struct TokenCounters {
+    requests: AtomicU64,
+    prompt_tokens: AtomicU64,
+    completion_tokens: AtomicU64,
+}
+
+#[derive(Default)]
+struct Counters {
+    requests: AtomicU64,
+    tokens: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl Counters {
+    fn snapshot(&self) -> UsageCounts {
+        UsageCounts {
+            requests: self.requests.load(Ordering::Relaxed),
+            tokens: self.tokens.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// One account's counters for one model, as saved across restarts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsageRecord {
+    pub account: String,
+    pub model: String,
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct UsageCounts {
+    pub requests: u64,
+    pub tokens: u64,
+    pub errors: u64,
+}
+
+/// Token usage and estimated cost for one account (API key name), overall and per model
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct AccountUsage {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub estimated_cost: f64,
+    pub models: BTreeMap<String, ModelUsage>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ModelUsage {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub estimated_cost: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StatsSnapshot {
+    pub started_at: DateTime<Utc>,
+    pub uptime_seconds: u64,
+    pub totals: UsageCounts,
+    pub models: BTreeMap<String, UsageCounts>,
+}
+
+impl RuntimeStats {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            started_at: Utc::now(),
+            totals: Arc::new(Counters::default()),
+            per_model: Arc::new(DashMap::new()),
+            per_account: Arc::new(DashMap::new()),
+        }
+    }
+
+    pub fn record_request(&self, model: &str) {
+        self.record(model, |c| &c.requests, 1);
+    }
+
+    pub fn record_tokens(&self, model: &str, tokens: u64) {
+        self.record(model, |c| &c.tokens, tokens);
+    }
+
+    pub fn record_error(&self, model: &str) {
+        self.record(model, |c| &c.errors, 1);
+    }
+
+    /// Attribute a finished generation's prompt and completion tokens to an account
+    pub fn record_usage(
+        &self,
+        account: &str,
+        model: &str,
+        prompt_tokens: u64,
+        completion_tokens: u64,
+    ) {
+        let counters = self
+            .per_account
+            .entry((account.to_string(), model.to_string()))
+            .or_default()
+            .clone();
+        counters.requests.fetch_add(1, Ordering::Relaxed);
+        counters
+            .prompt_tokens
+            .fetch_add(prompt_tokens, Ordering::Relaxed);
+        counters
+            .completion_tokens
+            .fetch_add(completion_tokens, Ordering::Relaxed);
+    }
+
+    /// Every account's counters, for saving across restarts
+    pub fn usage_records(&self) -> Vec<UsageRecord> {
+        self.per_account
+            .iter()
+            .map(|entry| {
+                let (account, model) = entry.key();
+                let counters = entry.value();
+                UsageRecord {
+                    account: account.clone(),
+                    model: model.clone(),
+                    requests: counters.requests.load(Ordering::Relaxed),
+                    prompt_tokens: counters.prompt_tokens.load(Ordering::Relaxed),
+                    completion_tokens: counters.completion_tokens.load(Ordering::Relaxed),
+                }
+            })
+            .collect()
+    }
+
+    /// Add saved counters to an account's usage, e.g. those of the previous run
+    pub fn restore_usage(&self, record: &UsageRecord) {
+        let counters = self
+            .per_account
+            .entry((record.account.clone(), record.model.clone()))
+            .or_default()
+            .clone();
+        counters.requests.fetch_add(record.requests, Ordering::Relaxed);
+        counters
+            .prompt_tokens
+            .fetch_add(record.prompt_tokens, Ordering::Relaxed);
+        counters
+            .completion_tokens
+            .fetch_add(record.completion_tokens, Ordering::Relaxed);
+    }
+
+    /// Per-account usage with costs estimated from the current pricing
+    pub fn usage(&self, pricing: &PricingConfig) -> BTreeMap<String, AccountUsage> {
+        let mut accounts: BTreeMap<String, AccountUsage> = BTreeMap::new();
+        for entry in self.per_account.iter() {
+            let (account, model) = entry.key();
+            let counters = entry.value();
+            let prompt_tokens = counters.prompt_tokens.load(Ordering::Relaxed);
+            let completion_tokens = counters.completion_tokens.load(Ordering::Relaxed);
+            let usage = ModelUsage {
+                requests: counters.requests.load(Ordering::Relaxed),
+                prompt_tokens,
+                completion_tokens,
+                estimated_cost: pricing.cost(model, prompt_tokens, completion_tokens),
+            };
+
+            let totals = accounts.entry(account.clone()).or_default();
+            totals.requests += usage.requests;
+            totals.prompt_tokens += usage.prompt_tokens;
+            totals.completion_tokens += usage.completion_tokens;
+            totals.estimated_cost += usage.estimated_cost;
+            totals.models.insert(model.clone(), usage);
+        }
+        accounts
+    }
+
+    fn record(&self, model: &str, field: fn(&Counters) -> &AtomicU64, n: u64) {
+        field(&self.totals).fetch_add(n, Ordering::Relaxed);
+        let counters = self.per_model.entry(model.to_string()).or_default().clone();
+        field(&counters).fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            started_at: self.started_at,
+            uptime_seconds: self.started.elapsed().as_secs(),
+            totals: self.totals.snapshot(),
+            models: self
+                .per_model
+                .iter()
+                .map(|entry| (entry.key().clone(), entry.value().snapshot()))
+                .collect(),
+        }
+    }
+}
+
+impl Default for RuntimeStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clone for RuntimeStats {
+    fn clone(&self) -> Self {
+        Self {
+            started: self.started,
+            started_at: self.started_at,
+            totals: self.totals.clone(),
+            per_model: self.per_model.clone(),
+            per_account: self.per_account.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats_totals_and_per_model() {
+        let stats = RuntimeStats::new();
+        stats.record_request("qwen");
+        stats.record_tokens("qwen", 5);
+        stats.record_request("phi");
+        stats.record_error("phi");
+
+        let snapshot = stats.snapshot();
+        assert_eq!(
+            snapshot.totals,
+            UsageCounts {
+                requests: 2,
+                tokens: 5,
+                errors: 1
+            }
+        );
+        assert_eq!(snapshot.models["qwen"].tokens, 5);
+        assert_eq!(snapshot.models["phi"].errors, 1);
+    }
+
+    #[test]
+    fn test_usage_cost_per_account() {
+        let stats = RuntimeStats::new();
+        stats.record_usage("team-a", "qwen", 1000, 500);
+        stats.record_usage("team-a", "qwen", 1000, 500);
+        stats.record_usage("team-a", "phi", 200, 100);
+        stats.record_usage("team-b", "phi", 100, 100);
+
+        let mut pricing = PricingConfig::default();
+        pricing.default.prompt_per_1k = 0.5;
+        pricing.models.insert(
+            "qwen".to_string(),
+            crate::config::TokenPrice {
+                prompt_per_1k: 1.0,
+                completion_per_1k: 2.0,
+            },
+        );
+
+        let usage = stats.usage(&pricing);
+        let team_a = &usage["team-a"];
+        assert_eq!(team_a.requests, 3);
+        assert_eq!(team_a.prompt_tokens, 2200);
+        assert_eq!(team_a.models["qwen"].estimated_cost, 4.0);
+        assert_eq!(team_a.models["phi"].estimated_cost, 0.1);
+        assert!((team_a.estimated_cost - 4.1).abs() < 1e-9);
+        assert_eq!(usage["team-b"].completion_tokens, 100);
+    }
+
+    #[test]
+    fn test_usage_records_round_trip() {
+        let stats = RuntimeStats::new();
+        stats.record_usage("team-a", "qwen", 1000, 500);
+
+        let restarted = RuntimeStats::new();
+        for record in stats.usage_records() {
+            restarted.restore_usage(&record);
+        }
+        restarted.record_usage("team-a", "qwen", 10, 5);
+        let usage = restarted.usage(&PricingConfig::default());
+        assert_eq!(usage["team-a"].requests, 2);
+        assert_eq!(usage["team-a"].prompt_tokens, 1010);
+        assert_eq!(usage["team-a"].completion_tokens, 505);
+    }
+}
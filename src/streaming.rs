@@ -0,0 +1,391 @@
+//! Chunk coalescing for the SSE and WebSocket forwarding paths.
+//!
+//! Fast models can produce thousands of tokens per second, and sending each one as its own
+//! HTTP chunk or WebSocket frame costs more CPU and bandwidth than the text itself. Tokens are
+//! buffered until `streaming.coalesce_window_ms` has passed since the first buffered token or
+//! the buffer reaches `streaming.coalesce_max_chars`, whichever comes first.
+//!
+//! Generation also runs decoupled from the client through a bounded queue of
+//! `streaming.buffer_tokens`. Once the queue is full the engine waits for the client instead
+//! of buffering without limit, and with `streaming.slow_consumer_timeout_ms` set a client that
+//! stays behind that long has its generation stopped and receives an error after the queued
+//! tokens.
+//!
+//! For long prompts [`with_prefill`] puts the engine's prompt processing reports in front of the
+//! chunks, so a client can show progress instead of a stream that seems stuck until the first
+//! token.
+
+use crate::config::StreamingConfig;
+use crate::engine::{Prefill, Token, TokenStream};
+use anyhow::{anyhow, Result};
+use async_stream::stream;
+use futures_util::{Stream, StreamExt};
+use metrics::increment_counter;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, error::TrySendError, UnboundedReceiver};
+use tokio::time::Instant;
+
+/// One forwarded piece of text and the number of engine tokens it contains
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Chunk {
+    pub text: Token,
+    pub tokens: u64,
+}
+
+// Text buffered while coalescing, not yet sent
+#[derive(Default)]
+struct Pending {
+    text: String,
+    chars: usize,
+    tokens: u64,
+}
+
+impl Pending {
+    fn take(&mut self) -> Chunk {
+        let pending = std::mem::take(self);
+        Chunk {
+            text: pending.text.into(),
+            tokens: pending.tokens,
+        }
+    }
+}
+
+pub type ChunkStream = Pin<Box<dyn Stream<Item = Result<Chunk>> + Send>>;
+
+/// Everything between the engine and an SSE or WebSocket client: the bounded queue, then
+/// coalescing
+pub fn forward(stream: TokenStream, config: &StreamingConfig) -> ChunkStream {
+    coalesce(bounded(stream, config), config)
+}
+
+/// A forwarded chunk, or prompt processing reported before the first one
+#[derive(Debug)]
+pub enum Streamed {
+    Prefill(Prefill),
+    Chunk(Result<Chunk>),
+}
+
+/// The engine's reports on `progress` until `stream` yields its first chunk, then the chunks.
+/// Reports covering fewer than `min_tokens` prompt tokens are dropped.
+pub fn with_prefill(
+    stream: ChunkStream,
+    progress: Option<UnboundedReceiver<Prefill>>,
+    min_tokens: usize,
+) -> Pin<Box<dyn Stream<Item = Streamed> + Send>> {
+    let Some(mut progress) = progress else {
+        return Box::pin(stream.map(Streamed::Chunk));
+    };
+    Box::pin(stream! {
+        let mut stream = stream;
+        loop {
+            // Reports already sent go out before the token that follows them. The branch for
+            // `progress` is disabled once the engine drops its sender.
+            let next = tokio::select! {
+                biased;
+                Some(report) = progress.recv() => Streamed::Prefill(report),
+                item = stream.next() => match item {
+                    Some(item) => Streamed::Chunk(item),
+                    None => return,
+                },
+            };
+            match next {
+                Streamed::Prefill(report) if report.total < min_tokens => {}
+                Streamed::Prefill(report) => yield Streamed::Prefill(report),
+                Streamed::Chunk(item) => {
+                    yield Streamed::Chunk(item);
+                    break;
+                }
+            }
+        }
+        while let Some(item) = stream.next().await {
+            yield Streamed::Chunk(item);
+        }
+    })
+}
+
+/// Pull tokens on a separate task into a queue of at most `streaming.buffer_tokens`. The task
+/// ends, dropping the engine stream, when the reader goes away or falls behind for longer than
+/// `streaming.slow_consumer_timeout_ms`.
+pub fn bounded(stream: TokenStream, config: &StreamingConfig) -> TokenStream {
+    let (tx, mut rx) = mpsc::channel(config.buffer_tokens.max(1));
+    let timeout = Duration::from_millis(config.slow_consumer_timeout_ms);
+    let abandoned = Arc::new(AtomicBool::new(false));
+
+    let producer_abandoned = abandoned.clone();
+    tokio::spawn(async move {
+        let mut stream = stream;
+        while let Some(item) = stream.next().await {
+            let permit = match tx.try_reserve() {
+                Ok(permit) => permit,
+                Err(TrySendError::Closed(())) => return,
+                Err(TrySendError::Full(())) => {
+                    increment_counter!("stream_backpressure_waits_total");
+                    let reserved = if timeout.is_zero() {
+                        tx.reserve().await
+                    } else {
+                        match tokio::time::timeout(timeout, tx.reserve()).await {
+                            Ok(reserved) => reserved,
+                            Err(_) => {
+                                increment_counter!("stream_slow_consumer_aborts_total");
+                                producer_abandoned.store(true, Ordering::SeqCst);
+                                return;
+                            }
+                        }
+                    };
+                    match reserved {
+                        Ok(permit) => permit,
+                        Err(_) => return,
+                    }
+                }
+            };
+            permit.send(item);
+        }
+    });
+
+    Box::pin(stream! {
+        while let Some(item) = rx.recv().await {
+            yield item;
+        }
+        if abandoned.load(Ordering::SeqCst) {
+            yield Err(anyhow!("client fell too far behind; generation stopped"));
+        }
+    })
+}
+
+/// Group tokens into chunks as configured. With coalescing disabled every token is its own
+/// chunk. Errors flush the buffer first so ordering is preserved.
+pub fn coalesce(stream: TokenStream, config: &StreamingConfig) -> ChunkStream {
+    let window = Duration::from_millis(config.coalesce_window_ms);
+    let max_chars = config.coalesce_max_chars;
+    if window.is_zero() && max_chars == 0 {
+        return Box::pin(stream.map(|item| item.map(|text| Chunk { text, tokens: 1 })));
+    }
+
+    Box::pin(stream! {
+        let mut inner = stream;
+        let mut buffer = Pending::default();
+        let mut deadline: Option<Instant> = None;
+
+        loop {
+            let next = match deadline {
+                Some(at) => match tokio::time::timeout_at(at, inner.next()).await {
+                    Ok(next) => next,
+                    Err(_) => {
+                        deadline = None;
+                        yield Ok(buffer.take());
+                        continue;
+                    }
+                },
+                None => inner.next().await,
+            };
+
+            match next {
+                Some(Ok(token)) => {
+                    if buffer.tokens == 0 && !window.is_zero() {
+                        deadline = Some(Instant::now() + window);
+                    }
+                    buffer.text.push_str(&token);
+                    buffer.chars += token.chars().count();
+                    buffer.tokens += 1;
+                    if max_chars > 0 && buffer.chars >= max_chars {
+                        deadline = None;
+                        yield Ok(buffer.take());
+                    }
+                }
+                Some(Err(e)) => {
+                    deadline = None;
+                    if buffer.tokens > 0 {
+                        yield Ok(buffer.take());
+                    }
+                    yield Err(e);
+                }
+                None => {
+                    if buffer.tokens > 0 {
+                        yield Ok(buffer.take());
+                    }
+                    break;
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::stream;
+    use std::sync::atomic::AtomicUsize;
+
+    fn tokens(items: Vec<Result<&'static str>>) -> TokenStream {
+        Box::pin(stream::iter(
+            items.into_iter().map(|item| item.map(Token::from)),
+        ))
+    }
+
+    fn config(coalesce_window_ms: u64, coalesce_max_chars: usize) -> StreamingConfig {
+        StreamingConfig {
+            coalesce_window_ms,
+            coalesce_max_chars,
+            ..Default::default()
+        }
+    }
+
+    // Endless token source that counts how many tokens were pulled from it
+    fn counting() -> (TokenStream, Arc<AtomicUsize>) {
+        let pulled = Arc::new(AtomicUsize::new(0));
+        let counter = pulled.clone();
+        let s = stream::repeat_with(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(Token::from("t"))
+        });
+        (Box::pin(s), pulled)
+    }
+
+    #[tokio::test]
+    async fn test_disabled_passes_tokens_through() {
+        let items = vec![Ok("a"), Ok("b")];
+        let chunks: Vec<_> = coalesce(tokens(items), &config(0, 0))
+            .map(|c| c.unwrap().text.to_string())
+            .collect()
+            .await;
+        assert_eq!(chunks, vec!["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn test_flushes_on_size_error_and_end() {
+        let items = vec![Ok("ab"), Ok("cd"), Ok("e"), Err(anyhow!("boom")), Ok("f")];
+        let chunks: Vec<_> = coalesce(tokens(items), &config(1000, 4)).collect().await;
+        let chunks: Vec<_> = chunks
+            .into_iter()
+            .map(|c| {
+                c.map(|c| (c.text.to_string(), c.tokens))
+                    .map_err(|e| e.to_string())
+            })
+            .collect();
+        assert_eq!(
+            chunks,
+            vec![
+                Ok(("abcd".to_string(), 2)),
+                Ok(("e".to_string(), 1)),
+                Err("boom".to_string()),
+                Ok(("f".to_string(), 1)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_size_limit_counts_characters() {
+        let items = vec![Ok("你好"), Ok("🙂"), Ok("世界"), Ok("!")];
+        let chunks: Vec<_> = coalesce(tokens(items), &config(1000, 3)).collect().await;
+        let chunks: Vec<_> = chunks
+            .into_iter()
+            .map(|c| c.unwrap().text.to_string())
+            .collect();
+        assert_eq!(chunks, vec!["你好🙂", "世界!"]);
+    }
+
+    #[tokio::test]
+    async fn test_flushes_when_window_elapses() {
+        let slow = stream! {
+            yield Ok(Token::from("a"));
+            yield Ok(Token::from("b"));
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            yield Ok(Token::from("c"));
+        };
+        let chunks: Vec<_> = coalesce(Box::pin(slow), &config(20, 0))
+            .map(|c| c.unwrap().text.to_string())
+            .collect()
+            .await;
+        assert_eq!(chunks, vec!["ab", "c"]);
+    }
+
+    #[tokio::test]
+    async fn test_prefill_reports_come_before_the_first_chunk() {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let slow = stream! {
+            for processed in [0, 600, 1200] {
+                tx.send(Prefill { processed, total: 1200 }).unwrap();
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            yield Ok(Token::from("a"));
+            tx.send(Prefill { processed: 1200, total: 1200 }).unwrap();
+            yield Ok(Token::from("b"));
+        };
+        let items: Vec<_> = with_prefill(coalesce(Box::pin(slow), &config(0, 0)), Some(rx), 1000)
+            .map(|item| match item {
+                Streamed::Prefill(report) => format!("{}/{}", report.processed, report.total),
+                Streamed::Chunk(chunk) => chunk.unwrap().text.to_string(),
+            })
+            .collect()
+            .await;
+        assert_eq!(items, vec!["0/1200", "600/1200", "1200/1200", "a", "b"]);
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        tx.send(Prefill { processed: 0, total: 10 }).unwrap();
+        let items: Vec<_> = with_prefill(coalesce(tokens(vec![Ok("a")]), &config(0, 0)), Some(rx), 1000)
+            .collect()
+            .await;
+        assert!(matches!(items.as_slice(), [Streamed::Chunk(Ok(_))]));
+    }
+
+    #[tokio::test]
+    async fn test_full_buffer_pauses_generation() {
+        let (tokens, pulled) = counting();
+        let config = StreamingConfig {
+            buffer_tokens: 4,
+            ..Default::default()
+        };
+        let mut stream = bounded(tokens, &config);
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // Four queued plus the one waiting for room
+        assert_eq!(pulled.load(Ordering::SeqCst), 5);
+
+        for _ in 0..3 {
+            assert_eq!(stream.next().await.unwrap().unwrap().as_str(), "t");
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(pulled.load(Ordering::SeqCst), 8);
+    }
+
+    #[tokio::test]
+    async fn test_slow_consumer_is_cut_off() {
+        let (tokens, pulled) = counting();
+        let config = StreamingConfig {
+            buffer_tokens: 2,
+            slow_consumer_timeout_ms: 20,
+            ..Default::default()
+        };
+        let stream = bounded(tokens, &config);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let items: Vec<_> = stream.collect().await;
+        assert_eq!(items.len(), 3);
+        assert!(items[..2].iter().all(|item| item.is_ok()));
+        assert!(items[2]
+            .as_ref()
+            .unwrap_err()
+            .to_string()
+            .contains("too far behind"));
+        assert_eq!(pulled.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_dropped_reader_stops_generation() {
+        let (tokens, pulled) = counting();
+        let config = StreamingConfig {
+            buffer_tokens: 2,
+            ..Default::default()
+        };
+        drop(bounded(tokens, &config));
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let before = pulled.load(Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(before <= 3);
+        assert_eq!(pulled.load(Ordering::SeqCst), before);
+    }
+}
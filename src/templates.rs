@@ -0,0 +1,159 @@
+//! Saved prompt templates.
+//!
+//! A template is named text with `{{variable}}` placeholders. Templates live in the session
+//! database and are managed through `/templates`; `/completions` and `/chat/completions` render
+//! one when a request names it in `template` and supplies the values in `variables`.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use thiserror::Error;
+
+/// Longest accepted template name
+pub const MAX_NAME_LENGTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct PromptTemplate {
+    pub name: String,
+    pub template: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default = "Utc::now")]
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum TemplateError {
+    #[error("prompt template '{0}' not found")]
+    NotFound(String),
+    #[error("missing template variables: {}", .0.join(", "))]
+    MissingVariables(Vec<String>),
+    #[error("unterminated placeholder at byte {0}")]
+    Unterminated(usize),
+    #[error("invalid placeholder '{{{{{0}}}}}' (names use letters, digits, '_', '-' and '.')")]
+    InvalidPlaceholder(String),
+}
+
+/// Names are used in URLs, so keep them to a safe set of characters
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+enum Part<'a> {
+    Text(&'a str),
+    Variable(&'a str),
+}
+
+fn parse(template: &str) -> Result<Vec<Part<'_>>, TemplateError> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while let Some(open) = rest.find("{{") {
+        parts.push(Part::Text(&rest[..open]));
+        let after = &rest[open + 2..];
+        let close = after.find("}}").ok_or(TemplateError::Unterminated(
+            template.len() - rest.len() + open,
+        ))?;
+        let name = after[..close].trim();
+        if !valid_name(name) {
+            return Err(TemplateError::InvalidPlaceholder(
+                after[..close].to_string(),
+            ));
+        }
+        parts.push(Part::Variable(name));
+        rest = &after[close + 2..];
+    }
+    parts.push(Part::Text(rest));
+    Ok(parts)
+}
+
+/// Variable names in order of first use
+pub fn variables(template: &str) -> Result<Vec<String>, TemplateError> {
+    let mut names: Vec<String> = Vec::new();
+    for part in parse(template)? {
+        if let Part::Variable(name) = part {
+            if !names.iter().any(|n| n == name) {
+                names.push(name.to_string());
+            }
+        }
+    }
+    Ok(names)
+}
+
+/// Substitute every placeholder. Strings are inserted as-is and other JSON values in their JSON
+/// form; extra variables are ignored.
+pub fn render(template: &str, values: &HashMap<String, Value>) -> Result<String, TemplateError> {
+    let parts = parse(template)?;
+    let missing: Vec<String> = variables(template)?
+        .into_iter()
+        .filter(|name| !values.contains_key(name))
+        .collect();
+    if !missing.is_empty() {
+        return Err(TemplateError::MissingVariables(missing));
+    }
+
+    let mut out = String::with_capacity(template.len());
+    for part in parts {
+        match part {
+            Part::Text(text) => out.push_str(text),
+            Part::Variable(name) => match &values[name] {
+                Value::String(s) => out.push_str(s),
+                other => out.push_str(&other.to_string()),
+            },
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn values(pairs: Value) -> HashMap<String, Value> {
+        serde_json::from_value(pairs).unwrap()
+    }
+
+    #[test]
+    fn test_render_substitutes_variables() {
+        let template = "Summarize in {{ words }} words for {{audience}}:\n{{text}}\n({{audience}})";
+        assert_eq!(
+            variables(template).unwrap(),
+            vec!["words", "audience", "text"]
+        );
+        let rendered = render(
+            template,
+            &values(json!({"words": 50, "audience": "kids", "text": "Rust", "unused": true})),
+        )
+        .unwrap();
+        assert_eq!(rendered, "Summarize in 50 words for kids:\nRust\n(kids)");
+    }
+
+    #[test]
+    fn test_render_reports_missing_variables() {
+        let err = render("{{a}} {{b}} {{c}}", &values(json!({"b": "x"}))).unwrap_err();
+        assert_eq!(
+            err,
+            TemplateError::MissingVariables(vec!["a".to_string(), "c".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_malformed_templates() {
+        assert_eq!(
+            variables("Hello {{name"),
+            Err(TemplateError::Unterminated(6))
+        );
+        assert!(matches!(
+            variables("{{ two words }}"),
+            Err(TemplateError::InvalidPlaceholder(_))
+        ));
+        assert!(valid_name("summarize-v2"));
+        assert!(!valid_name("../etc"));
+        assert!(!valid_name(""));
+    }
+}
@@ -0,0 +1,115 @@
+//! Stream transcripts for debugging clients (`streaming.record_transcripts`).
+//!
+//! With recording on, every streamed generation gets an id, returned in `X-Request-Id`, and
+//! the exact bytes sent to the client (SSE frames, or WebSocket messages) are kept with their
+//! offset from the start of the response. The transcript is saved to the session database when
+//! the stream ends or the client goes away, so `GET /requests/:id/transcript` shows what the
+//! client should have received and whether the server finished sending it.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct TranscriptChunk {
+    /// Milliseconds since the response started
+    pub offset_ms: u64,
+    pub data: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Transcript {
+    pub request_id: String,
+    pub endpoint: String,
+    pub started_at: DateTime<Utc>,
+    /// False when the stream was dropped before the server finished it, e.g. the client left
+    pub completed: bool,
+    pub chunks: Vec<TranscriptChunk>,
+}
+
+type Sink = Box<dyn FnOnce(Transcript) + Send>;
+
+/// Collects the chunks of one stream and hands the transcript to its sink when dropped
+pub struct TranscriptRecorder {
+    transcript: Option<Transcript>,
+    start: Instant,
+    sink: Option<Sink>,
+}
+
+impl TranscriptRecorder {
+    pub fn new(endpoint: &str, sink: impl FnOnce(Transcript) + Send + 'static) -> Self {
+        Self {
+            transcript: Some(Transcript {
+                request_id: uuid::Uuid::new_v4().to_string(),
+                endpoint: endpoint.to_string(),
+                started_at: Utc::now(),
+                completed: false,
+                chunks: Vec::new(),
+            }),
+            start: Instant::now(),
+            sink: Some(Box::new(sink)),
+        }
+    }
+
+    pub fn request_id(&self) -> &str {
+        self.transcript
+            .as_ref()
+            .map(|t| t.request_id.as_str())
+            .unwrap_or_default()
+    }
+
+    pub fn record(&mut self, data: impl Into<String>) {
+        if let Some(transcript) = &mut self.transcript {
+            transcript.chunks.push(TranscriptChunk {
+                offset_ms: self.start.elapsed().as_millis() as u64,
+                data: data.into(),
+            });
+        }
+    }
+
+    /// Drop the recorder without saving anything, for responses that turned out not to stream
+    pub fn discard(mut self) {
+        self.sink = None;
+    }
+
+    /// Mark the stream as sent in full
+    pub fn complete(&mut self) {
+        if let Some(transcript) = &mut self.transcript {
+            transcript.completed = true;
+        }
+    }
+}
+
+impl Drop for TranscriptRecorder {
+    fn drop(&mut self) {
+        if let (Some(transcript), Some(sink)) = (self.transcript.take(), self.sink.take()) {
+            sink(transcript);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn transcript_is_handed_over_on_drop() {
+        let saved = Arc::new(Mutex::new(None));
+        let sink = saved.clone();
+        let mut recorder = TranscriptRecorder::new("chat", move |t| {
+            *sink.lock().unwrap() = Some(t);
+        });
+        let id = recorder.request_id().to_string();
+        recorder.record("data: hello\n\n");
+        recorder.record("data: world\n\n");
+        drop(recorder);
+
+        let transcript = saved.lock().unwrap().take().unwrap();
+        assert_eq!(transcript.request_id, id);
+        assert_eq!(transcript.endpoint, "chat");
+        assert!(!transcript.completed);
+        let data: Vec<&str> = transcript.chunks.iter().map(|c| c.data.as_str()).collect();
+        assert_eq!(data, vec!["data: hello\n\n", "data: world\n\n"]);
+    }
+}
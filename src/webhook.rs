@@ -0,0 +1,218 @@
+use crate::config::WebhookConfig;
+use anyhow::{anyhow, Result};
+use hmac::{Hmac, Mac};
+use metrics::increment_counter;
+use sha2::Sha256;
+use std::net::IpAddr;
+use std::time::Duration;
+use tracing::{info, warn};
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+pub const TIMESTAMP_HEADER: &str = "X-Webhook-Timestamp";
+
+/// Delivers completion callbacks signed with the configured shared secret.
+///
+/// Receivers verify a delivery by computing `HMAC-SHA256(secret, "{timestamp}.{body}")` and
+/// comparing it with the `sha256=` value of the signature header.
+pub struct WebhookSender {
+    client: reqwest::Client,
+    config: WebhookConfig,
+}
+
+impl WebhookSender {
+    pub fn new(config: WebhookConfig) -> Self {
+        // A redirect could lead a checked URL anywhere, so none are followed
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout_seconds))
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap_or_default();
+        Self { client, config }
+    }
+
+    /// Whether callbacks can be accepted at all (a secret is required to sign them)
+    pub fn is_enabled(&self) -> bool {
+        self.config.secret.is_some()
+    }
+
+    /// Refuse a callback URL that is not http(s) or, unless its host is in
+    /// `webhooks.allowed_hosts`, that is not allowed there or resolves to an internal address
+    pub async fn check_url(&self, url: &str) -> Result<()> {
+        let url = reqwest::Url::parse(url).map_err(|_| anyhow!("callback_url must be an http(s) URL"))?;
+        let host = match url.host_str() {
+            Some(host) if matches!(url.scheme(), "http" | "https") => host.trim_matches(['[', ']']),
+            _ => return Err(anyhow!("callback_url must be an http(s) URL")),
+        };
+        let allowed = &self.config.allowed_hosts;
+        if allowed.iter().any(|a| a.eq_ignore_ascii_case(host)) {
+            return Ok(());
+        }
+        if !allowed.is_empty() {
+            return Err(anyhow!("callback_url host {} is not in webhooks.allowed_hosts", host));
+        }
+        let port = url.port_or_known_default().unwrap_or(80);
+        let addresses: Vec<IpAddr> = match host.parse() {
+            Ok(ip) => vec![ip],
+            Err(_) => tokio::net::lookup_host((host, port))
+                .await
+                .map_err(|_| anyhow!("callback_url host {} does not resolve", host))?
+                .map(|addr| addr.ip())
+                .collect(),
+        };
+        if addresses.is_empty() || addresses.into_iter().any(is_internal) {
+            return Err(anyhow!("callback_url must not point at an internal address"));
+        }
+        Ok(())
+    }
+
+    /// Hex-encoded HMAC-SHA256 over `"{timestamp}.{body}"`
+    pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+        let mut mac =
+            HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+        mac.update(timestamp.to_string().as_bytes());
+        mac.update(b".");
+        mac.update(body);
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    /// POST `payload` to `url`, retrying with exponential backoff on network errors and
+    /// non-2xx responses.
+    pub async fn deliver(&self, url: &str, payload: &serde_json::Value) -> Result<()> {
+        let secret = self
+            .config
+            .secret
+            .as_deref()
+            .ok_or_else(|| anyhow!("webhooks.secret is not configured"))?;
+        // The host may resolve elsewhere by now than when the job was accepted
+        self.check_url(url).await?;
+        let body = serde_json::to_vec(payload)?;
+        let mut backoff = Duration::from_millis(self.config.initial_backoff_ms);
+
+        for attempt in 0..=self.config.max_retries {
+            // Re-sign every attempt so the timestamp reflects the actual send time
+            let timestamp = chrono::Utc::now().timestamp();
+            let signature = Self::sign(secret, timestamp, &body);
+
+            let result = self
+                .client
+                .post(url)
+                .header("content-type", "application/json")
+                .header(TIMESTAMP_HEADER, timestamp.to_string())
+                .header(SIGNATURE_HEADER, format!("sha256={}", signature))
+                .body(body.clone())
+                .send()
+                .await;
+
+            match result {
+                Ok(resp) if resp.status().is_success() => {
+                    increment_counter!("webhook_deliveries_total");
+                    info!("Delivered webhook to {} (attempt {})", url, attempt + 1);
+                    return Ok(());
+                }
+                Ok(resp) => {
+                    warn!(
+                        "Webhook to {} returned {} (attempt {})",
+                        url,
+                        resp.status(),
+                        attempt + 1
+                    );
+                }
+                Err(err) => {
+                    warn!("Webhook to {} failed: {} (attempt {})", url, err, attempt + 1);
+                }
+            }
+
+            if attempt < self.config.max_retries {
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+
+        increment_counter!("webhook_failures_total");
+        Err(anyhow!(
+            "webhook delivery to {} failed after {} attempts",
+            url,
+            self.config.max_retries + 1
+        ))
+    }
+}
+
+/// Loopback, private, link-local (where cloud metadata services live), shared and unspecified
+/// addresses, which a callback must not reach
+fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || (a == 100 && (64..128).contains(&b))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(v4) => is_internal(IpAddr::V4(v4)),
+            None => {
+                let first = ip.segments()[0];
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || (first & 0xfe00) == 0xfc00
+                    || (first & 0xffc0) == 0xfe80
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signature_is_stable_and_secret_dependent() {
+        let a = WebhookSender::sign("secret", 1700000000, b"{\"text\":\"hi\"}");
+        let b = WebhookSender::sign("secret", 1700000000, b"{\"text\":\"hi\"}");
+        let c = WebhookSender::sign("other", 1700000000, b"{\"text\":\"hi\"}");
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert_eq!(a.len(), 64);
+    }
+
+    #[tokio::test]
+    async fn test_deliver_requires_secret() {
+        let sender = WebhookSender::new(WebhookConfig::default());
+        assert!(!sender.is_enabled());
+        assert!(sender
+            .deliver("http://127.0.0.1:1/", &serde_json::json!({}))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_internal_callback_urls_are_refused() {
+        let sender = WebhookSender::new(WebhookConfig::default());
+        for url in [
+            "http://127.0.0.1:8080/hook",
+            "http://localhost/hook",
+            "http://10.0.0.5/hook",
+            "http://192.168.1.1/hook",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://[::1]/hook",
+            "http://[fd00::1]/hook",
+            "http://[::ffff:127.0.0.1]/hook",
+            "ftp://example.com/hook",
+        ] {
+            assert!(sender.check_url(url).await.is_err(), "{}", url);
+        }
+        assert!(sender.check_url("https://93.184.216.34/hook").await.is_ok());
+
+        // Listed hosts are trusted, and with a list nothing else is
+        let sender = WebhookSender::new(WebhookConfig {
+            allowed_hosts: vec!["127.0.0.1".to_string()],
+            ..WebhookConfig::default()
+        });
+        assert!(sender.check_url("http://127.0.0.1:8080/hook").await.is_ok());
+        assert!(sender.check_url("https://93.184.216.34/hook").await.is_err());
+    }
+}
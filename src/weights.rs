@@ -0,0 +1,184 @@
+//! Model weights on disk.
+//!
+//! Reads parameter count, size and precision from the headers of `.safetensors` files without
+//! loading any tensor, for `GET /models/:id`. A safetensors file starts with a little-endian
+//! `u64` header length and a JSON header mapping every tensor to its dtype, shape and byte range.
+//! Weights downloaded from the Hugging Face Hub are found in its local cache, next to the
+//! tokenizer config that names the model's end-of-sequence token.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// Headers larger than this are not safetensors files we want to parse
+const MAX_HEADER_BYTES: u64 = 100 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WeightsSummary {
+    pub parameters: u64,
+    pub bytes: u64,
+    /// Lowercase dtype holding most parameters, e.g. `bf16`
+    pub dtype: String,
+}
+
+#[derive(Deserialize)]
+struct TensorInfo {
+    dtype: String,
+    shape: Vec<u64>,
+    data_offsets: [u64; 2],
+}
+
+/// Parameters and bytes per dtype of one safetensors file
+fn read_header(path: &Path) -> io::Result<HashMap<String, (u64, u64)>> {
+    let mut file = File::open(path)?;
+    let mut len = [0u8; 8];
+    file.read_exact(&mut len)?;
+    let len = u64::from_le_bytes(len);
+    if len > MAX_HEADER_BYTES {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "safetensors header too large"));
+    }
+    let mut header = vec![0u8; len as usize];
+    file.read_exact(&mut header)?;
+    let entries: HashMap<String, serde_json::Value> = serde_json::from_slice(&header)?;
+
+    let mut by_dtype: HashMap<String, (u64, u64)> = HashMap::new();
+    for (name, entry) in entries {
+        if name == "__metadata__" {
+            continue;
+        }
+        let tensor: TensorInfo = serde_json::from_value(entry)?;
+        let totals = by_dtype.entry(tensor.dtype.to_lowercase()).or_default();
+        totals.0 += tensor.shape.iter().product::<u64>();
+        totals.1 += tensor.data_offsets[1].saturating_sub(tensor.data_offsets[0]);
+    }
+    Ok(by_dtype)
+}
+
+/// Totals over every `.safetensors` file in `dir`; `None` when there are none
+pub fn summarize_dir(dir: &Path) -> io::Result<Option<WeightsSummary>> {
+    let mut by_dtype: HashMap<String, (u64, u64)> = HashMap::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("safetensors") {
+            continue;
+        }
+        for (dtype, (parameters, bytes)) in read_header(&path)? {
+            let totals = by_dtype.entry(dtype).or_default();
+            totals.0 += parameters;
+            totals.1 += bytes;
+        }
+    }
+    let Some(dtype) = by_dtype
+        .iter()
+        .max_by_key(|(dtype, (parameters, _))| (*parameters, std::cmp::Reverse((*dtype).clone())))
+        .map(|(dtype, _)| dtype.clone())
+    else {
+        return Ok(None);
+    };
+    Ok(Some(WeightsSummary {
+        parameters: by_dtype.values().map(|(p, _)| p).sum(),
+        bytes: by_dtype.values().map(|(_, b)| b).sum(),
+        dtype,
+    }))
+}
+
+/// Local snapshot of a Hub repo (`org/name`), newest first, from `HF_HUB_CACHE`, `HF_HOME/hub`
+/// or `~/.cache/huggingface/hub`
+pub fn hub_snapshot(repo: &str) -> Option<PathBuf> {
+    let cache = std::env::var_os("HF_HUB_CACHE")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HF_HOME").map(|home| PathBuf::from(home).join("hub")))
+        .or_else(|| {
+            std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache/huggingface/hub"))
+        })?;
+    let snapshots = cache
+        .join(format!("models--{}", repo.replace('/', "--")))
+        .join("snapshots");
+    std::fs::read_dir(snapshots)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+        .max()
+        .map(|(_, path)| path)
+}
+
+/// Directory a model is loaded from: its local `path`, or the Hub snapshot of `name`
+pub fn model_dir(path: Option<&Path>, name: &str) -> Option<PathBuf> {
+    match path {
+        Some(path) => path.is_dir().then(|| path.to_path_buf()),
+        None => hub_snapshot(name),
+    }
+}
+
+/// End-of-sequence token named in a model directory's `tokenizer_config.json`, either as a
+/// plain string or as `{"content": ...}`
+pub fn eos_token(dir: &Path) -> Option<String> {
+    let config = std::fs::read(dir.join("tokenizer_config.json")).ok()?;
+    let config: serde_json::Value = serde_json::from_slice(&config).ok()?;
+    let eos = &config["eos_token"];
+    eos.as_str()
+        .or_else(|| eos["content"].as_str())
+        .map(str::to_string)
+}
+
+/// Summary of the weights a model was loaded from: a local directory, or the Hub snapshot of
+/// `name`. Single-file weights (e.g. GGUF) are not inspected.
+pub fn summarize_model(path: Option<&Path>, name: &str) -> Option<WeightsSummary> {
+    let dir = model_dir(path, name)?;
+    match summarize_dir(&dir) {
+        Ok(summary) => summary,
+        Err(e) => {
+            tracing::debug!("Could not read weights in {}: {}", dir.display(), e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn write_safetensors(path: &Path, header: serde_json::Value) {
+        let header = header.to_string();
+        let mut file = File::create(path).unwrap();
+        file.write_all(&(header.len() as u64).to_le_bytes()).unwrap();
+        file.write_all(header.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn sums_parameters_across_shards() {
+        let dir = std::env::temp_dir().join(format!("llm_inference_weights_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        write_safetensors(
+            &dir.join("model-00001.safetensors"),
+            serde_json::json!({
+                "__metadata__": {"format": "pt"},
+                "embed": {"dtype": "BF16", "shape": [10, 4], "data_offsets": [0, 80]},
+            }),
+        );
+        write_safetensors(
+            &dir.join("model-00002.safetensors"),
+            serde_json::json!({
+                "norm": {"dtype": "F32", "shape": [4], "data_offsets": [0, 16]},
+            }),
+        );
+        std::fs::write(dir.join("config.json"), "{}").unwrap();
+        std::fs::write(
+            dir.join("tokenizer_config.json"),
+            r#"{"eos_token": {"content": "<|im_end|>", "special": true}}"#,
+        )
+        .unwrap();
+        assert_eq!(eos_token(&dir).as_deref(), Some("<|im_end|>"));
+
+        let summary = summarize_dir(&dir).unwrap().unwrap();
+        assert_eq!(summary.parameters, 44);
+        assert_eq!(summary.bytes, 96);
+        assert_eq!(summary.dtype, "bf16");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(summarize_model(Some(&dir), "missing/model").is_none());
+    }
+}
@@ -36,6 +36,140 @@ fn test_config_validation_no_models() {
     assert!(config.validate().is_err());
 }
 
+#[test]
+fn test_config_validation_metrics_path() {
+    let mut config = Config::default();
+    config.observability.metrics_path = "/internal/prometheus".to_string();
+    assert!(config.validate().is_ok());
+    for path in ["/admin/metrics", "/chat", "metrics", "/", "/metrics/:name"] {
+        config.observability.metrics_path = path.to_string();
+        assert!(config.validate().is_err(), "{} was accepted", path);
+    }
+
+    config.observability.metrics_path = "/metrics".to_string();
+    config.observability.metrics_port = Some(config.server.port);
+    assert!(config.validate().is_err());
+    config.observability.metrics_port = Some(9100);
+    assert!(config.validate().is_ok());
+}
+
+#[test]
+fn test_model_aliases() {
+    let mut config = Config::default();
+    config.models.available_models[0].aliases = vec!["gpt-3.5-turbo".to_string()];
+    assert!(config.validate().is_ok());
+    assert_eq!(config.find_model("gpt-3.5-turbo").unwrap().id, "qwen");
+    assert!(config.find_model("gpt-4").is_none());
+
+    // An alias may not name another model
+    config.models.available_models[1].aliases = vec!["qwen".to_string(), String::new()];
+    let err = config.validate().unwrap_err();
+    let invalid = err.downcast_ref::<ConfigValidationError>().unwrap();
+    let paths: Vec<&str> = invalid.issues.iter().map(|i| i.path.as_str()).collect();
+    assert_eq!(
+        paths,
+        vec!["models.available_models[1].aliases", "models.available_models[1].aliases"]
+    );
+}
+
+#[test]
+fn test_model_standby_devices() {
+    assert_eq!(parse_device("cuda:1"), Some(("cuda", 1)));
+    assert_eq!(parse_device("CPU"), Some(("cpu", 0)));
+    assert_eq!(parse_device("cuda:x"), None);
+    assert_eq!(parse_device("tpu"), None);
+
+    let mut config = Config::default();
+    config.models.available_models[0].standby = vec!["cuda:1".to_string(), "metal".to_string()];
+    assert!(config.validate().is_ok());
+
+    config.models.available_models[0].standby = vec!["gpu:1".to_string()];
+    let err = config.validate().unwrap_err();
+    let invalid = err.downcast_ref::<ConfigValidationError>().unwrap();
+    assert_eq!(invalid.issues[0].path, "models.available_models[0].standby");
+}
+
+#[test]
+fn test_config_validation_reports_all_issues() {
+    let mut config = Config::default();
+    config.server.port = 0;
+    config.models.default_device = "tpu".to_string();
+    config.models.available_models[1].id = "qwen".to_string();
+    config.models.available_models.push(ModelConfig {
+        id: "qwen-alias".to_string(),
+        name: "Qwen/Qwen2.5-0.5B-Instruct".to_string(),
+        path: None,
+        quantization: None,
+        context_length: Some(0),
+        aliases: Vec::new(),
+        standby: Vec::new(),
+        memory_gb: None,
+    });
+    config.limits.max_prompt_length = 0;
+
+    let err = config.validate().unwrap_err();
+    let invalid = err.downcast_ref::<ConfigValidationError>().unwrap();
+    let paths: Vec<&str> = invalid.issues.iter().map(|i| i.path.as_str()).collect();
+    assert_eq!(
+        paths,
+        vec![
+            "server.port",
+            "models.available_models[1].id",
+            "models.available_models[2].context_length",
+            "models.available_models[2].name",
+            "models.default_device",
+            "limits.max_prompt_length",
+        ]
+    );
+    assert!(err.to_string().contains("6 problem(s)"));
+}
+
+#[test]
+fn test_auto_device_and_model_memory_are_validated() {
+    let mut config = Config::default();
+    config.models.default_device = "AUTO".to_string();
+    config.models.available_models[0].memory_gb = Some(6.0);
+    assert!(config.validate().is_ok());
+
+    config.models.available_models[1].memory_gb = Some(0.0);
+    let err = config.validate().unwrap_err();
+    let invalid = err.downcast_ref::<ConfigValidationError>().unwrap();
+    assert_eq!(invalid.issues.len(), 1);
+    assert_eq!(invalid.issues[0].path, "models.available_models[1].memory_gb");
+}
+
+#[test]
+fn test_guardrail_rules_are_validated() {
+    let rule = |id: &str, patterns: &[&str]| GuardrailRule {
+        id: id.to_string(),
+        category: "privacy".to_string(),
+        patterns: patterns.iter().map(|p| p.to_string()).collect(),
+        action: GuardrailAction::Redact,
+        message: None,
+    };
+    let mut config = Config::default();
+    config.guardrails.rules = vec![rule("internal-hosts", &["corp.internal"])];
+    assert!(config.validate().is_ok());
+
+    config.guardrails.rules.push(rule("internal-hosts", &["corp.example", ""]));
+    config.guardrails.rules.push(GuardrailRule {
+        category: " ".to_string(),
+        ..rule("pii", &["ssn:"])
+    });
+    let err = config.validate().unwrap_err();
+    let invalid = err.downcast_ref::<ConfigValidationError>().unwrap();
+    let paths: Vec<&str> = invalid.issues.iter().map(|i| i.path.as_str()).collect();
+    assert_eq!(
+        paths,
+        vec![
+            "guardrails.rules[1].id",
+            "guardrails.rules[1].patterns",
+            "guardrails.rules[2].category",
+        ]
+    );
+    assert!(invalid.issues[0].message.contains("also guardrails.rules[0]"));
+}
+
 #[test]
 fn test_config_with_api_keys() {
     let mut config = Config::default();
@@ -45,10 +179,50 @@ fn test_config_with_api_keys() {
         name: "test".to_string(),
         rate_limit_per_minute: Some(100),
         enabled: true,
+        ..Default::default()
     });
     assert!(config.validate().is_ok());
 }
 
+#[test]
+fn test_api_key_defaults_and_caps_are_validated() {
+    let mut config = Config::default();
+    config.security.api_keys.push(ApiKeyConfig {
+        key: "test-key".to_string(),
+        name: "test".to_string(),
+        enabled: true,
+        default_model: Some("qwen".to_string()),
+        max_temperature: Some(0.8),
+        max_tokens: Some(256),
+        ..Default::default()
+    });
+    assert!(config.validate().is_ok());
+
+    let key = &mut config.security.api_keys[0];
+    key.default_model = Some("gpt-9".to_string());
+    key.max_temperature = Some(-1.0);
+    key.max_tokens = Some(0);
+    let paths: Vec<String> = config.validation_issues().into_iter().map(|i| i.path).collect();
+    assert_eq!(
+        paths,
+        [
+            "security.api_keys[0].default_model",
+            "security.api_keys[0].max_temperature",
+            "security.api_keys[0].max_tokens",
+        ]
+    );
+
+    let mut temperature = 1.2;
+    let mut max_tokens = 100;
+    let key = ApiKeyConfig {
+        max_temperature: Some(0.8),
+        max_tokens: Some(256),
+        ..Default::default()
+    };
+    assert_eq!(key.cap_params(&mut temperature, &mut max_tokens), ["temperature"]);
+    assert_eq!((temperature, max_tokens), (0.8, 100));
+}
+
 #[test]
 fn test_config_model_config() {
     let config = Config::default();
@@ -63,3 +237,414 @@ fn test_config_limits() {
     assert_eq!(config.limits.max_response_tokens, 2048);
     assert_eq!(config.limits.max_sessions, 1000);
 }
+
+fn env(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+    pairs
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+#[test]
+fn test_env_overrides() {
+    let config = Config::default()
+        .with_env_overrides(env(&[
+            ("LLM__SERVER__PORT", "8080"),
+            ("LLM__SERVER__HOST", "0.0.0.0"),
+            ("LLM__SECURITY__ALLOWED_ORIGINS", r#"["https://example.com"]"#),
+            ("LLM__MODELS__AVAILABLE_MODELS__1__CONTEXT_LENGTH", "8192"),
+            ("LLM__LIMITS__MAX_CONCURRENT_PER_KEY", "2"),
+            ("UNRELATED", "ignored"),
+        ]))
+        .unwrap();
+
+    assert_eq!(config.server.port, 8080);
+    assert_eq!(config.server.host, "0.0.0.0");
+    assert_eq!(config.security.allowed_origins, vec!["https://example.com"]);
+    assert_eq!(config.models.available_models[1].context_length, Some(8192));
+    assert_eq!(config.limits.max_concurrent_per_key, Some(2));
+}
+
+#[test]
+fn test_env_overrides_reject_invalid_values() {
+    let config = Config::default();
+    assert!(config
+        .with_env_overrides(env(&[("LLM__SERVER__PORT", "not-a-port")]))
+        .is_err());
+    assert!(config
+        .with_env_overrides(env(&[("LLM__SERVER__PORT", "0")]))
+        .is_err());
+    assert!(config
+        .with_env_overrides(env(&[("LLM__MODELS__AVAILABLE_MODELS__9__NAME", "x")]))
+        .is_err());
+}
+
+#[test]
+fn test_restart_required_changes() {
+    let old = Config::default();
+
+    let mut hot = old.clone();
+    hot.limits.max_prompt_length = 100;
+    hot.server.log_level = "debug".to_string();
+    hot.models.available_models.pop();
+    hot.observability.slow_request_threshold_ms = Some(5000);
+    assert_eq!(
+        old.changed_fields(&hot),
+        vec![
+            "limits.max_prompt_length",
+            "models.available_models",
+            "observability.slow_request_threshold_ms",
+            "server.log_level"
+        ]
+    );
+    assert!(old.restart_required_changes(&hot).is_empty());
+
+    let mut cold = old.clone();
+    cold.server.port = 4000;
+    cold.limits.max_sessions = 10;
+    assert_eq!(old.restart_required_changes(&cold), vec!["server.port"]);
+}
+
+#[test]
+fn test_session_store_settings() {
+    let mut value = toml::Value::try_from(Config::default()).unwrap();
+    value["session_store"] = toml::toml! {
+        pool_size = 2
+        synchronous = "normal"
+    }
+    .into();
+    let config: Config = value.try_into().unwrap();
+    assert_eq!(config.session_store.pool_size, 2);
+    assert_eq!(
+        config.session_store.synchronous,
+        SqliteSynchronousMode::Normal
+    );
+    assert_eq!(config.session_store.cache_size_kib, 2000);
+
+    let mut zero = Config::default();
+    zero.session_store.pool_size = 0;
+    assert!(zero.validate().is_err());
+
+    // The pool is built once at startup
+    let old = Config::default();
+    let mut cold = old.clone();
+    cold.session_store.synchronous = SqliteSynchronousMode::Off;
+    assert_eq!(
+        old.restart_required_changes(&cold),
+        vec!["session_store.synchronous"]
+    );
+}
+
+#[test]
+fn test_experiment_settings() {
+    let mut value = toml::Value::try_from(Config::default()).unwrap();
+    let experiments = toml::toml! {
+        [[experiments]]
+        name = "phi-vs-qwen"
+        model = "qwen"
+        [[experiments.variants]]
+        name = "phi"
+        weight = 20
+        model = "phi"
+        [[experiments.variants]]
+        name = "terse"
+        weight = 20
+        system_prompt = "Be brief."
+    }["experiments"]
+        .clone();
+    value
+        .as_table_mut()
+        .unwrap()
+        .insert("experiments".to_string(), experiments);
+    let config: Config = value.try_into().unwrap();
+    assert!(config.experiments[0].enabled);
+    assert!(config.validate().is_ok());
+
+    // Experiments apply on reload
+    assert!(Config::default()
+        .restart_required_changes(&config)
+        .is_empty());
+
+    let mut invalid = config.clone();
+    invalid.experiments[0].model = "llama".to_string();
+    invalid.experiments[0].variants[0].weight = 90;
+    invalid.experiments[0].variants[1].name = "control".to_string();
+    invalid.experiments[0].variants[1].system_prompt = None;
+    let err = invalid.validate().unwrap_err();
+    let invalid = err.downcast_ref::<ConfigValidationError>().unwrap();
+    let paths: Vec<&str> = invalid.issues.iter().map(|i| i.path.as_str()).collect();
+    assert_eq!(
+        paths,
+        vec![
+            "experiments[0].model",
+            "experiments[0].variants[1].name",
+            "experiments[0].variants[1]",
+            "experiments[0].variants",
+        ]
+    );
+}
+
+#[test]
+fn test_shadow_settings() {
+    let mut config = Config::default();
+    assert!(config.shadow.model.is_none());
+    config.shadow.model = Some("phi".to_string());
+    config.shadow.fraction = 0.5;
+    config.shadow.source_models = vec!["Qwen/Qwen2.5-0.5B-Instruct".to_string()];
+    assert!(config.validate().is_ok());
+    assert!(Config::default()
+        .restart_required_changes(&config)
+        .is_empty());
+
+    config.shadow.model = Some("llama".to_string());
+    config.shadow.fraction = 1.5;
+    let err = config.validate().unwrap_err();
+    let invalid = err.downcast_ref::<ConfigValidationError>().unwrap();
+    let paths: Vec<&str> = invalid.issues.iter().map(|i| i.path.as_str()).collect();
+    assert_eq!(paths, vec!["shadow.fraction", "shadow.model"]);
+}
+
+#[test]
+fn test_federation_settings() {
+    let mut config = Config::default();
+    assert!(config.federation.peers.is_empty());
+    config.federation.peers.push(PeerConfig {
+        url: "http://gpu-2:3000".to_string(),
+        api_key: Some("sk-peer-0123456789".to_string()),
+    });
+    assert!(config.validate().is_ok());
+    assert_eq!(
+        config.redacted().federation.peers[0].api_key.as_deref(),
+        Some("****6789")
+    );
+
+    config.federation.peers[0].url = "gpu-2:3000".to_string();
+    config.federation.refresh_seconds = 0;
+    let err = config.validate().unwrap_err();
+    let invalid = err.downcast_ref::<ConfigValidationError>().unwrap();
+    let paths: Vec<&str> = invalid.issues.iter().map(|i| i.path.as_str()).collect();
+    assert_eq!(
+        paths,
+        vec!["federation.refresh_seconds", "federation.peers[0].url"]
+    );
+}
+
+#[test]
+fn test_config_yaml_and_json_round_trip() {
+    let mut config = Config::default();
+    config.server.port = 8081;
+    config.limits.max_concurrent_per_key = Some(2);
+
+    for ext in ["yaml", "yml", "json", "toml"] {
+        let path = std::env::temp_dir().join(format!(
+            "llm_inference_config_{}_{}.{}",
+            std::process::id(),
+            ext,
+            ext
+        ));
+        let path = path.to_str().unwrap();
+        config.save(path).unwrap();
+        let loaded = Config::from_file(path).unwrap();
+        std::fs::remove_file(path).ok();
+        assert_eq!(loaded, config, "round trip through .{}", ext);
+    }
+}
+
+#[test]
+fn test_config_yaml_parses_by_extension() {
+    let path = std::env::temp_dir().join(format!("llm_inference_config_{}.yaml", std::process::id()));
+    std::fs::write(
+        &path,
+        "server:\n  port: 9000\nmodels:\n  available_models:\n    - id: qwen\n      name: Qwen/Qwen2.5-0.5B-Instruct\nsecurity: {}\nlimits: {}\nobservability: {}\n",
+    )
+    .unwrap();
+    let config = Config::from_file(path.to_str().unwrap()).unwrap();
+    std::fs::remove_file(&path).ok();
+    assert_eq!(config.server.port, 9000);
+    assert_eq!(config.models.available_models[0].id, "qwen");
+}
+
+#[test]
+fn test_config_profile_merges_over_base() {
+    let path =
+        std::env::temp_dir().join(format!("llm_inference_profile_{}.toml", std::process::id()));
+    std::fs::write(
+        &path,
+        r#"
+[server]
+port = 3000
+log_level = "debug"
+
+[models]
+available_models = [{ id = "qwen", name = "Qwen/Qwen2.5-0.5B-Instruct" }]
+
+[security]
+[limits]
+[observability]
+
+[profile.prod.server]
+port = 8080
+
+[profile.prod.limits]
+max_sessions = 50
+"#,
+    )
+    .unwrap();
+    let path = path.to_str().unwrap();
+
+    let base = Config::from_file(path).unwrap();
+    assert_eq!(base.server.port, 3000);
+
+    let prod = Config::from_file_with_profile(path, Some("prod")).unwrap();
+    assert_eq!(prod.server.port, 8080);
+    // Keys not mentioned in the profile keep their base values
+    assert_eq!(prod.server.log_level, "debug");
+    assert_eq!(prod.limits.max_sessions, 50);
+    assert_eq!(prod.models.available_models[0].id, "qwen");
+
+    assert!(Config::from_file_with_profile(path, Some("staging")).is_err());
+    std::fs::remove_file(path).ok();
+}
+
+#[test]
+fn test_persistence_can_be_turned_off_except_for_replicas() {
+    assert!(Config::default().persistence.enabled);
+    let mut config = Config::default();
+    config.persistence.enabled = false;
+    assert!(config.validate().is_ok());
+    // The store is only opened at startup
+    assert_eq!(Config::default().restart_required_changes(&config), vec!["persistence.enabled"]);
+
+    // Read-only replicas exist to serve another instance's sessions.db
+    config.server.read_only = true;
+    let err = config.validate().unwrap_err();
+    let invalid = err.downcast_ref::<ConfigValidationError>().unwrap();
+    assert_eq!(invalid.issues[0].path, "server.read_only");
+}
+
+#[test]
+fn test_tokenizer_only_excludes_read_only() {
+    let mut config = Config::default();
+    config.server.tokenizer_only = true;
+    assert!(config.validate().is_ok());
+    assert_eq!(
+        Config::default().restart_required_changes(&config),
+        vec!["server.tokenizer_only"]
+    );
+
+    config.server.read_only = true;
+    let err = config.validate().unwrap_err();
+    let invalid = err.downcast_ref::<ConfigValidationError>().unwrap();
+    assert_eq!(invalid.issues[0].path, "server.tokenizer_only");
+}
+
+#[test]
+fn test_selected_profile_from_args() {
+    let args = |a: &[&str]| a.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    assert_eq!(
+        Config::selected_profile(args(&["--profile", "prod"])),
+        Some("prod".to_string())
+    );
+    assert_eq!(
+        Config::selected_profile(args(&["--profile=dev"])),
+        Some("dev".to_string())
+    );
+}
+
+#[test]
+fn test_config_includes_are_merged() {
+    let dir = std::env::temp_dir().join(format!("llm_inference_include_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("models.toml"),
+        r#"
+[models]
+available_models = [{ id = "local", name = "org/local-model" }]
+default_device = "cpu"
+"#,
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("keys.yaml"),
+        "security:\n  api_keys:\n    - key: sk-secret\n      name: ops\n      enabled: true\n",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("config.toml"),
+        r#"
+include = ["models.toml", "keys.yaml"]
+
+[server]
+port = 3000
+
+[models]
+default_device = "cuda"
+
+[security]
+enable_auth = true
+
+[limits]
+[observability]
+"#,
+    )
+    .unwrap();
+
+    let config = Config::from_file(dir.join("config.toml").to_str().unwrap()).unwrap();
+    assert_eq!(config.models.available_models[0].id, "local");
+    // The including file wins over included values
+    assert_eq!(config.models.default_device, "cuda");
+    assert!(config.security.enable_auth);
+    assert_eq!(config.security.api_keys[0].key, "sk-secret");
+
+    // Including yourself is a cycle
+    std::fs::write(dir.join("loop.toml"), "include = [\"loop.toml\"]\n").unwrap();
+    let err = Config::from_file(dir.join("loop.toml").to_str().unwrap()).unwrap_err();
+    assert!(format!("{:#}", err).contains("cycle"));
+
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_redacted_masks_secrets() {
+    let mut config = Config::default();
+    config.security.api_keys.push(ApiKeyConfig {
+        key: "sk-0123456789abcdef".to_string(),
+        name: "ops".to_string(),
+        enabled: true,
+        ..Default::default()
+    });
+    config.webhooks.secret = Some("short".to_string());
+    config.jobs.upload_token = Some("upload-token-0123".to_string());
+    config.error_reporting.webhook_url = Some("https://hooks.example.com/T000/secret-path".to_string());
+
+    let redacted = config.redacted();
+    assert_eq!(redacted.security.api_keys[0].key, "****cdef");
+    assert_eq!(redacted.security.api_keys[0].name, "ops");
+    assert_eq!(redacted.webhooks.secret.as_deref(), Some("****"));
+    assert_eq!(redacted.jobs.upload_token.as_deref(), Some("****0123"));
+    assert_eq!(redacted.error_reporting.webhook_url.as_deref(), Some("****path"));
+}
+
+#[test]
+fn test_value_sources() {
+    let mut config = Config::default();
+    config.limits.max_sessions = 10;
+    let config = config
+        .with_env_overrides(vec![("LLM__SERVER__PORT".to_string(), "8080".to_string())])
+        .unwrap();
+
+    let sources = config.value_sources(vec![("LLM__SERVER__PORT".to_string(), "8080".to_string())]);
+    assert_eq!(sources["server.port"], ConfigSource::Env);
+    assert_eq!(sources["limits.max_sessions"], ConfigSource::File);
+    assert_eq!(sources["server.host"], ConfigSource::Default);
+}
+
+#[test]
+fn test_commented_template_matches_defaults() {
+    let template = Config::commented_template().unwrap();
+    assert!(template.contains("# trace, debug, info, warn, error"));
+    assert!(template.contains("# [[security.api_keys]]"));
+
+    let parsed: Config = toml::from_str(&template).unwrap();
+    assert_eq!(parsed, Config::default());
+}
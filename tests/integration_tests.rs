@@ -2,7 +2,14 @@ use axum::{
     body::Body,
     http::{Request, StatusCode},
 };
-use llm_inference::{config::Config, engine_mock::MockEngine, models::*, routes, state::AppState};
+use llm_inference::{
+    config::{self, Config},
+    engine_mock::MockEngine,
+    models::*,
+    observers::Observed,
+    routes,
+    state::{self, AppState},
+};
 use metrics_exporter_prometheus::PrometheusBuilder;
 use serde_json::json;
 use std::sync::Arc;
@@ -14,7 +21,9 @@ async fn setup_test_state() -> AppState {
     let handle = recorder.handle();
     let engine = Arc::new(MockEngine::new());
     let config = Config::default();
-    AppState::new(engine, handle, config).await.unwrap()
+    AppState::new_in_memory(engine, handle, config)
+        .await
+        .unwrap()
 }
 
 #[tokio::test]
@@ -89,6 +98,65 @@ async fn test_completions_endpoint() {
     assert_eq!(resp.status(), StatusCode::OK);
 }
 
+#[tokio::test]
+async fn test_completions_response_cache() {
+    let mut config = Config::default();
+    config.cache.enabled = true;
+    config.cache.semantic_threshold = Some(0.9);
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let state = AppState::new_in_memory(Arc::new(MockEngine::new()), handle, config)
+        .await
+        .unwrap();
+    let app = routes::router().with_state(state);
+
+    let complete = |prompt: &str| {
+        let payload = json!({
+            "model": "mock-model",
+            "prompt": prompt,
+            "temperature": 0.0
+        });
+        Request::builder()
+            .method("POST")
+            .uri("/completions")
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+            .unwrap()
+    };
+    let mut results = Vec::new();
+    for prompt in ["What is Rust", "What is Rust", "what is rust?"] {
+        let resp = app.clone().oneshot(complete(prompt)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        results.push(serde_json::from_slice::<serde_json::Value>(&body).unwrap());
+    }
+
+    assert_eq!(results[0]["cached"], false);
+    assert_eq!(results[1]["cached"], true);
+    assert_eq!(results[1]["cache"], "exact");
+    assert_eq!(results[2]["cache"], "semantic");
+    assert_eq!(results[2]["text"], results[0]["text"]);
+}
+
+#[tokio::test]
+async fn test_semantic_cache_needs_an_engine_with_embeddings() {
+    use llm_inference::engine_tokenizer::TokenizerEngine;
+
+    let mut config = Config::default();
+    config.cache.enabled = true;
+    config.cache.semantic_threshold = Some(0.9);
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let engine = Arc::new(TokenizerEngine::new(config.models.available_models.clone()));
+    let err = AppState::new_in_memory(engine.clone(), handle.clone(), config.clone())
+        .await
+        .err()
+        .expect("semantic cache without embeddings was accepted");
+    assert!(err.to_string().contains("cache.semantic_threshold"));
+
+    // Nor can a reload turn it on
+    let state = AppState::new_in_memory(engine, handle, Config::default()).await.unwrap();
+    assert!(state.apply_config(config).await.is_err());
+}
+
 #[tokio::test]
 async fn test_chat_completions_endpoint() {
     let state = setup_test_state().await;
@@ -113,62 +181,4122 @@ async fn test_chat_completions_endpoint() {
 }
 
 #[tokio::test]
-async fn test_session_management() {
+async fn test_chat_history_uses_chat_config() {
+    let mut config = Config::default();
+    config.chat.default_system_prompt = "Answer tersely.".to_string();
+    config.chat.max_history_messages = 3;
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let state = AppState::new_in_memory(Arc::new(MockEngine::new()), handle, config)
+        .await
+        .unwrap();
+    let app = routes::router().with_state(state.clone());
+    let session_id = format!("chat-config-{}", uuid::Uuid::new_v4());
+
+    for prompt in ["first", "second", "third"] {
+        let payload = json!({
+            "model-name": "mock-model",
+            "prompt": prompt,
+            "session-id": session_id,
+            "device": "cpu"
+        });
+        let req = Request::builder()
+            .method("POST")
+            .uri("/chat/completions")
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+            .unwrap();
+        let resp = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        // Drain the stream so the assistant reply is recorded
+        hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    }
+
+    let sessions = state.sessions.lock().await;
+    let history = &sessions[&session_id];
+    assert_eq!(history[0].role, "system");
+    assert_eq!(history[0].content, "Answer tersely.");
+    // System prompt + the two most recent messages before the final reply was appended
+    assert_eq!(history.len(), 4);
+    assert_eq!(history[2].content, "third");
+}
+
+#[tokio::test]
+async fn test_persona_sets_system_prompt_and_default_params() {
+    let mut config = Config::default();
+    config.personas.insert(
+        "support-bot".to_string(),
+        config::PersonaConfig {
+            system_prompt: "You help with billing questions.".to_string(),
+            temperature: Some(0.2),
+            max_tokens: Some(48),
+            ..Default::default()
+        },
+    );
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let state = AppState::new_in_memory(Arc::new(MockEngine::new()), handle, config)
+        .await
+        .unwrap();
+    let app = routes::router().with_state(state.clone());
+    let chat = |payload: serde_json::Value| {
+        Request::builder()
+            .method("POST")
+            .uri("/chat/completions")
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+            .unwrap()
+    };
+    let session_id = format!("persona-{}", uuid::Uuid::new_v4());
+
+    // The request's own max-token wins; the persona fills in the temperature
+    let resp = app
+        .clone()
+        .oneshot(chat(json!({
+            "model-name": "mock-model",
+            "prompt": "Why was I charged twice?",
+            "session-id": session_id,
+            "persona": "support-bot",
+            "max-token": 32
+        })))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let params: serde_json::Value =
+        serde_json::from_str(resp.headers()["x-effective-params"].to_str().unwrap()).unwrap();
+    assert_eq!(params["temperature"], 0.2);
+    assert_eq!(params["max_tokens"], 32);
+    hyper::body::to_bytes(resp.into_body()).await.unwrap();
+
+    let history = state.sessions.lock().await[&session_id].clone();
+    assert_eq!(history[0].role, "system");
+    assert_eq!(history[0].content, "You help with billing questions.");
+
+    // Values the client sent are kept even when they equal the defaults
+    let resp = app
+        .clone()
+        .oneshot(chat(json!({
+            "model-name": "mock-model",
+            "prompt": "hi",
+            "persona": "support-bot",
+            "temperature": 0.7,
+            "max-token": 128
+        })))
+        .await
+        .unwrap();
+    let params: serde_json::Value =
+        serde_json::from_str(resp.headers()["x-effective-params"].to_str().unwrap()).unwrap();
+    assert_eq!(params["temperature"], 0.7);
+    assert_eq!(params["max_tokens"], 128);
+    hyper::body::to_bytes(resp.into_body()).await.unwrap();
+
+    let resp = app
+        .oneshot(chat(json!({
+            "model-name": "mock-model",
+            "prompt": "hi",
+            "persona": "pirate"
+        })))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let body: serde_json::Value =
+        serde_json::from_slice(&hyper::body::to_bytes(resp.into_body()).await.unwrap()).unwrap();
+    assert_eq!(body["error"], "unknown persona 'pirate'");
+}
+
+#[tokio::test]
+async fn test_auto_fit_uses_the_context_left_after_the_prompt() {
+    let mut config = Config::default();
+    config.models.available_models[0].context_length = Some(20);
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let engine = Arc::new(MockEngine::new().with_models(["qwen"]));
+    let state = AppState::new_in_memory(engine, handle, config).await.unwrap();
+    let app = routes::router().with_state(state.clone());
+    let send = |uri: &str, payload: serde_json::Value| {
+        Request::builder()
+            .method("POST")
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+            .unwrap()
+    };
+    let effective = |resp: &axum::response::Response| -> serde_json::Value {
+        serde_json::from_str(resp.headers()["x-effective-params"].to_str().unwrap()).unwrap()
+    };
+
+    // The mock renders "<|user|> one two three <|assistant|>": 5 of the 20 tokens
+    let resp = app
+        .clone()
+        .oneshot(send(
+            "/completions",
+            json!({"model": "qwen", "prompt": "one two three", "max_tokens": 4, "auto_fit": true}),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(effective(&resp)["max_tokens"], 15);
+    assert!(!effective(&resp)["adjusted"]
+        .as_array()
+        .unwrap()
+        .contains(&json!("max_tokens")));
+
+    let resp = app
+        .clone()
+        .oneshot(send(
+            "/chat/completions",
+            json!({"model-name": "qwen", "prompt": "one two three", "auto-fit": true}),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(effective(&resp)["max_tokens"], 15);
+
+    let long_prompt = vec!["word"; 30].join(" ");
+    // A session turn that does not fit is not added to the session
+    let resp = app
+        .clone()
+        .oneshot(send(
+            "/chat/completions",
+            json!({"model-name": "qwen", "prompt": "hi", "session-id": "fit", "auto-fit": true}),
+        ))
+        .await
+        .unwrap();
+    hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let before = state.sessions.lock().await["fit"].clone();
+    let resp = app
+        .clone()
+        .oneshot(send(
+            "/chat/completions",
+            json!({"model-name": "qwen", "prompt": long_prompt, "session-id": "fit", "auto-fit": true}),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(state.sessions.lock().await["fit"], before);
+
+    let resp = app
+        .oneshot(send(
+            "/completions",
+            json!({"model": "qwen", "prompt": long_prompt, "auto_fit": true}),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let body: serde_json::Value =
+        serde_json::from_slice(&hyper::body::to_bytes(resp.into_body()).await.unwrap()).unwrap();
+    assert_eq!(
+        body["error"],
+        "prompt of 32 tokens leaves no room in the model's 20-token context"
+    );
+}
+
+#[tokio::test]
+async fn test_long_prompts_stream_prefill_progress_first() {
+    let mut config = Config::default();
+    config.streaming.prefill_events_min_tokens = 5;
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let engine = Arc::new(
+        MockEngine::new()
+            .with_models(["qwen"])
+            .with_first_token_delay(std::time::Duration::from_millis(40)),
+    );
+    let state = AppState::new_in_memory(engine, handle, config).await.unwrap();
+    let app = routes::router().with_state(state);
+    let chat = |prompt: &str| {
+        Request::builder()
+            .method("POST")
+            .uri("/chat/completions")
+            .header("content-type", "application/json")
+            .body(Body::from(
+                serde_json::to_vec(&json!({"model-name": "qwen", "prompt": prompt})).unwrap(),
+            ))
+            .unwrap()
+    };
+
+    // "<|user|> one two three <|assistant|>" is 5 tokens to the mock
+    let resp = app.clone().oneshot(chat("one two three")).await.unwrap();
+    let body = String::from_utf8(hyper::body::to_bytes(resp.into_body()).await.unwrap().to_vec()).unwrap();
+    assert!(
+        body.starts_with("event:prefill\ndata:{\"processed\":0,\"total\":5}\n\n"),
+        "{}",
+        body
+    );
+    let done = body.find("data:{\"processed\":5,\"total\":5}").expect("final prefill event");
+    assert!(done < body.find("data:hello").expect("first token"));
+
+    // Shorter prompts go straight to the tokens
+    let resp = app.oneshot(chat("one")).await.unwrap();
+    let body = String::from_utf8(hyper::body::to_bytes(resp.into_body()).await.unwrap().to_vec()).unwrap();
+    assert!(!body.contains("prefill"), "{}", body);
+}
+
+#[cfg(feature = "chaos")]
+#[tokio::test]
+async fn test_chaos_settings_inject_engine_errors() {
+    let state = setup_test_state().await;
+    let chaos = Arc::new(llm_inference::chaos::ChaosEngine::new(state.engine.clone()));
+    let app = routes::router().with_state(state.with_chaos(chaos));
+    let send = |method: &str, uri: &str, payload: serde_json::Value| {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+            .unwrap()
+    };
+    let completion = json!({"model": "mock-model", "prompt": "hi"});
+
+    let resp = app.clone().oneshot(send("POST", "/completions", completion.clone())).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = app
+        .clone()
+        .oneshot(send("PUT", "/debug/chaos", json!({"error_rate": 1.0})))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = app.clone().oneshot(send("POST", "/completions", completion)).await.unwrap();
+    assert!(resp.status().is_server_error());
+
+    let resp = app
+        .clone()
+        .oneshot(send("PUT", "/debug/chaos", json!({"panic_rate": 2.0})))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let resp = app
+        .oneshot(Request::builder().uri("/debug/chaos").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let settings: serde_json::Value =
+        serde_json::from_slice(&hyper::body::to_bytes(resp.into_body()).await.unwrap()).unwrap();
+    assert_eq!(settings["error_rate"], 1.0);
+    assert_eq!(settings["panic_rate"], 0.0);
+}
+
+#[tokio::test]
+async fn test_regenerate_replaces_last_answer() {
     let state = setup_test_state().await;
     let app = routes::router().with_state(state.clone());
+    let post = |uri: &str, payload: serde_json::Value| {
+        Request::builder()
+            .method("POST")
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+            .unwrap()
+    };
 
-    // List sessions
+    let payload = json!({"model-name": "mock-model", "prompt": "hi", "session-id": "regen"});
+    let resp = app.clone().oneshot(post("/chat/completions", payload)).await.unwrap();
+    hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    state.sessions.lock().await.get_mut("regen").unwrap()[2].content = "old answer".to_string();
+
+    let payload = json!({"model-name": "mock-model", "temperature": 1.0});
+    let resp = app
+        .clone()
+        .oneshot(post("/chat/history/regen/regenerate", payload.clone()))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert!(String::from_utf8_lossy(&body).contains("hi"));
+
+    let history = state.sessions.lock().await["regen"].clone();
+    assert_eq!(history.len(), 3);
+    assert_eq!(history[1].content, "hi");
+    assert_eq!(history[2].role, "assistant");
+    assert_eq!(history[2].content, "hello hi\ndone");
+
+    let resp = app
+        .oneshot(post("/chat/history/missing/regenerate", payload))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_cancelled_queued_regenerate_keeps_the_last_answer() {
+    use hyper::body::HttpBody;
+
+    let mut config = Config::default();
+    config.models.max_concurrent_requests = 1;
+    config.streaming.queue_update_interval_ms = 20;
+    let engine = MockEngine::new()
+        .with_tokens(vec!["x"; 200])
+        .with_token_delay(std::time::Duration::from_millis(20));
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let state = AppState::new_in_memory(Arc::new(engine), handle, config)
+        .await
+        .unwrap();
+    let app = routes::router().with_state(state.clone());
+    let post = |uri: &str, payload: serde_json::Value| {
+        Request::post(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(payload.to_string()))
+            .unwrap()
+    };
+    let message = |role: &str, content: &str| ChatMessage {
+        role: role.to_string(),
+        content: content.to_string(),
+        truncated: false,
+        partial: false,
+        timestamp: None,
+        continues: false,
+    };
+    let old = vec![message("user", "hi"), message("assistant", "old answer")];
+    state.sessions.lock().await.insert("regen".to_string(), old.clone());
+
+    // Another generation takes the only slot
+    let mut running = app
+        .clone()
+        .oneshot(post(
+            "/chat/completions",
+            json!({"model-name": "mock-model", "prompt": "busy"}),
+        ))
+        .await
+        .unwrap()
+        .into_body();
+    running.data().await.unwrap().unwrap();
+
+    let regenerate = tokio::spawn(app.clone().oneshot(post(
+        "/chat/history/regen/regenerate",
+        json!({"model-name": "mock-model"}),
+    )));
+    while state.concurrency_limiter.queued() == 0 {
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+    }
+    // Taken out of the queue before it ever gets the slot
+    let queued = state
+        .concurrency_limiter
+        .jobs()
+        .into_iter()
+        .find(|job| job.position.is_some())
+        .unwrap();
+    assert!(state.concurrency_limiter.cancel(queued.id));
+
+    let resp = regenerate.await.unwrap().unwrap();
+    assert!(!resp.status().is_success());
+    assert_eq!(state.sessions.lock().await["regen"], old);
+}
+
+#[tokio::test]
+async fn test_failed_regenerate_keeps_the_last_answer() {
+    let message = |role: &str, content: &str| ChatMessage {
+        role: role.to_string(),
+        content: content.to_string(),
+        truncated: false,
+        partial: false,
+        timestamp: None,
+        continues: false,
+    };
+    let old = vec![message("user", "hi"), message("assistant", "old answer")];
+
+    // The error comes after a token, so a partial reply was written before the session is restored
+    for stream in [false, true] {
+        let engine = MockEngine::new()
+            .with_tokens(["new", " answer"])
+            .with_error_after(1, "out of memory");
+        let handle = PrometheusBuilder::new().build_recorder().handle();
+        let state = AppState::new_in_memory(Arc::new(engine), handle, Config::default())
+            .await
+            .unwrap();
+        state.sessions.lock().await.insert("regen".to_string(), old.clone());
+        let app = routes::router().with_state(state.clone());
+
+        let req = Request::post("/chat/history/regen/regenerate")
+            .header("content-type", "application/json")
+            .body(Body::from(json!({"model-name": "mock-model", "stream": stream}).to_string()))
+            .unwrap();
+        let resp = app.oneshot(req).await.unwrap();
+        // Streamed, the error arrives after the 200
+        assert_eq!(resp.status().is_success(), stream);
+        hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(state.sessions.lock().await["regen"], old, "stream: {}", stream);
+    }
+}
+
+#[tokio::test]
+async fn test_export_history_as_markdown_and_html() {
+    let state = setup_test_state().await;
+    let app = routes::router().with_state(state.clone());
+
+    let payload = json!({"model-name": "mock-model", "prompt": "a < b?", "session-id": "export"});
     let req = Request::builder()
-        .method("GET")
-        .uri("/sessions")
-        .body(Body::empty())
+        .method("POST")
+        .uri("/chat/completions")
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+        .unwrap();
+    let resp = app.clone().oneshot(req).await.unwrap();
+    hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert!(state.sessions.lock().await["export"][1].timestamp.is_some());
+
+    let get = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+    let resp = app.clone().oneshot(get("/chat/history/export/export")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp.headers()["content-type"].to_str().unwrap().starts_with("text/markdown"));
+    assert!(resp.headers()["content-disposition"].to_str().unwrap().contains("export.md"));
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let markdown = String::from_utf8_lossy(&body);
+    assert!(markdown.starts_with("# Conversation export"));
+    assert!(markdown.contains("### User · "));
+    assert!(markdown.contains("a < b?"));
+
+    let resp = app
+        .clone()
+        .oneshot(get("/chat/history/export/export?format=html"))
+        .await
         .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp.headers()["content-type"].to_str().unwrap().starts_with("text/html"));
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let html = String::from_utf8_lossy(&body);
+    assert!(html.contains("<section class=\"user\">"));
+    assert!(html.contains("a &lt; b?"));
+
+    let resp = app.oneshot(get("/chat/history/missing/export")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_admin_session_stats_summarizes_store() {
+    let state = setup_test_state().await;
+    let app = routes::router().with_state(state);
 
+    for (session, prompt) in [("short", "hi"), ("long", "tell me everything about sqlite")] {
+        let payload = json!({"model-name": "mock-model", "prompt": prompt, "session-id": session});
+        let req = Request::builder()
+            .method("POST")
+            .uri("/chat/completions")
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+            .unwrap();
+        let resp = app.clone().oneshot(req).await.unwrap();
+        hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    }
+
+    let req = Request::builder()
+        .uri("/admin/sessions/stats?top=1")
+        .body(Body::empty())
+        .unwrap();
     let resp = app.oneshot(req).await.unwrap();
     assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let stats: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(stats["sessions"], 2);
+    assert_eq!(stats["messages"], 6);
+    assert_eq!(stats["undated_sessions"], 0);
+    assert!(stats["newest_activity"].is_string());
+    assert_eq!(stats["size_buckets"][0]["size"], "<1KiB");
+    assert_eq!(stats["size_buckets"][0]["sessions"], 2);
+    assert_eq!(stats["size_buckets"].as_array().unwrap().len(), 5);
+    let top = stats["top_sessions"].as_array().unwrap();
+    assert_eq!(top.len(), 1);
+    assert_eq!(top[0]["session_id"], "long");
+    assert!(top[0]["estimated_tokens"].as_u64().unwrap() > 0);
 }
 
 #[tokio::test]
-async fn test_prompt_length_validation() {
-    let mut config = Config::default();
-    config.limits.max_prompt_length = 10;
+async fn test_session_generation_is_broadcast_to_observers() {
+    let state = setup_test_state().await;
+    let app = routes::router().with_state(state.clone());
+    assert!(state.observers.subscribe("watched").is_none());
 
-    let builder = PrometheusBuilder::new();
-    let recorder = builder.build_recorder();
-    let handle = recorder.handle();
+    let payload = json!({"model-name": "mock-model", "prompt": "hi", "session-id": "watched"});
+    let req = Request::builder()
+        .method("POST")
+        .uri("/chat/completions")
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+        .unwrap();
+    let resp = app.oneshot(req).await.unwrap();
+    let (so_far, mut receiver) = state.observers.subscribe("watched").unwrap();
+    assert_eq!(so_far, "");
+    hyper::body::to_bytes(resp.into_body()).await.unwrap();
+
+    let mut text = String::new();
+    loop {
+        match receiver.recv().await.unwrap() {
+            Observed::Token(token) => text.push_str(&token),
+            Observed::Done { finish_reason } => {
+                assert!(!finish_reason.is_empty());
+                break;
+            }
+            Observed::Error(error) => panic!("unexpected error {}", error),
+        }
+    }
+    assert_eq!(text, state.sessions.lock().await["watched"][2].content);
+    assert!(state.observers.subscribe("watched").is_none());
+}
+
+#[tokio::test]
+async fn test_debug_render_shows_prompt_without_generating() {
     let engine = Arc::new(MockEngine::new());
-    let state = AppState::new(engine, handle, config).await.unwrap();
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let state = AppState::new_in_memory(engine.clone(), handle, Config::default())
+        .await
+        .unwrap();
     let app = routes::router().with_state(state);
+    let render = |payload: serde_json::Value| {
+        Request::builder()
+            .method("POST")
+            .uri("/debug/render")
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+            .unwrap()
+    };
 
     let payload = json!({
-        "model": "mock-model",
-        "prompt": "This is a very long prompt that exceeds the limit",
-        "max_tokens": 50,
-        "stream": false
+        "model-name": "mock-model",
+        "messages": [
+            {"role": "system", "content": "Be brief."},
+            {"role": "user", "content": "Hi there"}
+        ],
+        "stop": ["###"]
     });
+    let resp = app.clone().oneshot(render(payload)).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let rendered: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        rendered["prompt"],
+        "<|system|>\nBe brief.\n<|user|>\nHi there\n<|assistant|>\n"
+    );
+    assert_eq!(rendered["prompt_tokens"], 7);
+    assert_eq!(rendered["stop"], json!(["###", "</s>"]));
+    assert_eq!(engine.tokens_generated(), 0);
+
+    let resp = app
+        .oneshot(render(json!({"model-name": "no-such-model", "prompt": "Hi"})))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_trailing_assistant_message_is_continued() {
+    let state = setup_test_state().await;
+    let app = routes::router().with_state(state);
+    let send = |uri: &str, messages: serde_json::Value| {
+        let payload = json!({"model-name": "mock-model", "messages": messages});
+        Request::builder()
+            .method("POST")
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+            .unwrap()
+    };
+    let conversation = json!([
+        {"role": "user", "content": "Tell me a story"},
+        {"role": "assistant", "content": "Once upon a", "continue": true}
+    ]);
+
+    // The reply's turn is left open for the model to carry on
+    let resp = app.clone().oneshot(send("/debug/render", conversation.clone())).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let rendered: serde_json::Value =
+        serde_json::from_slice(&hyper::body::to_bytes(resp.into_body()).await.unwrap()).unwrap();
+    assert_eq!(rendered["prompt"], "<|user|>\nTell me a story\n<|assistant|>\nOnce upon a");
+
+    let resp = app.clone().oneshot(send("/chat/completions", conversation)).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = app
+        .oneshot(send(
+            "/chat/completions",
+            json!([
+                {"role": "user", "content": "Tell me a story", "continue": true},
+                {"role": "assistant", "content": "Once upon a"}
+            ]),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let body: serde_json::Value =
+        serde_json::from_slice(&hyper::body::to_bytes(resp.into_body()).await.unwrap()).unwrap();
+    assert_eq!(body["error"], "messages[0].continue is only allowed on the last message");
+}
+
+#[tokio::test]
+async fn test_chat_without_streaming_returns_one_json_body() {
+    let state = setup_test_state().await;
+    let app = routes::router().with_state(state);
+    let payload = json!({"model-name": "mock-model", "prompt": "Hi", "stream": false, "create-session": true});
+    let req = Request::post("/chat/completions")
+        .header("content-type", "application/json")
+        .body(Body::from(payload.to_string()))
+        .unwrap();
+
+    let resp = app.clone().oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()["content-type"], "application/json");
+    let session_id = resp.headers()["x-session-id"].to_str().unwrap().to_string();
+    let body: serde_json::Value =
+        serde_json::from_slice(&hyper::body::to_bytes(resp.into_body()).await.unwrap()).unwrap();
+    assert_eq!(body["message"], json!({"role": "assistant", "content": "hello Hi\ndone"}));
+    assert_eq!(body["usage"], json!({"prompt_tokens": 1, "completion_tokens": 5}));
+    assert_eq!(body["finish_reason"], "stop");
+
+    // The reply is saved to the session like a streamed one
+    let history = Request::get(format!("/chat/history/{}", session_id)).body(Body::empty()).unwrap();
+    let resp = app.oneshot(history).await.unwrap();
+    let history: serde_json::Value =
+        serde_json::from_slice(&hyper::body::to_bytes(resp.into_body()).await.unwrap()).unwrap();
+    let last = history.as_array().unwrap().last().unwrap();
+    assert_eq!(last["role"], "assistant");
+    assert_eq!(last["content"], "hello Hi\ndone");
+}
+
+#[tokio::test]
+async fn test_history_can_be_filtered_and_paged() {
+    let state = setup_test_state().await;
+    let history: Vec<llm_inference::models::ChatMessage> = serde_json::from_value(json!([
+        {"role": "system", "content": "Be brief."},
+        {"role": "user", "content": "a"},
+        {"role": "assistant", "content": "A"},
+        {"role": "user", "content": "b"},
+        {"role": "assistant", "content": "B"}
+    ]))
+    .unwrap();
+    state.sessions.lock().await.insert("paged".to_string(), history);
+    let app = routes::router().with_state(state);
+    let get = |query: &str| {
+        let req = Request::get(format!("/chat/history/paged{}", query)).body(Body::empty()).unwrap();
+        let app = app.clone();
+        async move {
+            let resp = app.oneshot(req).await.unwrap();
+            let status = resp.status();
+            let total = resp.headers().get("x-total-count").map(|v| v.to_str().unwrap().to_string());
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            (status, total, serde_json::from_slice::<serde_json::Value>(&body).unwrap())
+        }
+    };
+    let indexes = |body: &serde_json::Value| -> Vec<u64> {
+        body.as_array().unwrap().iter().map(|m| m["index"].as_u64().unwrap()).collect()
+    };
+
+    let (status, total, body) = get("").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(total.as_deref(), Some("5"));
+    assert_eq!(indexes(&body), vec![0, 1, 2, 3, 4]);
+
+    // The latest exchange, newest first, without the system prompt
+    let (_, _, body) = get("?roles=user,assistant&order=desc&limit=2").await;
+    assert_eq!(indexes(&body), vec![4, 3]);
+    assert_eq!(body[0]["content"], "B");
+
+    // The page before it
+    let (_, _, body) = get("?roles=user,assistant&order=desc&limit=2&to=3").await;
+    assert_eq!(indexes(&body), vec![2, 1]);
 
+    let (_, _, body) = get("?from=1&to=3&roles=assistant").await;
+    assert_eq!(indexes(&body), vec![2]);
+
+    let (status, _, body) = get("?roles=tool").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body["error"].as_str().unwrap().contains("'tool'"));
+    let (status, _, _) = get("?from=3&to=1").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_stats_endpoint_counts_requests() {
+    let state = setup_test_state().await;
+    let app = routes::router().with_state(state);
+
+    let payload = json!({
+        "model-name": "mock-model",
+        "prompt": "Hello",
+        "device": "cpu"
+    });
     let req = Request::builder()
         .method("POST")
-        .uri("/completions")
+        .uri("/chat/completions")
         .header("content-type", "application/json")
         .body(Body::from(serde_json::to_vec(&payload).unwrap()))
         .unwrap();
+    let resp = app.clone().oneshot(req).await.unwrap();
+    hyper::body::to_bytes(resp.into_body()).await.unwrap();
 
+    let req = Request::builder()
+        .method("GET")
+        .uri("/stats")
+        .body(Body::empty())
+        .unwrap();
     let resp = app.oneshot(req).await.unwrap();
-    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let stats: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(stats["totals"]["requests"], 1);
+    assert!(stats["totals"]["tokens"].as_u64().unwrap() > 0);
+    assert_eq!(stats["models"]["mock-model"]["requests"], 1);
+    assert_eq!(stats["concurrency"]["in_flight"], 0);
 }
 
 #[tokio::test]
-async fn test_metrics_endpoint() {
+async fn test_stats_shows_usage_of_the_callers_account_only() {
+    let state = setup_auth_state(vec![
+        config::ApiKeyConfig {
+            key: "alice-key".to_string(),
+            name: "alice".to_string(),
+            enabled: true,
+            ..Default::default()
+        },
+        config::ApiKeyConfig {
+            key: "ops-key".to_string(),
+            name: "ops".to_string(),
+            enabled: true,
+            admin: true,
+            ..Default::default()
+        },
+    ])
+    .await;
+    let app = routes::router().with_state(state);
+    for key in ["alice-key", "ops-key"] {
+        let resp = app.clone().oneshot(completion_request(key)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+    let accounts = |key: Option<&str>| {
+        let mut req = Request::get("/stats");
+        if let Some(key) = key {
+            req = req.header("authorization", format!("Bearer {}", key));
+        }
+        let app = app.clone();
+        async move {
+            let resp = app.oneshot(req.body(Body::empty()).unwrap()).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            let stats: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let accounts = stats["usage"]["accounts"].as_object().unwrap().clone();
+            accounts.keys().cloned().collect::<Vec<_>>()
+        }
+    };
+
+    assert_eq!(accounts(Some("alice-key")).await, vec!["alice"]);
+    assert_eq!(accounts(Some("ops-key")).await, vec!["alice", "ops"]);
+    assert!(accounts(None).await.is_empty());
+}
+
+#[tokio::test]
+async fn test_model_info_reports_load_state_and_usage() {
+    let mut config = Config::default();
+    config.models.available_models[0].aliases = vec!["mock-model".to_string()];
+    let engine = Arc::new(MockEngine::new());
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let state = AppState::new_in_memory(engine, handle, config).await.unwrap();
+    let app = routes::router().with_state(state);
+
+    let payload = json!({"model-name": "mock-model", "prompt": "Hello"});
+    let req = Request::builder()
+        .method("POST")
+        .uri("/chat/completions")
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+        .unwrap();
+    let resp = app.clone().oneshot(req).await.unwrap();
+    hyper::body::to_bytes(resp.into_body()).await.unwrap();
+
+    let get = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+    let resp = app.clone().oneshot(get("/models/qwen")).await.unwrap();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let info: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(info["loaded"]["device"], "cpu");
+    assert!(info["loaded"]["loaded_at"].is_string());
+    assert!(info["loaded"]["parameters"].is_null());
+    assert_eq!(info["stats"]["requests"], 1);
+    assert!(info["stats"]["tokens"].as_u64().unwrap() > 0);
+
+    // Not loaded by this engine and never used
+    let resp = app.oneshot(get("/models/phi")).await.unwrap();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let info: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert!(info["loaded"].is_null());
+    assert_eq!(info["stats"]["requests"], 0);
+}
+
+#[tokio::test]
+async fn test_admin_metrics_stream_pushes_snapshots() {
+    use hyper::body::HttpBody;
+
     let state = setup_test_state().await;
     let app = routes::router().with_state(state);
 
     let req = Request::builder()
         .method("GET")
-        .uri("/metrics")
+        .uri("/admin/metrics/stream")
         .body(Body::empty())
         .unwrap();
-
     let resp = app.oneshot(req).await.unwrap();
     assert_eq!(resp.status(), StatusCode::OK);
+
+    // The first snapshot is sent immediately
+    let mut body = resp.into_body();
+    let chunk = body.data().await.unwrap().unwrap();
+    let text = String::from_utf8(chunk.to_vec()).unwrap();
+    let data = text.trim().strip_prefix("data:").unwrap().trim();
+    let snapshot: serde_json::Value = serde_json::from_str(data).unwrap();
+    assert_eq!(snapshot["active_requests"], 0);
+    assert_eq!(snapshot["queue_depth"], 0);
+    assert!(snapshot["tokens_per_second"].is_number());
+}
+
+#[tokio::test]
+async fn test_events_stream_session_generation_and_model_lifecycle() {
+    use hyper::body::HttpBody;
+
+    let state = setup_test_state().await;
+    let app = routes::router().with_state(state);
+    let send = |method: &str, uri: &str, payload: Option<serde_json::Value>| {
+        let body = payload.map_or_else(Body::empty, |p| Body::from(serde_json::to_vec(&p).unwrap()));
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(body)
+            .unwrap()
+    };
+
+    let resp = app.clone().oneshot(send("GET", "/events", None)).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let mut events = resp.into_body();
+
+    let chat = json!({"model-name": "mock-model", "prompt": "hi", "session-id": "watched", "stream": false});
+    let resp = app.clone().oneshot(send("POST", "/chat/completions", Some(chat))).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let resp = app.clone().oneshot(send("DELETE", "/chat/history/watched", None)).await.unwrap();
+    assert!(resp.status().is_success());
+    let resp = app.oneshot(send("DELETE", "/admin/cache/mock-model", None)).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+    // Read until the model is reported unloaded
+    let mut received = Vec::new();
+    while !received.iter().any(|(name, _)| name == "model_unloaded") {
+        let chunk = tokio::time::timeout(std::time::Duration::from_secs(5), events.data())
+            .await
+            .expect("no event within 5s")
+            .unwrap()
+            .unwrap();
+        let text = String::from_utf8(chunk.to_vec()).unwrap();
+        for block in text.split("\n\n").filter(|b| !b.trim().is_empty()) {
+            let field = |prefix: &str| {
+                block
+                    .lines()
+                    .find_map(|line| line.strip_prefix(prefix))
+                    .map(|v| v.trim().to_string())
+            };
+            if let (Some(name), Some(data)) = (field("event:"), field("data:")) {
+                let data: serde_json::Value = serde_json::from_str(&data).unwrap();
+                assert_eq!(data["type"], name.as_str());
+                received.push((name, data));
+            }
+        }
+    }
+
+    let position = |name: &str| received.iter().position(|(n, _)| n == name).unwrap();
+    assert_eq!(received[position("session_created")].1["session_id"], "watched");
+    assert_eq!(received[position("generation_started")].1["model"], "mock-model");
+    let finished = &received[position("generation_finished")].1;
+    assert_eq!(finished["session_id"], "watched");
+    assert_eq!(finished["outcome"], "completed");
+    assert!(finished["tokens"].as_u64().unwrap() > 0);
+    assert!(position("session_created") < position("session_deleted"));
+    assert_eq!(received[position("model_unloaded")].1["model"], "mock-model");
+}
+
+#[tokio::test]
+async fn test_events_need_an_admin_key() {
+    let mut config = Config::default();
+    config.security.enable_auth = true;
+    config.security.api_keys = vec![
+        config::ApiKeyConfig {
+            key: "sk-admin-0000000001".to_string(),
+            name: "admin".to_string(),
+            enabled: true,
+            admin: true,
+            ..Default::default()
+        },
+        config::ApiKeyConfig {
+            key: "sk-user-00000000002".to_string(),
+            name: "user".to_string(),
+            enabled: true,
+            ..Default::default()
+        },
+    ];
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let state = AppState::new_in_memory(Arc::new(MockEngine::new()), handle, config)
+        .await
+        .unwrap();
+    let app = routes::router().with_state(state);
+    let events = |key: &str| {
+        Request::builder()
+            .uri("/events")
+            .header("authorization", format!("Bearer {}", key))
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let resp = app.clone().oneshot(events("sk-user-00000000002")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = app.oneshot(events("sk-admin-0000000001")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_session_management() {
+    let state = setup_test_state().await;
+    let app = routes::router().with_state(state.clone());
+
+    // List sessions
+    let req = Request::builder()
+        .method("GET")
+        .uri("/sessions")
+        .body(Body::empty())
+        .unwrap();
+
+    let resp = app.oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_in_memory_states_are_isolated() {
+    let first = setup_test_state().await;
+    let payload = json!({"model-name": "mock-model", "prompt": "hi", "session-id": "isolated"});
+    let req = Request::builder()
+        .method("POST")
+        .uri("/chat/completions")
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+        .unwrap();
+    let resp = routes::router()
+        .with_state(first.clone())
+        .oneshot(req)
+        .await
+        .unwrap();
+    hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert_eq!(first.sessions.lock().await["isolated"].len(), 3);
+
+    // A second state starts from its own empty database
+    let second = setup_test_state().await;
+    let req = Request::builder()
+        .uri("/sessions")
+        .body(Body::empty())
+        .unwrap();
+    let resp = routes::router()
+        .with_state(second)
+        .oneshot(req)
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let sessions: Vec<String> = serde_json::from_slice(&body).unwrap();
+    assert!(sessions.is_empty());
+}
+
+#[tokio::test]
+async fn test_deleting_session_cancels_its_generations() {
+    use std::sync::atomic::Ordering;
+
+    let state = setup_test_state().await;
+    let app = routes::router().with_state(state.clone());
+
+    let session_id = "cancel-me-session";
+    state
+        .sessions
+        .lock()
+        .await
+        .insert(session_id.to_string(), Vec::new());
+    let flag = state.session_cancellation(session_id);
+    assert!(!flag.load(Ordering::Relaxed));
+
+    let req = Request::builder()
+        .method("DELETE")
+        .uri(format!("/chat/history/{}", session_id))
+        .body(Body::empty())
+        .unwrap();
+    let resp = app.oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert!(flag.load(Ordering::Relaxed));
+
+    // A session recreated under the same id starts with a fresh flag
+    assert!(!state
+        .session_cancellation(session_id)
+        .load(Ordering::Relaxed));
+}
+
+#[tokio::test]
+async fn test_queued_stream_reports_position() {
+    use hyper::body::HttpBody;
+
+    let mut config = Config::default();
+    config.models.max_concurrent_requests = 1;
+    config.streaming.queue_update_interval_ms = 20;
+    config.streaming.record_transcripts = true;
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let state = AppState::new_in_memory(Arc::new(MockEngine::new()), handle, config)
+        .await
+        .unwrap();
+    let app = routes::router().with_state(state.clone());
+    let busy = state
+        .concurrency_limiter
+        .acquire("other", None, 0)
+        .await
+        .unwrap();
+
+    let req = Request::builder()
+        .method("POST")
+        .uri("/chat/completions")
+        .header("content-type", "application/json")
+        .body(Body::from(
+            json!({"model-name": "mock-model", "prompt": "Hello"}).to_string(),
+        ))
+        .unwrap();
+    let resp = app.oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()["content-type"], "text/event-stream");
+    assert!(resp.headers().contains_key("x-request-id"));
+    let params: serde_json::Value =
+        serde_json::from_slice(resp.headers()["x-effective-params"].as_bytes()).unwrap();
+    assert_eq!(params["model"], "mock-model");
+
+    // The response starts while the request is still waiting for the busy slot
+    let mut body = resp.into_body();
+    let first = body.data().await.unwrap().unwrap();
+    let first = std::str::from_utf8(&first).unwrap();
+    assert!(first.starts_with("event:queue\ndata:"), "{}", first);
+    let status: serde_json::Value =
+        serde_json::from_str(first.lines().nth(1).unwrap().trim_start_matches("data:")).unwrap();
+    assert_eq!(status["position"], 1);
+    assert_eq!(state.concurrency_limiter.queued(), 1);
+
+    drop(busy);
+    let rest = hyper::body::to_bytes(body).await.unwrap();
+    let rest = std::str::from_utf8(&rest).unwrap();
+    let after_queue = rest.rsplit("event:queue").next().unwrap();
+    assert!(after_queue.contains("\ndata:"), "{}", rest);
+    assert_eq!(state.concurrency_limiter.queued(), 0);
+}
+
+#[tokio::test]
+async fn test_full_queue_is_refused_with_retry_after() {
+    let mut config = Config::default();
+    config.models.max_concurrent_requests = 1;
+    config.overload.max_queue_length = 1;
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let state = AppState::new_in_memory(Arc::new(MockEngine::new()), handle, config)
+        .await
+        .unwrap();
+    let app = routes::router().with_state(state.clone());
+    let _busy = state
+        .concurrency_limiter
+        .acquire("other", None, 0)
+        .await
+        .unwrap();
+    let _queued = state.concurrency_limiter.reserve("other", None, 0).unwrap();
+
+    let req = Request::builder()
+        .method("POST")
+        .uri("/completions")
+        .header("content-type", "application/json")
+        .body(Body::from(
+            json!({"model": "mock-model", "prompt": "Hello"}).to_string(),
+        ))
+        .unwrap();
+    let resp = app.oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(resp.headers()["retry-after"], "1");
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["code"], "overloaded");
+    assert_eq!(body["retry_after_seconds"], 1);
+    assert_eq!(state.concurrency_limiter.queued(), 1);
+}
+
+#[tokio::test]
+async fn test_admin_queue_lists_and_cancels() {
+    use hyper::body::HttpBody;
+
+    let mut config = Config::default();
+    config.models.max_concurrent_requests = 1;
+    config.streaming.queue_update_interval_ms = 20;
+    let engine = MockEngine::new()
+        .with_tokens(vec!["x"; 200])
+        .with_token_delay(std::time::Duration::from_millis(20));
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let state = AppState::new_in_memory(Arc::new(engine), handle, config)
+        .await
+        .unwrap();
+    let app = routes::router().with_state(state);
+    // Different prompts, so the second request cannot follow the first generation
+    let chat = |prompt: &str| {
+        Request::post("/chat/completions")
+            .header("content-type", "application/json")
+            .body(Body::from(
+                json!({"model-name": "mock-model", "prompt": prompt}).to_string(),
+            ))
+            .unwrap()
+    };
+    let admin = |method: &str, uri: String| {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    // One generation takes the only slot, the next one queues behind it
+    let mut running = app
+        .clone()
+        .oneshot(chat("first"))
+        .await
+        .unwrap()
+        .into_body();
+    let first = running.data().await.unwrap().unwrap();
+    assert!(first.starts_with(b"data:"));
+    let mut queued = app
+        .clone()
+        .oneshot(chat("second"))
+        .await
+        .unwrap()
+        .into_body();
+    let first = queued.data().await.unwrap().unwrap();
+    assert!(first.starts_with(b"event:queue"));
+
+    let resp = app
+        .clone()
+        .oneshot(admin("GET", "/admin/queue".to_string()))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(status["available"], 0);
+    let job = &status["running"][0];
+    assert_eq!(job["model"], "mock-model");
+    assert_eq!(job["account"], "anonymous");
+    assert!(job["tokens"].as_u64().unwrap() >= 1);
+    assert_eq!(status["queued"][0]["position"], 1);
+    let running_id = job["id"].as_u64().unwrap();
+    let queued_id = status["queued"][0]["id"].as_u64().unwrap();
+
+    let resp = app
+        .clone()
+        .oneshot(admin("DELETE", format!("/admin/queue/{}", queued_id)))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    let rest = hyper::body::to_bytes(queued).await.unwrap();
+    assert!(std::str::from_utf8(&rest)
+        .unwrap()
+        .contains("data:__ERROR__:Request cancelled by an operator"));
+
+    let resp = app
+        .clone()
+        .oneshot(admin("DELETE", format!("/admin/queue/{}", running_id)))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    let rest = hyper::body::to_bytes(running).await.unwrap();
+    let rest = std::str::from_utf8(&rest).unwrap();
+    assert!(
+        rest.contains("data:__ERROR__:generation cancelled"),
+        "{}",
+        rest
+    );
+
+    let resp = app
+        .oneshot(admin("DELETE", format!("/admin/queue/{}", running_id)))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_admin_cache_lists_and_evicts_models() {
+    let state = setup_test_state().await;
+    let app = routes::router().with_state(state);
+    let admin = |method: &str, uri: &str| {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap()
+    };
+    let cached = |app: axum::Router| async move {
+        let resp = app.oneshot(admin("GET", "/admin/cache")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+    };
+
+    let cache = cached(app.clone()).await;
+    assert_eq!(cache["models"][0]["model"], "mock-model");
+    assert_eq!(cache["models"][0]["devices"], json!(["cpu"]));
+    assert!(cache["models"][0]["last_used"].is_string());
+
+    let resp = app.clone().oneshot(admin("DELETE", "/admin/cache/mock-model")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert_eq!(cached(app.clone()).await["models"], json!([]));
+    let resp = app.clone().oneshot(admin("DELETE", "/admin/cache/mock-model")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let resp = app.clone().oneshot(admin("DELETE", "/admin/cache/no-such-model")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    // The next generation loads it again
+    let chat = Request::post("/chat/completions")
+        .header("content-type", "application/json")
+        .body(Body::from(json!({"model-name": "mock-model", "prompt": "hi"}).to_string()))
+        .unwrap();
+    let resp = app.clone().oneshot(chat).await.unwrap();
+    hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert_eq!(cached(app).await["models"][0]["model"], "mock-model");
+}
+
+#[tokio::test]
+async fn test_drain_and_resume() {
+    let state = setup_test_state().await;
+    let app = routes::router().with_state(state.clone());
+    let post = |uri: &str, body: Body| {
+        Request::builder()
+            .method("POST")
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(body)
+            .unwrap()
+    };
+    let completion = || {
+        post(
+            "/completions",
+            Body::from(json!({"model": "mock-model", "prompt": "Hi"}).to_string()),
+        )
+    };
+    let readiness = || Request::get("/readiness").body(Body::empty()).unwrap();
+
+    let resp = app
+        .clone()
+        .oneshot(post(
+            "/admin/drain",
+            Body::from(json!({"retry_after_seconds": 5}).to_string()),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(status["draining"]["retry_after_seconds"], 5);
+    assert_eq!(status["in_flight"], 0);
+
+    let resp = app.clone().oneshot(completion()).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(resp.headers()["retry-after"], "5");
+    let resp = app.clone().oneshot(readiness()).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    let resp = app
+        .clone()
+        .oneshot(post("/admin/resume", Body::empty()))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = app.clone().oneshot(completion()).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = app.oneshot(readiness()).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_read_only_replica_refuses_generations() {
+    let mut config = Config::default();
+    config.server.read_only = true;
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let state = AppState::new_in_memory(Arc::new(MockEngine::new()), handle, config)
+        .await
+        .unwrap();
+    let app = routes::router().with_state(state);
+    let post = |uri: &str, body: serde_json::Value| {
+        Request::builder()
+            .method("POST")
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+
+    let resp = app
+        .clone()
+        .oneshot(post("/completions", json!({"model": "mock-model", "prompt": "Hi"})))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let error: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(error["code"], "read_only");
+    let resp = app
+        .clone()
+        .oneshot(post("/chat/completions", json!({"model-name": "mock-model", "prompt": "Hi"})))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    for uri in ["/models", "/sessions", "/stats", "/readiness"] {
+        let resp = app
+            .clone()
+            .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK, "{}", uri);
+    }
+}
+
+#[tokio::test]
+async fn test_tokenizer_only_server_tokenizes_and_refuses_generations() {
+    let mut config = Config::default();
+    config.server.tokenizer_only = true;
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let engine = Arc::new(MockEngine::new());
+    let state = AppState::new_in_memory(engine.clone(), handle, config)
+        .await
+        .unwrap();
+    let app = routes::router().with_state(state);
+    let post = |uri: &str, body: serde_json::Value| {
+        Request::builder()
+            .method("POST")
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+
+    let payload = json!({"model": "mock-model", "text": "count these words", "add_special_tokens": true});
+    let resp = app.clone().oneshot(post("/tokenize", payload)).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let tokenized: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(tokenized["model"], "mock-model");
+    assert_eq!(tokenized["tokens"], 4);
+    assert_eq!(tokenized["token_ids"][0], 1);
+    assert_eq!(tokenized["token_ids"].as_array().unwrap().len(), 4);
+
+    // Rendering needs only the tokenizer and chat template
+    let resp = app
+        .clone()
+        .oneshot(post("/debug/render", json!({"model-name": "mock-model", "prompt": "Hi"})))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = app
+        .clone()
+        .oneshot(post("/completions", json!({"model": "mock-model", "prompt": "Hi"})))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let error: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(error["code"], "tokenizer_only");
+
+    let resp = app
+        .oneshot(post("/tokenize", json!({"model": "no-such-model", "text": "Hi"})))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    assert_eq!(engine.tokens_generated(), 0);
+}
+
+#[tokio::test]
+async fn test_chat_creates_session() {
+    let state = setup_test_state().await;
+    let app = routes::router().with_state(state.clone());
+    let chat = |body: serde_json::Value| {
+        Request::builder()
+            .method("POST")
+            .uri("/chat/completions")
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+
+    let resp = app
+        .clone()
+        .oneshot(chat(json!({
+            "model-name": "mock-model",
+            "prompt": "Hello",
+            "create-session": true,
+        })))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let session_id = resp.headers()["x-session-id"].to_str().unwrap().to_string();
+    assert!(uuid::Uuid::parse_str(&session_id).is_ok());
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let body = std::str::from_utf8(&body).unwrap();
+    assert!(body.starts_with(&format!("event:session\ndata:{}\n", session_id)));
+
+    let history = state
+        .sessions
+        .lock()
+        .await
+        .get(&session_id)
+        .cloned()
+        .unwrap();
+    assert_eq!(history.last().unwrap().role, "assistant");
+
+    // A given session id is used as-is
+    let resp = app
+        .oneshot(chat(json!({
+            "model-name": "mock-model",
+            "prompt": "Again",
+            "session-id": session_id,
+            "create-session": true,
+        })))
+        .await
+        .unwrap();
+    assert!(resp.headers().get("x-session-id").is_none());
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert!(!std::str::from_utf8(&body)
+        .unwrap()
+        .contains("event:session"));
+    state.delete_session(&session_id).await;
+}
+
+#[tokio::test]
+async fn test_protocol_version_negotiation() {
+    let state = setup_test_state().await;
+    let app = routes::router().with_state(state.clone());
+    let chat = |version: &str| {
+        Request::builder()
+            .method("POST")
+            .uri("/chat/completions")
+            .header("content-type", "application/json")
+            .header("x-protocol-version", version)
+            .body(Body::from(
+                json!({"model-name": "mock-model", "prompt": "Hello", "create-session": true}).to_string(),
+            ))
+            .unwrap()
+    };
+
+    let resp = app.clone().oneshot(chat("2")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()["x-protocol-version"], "2");
+    let session_id = resp.headers()["x-session-id"].to_str().unwrap().to_string();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let frames: Vec<serde_json::Value> = std::str::from_utf8(&body)
+        .unwrap()
+        .lines()
+        .filter_map(|line| line.strip_prefix("data:"))
+        .map(|data| serde_json::from_str(data).unwrap())
+        .collect();
+    assert_eq!(frames[0], json!({"type": "session", "session_id": session_id}));
+    let text: String = frames
+        .iter()
+        .filter(|f| f["type"] == "token")
+        .map(|f| f["text"].as_str().unwrap())
+        .collect();
+    assert!(text.contains("Hello"), "{}", text);
+    let [usage, done] = &frames[frames.len() - 2..] else {
+        unreachable!()
+    };
+    assert_eq!(usage["type"], "usage");
+    assert!(usage["completion_tokens"].as_u64().unwrap() > 0);
+    assert_eq!(*done, json!({"type": "done", "finish_reason": "stop"}));
+    state.delete_session(&session_id).await;
+
+    // Newer versions than the server knows get the newest it has; nonsense is refused
+    let resp = app.clone().oneshot(chat("9")).await.unwrap();
+    assert_eq!(resp.headers()["x-protocol-version"], "2");
+    let session_id = resp.headers()["x-session-id"].to_str().unwrap().to_string();
+    state.delete_session(&session_id).await;
+    let resp = app.oneshot(chat("zero")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let error: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(error["code"], "unsupported_protocol_version");
+}
+
+#[tokio::test]
+async fn test_chat_with_caller_managed_messages() {
+    use llm_inference::engine::{EngineError, InferenceEngine, TokenStream};
+    use std::sync::Mutex;
+
+    // Records the conversation each generation was given
+    struct RecordingEngine(Arc<Mutex<Vec<Option<Vec<ChatMessage>>>>>);
+
+    #[async_trait::async_trait]
+    impl InferenceEngine for RecordingEngine {
+        async fn get_available_models(&self) -> Vec<String> {
+            vec!["qwen".to_string()]
+        }
+
+        async fn run_streaming_inference(
+            &self,
+            request: InferenceRequest,
+        ) -> Result<TokenStream, EngineError> {
+            self.0.lock().unwrap().push(request.messages);
+            Ok(Box::pin(futures_util::stream::iter([Ok("ok".into())])))
+        }
+    }
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let state = AppState::new_in_memory(
+        Arc::new(RecordingEngine(seen.clone())),
+        handle,
+        Config::default(),
+    )
+    .await
+    .unwrap();
+    let app = routes::router().with_state(state.clone());
+    let session_id = format!("caller-history-{}", uuid::Uuid::new_v4());
+    let chat = |body: serde_json::Value| {
+        Request::builder()
+            .method("POST")
+            .uri("/chat/completions")
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+
+    let messages = json!([
+        {"role": "system", "content": "Be brief."},
+        {"role": "user", "content": "Hi"},
+        {"role": "assistant", "content": "Hello!"},
+        {"role": "user", "content": "How are you?"}
+    ]);
+    let resp = app
+        .clone()
+        .oneshot(chat(json!({
+            "model-name": "qwen",
+
+            "messages": messages,
+            "session-id": session_id,
+        })))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert!(std::str::from_utf8(&body).unwrap().contains("data:ok"));
+
+    // The conversation reaches the model as sent and no session is created
+    let sent = seen.lock().unwrap()[0].clone().unwrap();
+    assert_eq!(sent.len(), 4);
+    assert_eq!(sent[3].content, "How are you?");
+    assert!(!state.sessions.lock().await.contains_key(&session_id));
+
+    for bad in [
+        json!([]),
+        json!([{"role": "tool", "content": "{}"}, {"role": "user", "content": "Hi"}]),
+    ] {
+        let resp = app
+            .clone()
+            .oneshot(chat(json!({"model-name": "qwen",  "messages": bad})))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+    assert_eq!(seen.lock().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_message_feedback() {
+    let state = setup_test_state().await;
+    let app = routes::router().with_state(state.clone());
+    let session_id = format!("feedback-{}", uuid::Uuid::new_v4());
+    state.sessions.lock().await.insert(
+        session_id.clone(),
+        vec![
+            ChatMessage {
+                role: "user".to_string(),
+                content: "Hi".to_string(),
+                truncated: false,
+                partial: false,
+                timestamp: None,
+                continues: false,
+            },
+            ChatMessage {
+                role: "assistant".to_string(),
+                content: "Hello!".to_string(),
+                truncated: false,
+                partial: false,
+                timestamp: None,
+                continues: false,
+            },
+        ],
+    );
+    let rate = |index: usize, body: serde_json::Value| {
+        Request::builder()
+            .method("POST")
+            .uri(format!(
+                "/chat/history/{}/messages/{}/feedback",
+                session_id, index
+            ))
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+
+    let resp = app
+        .clone()
+        .oneshot(rate(1, json!({"rating": "down"})))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    // Rating again replaces the earlier feedback
+    let resp = app
+        .clone()
+        .oneshot(rate(1, json!({"rating": "up", "comment": "Friendly"})))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+
+    // Only existing assistant replies can be rated
+    let resp = app
+        .clone()
+        .oneshot(rate(0, json!({"rating": "up"})))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let resp = app
+        .clone()
+        .oneshot(rate(2, json!({"rating": "up"})))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    let export = |query: &str| {
+        Request::builder()
+            .uri(format!("/feedback/export{}", query))
+            .body(Body::empty())
+            .unwrap()
+    };
+    let resp = app.clone().oneshot(export("?rating=up")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let lines: Vec<serde_json::Value> = std::str::from_utf8(&body)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .filter(|entry: &serde_json::Value| entry["session_id"] == session_id.as_str())
+        .collect();
+    assert_eq!(lines.len(), 1);
+    assert_eq!(lines[0]["comment"], "Friendly");
+    assert_eq!(lines[0]["messages"][1]["content"], "Hello!");
+
+    // Deleting the session deletes its feedback
+    state.delete_session(&session_id).await;
+    let resp = app.oneshot(export("")).await.unwrap();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert!(!std::str::from_utf8(&body).unwrap().contains(&session_id));
+}
+
+#[tokio::test]
+async fn test_prompt_templates() {
+    let state = setup_test_state().await;
+    let app = routes::router().with_state(state.clone());
+    let name = format!("summarize-{}", uuid::Uuid::new_v4());
+    let send = |method: &str, uri: String, body: serde_json::Value| {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+
+    let resp = app
+        .clone()
+        .oneshot(send(
+            "POST",
+            "/templates".to_string(),
+            json!({"name": name, "template": "Summarize for {{audience}}: {{ text }}"}),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let created: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(created["variables"], json!(["audience", "text"]));
+
+    // Creating it again conflicts; malformed templates are rejected
+    let resp = app
+        .clone()
+        .oneshot(send(
+            "POST",
+            "/templates".to_string(),
+            json!({"name": name, "template": "x"}),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+    let resp = app
+        .clone()
+        .oneshot(send(
+            "PUT",
+            format!("/templates/{}", name),
+            json!({"template": "{{unclosed"}),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let completion = |variables: serde_json::Value| {
+        send(
+            "POST",
+            "/completions".to_string(),
+            json!({"model": "mock-model", "template": name, "variables": variables}),
+        )
+    };
+    let resp = app
+        .clone()
+        .oneshot(completion(json!({"audience": "kids", "text": "Rust"})))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert!(json["text"]
+        .as_str()
+        .unwrap()
+        .contains("Summarize for kids: Rust"));
+
+    let resp = app
+        .clone()
+        .oneshot(completion(json!({"audience": "kids"})))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert!(String::from_utf8_lossy(&body).contains("missing template variables: text"));
+
+    let resp = app
+        .clone()
+        .oneshot(send("DELETE", format!("/templates/{}", name), json!(null)))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    let resp = app
+        .oneshot(completion(json!({"audience": "kids", "text": "Rust"})))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    assert!(!state.templates.contains_key(&name));
+}
+
+#[tokio::test]
+async fn test_experiment_routes_traffic_to_variant() {
+    let mut config = Config::default();
+    config.experiments.push(config::ExperimentConfig {
+        name: "phi-vs-qwen".to_string(),
+        model: "qwen".to_string(),
+        enabled: true,
+        variants: vec![config::ExperimentVariant {
+            name: "phi".to_string(),
+            weight: 100,
+            model: Some("phi".to_string()),
+            system_prompt: None,
+        }],
+    });
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let state = AppState::new_in_memory(Arc::new(MockEngine::new()), handle, config)
+        .await
+        .unwrap();
+    let app = routes::router().with_state(state);
+
+    let completion = |model: &str| {
+        Request::builder()
+            .method("POST")
+            .uri("/completions")
+            .header("content-type", "application/json")
+            .body(Body::from(
+                json!({"model": model, "prompt": "Hi", "max_tokens": 5}).to_string(),
+            ))
+            .unwrap()
+    };
+
+    let resp = app.clone().oneshot(completion("qwen")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()["x-experiment"], "phi-vs-qwen");
+    assert_eq!(resp.headers()["x-experiment-variant"], "phi");
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["model"], "phi");
+
+    // Other models are not part of the experiment
+    let resp = app.oneshot(completion("phi")).await.unwrap();
+    assert!(resp.headers().get("x-experiment").is_none());
+}
+
+#[tokio::test]
+async fn test_shadow_traffic_is_mirrored_and_discarded() {
+    use llm_inference::engine::{EngineError, InferenceEngine, TokenStream};
+    use std::sync::Mutex;
+
+    // Records which models were asked to generate
+    struct RecordingEngine(Arc<Mutex<Vec<String>>>);
+
+    #[async_trait::async_trait]
+    impl InferenceEngine for RecordingEngine {
+        async fn get_available_models(&self) -> Vec<String> {
+            vec!["qwen".to_string(), "phi".to_string()]
+        }
+
+        async fn run_streaming_inference(
+            &self,
+            request: InferenceRequest,
+        ) -> Result<TokenStream, EngineError> {
+            self.0.lock().unwrap().push(request.model_name.clone());
+            let answer = format!("answer from {}", request.model_name);
+            Ok(Box::pin(futures_util::stream::iter([Ok(answer.into())])))
+        }
+    }
+
+    let mut config = Config::default();
+    config.shadow.model = Some("phi".to_string());
+    config.shadow.fraction = 1.0;
+    let models = Arc::new(Mutex::new(Vec::new()));
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let state = AppState::new_in_memory(Arc::new(RecordingEngine(models.clone())), handle, config)
+        .await
+        .unwrap();
+    let app = routes::router().with_state(state.clone());
+
+    let req = Request::builder()
+        .method("POST")
+        .uri("/completions")
+        .header("content-type", "application/json")
+        .body(Body::from(
+            json!({"model": "qwen", "prompt": "Hi", "max_tokens": 5}).to_string(),
+        ))
+        .unwrap();
+    let resp = app.oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["text"], "answer from qwen");
+
+    // The shadow generation runs in the background and releases its slot when done
+    let max = state.config().models.max_concurrent_requests;
+    for _ in 0..100 {
+        if models.lock().unwrap().len() == 2 && state.concurrency_limiter.available() == max {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    let mut seen = models.lock().unwrap().clone();
+    seen.sort();
+    assert_eq!(seen, vec!["phi", "qwen"]);
+    assert_eq!(state.concurrency_limiter.available(), max);
+}
+
+#[tokio::test]
+async fn test_prompt_length_validation() {
+    let mut config = Config::default();
+    config.limits.max_prompt_length = 10;
+
+    let builder = PrometheusBuilder::new();
+    let recorder = builder.build_recorder();
+    let handle = recorder.handle();
+    let engine = Arc::new(MockEngine::new());
+    let state = AppState::new_in_memory(engine, handle, config)
+        .await
+        .unwrap();
+    let app = routes::router().with_state(state);
+
+    let payload = json!({
+        "model": "mock-model",
+        "prompt": "This is a very long prompt that exceeds the limit",
+        "max_tokens": 50,
+        "stream": false
+    });
+
+    let req = Request::builder()
+        .method("POST")
+        .uri("/completions")
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+        .unwrap();
+
+    let resp = app.oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_prompt_length_counts_characters() {
+    let mut config = Config::default();
+    config.limits.max_prompt_length = 4;
+
+    let builder = PrometheusBuilder::new();
+    let recorder = builder.build_recorder();
+    let handle = recorder.handle();
+    let engine = Arc::new(MockEngine::new());
+    let state = AppState::new_in_memory(engine, handle, config)
+        .await
+        .unwrap();
+    let app = routes::router().with_state(state);
+
+    // 12 and 16 bytes respectively, but within the 4 character limit
+    for (prompt, expected) in [
+        ("你好世界", StatusCode::OK),
+        ("🦀🦀🦀🦀", StatusCode::OK),
+        ("你好世界!", StatusCode::BAD_REQUEST),
+        ("🦀🦀🦀🦀🦀", StatusCode::BAD_REQUEST),
+    ] {
+        let payload = json!({
+            "model": "mock-model",
+            "prompt": prompt,
+            "max_tokens": 5,
+            "stream": false
+        });
+        let req = Request::builder()
+            .method("POST")
+            .uri("/completions")
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+            .unwrap();
+        let resp = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), expected, "prompt {:?}", prompt);
+    }
+}
+
+#[test]
+fn test_prune_history_counts_characters() {
+    let chat = config::ChatConfig {
+        max_history_tokens: Some(3),
+        ..Default::default()
+    };
+    let message = |role: &str, content: &str| ChatMessage {
+        role: role.to_string(),
+        content: content.to_string(),
+        truncated: false,
+        partial: false,
+        timestamp: None,
+        continues: false,
+    };
+    // Four characters each (one estimated token), although far more bytes
+    let mut history = vec![
+        message("system", "简短回答"),
+        message("user", "🙂🙂🙂🙂"),
+        message("assistant", "你好你好"),
+        message("user", "🦀🦀🦀🦀"),
+    ];
+    routes::prune_history(&mut history, &chat);
+    let contents: Vec<_> = history.iter().map(|m| m.content.as_str()).collect();
+    assert_eq!(contents, vec!["简短回答", "你好你好", "🦀🦀🦀🦀"]);
+}
+
+#[tokio::test]
+async fn test_per_key_concurrency_limit() {
+    let mut config = Config::default();
+    config.limits.max_concurrent_per_key = Some(1);
+
+    let builder = PrometheusBuilder::new();
+    let recorder = builder.build_recorder();
+    let handle = recorder.handle();
+    let engine = Arc::new(MockEngine::new());
+    let state = AppState::new_in_memory(engine, handle, config)
+        .await
+        .unwrap();
+
+    // Hold the only slot available to the anonymous key
+    let _held = state
+        .concurrency_limiter
+        .acquire("anon", Some(1), 0)
+        .await
+        .unwrap();
+    let app = routes::router().with_state(state);
+
+    let payload = json!({
+        "model": "mock-model",
+        "prompt": "Hello",
+        "max_tokens": 50,
+        "stream": false
+    });
+
+    let req = Request::builder()
+        .method("POST")
+        .uri("/completions")
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+        .unwrap();
+
+    let resp = app.oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+async fn setup_auth_state(keys: Vec<config::ApiKeyConfig>) -> AppState {
+    let mut config = Config::default();
+    config.security.enable_auth = true;
+    config.security.api_keys = keys;
+
+    let builder = PrometheusBuilder::new();
+    let recorder = builder.build_recorder();
+    let handle = recorder.handle();
+    let engine = Arc::new(MockEngine::new());
+    AppState::new_in_memory(engine, handle, config)
+        .await
+        .unwrap()
+}
+
+fn completion_request(key: &str) -> Request<Body> {
+    let payload = json!({
+        "model": "mock-model",
+        "prompt": "Hello",
+        "max_tokens": 50,
+        "stream": false
+    });
+
+    Request::builder()
+        .method("POST")
+        .uri("/completions")
+        .header("content-type", "application/json")
+        .header("authorization", format!("Bearer {}", key))
+        .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_expired_api_key_rejected() {
+    let state = setup_auth_state(vec![config::ApiKeyConfig {
+        key: "expired-test-key".to_string(),
+        name: "expired".to_string(),
+        enabled: true,
+        expires_at: Some(chrono::Utc::now() - chrono::Duration::seconds(60)),
+        ..Default::default()
+    }])
+    .await;
+    let app = routes::router().with_state(state);
+
+    let resp = app
+        .oneshot(completion_request("expired-test-key"))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["code"], "api_key_expired");
+}
+
+#[tokio::test]
+async fn test_api_key_default_model_and_parameter_caps() {
+    let state = setup_auth_state(vec![
+        config::ApiKeyConfig {
+            key: "capped-key".to_string(),
+            name: "capped".to_string(),
+            enabled: true,
+            default_model: Some("mock-model".to_string()),
+            max_temperature: Some(0.5),
+            max_tokens: Some(16),
+            ..Default::default()
+        },
+        config::ApiKeyConfig {
+            key: "plain-key".to_string(),
+            name: "plain".to_string(),
+            enabled: true,
+            ..Default::default()
+        },
+    ])
+    .await;
+    let app = routes::router().with_state(state);
+    let send = |key: &str, payload: serde_json::Value| {
+        Request::builder()
+            .method("POST")
+            .uri("/completions")
+            .header("content-type", "application/json")
+            .header("authorization", format!("Bearer {}", key))
+            .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+            .unwrap()
+    };
+    let payload = json!({"prompt": "Hello", "max_tokens": 100, "temperature": 1.5, "stream": false});
+
+    // The key names the model and lowers what the request asked for
+    let resp = app.clone().oneshot(send("capped-key", payload.clone())).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let params: serde_json::Value =
+        serde_json::from_str(resp.headers()["x-effective-params"].to_str().unwrap()).unwrap();
+    assert_eq!(params["model"], "mock-model");
+    assert_eq!(params["temperature"], 0.5);
+    assert_eq!(params["max_tokens"], 16);
+    let adjusted = params["adjusted"].as_array().unwrap();
+    assert!(adjusted.contains(&json!("temperature")));
+    assert!(adjusted.contains(&json!("max_tokens")));
+
+    // Without a default the model stays required
+    let resp = app.oneshot(send("plain-key", payload)).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let body: serde_json::Value =
+        serde_json::from_slice(&hyper::body::to_bytes(resp.into_body()).await.unwrap()).unwrap();
+    assert!(body["error"].as_str().unwrap().contains("model is required"));
+}
+
+#[tokio::test]
+async fn test_api_key_rotation() {
+    let state = setup_auth_state(vec![config::ApiKeyConfig {
+        key: "rotate-test-key".to_string(),
+        name: "rotating".to_string(),
+        enabled: true,
+        ..Default::default()
+    }])
+    .await;
+    let app = routes::router().with_state(state);
+
+    let req = Request::builder()
+        .method("POST")
+        .uri("/keys/rotate")
+        .header("authorization", "Bearer rotate-test-key")
+        .body(Body::empty())
+        .unwrap();
+    let resp = app.clone().oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let new_key = json["key"].as_str().unwrap().to_string();
+    assert!(!json["previous_key_expires_at"].is_null());
+
+    // Both keys work during the grace window
+    let resp = app.clone().oneshot(completion_request(&new_key)).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = app
+        .oneshot(completion_request("rotate-test-key"))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_completion_callback_requires_secret() {
+    let state = setup_test_state().await;
+    let app = routes::router().with_state(state);
+
+    let payload = json!({
+        "model": "mock-model",
+        "prompt": "Hello",
+        "callback_url": "http://127.0.0.1:9/hook"
+    });
+
+    let req = Request::builder()
+        .method("POST")
+        .uri("/completions")
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+        .unwrap();
+
+    let resp = app.oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_completion_callback_is_signed() {
+    use axum::http::HeaderMap;
+    use llm_inference::webhook::{WebhookSender, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+
+    // Local receiver that hands the first delivery back to the test
+    let (tx, rx) = tokio::sync::oneshot::channel::<(HeaderMap, hyper::body::Bytes)>();
+    let tx = Arc::new(std::sync::Mutex::new(Some(tx)));
+    let receiver = axum::Router::new().route(
+        "/hook",
+        axum::routing::post(move |headers: HeaderMap, body: hyper::body::Bytes| {
+            let tx = tx.clone();
+            async move {
+                if let Some(tx) = tx.lock().unwrap().take() {
+                    let _ = tx.send((headers, body));
+                }
+                StatusCode::OK
+            }
+        }),
+    );
+    let server =
+        axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(receiver.into_make_service());
+    let addr = server.local_addr();
+    tokio::spawn(server);
+
+    let mut config = Config::default();
+    config.webhooks.secret = Some("test-secret".to_string());
+    config.webhooks.allowed_hosts = vec!["127.0.0.1".to_string()];
+    let builder = PrometheusBuilder::new();
+    let recorder = builder.build_recorder();
+    let handle = recorder.handle();
+    let engine = Arc::new(MockEngine::new());
+    let state = AppState::new_in_memory(engine, handle, config)
+        .await
+        .unwrap();
+    let app = routes::router().with_state(state);
+
+    let payload = json!({
+        "model": "mock-model",
+        "prompt": "Hello",
+        "callback_url": format!("http://{}/hook", addr)
+    });
+    let req = Request::builder()
+        .method("POST")
+        .uri("/completions")
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+        .unwrap();
+
+    let resp = app.oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::ACCEPTED);
+
+    let (headers, body) = tokio::time::timeout(std::time::Duration::from_secs(5), rx)
+        .await
+        .expect("callback delivered")
+        .unwrap();
+    let timestamp: i64 = headers[TIMESTAMP_HEADER].to_str().unwrap().parse().unwrap();
+    let expected = format!(
+        "sha256={}",
+        WebhookSender::sign("test-secret", timestamp, &body)
+    );
+    assert_eq!(headers[SIGNATURE_HEADER].to_str().unwrap(), expected);
+
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["status"], "completed");
+    assert!(json["text"].as_str().unwrap().contains("Hello"));
+}
+
+#[tokio::test]
+async fn test_completion_job_streams_to_file() {
+    let dir = std::env::temp_dir().join(format!("llm_inference_job_outputs_{}", std::process::id()));
+    let mut config = Config::default();
+    config.jobs.output_dir = dir.clone();
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let engine = Arc::new(MockEngine::new());
+    let state = AppState::new_in_memory(engine, handle, config).await.unwrap();
+    let app = routes::router().with_state(state);
+
+    let payload = json!({"model": "mock-model", "prompt": "Hello", "output": {"type": "file"}});
+    let req = Request::builder()
+        .method("POST")
+        .uri("/completions")
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+        .unwrap();
+    let resp = app.clone().oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::ACCEPTED);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let accepted: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let status_url = accepted["status_url"].as_str().unwrap().to_string();
+
+    let get = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+    let mut job = serde_json::Value::Null;
+    for _ in 0..50 {
+        let resp = app.clone().oneshot(get(&status_url)).await.unwrap();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        job = serde_json::from_slice(&body).unwrap();
+        if job["status"] != "running" {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(job["status"], "completed");
+    assert_eq!(job["output_bytes"], "hello Hello\ndone".len());
+    let download_url = job["download_url"].as_str().unwrap();
+    assert_eq!(download_url, format!("{}/output", status_url));
+
+    let resp = app.clone().oneshot(get(download_url)).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert_eq!(&body[..], b"hello Hello\ndone");
+
+    let resp = app.oneshot(get("/jobs/unknown")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_engine_panic_is_reported_once() {
+    use llm_inference::engine::{EngineError, InferenceEngine, TokenStream};
+
+    struct PanickingEngine;
+
+    #[async_trait::async_trait]
+    impl InferenceEngine for PanickingEngine {
+        async fn get_available_models(&self) -> Vec<String> {
+            vec!["mock-model".to_string()]
+        }
+
+        async fn run_streaming_inference(
+            &self,
+            _request: InferenceRequest,
+        ) -> Result<TokenStream, EngineError> {
+            panic!("CUDA out of memory");
+        }
+    }
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<serde_json::Value>();
+    let receiver = axum::Router::new().route(
+        "/errors",
+        axum::routing::post(move |axum::Json(report): axum::Json<serde_json::Value>| {
+            let tx = tx.clone();
+            async move {
+                let _ = tx.send(report);
+                StatusCode::OK
+            }
+        }),
+    );
+    let server =
+        axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(receiver.into_make_service());
+    let addr = server.local_addr();
+    tokio::spawn(server);
+
+    let mut config = Config::default();
+    config.error_reporting.webhook_url = Some(format!("http://{}/errors", addr));
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let state = AppState::new_in_memory(Arc::new(PanickingEngine), handle, config)
+        .await
+        .unwrap();
+    let app = routes::router().with_state(state);
+
+    let payload = json!({
+        "model": "mock-model",
+        "prompt": "Hello",
+        "stream": false
+    });
+    let req = Request::builder()
+        .method("POST")
+        .uri("/completions")
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+        .unwrap();
+    let resp = app.oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+    let report = tokio::time::timeout(std::time::Duration::from_secs(5), rx.recv())
+        .await
+        .expect("error report delivered")
+        .unwrap();
+    assert_eq!(report["kind"], "panic");
+    assert_eq!(report["message"], "CUDA out of memory");
+    assert_eq!(report["context"]["model"], "mock-model");
+
+    // The 500 caused by the panic is not reported a second time
+    let second = tokio::time::timeout(std::time::Duration::from_millis(300), rx.recv()).await;
+    assert!(second.is_err());
+}
+
+#[tokio::test]
+async fn test_generation_time_limit() {
+    use llm_inference::engine::{EngineError, InferenceEngine, Token, TokenStream};
+
+    // Never reaches a stop condition on its own
+    struct LoopingEngine;
+
+    #[async_trait::async_trait]
+    impl InferenceEngine for LoopingEngine {
+        async fn get_available_models(&self) -> Vec<String> {
+            vec!["mock-model".to_string()]
+        }
+
+        async fn run_streaming_inference(
+            &self,
+            _request: InferenceRequest,
+        ) -> Result<TokenStream, EngineError> {
+            Ok(Box::pin(async_stream::stream! {
+                loop {
+                    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                    yield Ok(Token::from("again "));
+                }
+            }))
+        }
+    }
+
+    let mut config = Config::default();
+    config.limits.max_generation_seconds = Some(1);
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let state = AppState::new_in_memory(Arc::new(LoopingEngine), handle, config)
+        .await
+        .unwrap();
+    let app = routes::router().with_state(state);
+
+    let started = std::time::Instant::now();
+    let resp = app
+        .clone()
+        .oneshot(completion_request("anon"))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["finish_reason"], "time_limit");
+    assert!(json["tokens"].as_u64().unwrap() > 0);
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+
+    // Streams end with a named finish event instead of an error
+    let payload = json!({
+        "model": "mock-model",
+        "prompt": "Hello",
+        "stream": true
+    });
+    let req = Request::builder()
+        .method("POST")
+        .uri("/completions")
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+        .unwrap();
+    let resp = app.oneshot(req).await.unwrap();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.contains("data:again"));
+    assert!(body.contains("event:finish\ndata:{\"finish_reason\":\"time_limit\"}"));
+    assert!(!body.contains("__ERROR__"));
+}
+
+#[tokio::test]
+async fn test_identical_requests_share_one_generation() {
+    use llm_inference::engine::{EngineError, InferenceEngine, Token, TokenStream};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Slow enough that both requests overlap; counts how often it is started
+    struct CountingEngine(Arc<AtomicUsize>);
+
+    #[async_trait::async_trait]
+    impl InferenceEngine for CountingEngine {
+        async fn get_available_models(&self) -> Vec<String> {
+            vec!["mock-model".to_string()]
+        }
+
+        async fn run_streaming_inference(
+            &self,
+            _request: InferenceRequest,
+        ) -> Result<TokenStream, EngineError> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(Box::pin(async_stream::stream! {
+                for word in ["one ", "two ", "three"] {
+                    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                    yield Ok(Token::from(word));
+                }
+            }))
+        }
+    }
+
+    let starts = Arc::new(AtomicUsize::new(0));
+    let mut config = Config::default();
+    config.cache.share_in_flight = true;
+    config.security.enable_auth = true;
+    config.security.api_keys = ["alice", "bob"]
+        .map(|name| config::ApiKeyConfig {
+            key: format!("sk-{}-00000000001", name),
+            name: name.to_string(),
+            enabled: true,
+            ..Default::default()
+        })
+        .to_vec();
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let state = AppState::new_in_memory(Arc::new(CountingEngine(starts.clone())), handle, config)
+        .await
+        .unwrap();
+    let app = routes::router().with_state(state.clone());
+    let (alice, bob) = ("sk-alice-00000000001", "sk-bob-00000000001");
+
+    let first = tokio::spawn(app.clone().oneshot(completion_request(alice)));
+    tokio::time::sleep(std::time::Duration::from_millis(150)).await;
+    let second = tokio::spawn(app.clone().oneshot(completion_request(alice)));
+    // Another account never joins
+    let other = tokio::spawn(app.clone().oneshot(completion_request(bob)));
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+    // The shared generation keeps its slot after the request that started it goes away
+    first.abort();
+    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    assert_eq!(state.concurrency_limiter.in_flight(), 2);
+
+    for resp in [second.await.unwrap().unwrap(), other.await.unwrap().unwrap()] {
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["text"], "one two three");
+    }
+    assert_eq!(starts.load(Ordering::SeqCst), 2);
+    assert_eq!(state.concurrency_limiter.in_flight(), 0);
+    assert!(state.in_flight.is_empty());
+
+    // Once finished, the same request runs again
+    let resp = app.oneshot(completion_request(alice)).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(starts.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn test_config_reload_applies_limits_live() {
+    let state = setup_test_state().await;
+    let app = routes::router().with_state(state.clone());
+
+    let mut new_config = (*state.config()).clone();
+    new_config.limits.max_prompt_length = 3;
+    let changed = state.apply_config(new_config).await.unwrap();
+    assert_eq!(changed, vec!["limits.max_prompt_length"]);
+
+    // The running router picks up the new limit without a restart
+    let resp = app.oneshot(completion_request("anon")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let mut restart_needed = (*state.config()).clone();
+    restart_needed.server.port = 4242;
+    let err = state.apply_config(restart_needed).await.unwrap_err();
+    assert!(matches!(
+        err,
+        llm_inference::state::ConfigReloadError::RestartRequired(ref fields) if fields == &["server.port"]
+    ));
+    assert_eq!(state.config().server.port, 3000);
+}
+
+#[tokio::test]
+async fn test_admin_config_is_redacted() {
+    let state = setup_auth_state(vec![
+        config::ApiKeyConfig {
+            key: "sk-admin-0000000001".to_string(),
+            name: "admin".to_string(),
+            enabled: true,
+            admin: true,
+            ..Default::default()
+        },
+        config::ApiKeyConfig {
+            key: "sk-user-00000000002".to_string(),
+            name: "user".to_string(),
+            enabled: true,
+            ..Default::default()
+        },
+    ])
+    .await;
+    let app = routes::router().with_state(state);
+
+    let request = |key: &str| {
+        Request::builder()
+            .method("GET")
+            .uri("/admin/config")
+            .header("authorization", format!("Bearer {}", key))
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let resp = app
+        .clone()
+        .oneshot(request("sk-user-00000000002"))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    let resp = app.oneshot(request("sk-admin-0000000001")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let text = String::from_utf8(body.to_vec()).unwrap();
+    assert!(!text.contains("sk-admin-0000000001"));
+    let json: serde_json::Value = serde_json::from_str(&text).unwrap();
+    assert_eq!(json["config"]["security"]["api_keys"][0]["key"], "****0001");
+    assert_eq!(json["sources"]["security.enable_auth"], "file");
+}
+
+#[tokio::test]
+async fn test_usage_is_attributed_per_key() {
+    let state = setup_auth_state(vec![
+        config::ApiKeyConfig {
+            key: "sk-admin-0000000001".to_string(),
+            name: "ops".to_string(),
+            enabled: true,
+            admin: true,
+            ..Default::default()
+        },
+        config::ApiKeyConfig {
+            key: "sk-user-00000000002".to_string(),
+            name: "research".to_string(),
+            enabled: true,
+            ..Default::default()
+        },
+    ])
+    .await;
+    let mut priced = (*state.config()).clone();
+    priced.pricing.default.completion_per_1k = 1000.0;
+    state.apply_config(priced).await.unwrap();
+    let app = routes::router().with_state(state);
+
+    for key in ["sk-admin-0000000001", "sk-user-00000000002"] {
+        let resp = app.clone().oneshot(completion_request(key)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    let usage = |key: &str| {
+        Request::builder()
+            .method("GET")
+            .uri("/usage")
+            .header("authorization", format!("Bearer {}", key))
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let resp = app
+        .clone()
+        .oneshot(usage("sk-user-00000000002"))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let accounts = json["accounts"].as_object().unwrap();
+    assert_eq!(accounts.len(), 1);
+    let research = &accounts["research"];
+    assert_eq!(research["requests"], 1);
+    // "Hello" is estimated at 2 prompt tokens
+    assert_eq!(research["prompt_tokens"], 2);
+    let completion_tokens = research["completion_tokens"].as_u64().unwrap();
+    assert!(completion_tokens > 0);
+    assert_eq!(research["estimated_cost"], completion_tokens as f64);
+    assert_eq!(json["currency"], "USD");
+
+    let resp = app.oneshot(usage("sk-admin-0000000001")).await.unwrap();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert!(json["accounts"]["ops"]["models"]["mock-model"].is_object());
+    assert!(json["accounts"]["research"].is_object());
+}
+
+#[test]
+fn test_configured_histogram_buckets() {
+    use metrics::Recorder;
+
+    let mut observability = Config::default().observability;
+    observability.metric_buckets.insert(
+        "completions_tokens_per_second".to_string(),
+        vec![10.0, 50.0],
+    );
+    let recorder = state::prometheus_builder(&observability)
+        .unwrap()
+        .build_recorder();
+    let handle = recorder.handle();
+
+    let ttft = metrics::Key::from_name("time_to_first_token_seconds");
+    recorder.register_histogram(&ttft).record(0.3);
+    let tps = metrics::Key::from_name("completions_tokens_per_second");
+    recorder.register_histogram(&tps).record(20.0);
+    let other = metrics::Key::from_name("chat_tokens_per_second");
+    recorder.register_histogram(&other).record(20.0);
+
+    let rendered = handle.render();
+    assert!(rendered.contains("time_to_first_token_seconds_bucket{le=\"0.5\"} 1"));
+    assert!(rendered.contains("completions_tokens_per_second_bucket{le=\"50\"} 1"));
+    // Metrics without configured buckets stay summaries
+    assert!(rendered.contains("chat_tokens_per_second{quantile="));
+}
+
+#[tokio::test]
+async fn test_metrics_endpoint() {
+    let state = setup_test_state().await;
+    let app = routes::router().with_state(state);
+
+    let req = Request::builder()
+        .method("GET")
+        .uri("/metrics")
+        .body(Body::empty())
+        .unwrap();
+
+    let resp = app.oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_metrics_token_replaces_api_key() {
+    let mut config = Config::default();
+    config.security.enable_auth = true;
+    config.security.api_keys = vec![config::ApiKeyConfig {
+        key: "sk-user-00000000002".to_string(),
+        name: "user".to_string(),
+        enabled: true,
+        ..Default::default()
+    }];
+    config.observability.metrics_token = Some("scrape-token".to_string());
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let state = AppState::new_in_memory(Arc::new(MockEngine::new()), handle, config)
+        .await
+        .unwrap();
+    let app = routes::router()
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            routes::rate_limit,
+        ))
+        .with_state(state);
+
+    let scrape = |auth: Option<&str>| {
+        let mut req = Request::builder().method("GET").uri("/metrics");
+        if let Some(auth) = auth {
+            req = req.header("authorization", format!("Bearer {}", auth));
+        }
+        req.body(Body::empty()).unwrap()
+    };
+
+    let resp = app.clone().oneshot(scrape(None)).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let resp = app
+        .clone()
+        .oneshot(scrape(Some("sk-user-00000000002")))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let resp = app.oneshot(scrape(Some("scrape-token"))).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_openapi_spec_and_swagger_ui_skip_auth() {
+    let mut config = Config::default();
+    config.security.enable_auth = true;
+    config.server.swagger_ui = true;
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let state = AppState::new_in_memory(Arc::new(MockEngine::new()), handle, config)
+        .await
+        .unwrap();
+    let app = routes::router()
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            routes::rate_limit,
+        ))
+        .with_state(state);
+    let get = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+
+    let resp = app.clone().oneshot(get("/openapi.json")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let spec: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert!(spec["openapi"].as_str().unwrap().starts_with("3."));
+    let chat = &spec["paths"]["/chat/completions"]["post"];
+    assert_eq!(
+        chat["requestBody"]["content"]["application/json"]["schema"]["$ref"],
+        "#/components/schemas/InferenceRequest"
+    );
+    assert!(spec["paths"]["/models/{model_id}"]["get"].is_object());
+    assert_eq!(spec["components"]["securitySchemes"]["api_key"]["scheme"], "bearer");
+
+    let resp = app.clone().oneshot(get("/docs")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert!(String::from_utf8_lossy(&body).contains("/openapi.json"));
+
+    // Everything else still needs a key
+    let resp = app.oneshot(get("/models")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_swagger_ui_is_off_by_default() {
+    let app = routes::router().with_state(setup_test_state().await);
+    let resp = app
+        .oneshot(Request::builder().uri("/docs").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_debug_trace_header_is_admin_only() {
+    let mut config = Config::default();
+    config.security.enable_auth = true;
+    config.security.api_keys = vec![
+        config::ApiKeyConfig {
+            key: "sk-admin-0000000001".to_string(),
+            name: "admin".to_string(),
+            enabled: true,
+            admin: true,
+            ..Default::default()
+        },
+        config::ApiKeyConfig {
+            key: "sk-user-00000000002".to_string(),
+            name: "user".to_string(),
+            enabled: true,
+            ..Default::default()
+        },
+    ];
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let state = AppState::new_in_memory(Arc::new(MockEngine::new()), handle, config)
+        .await
+        .unwrap();
+    let app = routes::router()
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            routes::debug_trace,
+        ))
+        .with_state(state);
+
+    let completion = |key: &str, traced: bool| {
+        let mut req = Request::builder()
+            .method("POST")
+            .uri("/completions")
+            .header("content-type", "application/json")
+            .header("authorization", format!("Bearer {}", key));
+        if traced {
+            req = req.header("x-debug-trace", "true");
+        }
+        let payload = json!({"model": "mock-model", "prompt": "Hello"});
+        req.body(Body::from(serde_json::to_vec(&payload).unwrap())).unwrap()
+    };
+
+    let resp = app.clone().oneshot(completion("sk-admin-0000000001", true)).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let trace_id = resp.headers()["x-trace-id"].to_str().unwrap().to_string();
+    assert_eq!(trace_id.len(), 36);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert!(String::from_utf8_lossy(&body).contains("Hello"));
+
+    let resp = app.clone().oneshot(completion("sk-user-00000000002", true)).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    let resp = app.oneshot(completion("sk-user-00000000002", false)).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp.headers().get("x-trace-id").is_none());
+}
+
+#[tokio::test]
+async fn test_metrics_path_follows_config() {
+    let mut config = Config::default();
+    config.observability.metrics_path = "/internal/prometheus".to_string();
+    let observability = config.observability.clone();
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let state = AppState::new_in_memory(Arc::new(MockEngine::new()), handle, config)
+        .await
+        .unwrap();
+    let get = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+
+    let app = routes::router_for(&observability).with_state(state.clone());
+    let resp = app.clone().oneshot(get("/internal/prometheus")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = app.oneshot(get("/metrics")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    // With a metrics port the API port leaves the endpoint to the metrics router
+    let observability = config::ObservabilityConfig {
+        metrics_port: Some(9100),
+        ..observability
+    };
+    let app = routes::router_for(&observability).with_state(state.clone());
+    let resp = app.oneshot(get("/internal/prometheus")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let metrics = routes::metrics_router(&observability.metrics_path).with_state(state);
+    let resp = metrics.oneshot(get("/internal/prometheus")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_metrics_are_pushed() {
+    use metrics::Recorder;
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<String>();
+    let receiver = axum::Router::new().route(
+        "/metrics/job/llm",
+        axum::routing::put(move |body: String| {
+            let tx = tx.clone();
+            async move {
+                let _ = tx.send(body);
+                StatusCode::OK
+            }
+        }),
+    );
+    let server =
+        axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(receiver.into_make_service());
+    let addr = server.local_addr();
+    tokio::spawn(server);
+
+    let recorder = PrometheusBuilder::new().build_recorder();
+    recorder
+        .register_counter(&metrics::Key::from_name("pushed_total"))
+        .increment(3);
+    let pusher = llm_inference::metrics_push::spawn_pusher(
+        recorder.handle(),
+        format!("http://{}/metrics/job/llm", addr),
+        std::time::Duration::from_millis(50),
+    );
+
+    let body = tokio::time::timeout(std::time::Duration::from_secs(5), rx.recv())
+        .await
+        .expect("metrics pushed")
+        .unwrap();
+    pusher.abort();
+    assert!(body.contains("pushed_total 3"));
+}
+
+#[tokio::test]
+async fn test_effective_params_header() {
+    use llm_inference::engine::{EngineError, InferenceEngine, TokenStream};
+
+    // Loads every model on the CPU, whatever device was asked for
+    struct CpuOnlyEngine;
+
+    #[async_trait::async_trait]
+    impl InferenceEngine for CpuOnlyEngine {
+        async fn get_available_models(&self) -> Vec<String> {
+            vec!["qwen".to_string()]
+        }
+
+        async fn run_streaming_inference(
+            &self,
+            _request: InferenceRequest,
+        ) -> Result<TokenStream, EngineError> {
+            Ok(Box::pin(futures_util::stream::iter([Ok("ok".into())])))
+        }
+
+        async fn loaded_device(&self, _model: &str) -> Option<String> {
+            Some("cpu".to_string())
+        }
+    }
+
+    let mut config = Config::default();
+    config.limits.max_response_tokens = 64;
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let state = AppState::new_in_memory(Arc::new(CpuOnlyEngine), handle, config)
+        .await
+        .unwrap();
+    let app = routes::router().with_state(state);
+    let effective = |resp: &axum::response::Response| -> serde_json::Value {
+        let header = resp.headers()["x-effective-params"].to_str().unwrap();
+        serde_json::from_str(header).unwrap()
+    };
+
+    // Over-long max_tokens, a top_p the engine ignores, a priority above the key's cap and the
+    // default `cuda` device, which this engine cannot provide
+    let payload = json!({
+        "model": "qwen",
+        "prompt": "hi",
+        "max_tokens": 1000,
+        "top_p": 1.5,
+        "priority": 5
+    });
+    let req = Request::builder()
+        .method("POST")
+        .uri("/completions")
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+        .unwrap();
+    let resp = app.clone().oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let params = effective(&resp);
+    assert_eq!(params["max_tokens"], 64);
+    assert_eq!(params["top_p"], 1.0);
+    assert_eq!(params["priority"], 0);
+    assert_eq!(params["device"], "cpu");
+    assert_eq!(
+        params["adjusted"],
+        json!(["max_tokens", "top_p", "priority", "device"])
+    );
+
+    // Chat falls back from the requested device; untouched parameters are echoed as sent
+    let payload = json!({
+        "model-name": "qwen",
+        "prompt": "hi",
+        "device": "CUDA",
+        "temperature": 0.2,
+        "max-token": 32
+    });
+    let req = Request::builder()
+        .method("POST")
+        .uri("/chat/completions")
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+        .unwrap();
+    let resp = app.oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let params = effective(&resp);
+    assert_eq!(params["temperature"], 0.2);
+    assert_eq!(params["max_tokens"], 32);
+    assert_eq!(params["device"], "cpu");
+    assert_eq!(params["adjusted"], json!(["device"]));
+}
+
+#[tokio::test]
+async fn test_device_fallback_is_reported() {
+    use llm_inference::engine::{EngineError, InferenceEngine, TokenStream};
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Loads every model on the CPU, whatever device was asked for, and reports that copy before
+    // generating. It never reports a loaded device, as before a model's first load.
+    #[derive(Default)]
+    struct CpuOnlyEngine {
+        generations: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl InferenceEngine for CpuOnlyEngine {
+        async fn get_available_models(&self) -> Vec<String> {
+            vec!["qwen".to_string()]
+        }
+
+        async fn run_streaming_inference(
+            &self,
+            request: InferenceRequest,
+        ) -> Result<TokenStream, EngineError> {
+            if let Some(placement) = &request.placement {
+                placement.check("cpu")?;
+            }
+            self.generations.fetch_add(1, Ordering::SeqCst);
+            Ok(Box::pin(futures_util::stream::iter([Ok("ok".into())])))
+        }
+    }
+
+    let engine = Arc::new(CpuOnlyEngine::default());
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let state = AppState::new_in_memory(engine.clone(), handle, Config::default())
+        .await
+        .unwrap();
+    let app = routes::router().with_state(state);
+    let post = |uri: &str, payload: serde_json::Value| {
+        Request::builder()
+            .method("POST")
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+            .unwrap()
+    };
+
+    // The default device is cuda; the answer still comes, with a warning
+    let payload = json!({"model": "qwen", "prompt": "hi"});
+    let resp = app
+        .clone()
+        .oneshot(post("/completions", payload))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["text"], "ok");
+    assert_eq!(body["warnings"][0]["code"], "device_fallback");
+    assert_eq!(body["warnings"][0]["requested"], "cuda");
+    assert_eq!(body["warnings"][0]["device"], "cpu");
+
+    // Strict requests are refused instead, before anything is generated
+    let generations = engine.generations.load(Ordering::SeqCst);
+    let payload = json!({"model": "qwen", "prompt": "hi", "strict_device": true});
+    let resp = app
+        .clone()
+        .oneshot(post("/completions", payload))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["code"], "device_unavailable");
+    assert_eq!(body["device"], "cpu");
+    assert_eq!(engine.generations.load(Ordering::SeqCst), generations);
+
+    // Chat streams carry the warning as an SSE event before the text
+    let payload = json!({"model-name": "qwen", "prompt": "hi", "device": "metal"});
+    let resp = app
+        .clone()
+        .oneshot(post("/chat/completions", payload))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    let warning = body.find("event:warning").expect("warning event");
+    assert!(warning < body.find("data:ok").unwrap());
+    assert!(body.contains(r#""requested":"metal""#));
+
+    let payload =
+        json!({"model-name": "qwen", "prompt": "hi", "device": "metal", "strict-device": true});
+    let resp = app
+        .clone()
+        .oneshot(post("/chat/completions", payload))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    // Wherever an `auto` model was placed is what the request asked for, strict or not
+    let payload =
+        json!({"model-name": "qwen", "prompt": "hi", "device": "auto", "strict-device": true});
+    let resp = app
+        .clone()
+        .oneshot(post("/chat/completions", payload))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(!body.contains("event:warning"));
+    assert!(body.contains("data:ok"));
+
+    // Unknown devices are rejected up front
+    let payload = json!({"model-name": "qwen", "prompt": "hi", "device": "tpu"});
+    let resp = app
+        .oneshot(post("/chat/completions", payload))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_standby_copy_on_another_ordinal_is_not_a_fallback() {
+    use llm_inference::engine::{EngineError, InferenceEngine, TokenStream};
+
+    // Leases the standby copy on the second GPU for every request
+    struct StandbyEngine;
+
+    #[async_trait::async_trait]
+    impl InferenceEngine for StandbyEngine {
+        async fn get_available_models(&self) -> Vec<String> {
+            vec!["qwen".to_string()]
+        }
+
+        async fn run_streaming_inference(
+            &self,
+            request: InferenceRequest,
+        ) -> Result<TokenStream, EngineError> {
+            if let Some(placement) = &request.placement {
+                placement.check("cuda:1")?;
+            }
+            Ok(Box::pin(futures_util::stream::iter([Ok("ok".into())])))
+        }
+    }
+
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let state = AppState::new_in_memory(Arc::new(StandbyEngine), handle, Config::default())
+        .await
+        .unwrap();
+    let app = routes::router().with_state(state);
+
+    // `cuda` names the kind of device; the copy on cuda:1 is one
+    let payload = json!({"model": "qwen", "prompt": "hi", "device": "cuda", "strict_device": true});
+    let req = Request::post("/completions")
+        .header("content-type", "application/json")
+        .body(Body::from(payload.to_string()))
+        .unwrap();
+    let resp = app.oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let params: serde_json::Value =
+        serde_json::from_str(resp.headers()["x-effective-params"].to_str().unwrap()).unwrap();
+    assert_eq!(params["device"], "cuda:1");
+    assert_eq!(params["adjusted"], json!([]));
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["text"], "ok");
+    assert!(body.get("warnings").is_none());
+}
+
+#[tokio::test]
+async fn test_scripted_engine_failures() {
+    let post = |payload: serde_json::Value| {
+        Request::builder()
+            .method("POST")
+            .uri("/completions")
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+            .unwrap()
+    };
+    let app_with = |engine: MockEngine| async move {
+        let handle = PrometheusBuilder::new().build_recorder().handle();
+        let state = AppState::new_in_memory(Arc::new(engine), handle, Config::default())
+            .await
+            .unwrap();
+        routes::router().with_state(state)
+    };
+
+    // A mid-stream error fails a non-streaming completion
+    let engine = MockEngine::new()
+        .with_tokens(["one", " two", " three"])
+        .with_error_after(2, "out of memory");
+    let app = app_with(engine).await;
+    let payload = json!({"model": "mock-model", "prompt": "hi"});
+    let resp = app.clone().oneshot(post(payload)).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert!(String::from_utf8_lossy(&body).contains("out of memory"));
+
+    // ...while a stream delivers the tokens produced so far, then the error
+    let payload = json!({"model": "mock-model", "prompt": "hi", "stream": true});
+    let resp = app.oneshot(post(payload)).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.contains("data:one"));
+    assert!(body.contains("data:__ERROR__:out of memory"));
+    assert!(!body.contains("three"));
+
+    // A model that cannot be loaded fails before any token
+    let app = app_with(MockEngine::new().with_load_error("weights missing")).await;
+    let payload = json!({"model": "mock-model", "prompt": "hi", "stream": true});
+    let resp = app.oneshot(post(payload)).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+}
+
+#[tokio::test]
+async fn test_engine_errors_map_to_statuses() {
+    use llm_inference::engine::{EngineError, InferenceEngine, TokenStream};
+
+    // Fails each request with the error named by its prompt
+    struct FailingEngine;
+
+    #[async_trait::async_trait]
+    impl InferenceEngine for FailingEngine {
+        async fn get_available_models(&self) -> Vec<String> {
+            vec!["qwen".to_string()]
+        }
+
+        async fn run_streaming_inference(
+            &self,
+            request: InferenceRequest,
+        ) -> Result<TokenStream, EngineError> {
+            Err(match request.prompt.as_str() {
+                "missing" => EngineError::ModelNotFound(request.model_name),
+                "long" => EngineError::ContextOverflow("4096 tokens at most".to_string()),
+                "cancel" => EngineError::Cancelled,
+                "busy" => EngineError::Overloaded(7),
+                "slow" => EngineError::Timeout(30),
+                _ => EngineError::Cuda("device lost".to_string()),
+            })
+        }
+    }
+
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let state = AppState::new_in_memory(Arc::new(FailingEngine), handle, Config::default())
+        .await
+        .unwrap();
+    let app = routes::router().with_state(state);
+
+    for (prompt, status, code) in [
+        ("missing", StatusCode::NOT_FOUND, "model_not_found"),
+        ("long", StatusCode::BAD_REQUEST, "context_overflow"),
+        ("cancel", StatusCode::SERVICE_UNAVAILABLE, "cancelled"),
+        ("busy", StatusCode::SERVICE_UNAVAILABLE, "overloaded"),
+        ("slow", StatusCode::GATEWAY_TIMEOUT, "timeout"),
+        ("crash", StatusCode::INTERNAL_SERVER_ERROR, "backend_error"),
+    ] {
+        for (uri, payload) in [
+            ("/completions", json!({"model": "qwen", "prompt": prompt})),
+            (
+                "/chat/completions",
+                json!({"model-name": "qwen", "prompt": prompt}),
+            ),
+        ] {
+            let req = Request::builder()
+                .method("POST")
+                .uri(uri)
+                .header("content-type", "application/json")
+                .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+                .unwrap();
+            let resp = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(resp.status(), status, "{} {}", uri, prompt);
+            if code == "overloaded" {
+                assert_eq!(resp.headers()["retry-after"], "7");
+            }
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body["code"], code);
+        }
+    }
+}
+
+#[cfg(feature = "client")]
+#[tokio::test]
+async fn test_client_against_live_server() {
+    use futures_util::StreamExt;
+    use llm_inference::client::{Client, ClientError};
+
+    let state = setup_auth_state(vec![config::ApiKeyConfig {
+        key: "client-test-key".to_string(),
+        name: "client".to_string(),
+        enabled: true,
+        ..Default::default()
+    }])
+    .await;
+    let app = routes::router().with_state(state);
+    let server = axum::Server::bind(&std::net::SocketAddr::from(([127, 0, 0, 1], 0)))
+        .serve(app.into_make_service());
+    let url = format!("http://{}/", server.local_addr());
+    tokio::spawn(server);
+
+    let completion: CompletionRequest =
+        serde_json::from_value(json!({"model": "mock-model", "prompt": "there"})).unwrap();
+    let err = Client::new(&url).complete(&completion).await.unwrap_err();
+    match err {
+        ClientError::Api { status, code, .. } => {
+            assert_eq!(status, 401);
+            assert_eq!(code.as_deref(), Some("missing_api_key"));
+        }
+        other => panic!("unexpected error: {other}"),
+    }
+
+    let client = Client::new(&url).with_api_key("client-test-key");
+    assert_eq!(client.models().await.unwrap(), vec!["mock-model"]);
+
+    let mut request = Client::chat_request("mock-model", "hi");
+    request.create_session = true;
+    let stream = client.chat_stream(&request).await.unwrap();
+    let session_id = stream.session_id.clone().unwrap();
+    let tokens: Vec<String> = stream.map(|t| t.unwrap().into()).collect().await;
+    assert_eq!(tokens.concat(), "hello hi\ndone");
+
+    assert!(client.sessions().await.unwrap().contains(&session_id));
+    let history = client.history(&session_id).await.unwrap();
+    assert_eq!(history.last().unwrap().content, "hello hi\ndone");
+    client.delete_session(&session_id).await.unwrap();
+    assert!(client.history(&session_id).await.unwrap().is_empty());
+
+    let completion = client.complete(&completion).await.unwrap();
+    assert_eq!(completion.text, "hello there\ndone");
+    assert_eq!(completion.finish_reason, "stop");
+}
+
+#[tokio::test]
+async fn test_frontend_fallback_leaves_api_routes_alone() {
+    let state = setup_test_state().await;
+    let app = routes::router()
+        .with_state(state)
+        .fallback(llm_inference::frontend::serve);
+
+    let resp = app
+        .clone()
+        .oneshot(Request::get("/models").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // Not an API route and not a UI file, embedded or on disk
+    let resp = app
+        .oneshot(
+            Request::get("/assets/missing-file.js")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_federation_forwards_peer_models() {
+    let peer_engine = Arc::new(MockEngine::new().with_models(["peer-model"]));
+    let peer_state = AppState::new_in_memory(
+        peer_engine,
+        PrometheusBuilder::new().build_recorder().handle(),
+        Config::default(),
+    )
+    .await
+    .unwrap();
+    let peer_server = axum::Server::bind(&std::net::SocketAddr::from(([127, 0, 0, 1], 0)))
+        .serve(routes::router().with_state(peer_state).into_make_service());
+    let peer = config::PeerConfig {
+        url: format!("http://{}", peer_server.local_addr()),
+        api_key: None,
+    };
+    tokio::spawn(peer_server);
+
+    let state = setup_test_state().await;
+    state.federation.refresh(&[peer]).await;
+    let app = routes::router().with_state(state);
+
+    let resp = app
+        .clone()
+        .oneshot(Request::get("/models").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let list: ModelsList = serde_json::from_slice(&body).unwrap();
+    assert_eq!(list.models, vec!["mock-model", "peer-model"]);
+
+    let resp = app
+        .clone()
+        .oneshot(
+            Request::post("/completions")
+                .header("content-type", "application/json")
+                .body(Body::from(
+                    json!({"model": "peer-model", "prompt": "there"}).to_string(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["text"], "hello there\ndone");
+
+    // Peers get this server's own models and are not forwarded again
+    let resp = app
+        .clone()
+        .oneshot(
+            Request::get("/models")
+                .header("x-federation-forwarded", "1")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let list: ModelsList = serde_json::from_slice(&body).unwrap();
+    assert_eq!(list.models, vec!["mock-model"]);
+    let resp = app
+        .oneshot(
+            Request::post("/completions")
+                .header("content-type", "application/json")
+                .header("x-federation-forwarded", "1")
+                .body(Body::from(
+                    json!({"model": "peer-model", "prompt": "there"}).to_string(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::LOOP_DETECTED);
+}
+
+#[tokio::test]
+async fn test_compress_history_condenses_older_turns() {
+    let engine = Arc::new(MockEngine::new().with_tokens(["they", " talked"]));
+    let mut config = Config::default();
+    config.models.available_models[0].context_length = Some(300);
+    let model = config.models.available_models[0].name.clone();
+    let state = AppState::new_in_memory(
+        engine.clone(),
+        PrometheusBuilder::new().build_recorder().handle(),
+        config,
+    )
+    .await
+    .unwrap();
+
+    let turn = |role: &str, content: String| ChatMessage {
+        role: role.to_string(),
+        content,
+        truncated: false,
+        partial: false,
+        timestamp: None,
+        continues: false,
+    };
+    let history = vec![
+        turn("system", "Be brief.".to_string()),
+        turn("user", "a".repeat(400)),
+        turn("assistant", "b".repeat(400)),
+        turn("user", "c".repeat(400)),
+        turn("assistant", "d".repeat(400)),
+        turn("user", "and now?".to_string()),
+    ];
+    let mut req: InferenceRequest =
+        serde_json::from_value(json!({"model-name": model, "compress-history": true})).unwrap();
+    req.messages = Some(history.clone());
+
+    state.compress_history(&mut req).await;
+    let messages = req.messages.unwrap();
+    assert_eq!(messages.len(), 4);
+    assert_eq!(messages[0], history[0]);
+    assert_eq!(
+        messages[1].content,
+        "Summary of the earlier conversation: they talked"
+    );
+    assert_eq!(messages[2..], history[4..]);
+    assert_eq!(engine.tokens_generated(), 2);
+
+    // Short conversations are sent as they are
+    let mut req: InferenceRequest =
+        serde_json::from_value(json!({"model-name": model, "compress-history": true})).unwrap();
+    req.messages = Some(history[..2].to_vec());
+    state.compress_history(&mut req).await;
+    assert_eq!(req.messages.unwrap(), history[..2]);
+    assert_eq!(engine.tokens_generated(), 2);
+}
+
+#[tokio::test]
+async fn test_idle_sessions_are_compacted() {
+    let engine = Arc::new(MockEngine::new().with_tokens(["they", " talked"]));
+    let mut config = Config::default();
+    config.compaction.keep_messages = 2;
+    let state = AppState::new_in_memory(engine, PrometheusBuilder::new().build_recorder().handle(), config)
+        .await
+        .unwrap();
+
+    let at = |hours_ago: i64| Some(chrono::Utc::now() - chrono::Duration::hours(hours_ago));
+    let turn = |role: &str, content: &str, timestamp| ChatMessage {
+        role: role.to_string(),
+        content: content.to_string(),
+        truncated: false,
+        partial: false,
+        timestamp,
+        continues: false,
+    };
+    let history = |hours_ago| {
+        vec![
+            turn("system", "Be brief.", None),
+            turn("user", "My order is late.", at(hours_ago + 1)),
+            turn("assistant", "Which order?", at(hours_ago + 1)),
+            turn("user", "Number 42.", at(hours_ago)),
+            turn("assistant", "It ships tomorrow.", at(hours_ago)),
+        ]
+    };
+    let idle = history(48);
+    // Saved before messages were timestamped
+    let undated: Vec<ChatMessage> = idle.iter().map(|m| turn(&m.role, &m.content, None)).collect();
+    {
+        let mut sessions = state.sessions.lock().await;
+        sessions.insert("idle".to_string(), idle.clone());
+        sessions.insert("undated".to_string(), undated);
+        sessions.insert("active".to_string(), history(1));
+    }
+
+    assert_eq!(state.compact_idle_sessions().await, 2);
+    let sessions = state.sessions.lock().await;
+    assert_eq!(sessions["undated"].len(), 4);
+    let compacted = &sessions["idle"];
+    assert_eq!(compacted.len(), 4);
+    assert_eq!(compacted[0], idle[0]);
+    assert_eq!(compacted[1].content, "Summary of the earlier conversation: they talked");
+    assert_eq!(compacted[2..], idle[3..]);
+    assert_eq!(sessions["active"].len(), 5);
+    drop(sessions);
+
+    // Already compacted sessions are left alone until they grow
+    assert_eq!(state.compact_idle_sessions().await, 0);
+}
+
+#[tokio::test]
+async fn test_stream_transcripts_are_recorded() {
+    let mut config = Config::default();
+    config.streaming.record_transcripts = true;
+    config.security.enable_auth = true;
+    config.security.api_keys = vec![
+        config::ApiKeyConfig {
+            key: "user-key".to_string(),
+            name: "user".to_string(),
+            enabled: true,
+            ..Default::default()
+        },
+        config::ApiKeyConfig {
+            key: "admin-key".to_string(),
+            name: "ops".to_string(),
+            enabled: true,
+            admin: true,
+            ..Default::default()
+        },
+    ];
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let state = AppState::new_in_memory(Arc::new(MockEngine::new()), handle, config)
+        .await
+        .unwrap();
+    let app = routes::router().with_state(state);
+
+    let payload = json!({"model-name": "mock-model", "prompt": "hi"});
+    let req = Request::builder()
+        .method("POST")
+        .uri("/chat/completions")
+        .header("content-type", "application/json")
+        .header("authorization", "Bearer user-key")
+        .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+        .unwrap();
+    let resp = app.clone().oneshot(req).await.unwrap();
+    let request_id = resp.headers()["x-request-id"].to_str().unwrap().to_string();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let transcript_request = |uri: String, key: &str| {
+        Request::get(uri)
+            .header("authorization", format!("Bearer {}", key))
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    // Only admins read transcripts
+    let req = transcript_request(format!("/requests/{}/transcript", request_id), "user-key");
+    let resp = app.clone().oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    // The transcript is saved in the background once the stream ends
+    let mut transcript = None;
+    for _ in 0..50 {
+        let req = transcript_request(format!("/requests/{}/transcript", request_id), "admin-key");
+        let resp = app.clone().oneshot(req).await.unwrap();
+        if resp.status() == StatusCode::OK {
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            transcript = Some(serde_json::from_slice::<serde_json::Value>(&body).unwrap());
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    let transcript = transcript.expect("transcript was saved");
+    assert_eq!(transcript["endpoint"], "chat");
+    assert_eq!(transcript["completed"], true);
+    let sent: String = transcript["chunks"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|c| c["data"].as_str().unwrap())
+        .collect();
+    assert_eq!(sent.as_bytes(), &body[..]);
+
+    let req = transcript_request("/requests/unknown/transcript".to_string(), "admin-key");
+    let resp = app.oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_failing_canary_takes_model_out_of_rotation() {
+    let mut config = Config::default();
+    config.canary.failure_threshold = 2;
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let engine = Arc::new(MockEngine::new().with_error_after(0, "CUDA error: illegal memory access"));
+    let state = AppState::new_in_memory(engine, handle, config).await.unwrap();
+    let app = routes::router().with_state(state.clone());
+    let completion = || {
+        Request::builder()
+            .method("POST")
+            .uri("/completions")
+            .header("content-type", "application/json")
+            .body(Body::from(
+                json!({"model": "mock-model", "prompt": "hi"}).to_string(),
+            ))
+            .unwrap()
+    };
+
+    state.run_canary_checks().await;
+    assert!(!state.model_unhealthy("mock-model"));
+    state.run_canary_checks().await;
+    assert!(state.model_unhealthy("mock-model"));
+
+    let resp = app.clone().oneshot(completion()).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["code"], "model_unhealthy");
+
+    let resp = app
+        .oneshot(Request::get("/readiness").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["unhealthy_models"], json!(["mock-model"]));
+
+    // A passing check brings the model back
+    state.model_health.record_success("mock-model");
+    assert!(!state.model_unhealthy("mock-model"));
+}
+
+#[tokio::test]
+async fn test_eval_runs_items_and_datasets() {
+    let dir = std::env::temp_dir().join(format!("llm_inference_eval_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("smoke.jsonl"),
+        "{\"prompt\": \"ping\", \"expected\": \"hello ping\"}\n\n{\"prompt\": \"pong\"}\n",
+    )
+    .unwrap();
+    let mut config = Config::default();
+    config.eval.dataset_dir = Some(dir.clone());
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let state = AppState::new_in_memory(Arc::new(MockEngine::new()), handle, config)
+        .await
+        .unwrap();
+    let app = routes::router().with_state(state);
+    let eval = |body: serde_json::Value| {
+        Request::builder()
+            .method("POST")
+            .uri("/eval")
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+
+    let resp = app
+        .clone()
+        .oneshot(eval(json!({
+            "model": "mock-model",
+            "items": [
+                {"prompt": "ping", "expected": "ping"},
+                {"prompt": "pong", "expected": "nope"},
+                {"prompt": "bare"},
+            ],
+            "concurrency": 2,
+        })))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let run: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(run["concurrency"], 2);
+    let passed: Vec<_> = run["results"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|r| r["passed"].clone())
+        .collect();
+    assert_eq!(passed, vec![json!(true), json!(false), json!(null)]);
+    assert_eq!(run["results"][2]["output"], "hello bare\ndone");
+    let summary = &run["summary"];
+    assert_eq!((summary["items"].clone(), summary["scored"].clone()), (json!(3), json!(2)));
+    assert_eq!(summary["pass_rate"], 0.5);
+    assert_eq!(summary["total_tokens"], 15);
+
+    // Datasets are read from eval.dataset_dir
+    let resp = app
+        .clone()
+        .oneshot(eval(json!({"model": "mock-model", "dataset": "smoke", "match_mode": "exact"})))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let run: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(run["summary"]["items"], 2);
+    assert_eq!(run["results"][0]["passed"], false);
+
+    let resp = app
+        .clone()
+        .oneshot(eval(json!({"model": "mock-model", "dataset": "missing"})))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let resp = app
+        .oneshot(eval(json!({"model": "mock-model", "dataset": "smoke", "items": [{"prompt": "x"}]})))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_eval_items_count_against_the_rate_limit_and_guardrails() {
+    let mut config = Config::default();
+    config.security.enable_auth = true;
+    config.security.api_keys = vec![config::ApiKeyConfig {
+        key: "eval-key".to_string(),
+        name: "ci".to_string(),
+        enabled: true,
+        default_model: Some("mock-model".to_string()),
+        rate_limit_per_minute: Some(3),
+        ..Default::default()
+    }];
+    config.guardrails.rules = vec![config::GuardrailRule {
+        id: "no-credentials".to_string(),
+        category: "security".to_string(),
+        patterns: vec!["BEGIN RSA PRIVATE KEY".to_string()],
+        action: config::GuardrailAction::Block,
+        message: Some("Private keys cannot be sent to the model".to_string()),
+    }];
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let state = AppState::new_in_memory(Arc::new(MockEngine::new()), handle, config)
+        .await
+        .unwrap();
+    let app = routes::router().with_state(state);
+    let eval = |body: serde_json::Value| {
+        Request::post("/eval")
+            .header("content-type", "application/json")
+            .header("authorization", "Bearer eval-key")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+
+    // The model comes from the key; the fourth item is one request over the limit
+    let resp = app
+        .clone()
+        .oneshot(eval(json!({
+            "items": [
+                {"prompt": "one"},
+                {"prompt": "BEGIN RSA PRIVATE KEY"},
+                {"prompt": "three"},
+                {"prompt": "four"},
+            ],
+            "concurrency": 1,
+        })))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let run: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(run["model"], "mock-model");
+    let results = run["results"].as_array().unwrap();
+    assert_eq!(results[0]["output"], "hello one\ndone");
+    assert_eq!(results[1]["error"], "Private keys cannot be sent to the model");
+    assert_eq!(results[2]["output"], "hello three\ndone");
+    assert_eq!(results[3]["error"], "rate limit exceeded");
+
+    // The run used up the key's budget
+    let resp = app
+        .oneshot(eval(json!({"items": [{"prompt": "five"}]})))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn test_eval_items_are_capped_to_the_key() {
+    let state = setup_auth_state(vec![config::ApiKeyConfig {
+        key: "capped-key".to_string(),
+        name: "capped".to_string(),
+        enabled: true,
+        max_tokens: Some(3),
+        ..Default::default()
+    }])
+    .await;
+    let app = routes::router().with_state(state);
+    let payload = json!({"model": "mock-model", "items": [{"prompt": "ping"}], "max_tokens": 100});
+    let req = Request::post("/eval")
+        .header("content-type", "application/json")
+        .header("authorization", "Bearer capped-key")
+        .body(Body::from(payload.to_string()))
+        .unwrap();
+
+    // The mock's five tokens run past the key's cap of three
+    let resp = app.oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let run: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(run["results"][0]["finish_reason"], "length");
+}
+
+#[tokio::test]
+async fn test_eval_items_report_why_they_got_no_slot() {
+    let mut config = Config::default();
+    config.models.max_concurrent_requests = 1;
+    config.overload.max_queue_length = 1;
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let state = AppState::new_in_memory(Arc::new(MockEngine::new()), handle, config)
+        .await
+        .unwrap();
+    let app = routes::router().with_state(state.clone());
+    let _busy = state
+        .concurrency_limiter
+        .acquire("other", None, 0)
+        .await
+        .unwrap();
+    let _queued = state.concurrency_limiter.reserve("other", None, 0).unwrap();
+
+    let req = Request::post("/eval")
+        .header("content-type", "application/json")
+        .body(Body::from(
+            json!({"model": "mock-model", "items": [{"prompt": "one"}]}).to_string(),
+        ))
+        .unwrap();
+    let resp = app.oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let run: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    // Load shedding, not the per-key concurrency limit
+    assert_eq!(run["results"][0]["error"], "server is overloaded; retry after 1 seconds");
+}
+
+#[tokio::test]
+async fn test_request_log_query_and_replay() {
+    let mut config = Config::default();
+    config.request_log.enabled = true;
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let state = AppState::new_in_memory(Arc::new(MockEngine::new()), handle, config)
+        .await
+        .unwrap();
+    let app = routes::router().with_state(state);
+    let get = |uri: String| Request::builder().uri(uri).body(Body::empty()).unwrap();
+
+    let payload = json!({"model": "mock-model", "prompt": "audit me", "max_tokens": 50, "temperature": 0.2});
+    let req = Request::builder()
+        .method("POST")
+        .uri("/completions")
+        .header("content-type", "application/json")
+        .body(Body::from(payload.to_string()))
+        .unwrap();
+    let resp = app.clone().oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let id = resp.headers()["x-request-log-id"].to_str().unwrap().to_string();
+
+    // Entries are written in the background
+    let mut entry = None;
+    for _ in 0..50 {
+        let resp = app.clone().oneshot(get(format!("/admin/requests/{}", id))).await.unwrap();
+        if resp.status() == StatusCode::OK {
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            entry = Some(serde_json::from_slice::<serde_json::Value>(&body).unwrap());
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    let entry = entry.expect("request was logged");
+    assert_eq!(entry["prompt"], "audit me");
+    assert_eq!(entry["response"], "hello audit me\ndone");
+    assert_eq!(entry["params"]["temperature"], 0.2);
+    assert_eq!(entry["completion_tokens"], 5);
+    assert_eq!(entry["finish_reason"], "stop");
+
+    let resp = app.clone().oneshot(get("/admin/requests?model=mock-model".into())).await.unwrap();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let listed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(listed["requests"][0]["id"], id.as_str());
+    let resp = app.clone().oneshot(get("/admin/requests?model=other".into())).await.unwrap();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let listed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(listed["requests"], json!([]));
+
+    let replay = Request::builder()
+        .method("POST")
+        .uri(format!("/admin/requests/{}/replay", id))
+        .body(Body::empty())
+        .unwrap();
+    let resp = app.clone().oneshot(replay).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_ne!(resp.headers()["x-request-log-id"], id.as_str());
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let replayed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(replayed["text"], "hello audit me\ndone");
+
+    let resp = app.oneshot(get("/admin/requests/missing".into())).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_guardrails_refuse_with_structured_refusals_and_redact() {
+    let mut config = Config::default();
+    config.request_log.enabled = true;
+    config.guardrails.rules = vec![
+        config::GuardrailRule {
+            id: "no-credentials".to_string(),
+            category: "security".to_string(),
+            patterns: vec!["BEGIN RSA PRIVATE KEY".to_string()],
+            action: config::GuardrailAction::Block,
+            message: Some("Private keys cannot be sent to the model".to_string()),
+        },
+        config::GuardrailRule {
+            id: "internal-hosts".to_string(),
+            category: "privacy".to_string(),
+            patterns: vec!["corp.internal".to_string()],
+            action: config::GuardrailAction::Redact,
+            message: None,
+        },
+    ];
+    let handle = PrometheusBuilder::new().build_recorder().handle();
+    let engine = Arc::new(MockEngine::new());
+    let state = AppState::new_in_memory(engine.clone(), handle, config)
+        .await
+        .unwrap();
+    let app = routes::router().with_state(state);
+    let post = |uri: &str, body: serde_json::Value| {
+        Request::builder()
+            .method("POST")
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+    let refusal = json!({
+        "category": "security",
+        "policy_id": "no-credentials",
+        "message": "Private keys cannot be sent to the model"
+    });
+
+    let payload = json!({"model": "mock-model", "prompt": "-----begin rsa private key-----"});
+    let resp = app.clone().oneshot(post("/completions", payload)).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let id = resp.headers()["x-request-log-id"].to_str().unwrap().to_string();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let error: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(error["code"], "refused");
+    assert_eq!(error["refusal"], refusal);
+
+    let messages = json!([{"role": "user", "content": "BEGIN RSA PRIVATE KEY"}]);
+    let resp = app
+        .clone()
+        .oneshot(post("/chat/completions", json!({"model-name": "mock-model", "messages": messages})))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let error: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(error["refusal"], refusal);
+    assert_eq!(engine.tokens_generated(), 0);
+
+    // Redacted prompts go through, with the rule reported alongside the effective parameters
+    let payload = json!({"model": "mock-model", "prompt": "ping db.corp.internal"});
+    let resp = app.clone().oneshot(post("/completions", payload)).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let params: serde_json::Value =
+        serde_json::from_str(resp.headers()["x-effective-params"].to_str().unwrap()).unwrap();
+    assert!(params["adjusted"].as_array().unwrap().contains(&json!("prompt")));
+    assert_eq!(params["guardrails"][0]["policy_id"], "internal-hosts");
+    assert_eq!(params["guardrails"][0]["category"], "privacy");
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let completion: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(completion["text"], "hello ping db.[REDACTED]\ndone");
+
+    // The refusal is in the audit trail
+    let mut entry = None;
+    for _ in 0..50 {
+        let get = Request::builder().uri(format!("/admin/requests/{}", id)).body(Body::empty()).unwrap();
+        let resp = app.clone().oneshot(get).await.unwrap();
+        if resp.status() == StatusCode::OK {
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            entry = Some(serde_json::from_slice::<serde_json::Value>(&body).unwrap());
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    let entry = entry.expect("refusal was logged");
+    assert_eq!(entry["refusal"], refusal);
+    assert_eq!(entry["error"], "Private keys cannot be sent to the model");
+    assert_eq!(entry["response"], "");
+}
+
+#[tokio::test]
+async fn test_interrupted_replies_are_saved_as_partial() {
+    use hyper::body::HttpBody;
+
+    let app_with = |engine: MockEngine| async move {
+        let handle = PrometheusBuilder::new().build_recorder().handle();
+        let state = AppState::new_in_memory(Arc::new(engine), handle, Config::default())
+            .await
+            .unwrap();
+        (routes::router().with_state(state.clone()), state)
+    };
+    let chat = |session: &str| {
+        Request::builder()
+            .method("POST")
+            .uri("/chat/completions")
+            .header("content-type", "application/json")
+            .body(Body::from(
+                json!({"model-name": "mock-model", "prompt": "Write a saga", "session-id": session}).to_string(),
+            ))
+            .unwrap()
+    };
+
+    // An engine error keeps the text generated before it
+    let engine = MockEngine::new()
+        .with_tokens(["Once", " upon", " a time"])
+        .with_error_after(2, "out of memory");
+    let (app, state) = app_with(engine).await;
+    let resp = app.oneshot(chat("failing")).await.unwrap();
+    hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let history = state.sessions.lock().await["failing"].clone();
+    let reply = history.last().unwrap();
+    assert_eq!((reply.role.as_str(), reply.content.as_str()), ("assistant", "Once upon"));
+    assert!(reply.partial);
+
+    // So does a client that goes away mid-stream
+    let engine = MockEngine::new()
+        .with_tokens(["Once", " upon", " a time"])
+        .with_token_delay(std::time::Duration::from_millis(50));
+    let (app, state) = app_with(engine).await;
+    let resp = app.oneshot(chat("dropped")).await.unwrap();
+    let mut body = resp.into_body();
+    let first = body.data().await.unwrap().unwrap();
+    assert!(std::str::from_utf8(&first).unwrap().contains("Once"));
+    drop(body);
+    let mut saved = None;
+    for _ in 0..50 {
+        saved = state.sessions.lock().await["dropped"]
+            .last()
+            .filter(|m| m.role == "assistant")
+            .cloned();
+        if saved.is_some() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    let reply = saved.expect("partial reply was saved");
+    assert!(reply.partial);
+    assert!(reply.content.starts_with("Once"));
+
+    // One failing before its first token leaves no empty reply behind, streamed or not
+    for stream in [true, false] {
+        let engine = MockEngine::new().with_error_after(0, "out of memory");
+        let (app, state) = app_with(engine).await;
+        let req = Request::post("/chat/completions")
+            .header("content-type", "application/json")
+            .body(Body::from(
+                json!({"model-name": "mock-model", "prompt": "Write a saga", "session-id": "empty", "stream": stream})
+                    .to_string(),
+            ))
+            .unwrap();
+        let resp = app.oneshot(req).await.unwrap();
+        hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let history = state.sessions.lock().await["empty"].clone();
+        assert_eq!(history.last().unwrap().role, "user", "stream: {}", stream);
+    }
+
+    // A completed reply is not partial
+    let (app, state) = app_with(MockEngine::new()).await;
+    let resp = app.oneshot(chat("complete")).await.unwrap();
+    hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert!(!state.sessions.lock().await["complete"].last().unwrap().partial);
 }